@@ -30,11 +30,16 @@ pub enum Expression {
         name: String,
         args: Vec<Expression>,
     },
-    /// Indexing into tensors: a[i], a[i][j]
+    /// Indexing into tensors: a[i], a[i][j], a[i, j], a[1:3, :]
     Index {
         target: Box<Expression>,
         indices: Vec<Expression>,
     },
+    /// Range inside index brackets (e.g., 1:3, :, 2:); bounds are optional
+    Range {
+        start: Option<Box<Expression>>,
+        end: Option<Box<Expression>>,
+    },
     /// Type cast (e.g., x as f64)
     Cast {
         expr: Box<Expression>,
@@ -86,10 +91,26 @@ pub enum Statement {
     },
     /// Minimize statement (e.g., minimize loss)
     Minimize(Expression),
-    /// Optimize loop (e.g., optimize(model) until loss < 0.01 { ... })
+    /// Optimize loop (e.g., optimize(model) with adam(lr=0.01) schedule cosine(period=500) max_iter 5000 until loss < 0.01 { ... })
     OptimizeLoop {
-        target: String,
-        condition: Expression,
+        /// Learnables updated by the loop: `optimize(w1, b1)`
+        targets: Vec<String>,
+        /// `with name(key=value, ...)`; None falls back to the deprecated hyperparameter variables
+        optimizer: Option<OptimizerSpec>,
+        /// `schedule name(key=value, ...)`; None keeps the learning rate constant
+        schedule: Option<ScheduleSpec>,
+        /// `grad name(key=value) [for p1, p2]` clauses, applied in order
+        grad_transforms: Vec<GradTransformSpec>,
+        /// `group(lr=...) for p1, p2` clauses
+        param_groups: Vec<ParamGroupSpec>,
+        /// `stop name(key=value) [on expr]` clauses; the first one to trigger ends the loop
+        stop_criteria: Vec<StopSpec>,
+        /// `max_iter N`
+        max_iter: Option<usize>,
+        /// `until expr`; optional when the header has `max_iter` or `stop` clauses
+        condition: Option<Expression>,
+        /// `-> name`: binds `name.iterations`, `name.reason`, ... after the loop
+        stats: Option<String>,
         body: Vec<Statement>,
     },
     /// Expression statement (e.g., print(...))
@@ -120,6 +141,14 @@ pub enum Statement {
     Free {
         name: String,
     },
+    /// Exclude learnables from optimizer updates: freeze w1, b1;
+    Freeze {
+        names: Vec<String>,
+    },
+    /// Undo freeze: unfreeze w1, b1;
+    Unfreeze {
+        names: Vec<String>,
+    },
     /// Reallocation: realloc name = [new_shape];
     /// Resizes a heap-allocated tensor (preserves data where possible)
     Realloc {
@@ -129,37 +158,201 @@ pub enum Statement {
     /// Reset optimizer state: reset_optimizer();
     /// Clears Adam m/v/t state to restart optimization from scratch
     ResetOptimizer,
-    /// Load tensor from CSV file: let data = load_csv("path.csv");
+    /// Seed the random number generator: seed(42);
+    /// Makes every subsequent random draw reproducible
+    Seed(Expression),
+    /// Load tensor from CSV file: load_csv data = "path.csv"; or
+    /// let X = load_csv("path.csv", header=true, columns=["x1", "x2"]);
+    /// `let (X, y) = load_csv(..., labels=["y"]);` binds the label columns to `labels_name`
     LoadCsv {
         name: String,
+        labels_name: Option<String>,
         path: String,
+        options: CsvOptions,
     },
-    /// Save tensor to CSV file: save_csv(tensor, "path.csv");
+    /// Save tensor to CSV file: save_csv tensor, "path.csv"; with an optional header=["a", "b"]
     SaveCsv {
         tensor: Expression,
         path: String,
+        header: Vec<String>,
     },
-    /// Load tensors from Safetensors file: let model = load_safetensors("model.safetensors");
+    /// Load all tensors from Safetensors file: load_safetensors model = "model.safetensors";
+    /// binds each tensor as `model.<tensor name>`
     LoadSafetensors {
         name: String,
         path: String,
     },
-    /// Save tensors to Safetensors file: save_safetensors(tensors, "model.safetensors");
+    /// Save tensors to Safetensors file: save_safetensors { W: W }, "model.safetensors";
+    /// An optional dtype ("F32", "BF16", ...) follows the path; the default is F64
     SaveSafetensors {
         tensors: Vec<(String, Expression)>,
         path: String,
+        dtype: Option<String>,
+        /// `__metadata__` entries: `, metadata { format: "pt" }`
+        metadata: Vec<(String, String)>,
+    },
+    /// Load a NumPy array: load_npy X = "x.npy";
+    LoadNpy {
+        name: String,
+        path: String,
+    },
+    /// Save a tensor as a NumPy array: save_npy X, "x.npy"; with an optional dtype such as "<f4"
+    SaveNpy {
+        tensor: Expression,
+        path: String,
+        dtype: Option<String>,
+    },
+    /// Load all arrays of a NumPy archive: load_npz data = "data.npz";
+    /// binds each array as `data.<array name>`
+    LoadNpz {
+        name: String,
+        path: String,
+    },
+    /// Save tensors as an uncompressed NumPy archive: save_npz { X: X, y: y }, "data.npz";
+    SaveNpz {
+        arrays: Vec<(String, Expression)>,
+        path: String,
+        dtype: Option<String>,
+    },
+    /// Save parameters, optimizer state and RNG: checkpoint("run.ckpt");
+    /// Inside an optimize body, checkpoint("run.ckpt", 100); saves every 100 steps and at the end
+    Checkpoint {
+        path: String,
+        every: Option<usize>,
+    },
+    /// Restore a checkpoint: resume("run.ckpt");
+    Resume {
+        path: String,
+    },
+    /// Streaming dataset over a .csv, .npy or .safetensors file, read batch by batch:
+    /// dataset train = "big.csv", header=true, shuffle=true, seed=7, epochs=3;
+    Dataset {
+        name: String,
+        path: String,
+        options: DatasetOptions,
+    },
+    /// Build an ONNX model into the graph: let net = import_onnx("m.onnx"[, X, ...]);
+    /// binds `net` to its output and `net.<initializer>` to learnables
+    ImportOnnx {
+        name: String,
+        path: String,
+        inputs: Vec<Expression>,
     },
     /// Batch loop: batch item, index in data with batch_size { body }
-    /// Iterates over data in batches
+    /// Iterates over data in batches; `batch (x, y), i, epoch in (X, Y) with batch_size 32
+    /// shuffle epochs 10 { }` batches several sources in lockstep
     BatchLoop {
-        item_name: String,
+        /// One name per source
+        item_names: Vec<String>,
         index_name: Option<String>,
-        data: Expression,
+        epoch_name: Option<String>,
+        data: Vec<Expression>,
         batch_size: Expression,
+        options: BatchOptions,
         body: Vec<Statement>,
     },
 }
 
+/// Optimizer named in an optimize header: `with adam(lr=0.01, beta1=0.9)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptimizerSpec {
+    pub name: String,
+    /// Hyperparameters in source order
+    pub params: Vec<(String, f64)>,
+}
+
+/// Learning-rate schedule named in an optimize header: `schedule step(step_size=100, gamma=0.5)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleSpec {
+    pub name: String,
+    /// Schedule parameters in source order
+    pub params: Vec<(String, f64)>,
+}
+
+/// Gradient transform named in an optimize header: `grad clip_norm(max_norm=1.0) for w1, b1`
+#[derive(Debug, Clone, PartialEq)]
+pub struct GradTransformSpec {
+    pub name: String,
+    pub params: Vec<(String, f64)>,
+    /// Learnables listed after `for`; empty applies the transform to every learnable
+    pub targets: Vec<String>,
+}
+
+/// Parameter group in an optimize header: `group(lr=0.001) for w2, b2`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamGroupSpec {
+    pub params: Vec<(String, f64)>,
+    pub targets: Vec<String>,
+}
+
+/// Stopping criterion in an optimize header: `stop patience(patience=10) on val_loss`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopSpec {
+    pub name: String,
+    pub params: Vec<(String, f64)>,
+    /// Expression after `on`, evaluated every iteration; only `patience` takes one
+    pub monitor: Option<Expression>,
+}
+
+/// Options of `load_csv("data.csv", header=true, delimiter=";", columns=["x1", "x2"], na=0.0)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvOptions {
+    /// The first record holds column names
+    pub header: bool,
+    pub delimiter: char,
+    /// Columns to load; empty loads every column that is not a label
+    pub columns: Vec<CsvColumn>,
+    /// Columns loaded as a separate label tensor
+    pub labels: Vec<CsvColumn>,
+    /// Value for empty and NA cells; `None` loads them as NaN
+    pub na: Option<f64>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions { header: false, delimiter: ',', columns: Vec::new(), labels: Vec::new(), na: None }
+    }
+}
+
+/// Options of a `dataset` statement
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatasetOptions {
+    /// Column selection and parsing of CSV datasets
+    pub csv: CsvOptions,
+    /// Tensor of a safetensors file whose rows are streamed
+    pub tensor: Option<String>,
+    /// Visit the rows in a new random order every epoch
+    pub shuffle: bool,
+    /// Seed of the shuffle; drawn from the program RNG if absent
+    pub seed: Option<u64>,
+    /// Skip the last batch of an epoch if it is smaller than the batch size
+    pub drop_last: bool,
+    pub epochs: usize,
+}
+
+impl Default for DatasetOptions {
+    fn default() -> Self {
+        DatasetOptions { csv: CsvOptions::default(), tensor: None, shuffle: false, seed: None, drop_last: false, epochs: 1 }
+    }
+}
+
+/// Options after the batch size of a batch loop: `shuffle(seed=7) drop_last epochs 10`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BatchOptions {
+    pub shuffle: bool,
+    /// Seed of the shuffle; drawn from the program RNG if absent
+    pub seed: Option<u64>,
+    pub drop_last: bool,
+    pub epochs: Option<Expression>,
+}
+
+/// CSV column selected by header name or zero-based index
+#[derive(Debug, Clone, PartialEq)]
+pub enum CsvColumn {
+    Name(String),
+    Index(usize),
+}
+
 /// Function definition
 #[derive(Debug, Clone, PartialEq)]
 pub struct FunctionDef {
@@ -228,6 +421,16 @@ impl fmt::Display for Expression {
                 let idx_str = indices.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ");
                 write!(f, "{}[{}]", target, idx_str)
             }
+            Expression::Range { start, end } => {
+                if let Some(start) = start {
+                    write!(f, "{}", start)?;
+                }
+                write!(f, ":")?;
+                if let Some(end) = end {
+                    write!(f, "{}", end)?;
+                }
+                Ok(())
+            }
             Expression::Cast { expr, target_type } => {
                 write!(f, "({} as {})", expr, target_type)
             }
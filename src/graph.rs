@@ -1,7 +1,8 @@
-use std::collections::HashMap;
-use crate::ast::{BinaryOperator, Expression, Statement, UnaryOperator};
-use rand::Rng;
-use rand_distr::{Normal, Distribution};
+use std::collections::{HashMap, HashSet, VecDeque};
+use crate::ast::{BinaryOperator, CsvColumn, CsvOptions, DatasetOptions, Expression, GradTransformSpec, OptimizerSpec, ParamGroupSpec, ScheduleSpec, Statement, StopSpec, UnaryOperator};
+use rand_chacha::ChaCha12Rng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Normal, Distribution, StandardNormal};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct NodeId(usize);
@@ -168,12 +169,42 @@ pub enum NodeType {
     FreedTensor(String),
 }
 
+/// Execution mode of a graph. Eval disables dropout and freezes batch_norm statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExecutionMode {
+    #[default]
+    Train,
+    Eval,
+}
+
 /// Optimizer type for training
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OptimizerType {
     SGD,
     Adam,
     RMSprop,
+    /// SGD with heavy-ball momentum
+    Momentum,
+    /// SGD with Nesterov momentum
+    Nesterov,
+    /// Adam with decoupled weight decay
+    AdamW,
+    Adagrad,
+    Adadelta,
+    /// Sign-based update with interpolated momentum (Chen et al., 2023)
+    Lion,
+    /// Limited-memory BFGS with a strong Wolfe line search
+    LBFGS,
+    /// Truncated Newton: conjugate gradient on Hessian-vector products, then a line search
+    NewtonCG,
+}
+
+impl OptimizerType {
+    /// Second-order optimizers re-evaluate the objective several times per step, so they are
+    /// driven by `optimize_step_second_order` instead of a precomputed gradient
+    pub fn is_second_order(self) -> bool {
+        matches!(self, OptimizerType::LBFGS | OptimizerType::NewtonCG)
+    }
 }
 
 impl Default for OptimizerType {
@@ -190,6 +221,13 @@ pub struct OptimizerConfig {
     pub beta1: f64,        // Adam: momentum decay (default 0.9)
     pub beta2: f64,        // Adam/RMSprop: squared gradient decay (default 0.999 for Adam, 0.9 for RMSprop)
     pub epsilon: f64,      // Small constant to prevent division by zero (default 1e-8)
+    pub momentum: f64,     // Momentum/Nesterov: velocity decay (default 0.9)
+    pub weight_decay: f64, // AdamW/Lion: decoupled weight decay (default 0.0)
+    pub history_size: usize, // L-BFGS: number of stored correction pairs (default 10)
+    pub schedule: LrSchedule, // Learning-rate schedule applied on top of learning_rate (default constant)
+    pub grad_transforms: Vec<GradTransform>, // Applied in order to the gradients before each update
+    pub params: Vec<String>, // Learnables updated by the step; empty means every learnable
+    pub param_groups: Vec<ParamGroup>, // Learnables trained with their own learning rate
 }
 
 impl Default for OptimizerConfig {
@@ -200,6 +238,13 @@ impl Default for OptimizerConfig {
             beta1: 0.9,
             beta2: 0.999,
             epsilon: 1e-8,
+            momentum: 0.9,
+            weight_decay: 0.0,
+            history_size: 10,
+            schedule: LrSchedule::default(),
+            grad_transforms: Vec::new(),
+            params: Vec::new(),
+            param_groups: Vec::new(),
         }
     }
 }
@@ -220,6 +265,7 @@ impl OptimizerConfig {
             beta1,
             beta2,
             epsilon,
+            ..Default::default()
         }
     }
 
@@ -230,20 +276,708 @@ impl OptimizerConfig {
             beta1: 0.0, // Not used for RMSprop
             beta2,
             epsilon,
+            ..Default::default()
+        }
+    }
+
+    pub fn momentum(learning_rate: f64, momentum: f64) -> Self {
+        OptimizerConfig {
+            optimizer_type: OptimizerType::Momentum,
+            learning_rate,
+            momentum,
+            ..Default::default()
+        }
+    }
+
+    pub fn nesterov(learning_rate: f64, momentum: f64) -> Self {
+        OptimizerConfig {
+            optimizer_type: OptimizerType::Nesterov,
+            learning_rate,
+            momentum,
+            ..Default::default()
+        }
+    }
+
+    pub fn adamw(learning_rate: f64, beta1: f64, beta2: f64, epsilon: f64, weight_decay: f64) -> Self {
+        OptimizerConfig {
+            optimizer_type: OptimizerType::AdamW,
+            learning_rate,
+            beta1,
+            beta2,
+            epsilon,
+            weight_decay,
+            ..Default::default()
+        }
+    }
+
+    pub fn adagrad(learning_rate: f64, epsilon: f64) -> Self {
+        OptimizerConfig {
+            optimizer_type: OptimizerType::Adagrad,
+            learning_rate,
+            epsilon,
+            ..Default::default()
+        }
+    }
+
+    /// Adadelta; `rho` (stored in beta2) decays both accumulators. A learning rate of 1.0
+    /// gives the original, learning-rate-free algorithm.
+    pub fn adadelta(learning_rate: f64, rho: f64, epsilon: f64) -> Self {
+        OptimizerConfig {
+            optimizer_type: OptimizerType::Adadelta,
+            learning_rate,
+            beta2: rho,
+            epsilon,
+            ..Default::default()
+        }
+    }
+
+    pub fn lion(learning_rate: f64, beta1: f64, beta2: f64, weight_decay: f64) -> Self {
+        OptimizerConfig {
+            optimizer_type: OptimizerType::Lion,
+            learning_rate,
+            beta1,
+            beta2,
+            weight_decay,
+            ..Default::default()
+        }
+    }
+
+    /// L-BFGS; `learning_rate` is the initial trial step of the line search (1.0 is the usual choice)
+    pub fn lbfgs(learning_rate: f64, history_size: usize) -> Self {
+        OptimizerConfig {
+            optimizer_type: OptimizerType::LBFGS,
+            learning_rate,
+            history_size,
+            ..Default::default()
+        }
+    }
+
+    pub fn newton_cg(learning_rate: f64) -> Self {
+        OptimizerConfig {
+            optimizer_type: OptimizerType::NewtonCG,
+            learning_rate,
+            ..Default::default()
+        }
+    }
+
+    /// Learning rate of the next step under `schedule`
+    pub fn learning_rate_at(&self, state: &OptimizerState) -> f64 {
+        self.schedule.learning_rate(self.learning_rate, state)
+    }
+
+    /// This configuration with the schedule folded into `learning_rate` (and scaled into every
+    /// group's rate) for the next step
+    pub fn scheduled(&self, state: &OptimizerState) -> OptimizerConfig {
+        let lr = self.learning_rate_at(state);
+        let factor = lr / self.learning_rate;
+        OptimizerConfig {
+            learning_rate: lr,
+            schedule: LrSchedule::default(),
+            param_groups: self.param_groups.iter()
+                .map(|group| ParamGroup { learning_rate: group.learning_rate * factor, ..group.clone() })
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Learning rate for the learnable `name`, or None if this configuration does not update it
+    pub fn learning_rate_for(&self, name: &str) -> Option<f64> {
+        if !self.params.is_empty() && !self.params.iter().any(|p| p == name) {
+            return None;
+        }
+        let group = self.param_groups.iter().find(|group| group.params.iter().any(|p| p == name));
+        Some(group.map_or(self.learning_rate, |group| group.learning_rate))
+    }
+
+    /// Build the configuration for an optimize header such as `with adam(lr=0.01, beta1=0.9)`.
+    /// Unknown optimizers, parameters the optimizer does not use, duplicates and out-of-range
+    /// values are rejected. Omitted parameters keep their defaults (lr 0.01, or 1.0 for the
+    /// second-order optimizers).
+    pub fn from_spec(spec: &OptimizerSpec) -> Result<Self, String> {
+        use OptimizerType::*;
+        // Accepted parameters besides `lr`; `learning_rate` and `epsilon` are aliases of `lr`/`eps`
+        let (optimizer_type, accepted): (OptimizerType, &[&str]) = match spec.name.as_str() {
+            "sgd" => (SGD, &[]),
+            "momentum" => (Momentum, &["momentum"]),
+            "nesterov" => (Nesterov, &["momentum"]),
+            "adam" => (Adam, &["beta1", "beta2", "eps"]),
+            "adamw" => (AdamW, &["beta1", "beta2", "eps", "weight_decay"]),
+            "rmsprop" => (RMSprop, &["beta2", "rho", "eps"]),
+            "adagrad" => (Adagrad, &["eps"]),
+            "adadelta" => (Adadelta, &["rho", "eps"]),
+            "lion" => (Lion, &["beta1", "beta2", "weight_decay"]),
+            "lbfgs" => (LBFGS, &["history_size"]),
+            "newton_cg" => (NewtonCG, &[]),
+            other => {
+                return Err(format!(
+                    "Unknown optimizer '{}' (expected sgd, momentum, nesterov, adam, adamw, rmsprop, \
+                     adagrad, adadelta, lion, lbfgs or newton_cg)",
+                    other
+                ))
+            }
+        };
+
+        let mut config = OptimizerConfig {
+            optimizer_type,
+            learning_rate: if optimizer_type.is_second_order() { 1.0 } else { 0.01 },
+            beta2: match optimizer_type {
+                RMSprop | Adadelta => 0.9,
+                Lion => 0.99,
+                _ => 0.999,
+            },
+            epsilon: if optimizer_type == Adadelta { 1e-6 } else { 1e-8 },
+            weight_decay: if optimizer_type == AdamW { 0.01 } else { 0.0 },
+            ..Default::default()
+        };
+
+        let mut assigned: Vec<&str> = Vec::new();
+        for (key, value) in &spec.params {
+            let key = match key.as_str() {
+                "learning_rate" => "lr",
+                "epsilon" => "eps",
+                k => k,
+            };
+            if key != "lr" && !accepted.contains(&key) {
+                let mut names = vec!["lr"];
+                names.extend_from_slice(accepted);
+                return Err(format!("{} does not take '{}' (accepted: {})", spec.name, key, names.join(", ")));
+            }
+            // rho is the RMSprop/Adadelta name for beta2
+            let field = if key == "rho" { "beta2" } else { key };
+            if assigned.contains(&field) {
+                return Err(format!("{} is given more than once for {}", key, spec.name));
+            }
+            assigned.push(field);
+
+            let value = *value;
+            let in_unit = (0.0..1.0).contains(&value);
+            match field {
+                "lr" if value.is_finite() && value > 0.0 => config.learning_rate = value,
+                "beta1" if in_unit => config.beta1 = value,
+                "beta2" if in_unit => config.beta2 = value,
+                "momentum" if in_unit => config.momentum = value,
+                "eps" if value.is_finite() && value > 0.0 => config.epsilon = value,
+                "weight_decay" if value.is_finite() && value >= 0.0 => config.weight_decay = value,
+                "history_size" if value >= 1.0 && value.fract() == 0.0 => config.history_size = value as usize,
+                _ => {
+                    let expected = match field {
+                        "beta1" | "beta2" | "momentum" => "in [0, 1)",
+                        "weight_decay" => ">= 0",
+                        "history_size" => "a positive integer",
+                        _ => "> 0",
+                    };
+                    return Err(format!("{} {} must be {}, got {}", spec.name, key, expected, value));
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Shape of a learning-rate schedule; `s` is the step count after warmup
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ScheduleKind {
+    #[default]
+    Constant,
+    /// lr * gamma^floor(s / step_size)
+    Step { step_size: usize, gamma: f64 },
+    /// lr * gamma^s
+    Exponential { gamma: f64 },
+    /// Cosine annealing from lr to min_lr over `period` steps, restarting with the period
+    /// multiplied by `t_mult` after each cycle (SGDR)
+    Cosine { period: usize, t_mult: f64, min_lr: f64 },
+    /// Multiply by `factor` whenever the loss has not improved by a relative `threshold` for
+    /// `patience` steps, never going below min_lr
+    Plateau { factor: f64, patience: usize, threshold: f64, min_lr: f64 },
+}
+
+/// Learning-rate schedule, evaluated from the optimizer step count `OptimizerState::t`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LrSchedule {
+    pub kind: ScheduleKind,
+    /// Linear warmup: step t < warmup uses lr * (t + 1) / warmup
+    pub warmup: usize,
+}
+
+impl LrSchedule {
+    /// Build a schedule from an optimize header such as `schedule cosine(period=100, warmup=10)`,
+    /// validating it like `OptimizerConfig::from_spec`
+    pub fn from_spec(spec: &ScheduleSpec) -> Result<Self, String> {
+        let accepted: &[&str] = match spec.name.as_str() {
+            "constant" => &[],
+            "step" => &["step_size", "gamma"],
+            "exponential" => &["gamma"],
+            "cosine" => &["period", "t_mult", "min_lr"],
+            "plateau" => &["factor", "patience", "threshold", "min_lr"],
+            other => {
+                return Err(format!(
+                    "Unknown schedule '{}' (expected constant, step, exponential, cosine or plateau)",
+                    other
+                ))
+            }
+        };
+
+        let mut values: HashMap<&str, f64> = HashMap::new();
+        for (key, value) in &spec.params {
+            let key = key.as_str();
+            if key != "warmup" && !accepted.contains(&key) {
+                let mut names = vec!["warmup"];
+                names.extend_from_slice(accepted);
+                return Err(format!("{} schedule does not take '{}' (accepted: {})", spec.name, key, names.join(", ")));
+            }
+            if values.insert(key, *value).is_some() {
+                return Err(format!("{} is given more than once for the {} schedule", key, spec.name));
+            }
+            let valid = match key {
+                "warmup" | "patience" => *value >= 0.0 && value.fract() == 0.0,
+                "step_size" | "period" => *value >= 1.0 && value.fract() == 0.0,
+                "gamma" | "factor" => *value > 0.0 && *value <= 1.0,
+                "t_mult" => *value >= 1.0 && value.is_finite(),
+                _ => *value >= 0.0 && value.is_finite(),
+            };
+            if !valid {
+                let expected = match key {
+                    "warmup" | "patience" => "a non-negative integer",
+                    "step_size" | "period" => "a positive integer",
+                    "gamma" | "factor" => "in (0, 1]",
+                    "t_mult" => ">= 1",
+                    _ => ">= 0",
+                };
+                return Err(format!("{} schedule {} must be {}, got {}", spec.name, key, expected, value));
+            }
+        }
+
+        let get = |key: &str, default: f64| values.get(key).copied().unwrap_or(default);
+        let kind = match spec.name.as_str() {
+            "step" => ScheduleKind::Step { step_size: get("step_size", 100.0) as usize, gamma: get("gamma", 0.1) },
+            "exponential" => ScheduleKind::Exponential { gamma: get("gamma", 0.99) },
+            "cosine" => ScheduleKind::Cosine {
+                period: get("period", 100.0) as usize,
+                t_mult: get("t_mult", 1.0),
+                min_lr: get("min_lr", 0.0),
+            },
+            "plateau" => ScheduleKind::Plateau {
+                factor: get("factor", 0.1),
+                patience: get("patience", 10.0) as usize,
+                threshold: get("threshold", 1e-4),
+                min_lr: get("min_lr", 0.0),
+            },
+            _ => ScheduleKind::Constant,
+        };
+        Ok(LrSchedule { kind, warmup: get("warmup", 0.0) as usize })
+    }
+
+    /// Learning rate for the step after `state.t` completed steps
+    pub fn learning_rate(&self, base_lr: f64, state: &OptimizerState) -> f64 {
+        let t = state.t;
+        if t < self.warmup {
+            return base_lr * (t + 1) as f64 / self.warmup as f64;
+        }
+        let s = t - self.warmup;
+        match self.kind {
+            ScheduleKind::Constant => base_lr,
+            ScheduleKind::Step { step_size, gamma } => base_lr * gamma.powi((s / step_size) as i32),
+            ScheduleKind::Exponential { gamma } => base_lr * gamma.powi(s as i32),
+            ScheduleKind::Cosine { period, t_mult, min_lr } => {
+                // Cycle i lasts period * t_mult^i steps and starts after the geometric sum of the
+                // earlier cycles, so the current one is found from a logarithm
+                let (p, s) = (period as f64, s as f64);
+                let (pos, cycle_len) = if t_mult == 1.0 {
+                    (s % p, p)
+                } else {
+                    let start = |i: f64| p * (t_mult.powf(i) - 1.0) / (t_mult - 1.0);
+                    let mut i = (1.0 + s * (t_mult - 1.0) / p).log(t_mult).floor();
+                    // Rounding can put a step at a cycle boundary into the neighbouring cycle
+                    if start(i) > s {
+                        i -= 1.0;
+                    } else if start(i + 1.0) <= s {
+                        i += 1.0;
+                    }
+                    (s - start(i), p * t_mult.powf(i))
+                };
+                min_lr + (base_lr - min_lr) * (1.0 + (std::f64::consts::PI * pos / cycle_len).cos()) / 2.0
+            }
+            ScheduleKind::Plateau { factor, min_lr, .. } => {
+                (base_lr * factor.powi(state.plateau_reductions as i32)).max(min_lr)
+            }
+        }
+    }
+}
+
+/// Gradient transform applied between `backward_pass` and the optimizer update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GradTransformKind {
+    /// Clamp every gradient element to [-max, max]
+    ClipValue(f64),
+    /// Rescale the gradients so that their joint L2 norm is at most max_norm
+    ClipNorm(f64),
+    /// Add weight * sign(param) (L1 penalty)
+    L1(f64),
+    /// Add weight * param (L2 penalty)
+    L2(f64),
+    /// Add N(0, std) noise drawn from the graph RNG
+    Noise(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GradTransform {
+    pub kind: GradTransformKind,
+    /// Names of the learnables it applies to; empty means every learnable
+    pub params: Vec<String>,
+}
+
+impl GradTransform {
+    /// Build a transform from an optimize header clause such as `grad clip_norm(max_norm=1.0)`
+    pub fn from_spec(spec: &GradTransformSpec) -> Result<Self, String> {
+        let (key, expected, valid): (&str, &str, fn(f64) -> bool) = match spec.name.as_str() {
+            "clip_value" => ("max", "> 0", |v| v > 0.0),
+            "clip_norm" => ("max_norm", "> 0", |v| v > 0.0),
+            "l1" | "l2" => ("weight", ">= 0", |v| v >= 0.0),
+            "noise" => ("std", ">= 0", |v| v >= 0.0),
+            other => {
+                return Err(format!(
+                    "Unknown gradient transform '{}' (expected clip_value, clip_norm, l1, l2 or noise)",
+                    other
+                ))
+            }
+        };
+        let value = match spec.params.as_slice() {
+            [(k, v)] if k == key => *v,
+            _ => return Err(format!("{} takes exactly one parameter, {}", spec.name, key)),
+        };
+        if !valid(value) || !value.is_finite() {
+            return Err(format!("{} {} must be {}, got {}", spec.name, key, expected, value));
+        }
+        let kind = match spec.name.as_str() {
+            "clip_value" => GradTransformKind::ClipValue(value),
+            "clip_norm" => GradTransformKind::ClipNorm(value),
+            "l1" => GradTransformKind::L1(value),
+            "l2" => GradTransformKind::L2(value),
+            _ => GradTransformKind::Noise(value),
+        };
+        Ok(GradTransform { kind, params: spec.targets.clone() })
+    }
+}
+
+/// Learnables trained with their own learning rate: `group(lr=0.001) for w2, b2`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamGroup {
+    pub params: Vec<String>,
+    pub learning_rate: f64,
+}
+
+impl ParamGroup {
+    pub fn from_spec(spec: &ParamGroupSpec) -> Result<Self, String> {
+        let learning_rate = match spec.params.as_slice() {
+            [(key, value)] if key == "lr" || key == "learning_rate" => *value,
+            _ => return Err("group takes exactly one parameter, lr".to_string()),
+        };
+        if !(learning_rate.is_finite() && learning_rate > 0.0) {
+            return Err(format!("group lr must be > 0, got {}", learning_rate));
+        }
+        if spec.targets.is_empty() {
+            return Err("group needs 'for' followed by the learnables it applies to".to_string());
+        }
+        Ok(ParamGroup { params: spec.targets.clone(), learning_rate })
+    }
+}
+
+/// Built-in stopping criterion of an optimize loop, from a `stop name(...)` header clause
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopCriterion {
+    /// Relative loss change over `window` steps below `tol`
+    RelImprovement { tol: f64, window: usize },
+    /// Global gradient norm below `tol`
+    GradNorm { tol: f64 },
+    /// No improvement of the monitored value by more than `min_delta` for `patience` steps
+    Early { patience: usize, min_delta: f64, restore_best: bool },
+    /// Wall-clock budget in seconds
+    TimeLimit { seconds: f64 },
+}
+
+impl StopCriterion {
+    pub fn from_spec(spec: &StopSpec) -> Result<Self, String> {
+        let (allowed, required): (&[&str], &str) = match spec.name.as_str() {
+            "rel_improvement" => (&["tol", "window"], "tol"),
+            "grad_norm" => (&["tol"], "tol"),
+            "early" => (&["patience", "min_delta", "restore_best"], "patience"),
+            "time_limit" => (&["seconds"], "seconds"),
+            other => {
+                return Err(format!(
+                    "Unknown stop criterion '{}' (expected rel_improvement, grad_norm, early or time_limit)",
+                    other
+                ))
+            }
+        };
+        let mut values: HashMap<&str, f64> = HashMap::new();
+        for (key, value) in &spec.params {
+            if !allowed.contains(&key.as_str()) {
+                return Err(format!("stop {} does not take '{}' (accepted: {})", spec.name, key, allowed.join(", ")));
+            }
+            if values.insert(key.as_str(), *value).is_some() {
+                return Err(format!("stop {} parameter {} is given more than once", spec.name, key));
+            }
+            if !value.is_finite() {
+                return Err(format!("stop {} {} must be finite, got {}", spec.name, key, value));
+            }
+        }
+        let Some(&primary) = values.get(required) else {
+            return Err(format!("stop {} needs {}", spec.name, required));
+        };
+        let count = |key: &str, value: f64| {
+            if value >= 1.0 && value.fract() == 0.0 {
+                Ok(value as usize)
+            } else {
+                Err(format!("stop {} {} must be a positive integer, got {}", spec.name, key, value))
+            }
+        };
+        let positive = |key: &str, value: f64| {
+            if value > 0.0 {
+                Ok(value)
+            } else {
+                Err(format!("stop {} {} must be > 0, got {}", spec.name, key, value))
+            }
+        };
+        if spec.name == "early" {
+            if spec.monitor.is_none() {
+                return Err("stop early needs 'on' followed by the value to monitor".to_string());
+            }
+        } else if spec.monitor.is_some() {
+            return Err(format!("stop {} does not take 'on'", spec.name));
+        }
+
+        match spec.name.as_str() {
+            "rel_improvement" => Ok(StopCriterion::RelImprovement {
+                tol: positive("tol", primary)?,
+                window: count("window", values.get("window").copied().unwrap_or(1.0))?,
+            }),
+            "grad_norm" => Ok(StopCriterion::GradNorm { tol: positive("tol", primary)? }),
+            "early" => {
+                let min_delta = values.get("min_delta").copied().unwrap_or(0.0);
+                if min_delta < 0.0 {
+                    return Err(format!("stop early min_delta must be >= 0, got {}", min_delta));
+                }
+                let restore_best = match values.get("restore_best").copied().unwrap_or(0.0) {
+                    v if v == 0.0 || v == 1.0 => v == 1.0,
+                    v => return Err(format!("stop early restore_best must be 0 or 1, got {}", v)),
+                };
+                Ok(StopCriterion::Early { patience: count("patience", primary)?, min_delta, restore_best })
+            }
+            _ => Ok(StopCriterion::TimeLimit { seconds: positive("seconds", primary)? }),
+        }
+    }
+}
+
+/// Why an optimize loop ended; `code()` is the value bound to `stats.reason`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// Not run: `--resume` continues from a checkpoint written after this loop
+    Skipped,
+    Until,
+    MaxIter,
+    RelImprovement,
+    GradNorm,
+    Early,
+    TimeLimit,
+}
+
+impl StopReason {
+    pub fn code(self) -> f64 {
+        match self {
+            StopReason::Skipped => 0.0,
+            StopReason::Until => 1.0,
+            StopReason::MaxIter => 2.0,
+            StopReason::RelImprovement => 3.0,
+            StopReason::GradNorm => 4.0,
+            StopReason::Early => 5.0,
+            StopReason::TimeLimit => 6.0,
+        }
+    }
+
+    /// The loop reached a point it considers optimal, as opposed to running out of budget or
+    /// patience
+    pub fn converged(self) -> bool {
+        matches!(self, StopReason::Until | StopReason::RelImprovement | StopReason::GradNorm)
+    }
+}
+
+/// Evaluates the `stop` criteria of one optimize loop, step by step
+#[derive(Debug, Clone)]
+pub struct StopMonitor {
+    criteria: Vec<StopCriterion>,
+    /// Node of the `on` expression of `stop early`
+    monitor: Option<NodeId>,
+    started: std::time::Instant,
+    /// Recent losses, oldest first, for `rel_improvement`
+    losses: VecDeque<f64>,
+    best_loss: f64,
+    best_monitored: f64,
+    /// Steps since the monitored value last improved
+    wait: usize,
+    improved: bool,
+    /// Flattened learnables at the best monitored value and the loss there, for `restore_best`
+    best_params: Option<(Vec<f64>, f64)>,
+}
+
+/// The part of a `StopMonitor` that a checkpoint carries over to the resumed loop. The time
+/// limit is measured from the resume, not from the original start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopProgress {
+    pub losses: Vec<f64>,
+    pub best_loss: f64,
+    pub best_monitored: f64,
+    pub wait: usize,
+    pub best_params: Option<(Vec<f64>, f64)>,
+}
+
+impl StopMonitor {
+    pub fn new(criteria: Vec<StopCriterion>, monitor: Option<NodeId>) -> Self {
+        StopMonitor {
+            criteria,
+            monitor,
+            started: std::time::Instant::now(),
+            losses: VecDeque::new(),
+            best_loss: f64::INFINITY,
+            best_monitored: f64::INFINITY,
+            wait: 0,
+            improved: false,
+            best_params: None,
+        }
+    }
+
+    pub fn progress(&self) -> StopProgress {
+        StopProgress {
+            losses: self.losses.iter().copied().collect(),
+            best_loss: self.best_loss,
+            best_monitored: self.best_monitored,
+            wait: self.wait,
+            best_params: self.best_params.clone(),
+        }
+    }
+
+    /// Continue from the progress saved in a checkpoint
+    pub fn resume(&mut self, progress: &StopProgress) {
+        self.losses = progress.losses.iter().copied().collect();
+        self.best_loss = progress.best_loss;
+        self.best_monitored = progress.best_monitored;
+        self.wait = progress.wait;
+        self.best_params = progress.best_params.clone();
+    }
+
+    pub fn monitor(&self) -> Option<NodeId> {
+        self.monitor
+    }
+
+    /// Check the criteria that depend on the current point, before the optimizer steps away from
+    /// it. `monitored` is the value of the `stop early` expression.
+    pub fn before_step(&mut self, loss: f64, monitored: Option<f64>) -> Option<StopReason> {
+        if loss < self.best_loss {
+            self.best_loss = loss;
+        }
+        self.improved = false;
+        let mut reason = None;
+        for criterion in &self.criteria {
+            let stop = match *criterion {
+                StopCriterion::RelImprovement { tol, window } => {
+                    self.losses.push_back(loss);
+                    if self.losses.len() <= window {
+                        continue;
+                    }
+                    let previous = self.losses.pop_front().unwrap_or(loss);
+                    // Relative to max(|f_prev|, |f|, 1) so that losses near zero compare absolutely
+                    let scale = previous.abs().max(loss.abs()).max(1.0);
+                    (previous - loss).abs() / scale < tol
+                }
+                StopCriterion::Early { patience, min_delta, .. } => {
+                    let value = monitored.unwrap_or(f64::NAN);
+                    if value < self.best_monitored - min_delta {
+                        self.best_monitored = value;
+                        self.wait = 0;
+                        self.improved = true;
+                        false
+                    } else {
+                        self.wait += 1;
+                        self.wait >= patience
+                    }
+                }
+                StopCriterion::TimeLimit { seconds } => self.elapsed() >= seconds,
+                StopCriterion::GradNorm { .. } => false,
+            };
+            if stop && reason.is_none() {
+                reason = Some(match criterion {
+                    StopCriterion::RelImprovement { .. } => StopReason::RelImprovement,
+                    StopCriterion::Early { .. } => StopReason::Early,
+                    _ => StopReason::TimeLimit,
+                });
+            }
+        }
+        reason
+    }
+
+    /// Check the criteria that depend on the step just taken: `grad_norm` is the global gradient
+    /// norm at the point the step started from
+    pub fn after_step(&self, grad_norm: f64) -> Option<StopReason> {
+        self.criteria.iter().find_map(|criterion| match *criterion {
+            StopCriterion::GradNorm { tol } if grad_norm < tol => Some(StopReason::GradNorm),
+            _ => None,
+        })
+    }
+
+    pub fn needs_grad_norm(&self) -> bool {
+        self.criteria.iter().any(|c| matches!(c, StopCriterion::GradNorm { .. }))
+    }
+
+    /// The last `before_step` set a new best monitored value
+    pub fn improved(&self) -> bool {
+        self.improved
+    }
+
+    pub fn restores_best(&self) -> bool {
+        self.criteria.iter().any(|c| matches!(c, StopCriterion::Early { restore_best: true, .. }))
+    }
+
+    /// Remember the learnables at a new best monitored value, and the loss they give
+    pub fn keep_best(&mut self, params: Vec<f64>, loss: f64) {
+        self.best_params = Some((params, loss));
+    }
+
+    pub fn best_params(&self) -> Option<(&[f64], f64)> {
+        self.best_params.as_ref().map(|(params, loss)| (params.as_slice(), *loss))
+    }
+
+    /// Best monitored value with `stop early`, otherwise the lowest loss seen
+    pub fn best(&self) -> f64 {
+        if self.monitor.is_some() {
+            self.best_monitored
+        } else {
+            self.best_loss
         }
     }
+
+    /// Seconds since the loop started
+    pub fn elapsed(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
 }
 
-/// State for adaptive optimizers (Adam, RMSprop)
+/// State for stateful optimizers
 /// Stores first moment (m) and second moment (v) for each learnable parameter
 #[derive(Debug, Clone, Default)]
 pub struct OptimizerState {
-    /// First moment estimates (momentum) - for Adam
+    /// First moment estimates - Adam/AdamW/Lion momentum, Momentum/Nesterov velocity,
+    /// Adadelta running average of squared updates
     pub m: HashMap<NodeId, Value>,
-    /// Second moment estimates (squared gradients) - for Adam and RMSprop
+    /// Second moment estimates (squared gradients) - Adam/AdamW, RMSprop, Adagrad (running sum)
+    /// and Adadelta
     pub v: HashMap<NodeId, Value>,
-    /// Current timestep for bias correction in Adam
+    /// Completed optimizer steps; drives Adam bias correction and the learning-rate schedule
     pub t: usize,
+    /// L-BFGS correction pairs (s, y) over the flattened learnables, oldest first
+    pub lbfgs_history: VecDeque<(Vec<f64>, Vec<f64>)>,
+    /// Reduce-on-plateau: best loss so far, steps without improvement and reductions applied
+    pub plateau_best: Option<f64>,
+    pub plateau_wait: usize,
+    pub plateau_reductions: usize,
 }
 
 impl OptimizerState {
@@ -252,6 +986,31 @@ impl OptimizerState {
             m: HashMap::new(),
             v: HashMap::new(),
             t: 0,
+            lbfgs_history: VecDeque::new(),
+            plateau_best: None,
+            plateau_wait: 0,
+            plateau_reductions: 0,
+        }
+    }
+
+    /// Record the loss of the step just taken; only the plateau schedule uses it
+    pub fn observe_loss(&mut self, config: &OptimizerConfig, loss: f64) {
+        let ScheduleKind::Plateau { patience, threshold, .. } = config.schedule.kind else { return };
+        if self.t <= config.schedule.warmup {
+            return;
+        }
+        match self.plateau_best {
+            Some(best) if loss.is_nan() || loss >= best - threshold * best.abs() => {
+                self.plateau_wait += 1;
+                if self.plateau_wait > patience {
+                    self.plateau_reductions += 1;
+                    self.plateau_wait = 0;
+                }
+            }
+            _ => {
+                self.plateau_best = Some(loss);
+                self.plateau_wait = 0;
+            }
         }
     }
 
@@ -260,9 +1019,17 @@ impl OptimizerState {
         self.m.clear();
         self.v.clear();
         self.t = 0;
+        self.lbfgs_history.clear();
+        self.plateau_best = None;
+        self.plateau_wait = 0;
+        self.plateau_reductions = 0;
     }
 }
 
+/// Gradient rule for builtins handled by `backprop_multi`: (name, argument values, upstream
+/// gradient) -> one optional local gradient per argument
+type GradRule = fn(&str, &[Value], &Value) -> Result<Vec<Option<Value>>, String>;
+
 #[derive(Debug, Clone)]
 pub struct ComputationalGraph {
     nodes: HashMap<NodeId, Node>,
@@ -270,6 +1037,32 @@ pub struct ComputationalGraph {
     learnables: Vec<String>,
     /// Track heap-allocated tensors for memory management
     heap_allocations: HashMap<String, NodeId>,
+    /// Train/eval mode for dropout and batch_norm
+    mode: ExecutionMode,
+    /// Running (mean, var) per batch_norm node, persisted across forward passes
+    running_stats: HashMap<NodeId, (Vec<f64>, Vec<f64>)>,
+    /// Batch (mean, unbiased var, momentum) of each batch_norm node from the last training-mode
+    /// forward pass, folded into `running_stats` once per backward pass
+    batch_stats: HashMap<NodeId, (Vec<f64>, Vec<f64>, f64)>,
+    /// Keep-mask (scaled by 1 / (1 - p)) sampled by each dropout node in the last forward pass
+    dropout_masks: HashMap<NodeId, Vec<f64>>,
+    /// Source of all randomness (rand builtins, initializers, realloc, dropout)
+    rng: ChaCha12Rng,
+    /// Seed of `rng`, if it was seeded explicitly
+    seed: Option<u64>,
+    /// Learning rate of the running optimize step, read by `current_lr()`
+    current_lr: f64,
+    /// Global gradient norm of the last optimize step before any transform, read by `grad_norm()`
+    grad_norm: f64,
+    /// Learnables excluded from every optimizer update (`freeze w;`)
+    frozen: HashSet<String>,
+    /// Metric name of each `log(value, "name")` node
+    metric_names: HashMap<NodeId, String>,
+    /// Latest value of each logged metric, in the order the names were first recorded
+    metrics: Vec<(String, f64)>,
+    /// Safetensors files read by `load_safetensors("path", "name")` in the statement being
+    /// lowered, so that a statement loading several tensors opens each file once
+    statement_files: HashMap<String, std::sync::Arc<SafetensorsFile>>,
 }
 
 impl ComputationalGraph {
@@ -279,33 +1072,252 @@ impl ComputationalGraph {
             next_id: 0,
             learnables: Vec::new(),
             heap_allocations: HashMap::new(),
+            mode: ExecutionMode::Train,
+            running_stats: HashMap::new(),
+            batch_stats: HashMap::new(),
+            dropout_masks: HashMap::new(),
+            rng: ChaCha12Rng::from_entropy(),
+            seed: None,
+            current_lr: 0.0,
+            grad_norm: 0.0,
+            frozen: HashSet::new(),
+            metric_names: HashMap::new(),
+            metrics: Vec::new(),
+            statement_files: HashMap::new(),
         }
     }
 
-    pub fn add_constant(&mut self, value: f64) -> NodeId {
-        let id = NodeId::new(self.next_id);
-        self.next_id += 1;
-
-        let node = Node {
-            id,
-            node_type: NodeType::Constant(Value::Scalar(value)),
-            inputs: Vec::new(),
-            value: Some(Value::Scalar(value)),
-            gradient: None,
-        };
+    /// Close the files opened while lowering the previous statement
+    pub fn begin_statement(&mut self) {
+        self.statement_files.clear();
+    }
 
-        self.nodes.insert(id, node);
-        id
+    fn statement_safetensors(&mut self, path: &str) -> Result<std::sync::Arc<SafetensorsFile>, String> {
+        if let Some(file) = self.statement_files.get(path) {
+            return Ok(file.clone());
+        }
+        let file = std::sync::Arc::new(SafetensorsFile::open(path)?);
+        self.statement_files.insert(path.to_string(), file.clone());
+        Ok(file)
     }
 
-    pub fn add_constant_tensor(&mut self, data: Vec<f64>, shape: Vec<usize>) -> Result<NodeId, String> {
-        let tensor = Tensor::new(data, shape)?;
-        let id = NodeId::new(self.next_id);
-        self.next_id += 1;
+    /// Reseed the graph RNG. Runs with the same seed draw the same random values.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = ChaCha12Rng::seed_from_u64(seed);
+        self.seed = Some(seed);
+    }
 
-        let node = Node {
-            id,
-            node_type: NodeType::Constant(Value::Tensor(tensor.clone())),
+    /// Reseed the RNG for a `seed()` statement. Per-step random nodes are marked stale so that
+    /// nothing reads a draw from the old stream; sample-once nodes that have not been drawn yet
+    /// will draw from the new one
+    pub fn reseed(&mut self, seed: u64) {
+        self.set_seed(seed);
+        for node in self.nodes.values_mut() {
+            if matches!(&node.node_type, NodeType::FunctionCall(name) if sampling_of(name) == Some(Sampling::PerStep)) {
+                node.value = None;
+            }
+        }
+    }
+
+    /// Draw a seed for a derived generator, such as a dataset shuffle, from the graph RNG
+    pub fn draw_seed(&mut self) -> u64 {
+        self.rng.gen()
+    }
+
+    /// The last explicit seed, or None if the RNG was seeded from entropy
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// Position of the RNG as (key, stream, word position), enough to continue its sequence
+    pub fn rng_state(&self) -> ([u8; 32], u64, u128) {
+        (self.rng.get_seed(), self.rng.get_stream(), self.rng.get_word_pos())
+    }
+
+    pub fn set_rng_state(&mut self, key: [u8; 32], stream: u64, word_pos: u128) {
+        let mut rng = ChaCha12Rng::from_seed(key);
+        rng.set_stream(stream);
+        rng.set_word_pos(word_pos);
+        self.rng = rng;
+    }
+
+    /// Set the value returned by `current_lr()`; called before each optimize step
+    pub fn set_current_lr(&mut self, lr: f64) {
+        self.current_lr = lr;
+    }
+
+    pub fn current_lr(&self) -> f64 {
+        self.current_lr
+    }
+
+    /// Global L2 norm of the learnable gradients at the last optimizer update, before clipping
+    pub fn grad_norm(&self) -> f64 {
+        self.grad_norm
+    }
+
+    /// Values recorded by `log(value, "name")` nodes in the last forward pass
+    pub fn metrics(&self) -> &[(String, f64)] {
+        &self.metrics
+    }
+
+    /// Exclude the learnable `name` from optimizer updates until `unfreeze`
+    pub fn freeze(&mut self, name: &str) -> Result<(), String> {
+        if !self.learnables.iter().any(|l| l == name) {
+            return Err(format!("Cannot freeze '{}': not a learnable parameter", name));
+        }
+        self.frozen.insert(name.to_string());
+        Ok(())
+    }
+
+    pub fn unfreeze(&mut self, name: &str) -> Result<(), String> {
+        if !self.learnables.iter().any(|l| l == name) {
+            return Err(format!("Cannot unfreeze '{}': not a learnable parameter", name));
+        }
+        self.frozen.remove(name);
+        Ok(())
+    }
+
+    pub fn is_frozen(&self, name: &str) -> bool {
+        self.frozen.contains(name)
+    }
+
+    /// Learning rate `config` applies to `node`, or None if the step leaves it unchanged (not a
+    /// learnable, frozen, or not among `config.params`)
+    fn update_lr(&self, node: &Node, config: &OptimizerConfig) -> Option<f64> {
+        match &node.node_type {
+            NodeType::Learnable(name) if !self.frozen.contains(name) => config.learning_rate_for(name),
+            _ => None,
+        }
+    }
+
+    /// Learnables (in creation order) that a step with `config` updates
+    pub fn trainable_ids(&self, config: &OptimizerConfig) -> Vec<NodeId> {
+        self.learnable_ids().into_iter().filter(|id| self.update_lr(&self.nodes[id], config).is_some()).collect()
+    }
+
+    pub fn set_mode(&mut self, mode: ExecutionMode) {
+        self.mode = mode;
+    }
+
+    pub fn mode(&self) -> ExecutionMode {
+        self.mode
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.mode == ExecutionMode::Train
+    }
+
+    /// Running (mean, var) of a batch_norm call, if it has seen a training step. `id` is the
+    /// learnable gamma when the call has one, otherwise the batch_norm node itself.
+    pub fn running_stats(&self, id: NodeId) -> Option<&(Vec<f64>, Vec<f64>)> {
+        self.running_stats.get(&id)
+    }
+
+    /// Running statistics keyed by the learnable gamma's name, or `#<node index>` for calls without
+    /// one, so that a checkpoint can carry them to a new lowering of the same program
+    pub(crate) fn running_stats_by_name(&self) -> Vec<(String, Vec<f64>, Vec<f64>)> {
+        let mut stats: Vec<_> = self.running_stats.iter()
+            .map(|(id, (mean, var))| {
+                let key = match self.nodes.get(id).map(|n| &n.node_type) {
+                    Some(NodeType::Learnable(name)) => name.clone(),
+                    _ => format!("#{}", id.index()),
+                };
+                (key, mean.clone(), var.clone())
+            })
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+
+    pub(crate) fn set_running_stats_by_name(&mut self, key: &str, mean: Vec<f64>, var: Vec<f64>) {
+        let id = match key.strip_prefix('#') {
+            Some(index) => index.parse().ok().map(NodeId::new),
+            None => self.learnables_by_name().into_iter().find(|(name, _)| name == key).map(|(_, id)| id),
+        };
+        if let Some(id) = id {
+            self.running_stats.insert(id, (mean, var));
+        }
+    }
+
+    /// Key of the running statistics of a batch_norm node. Calls sharing a learnable gamma share
+    /// statistics, so a layer evaluated inside `optimize` and again afterwards behaves as one layer.
+    fn stats_key(&self, node_id: NodeId) -> NodeId {
+        self.nodes.get(&node_id)
+            .and_then(|n| n.inputs.get(1).copied())
+            .filter(|g| matches!(self.nodes.get(g).map(|n| &n.node_type), Some(NodeType::Learnable(_))))
+            .unwrap_or(node_id)
+    }
+
+    /// Inference-mode batch_norm folded into per-element (scale, shift) so that
+    /// `out = x * scale + shift`, using the running statistics of `id` and the given argument values
+    pub(crate) fn batch_norm_affine(&self, id: NodeId, args: &[Value]) -> Result<(Vec<f64>, Vec<f64>), String> {
+        check_norm_arity("batch_norm", args)?;
+        let x = tensor_of(&args[0]);
+        let layout = NormLayout::new("batch_norm", &x.shape)?;
+        let (gamma, beta) = (affine_param(args, 1, &layout, 1.0)?, affine_param(args, 2, &layout, 0.0)?);
+        let (_, eps) = norm_hyperparams("batch_norm", args)?;
+        let (mean, var) = self.running_stats.get(&self.stats_key(id)).cloned()
+            .unwrap_or_else(|| (vec![0.0; layout.groups], vec![1.0; layout.groups]));
+        let (mut scale, mut shift) = (Vec::with_capacity(x.data.len()), Vec::with_capacity(x.data.len()));
+        for i in 0..x.data.len() {
+            let c = layout.group(i);
+            let sc = gamma[c] / (var[c] + eps).sqrt();
+            scale.push(sc);
+            shift.push(beta[c] - mean[c] * sc);
+        }
+        Ok((scale, shift))
+    }
+
+    /// Fold the batch statistics of the last forward pass into the batch_norm running
+    /// statistics. Forward passes are re-run freely during lowering, so the update happens once
+    /// per training step (backward pass) rather than once per forward pass.
+    fn update_running_stats(&mut self) {
+        for (id, (mean, var, momentum)) in self.batch_stats.drain() {
+            let (rm, rv) = self.running_stats.entry(id)
+                .or_insert_with(|| (vec![0.0; mean.len()], vec![1.0; var.len()]));
+            if rm.len() != mean.len() {
+                *rm = vec![0.0; mean.len()];
+                *rv = vec![1.0; var.len()];
+            }
+            for c in 0..mean.len() {
+                rm[c] = (1.0 - momentum) * rm[c] + momentum * mean[c];
+                rv[c] = (1.0 - momentum) * rv[c] + momentum * var[c];
+            }
+        }
+    }
+
+    pub fn add_constant(&mut self, value: f64) -> NodeId {
+        let id = NodeId::new(self.next_id);
+        self.next_id += 1;
+
+        let node = Node {
+            id,
+            node_type: NodeType::Constant(Value::Scalar(value)),
+            inputs: Vec::new(),
+            value: Some(Value::Scalar(value)),
+            gradient: None,
+        };
+
+        self.nodes.insert(id, node);
+        id
+    }
+
+    /// Constant for a tensor read from a file; a zero-dimensional tensor becomes a scalar
+    pub fn add_loaded_tensor(&mut self, data: Vec<f64>, shape: Vec<usize>) -> Result<NodeId, String> {
+        match (shape.is_empty(), data.as_slice()) {
+            (true, [value]) => Ok(self.add_constant(*value)),
+            _ => self.add_constant_tensor(data, shape),
+        }
+    }
+
+    pub fn add_constant_tensor(&mut self, data: Vec<f64>, shape: Vec<usize>) -> Result<NodeId, String> {
+        let tensor = Tensor::new(data, shape)?;
+        let id = NodeId::new(self.next_id);
+        self.next_id += 1;
+
+        let node = Node {
+            id,
+            node_type: NodeType::Constant(Value::Tensor(tensor.clone())),
             inputs: Vec::new(),
             value: Some(Value::Tensor(tensor)),
             gradient: None,
@@ -539,14 +1551,47 @@ impl ComputationalGraph {
             }
             Expression::Identifier(name) => variables.get(name).copied().ok_or_else(|| format!("Undefined variable: {}", name)),
             Expression::Index { target, indices } => {
-                // Lower as a function call: index(target, i, j, ...)
                 let t_id = self.build_from_expression_with_functions(target, variables, functions)?;
+                let is_slice = |indices: &[Expression]| indices.iter().any(|idx| matches!(idx, Expression::Range { .. }));
+                let sliced_target = matches!(&**target, Expression::Index { indices, .. } if is_slice(indices));
+                if is_slice(indices) || sliced_target {
+                    // Lower a[1:3, i] into a chain of slice/select calls. Axes are visited from
+                    // last to first so that select dropping an axis does not shift the others.
+                    // Indexing a slice, a[1:3][0], may leave trailing axes, so it selects too.
+                    let mut current = t_id;
+                    for (axis, idx) in indices.iter().enumerate().rev() {
+                        let axis_id = self.add_constant(axis as f64);
+                        match idx {
+                            Expression::Range { start: None, end: None } => {}
+                            Expression::Range { start, end } => {
+                                let start_id = match start {
+                                    Some(e) => self.build_from_expression_with_functions(e, variables, functions)?,
+                                    None => self.add_constant(0.0),
+                                };
+                                let mut args = vec![current, axis_id, start_id];
+                                if let Some(e) = end {
+                                    args.push(self.build_from_expression_with_functions(e, variables, functions)?);
+                                }
+                                current = self.add_function_call("slice".to_string(), args);
+                            }
+                            other => {
+                                let i_id = self.build_from_expression_with_functions(other, variables, functions)?;
+                                current = self.add_function_call("select".to_string(), vec![current, axis_id, i_id]);
+                            }
+                        }
+                    }
+                    return Ok(current);
+                }
+                // Lower as a function call: index(target, i, j, ...)
                 let mut args = vec![t_id];
                 for idx in indices {
                     args.push(self.build_from_expression_with_functions(idx, variables, functions)?);
                 }
                 Ok(self.add_function_call("index".to_string(), args))
             }
+            Expression::Range { .. } => {
+                Err("Range expressions are only allowed inside index brackets".to_string())
+            }
             Expression::BinaryOp { left, op, right } => {
                 let left_id = self.build_from_expression_with_functions(left, variables, functions)?;
                 let right_id = self.build_from_expression_with_functions(right, variables, functions)?;
@@ -590,6 +1635,38 @@ impl ComputationalGraph {
                     }
                 }
 
+                // log(value, "name") records a metric; log(x) is the natural logarithm
+                if name == "log" && args.len() == 2 {
+                    let Expression::StringLiteral(metric) = &args[1] else {
+                        return Err("log(value, \"name\") expects a string literal as the metric name".to_string());
+                    };
+                    if METRICS_LOG_FIELDS.contains(&metric.as_str()) {
+                        return Err(format!("Metric name '{}' is reserved for the optimize-loop log", metric));
+                    }
+                    let value_id = self.build_from_expression_with_functions(&args[0], variables, functions)?;
+                    let id = self.add_function_call("log_metric".to_string(), vec![value_id]);
+                    self.metric_names.insert(id, metric.clone());
+                    return Ok(id);
+                }
+
+                // load_safetensors("path", "name"), load_npy("path") and load_npz("path", "name")
+                // read one tensor while the graph is built
+                if matches!(name.as_str(), "load_safetensors" | "load_npy" | "load_npz") {
+                    let (data, shape) = match (name.as_str(), &args[..]) {
+                        ("load_safetensors", [Expression::StringLiteral(path), Expression::StringLiteral(tensor)]) => {
+                            let tensor = self.statement_safetensors(path)?.tensor_or_param(tensor)?;
+                            (tensor.data, tensor.shape)
+                        }
+                        ("load_npy", [Expression::StringLiteral(path)]) => load_npy_file(path)?,
+                        ("load_npz", [Expression::StringLiteral(path), Expression::StringLiteral(array)]) => {
+                            load_npz_array(path, array)?
+                        }
+                        ("load_npy", _) => return Err("load_npy(\"path\") expects a string literal".to_string()),
+                        _ => return Err(format!("{}(\"path\", \"name\") expects two string literals", name)),
+                    };
+                    return self.add_loaded_tensor(data, shape);
+                }
+
                 // Check if this is a user-defined function
                 if let Some(user_fn) = functions.get(name) {
                     // Inline the user function
@@ -642,6 +1719,7 @@ impl ComputationalGraph {
         let mut last_node: Option<NodeId> = None;
 
         for stmt in body {
+            self.begin_statement();
             match stmt {
                 Statement::LetDeclaration { name, value } => {
                     let val_id = self.build_from_expression_with_functions(value, variables, functions)?;
@@ -749,6 +1827,16 @@ impl ComputationalGraph {
                     variables.remove(name);
                     // free doesn't produce a value, but we keep last_node unchanged
                 }
+                Statement::Freeze { names } => {
+                    for name in names {
+                        self.freeze(name)?;
+                    }
+                }
+                Statement::Unfreeze { names } => {
+                    for name in names {
+                        self.unfreeze(name)?;
+                    }
+                }
                 Statement::Realloc { name, shape } => {
                     // Evaluate shape dimensions at lowering time
                     let mut dims = Vec::new();
@@ -765,37 +1853,71 @@ impl ComputationalGraph {
                     variables.insert(name.clone(), node_id);
                     last_node = Some(node_id);
                 }
-                Statement::LoadCsv { name, path } => {
-                    // Load CSV file and create tensor
-                    let tensor_data = load_csv_file(path)?;
-                    let node_id = self.add_constant_tensor(tensor_data.0, tensor_data.1)?;
+                Statement::LoadCsv { name, labels_name, path, options } => {
+                    // Load the selected CSV columns, and the label columns if split off
+                    let (features, labels) = load_csv_with(path, options)?;
+                    let node_id = self.add_constant_tensor(features.data, features.shape)?;
                     variables.insert(name.clone(), node_id);
                     last_node = Some(node_id);
+                    if let (Some(labels_name), Some(labels)) = (labels_name, labels) {
+                        let labels_id = self.add_constant_tensor(labels.data, labels.shape)?;
+                        variables.insert(labels_name.clone(), labels_id);
+                    }
                 }
-                Statement::SaveCsv { tensor, path } => {
+                Statement::SaveCsv { tensor, path, header } => {
                     // Evaluate tensor and save to CSV
                     let tensor_id = self.build_from_expression_with_functions(tensor, variables, functions)?;
                     self.forward_pass()?;
                     let tensor_val = self.get_node(tensor_id)
                         .and_then(|n| n.value.clone())
                         .ok_or_else(|| "Cannot evaluate tensor for save_csv".to_string())?;
-                    save_csv_file(&tensor_val, path)?;
+                    save_csv_file_with(&tensor_val, path, header)?;
                     last_node = Some(tensor_id);
                 }
                 Statement::LoadSafetensors { name, path } => {
-                    // Load Safetensors file and create a dictionary-like structure
-                    let tensors = load_safetensors_file(path)?;
-                    // For simplicity, if there's only one tensor, use it directly
-                    // Otherwise, we store the first tensor (full support would need dict type)
-                    if let Some((_, (data, shape))) = tensors.into_iter().next() {
-                        let node_id = self.add_constant_tensor(data, shape)?;
-                        variables.insert(name.clone(), node_id);
+                    for (variable, tensor) in safetensors_bindings(name, path)? {
+                        let node_id = self.add_loaded_tensor(tensor.data, tensor.shape)?;
+                        variables.insert(variable, node_id);
                         last_node = Some(node_id);
-                    } else {
-                        return Err(format!("No tensors found in safetensors file: {}", path));
                     }
                 }
-                Statement::SaveSafetensors { tensors, path } => {
+                Statement::LoadNpy { name, path } => {
+                    let (data, shape) = load_npy_file(path)?;
+                    let node_id = self.add_loaded_tensor(data, shape)?;
+                    variables.insert(name.clone(), node_id);
+                    last_node = Some(node_id);
+                }
+                Statement::SaveNpy { tensor, path, dtype } => {
+                    let tensor_id = self.build_from_expression_with_functions(tensor, variables, functions)?;
+                    self.forward_pass()?;
+                    let tensor_val = self.get_node(tensor_id)
+                        .and_then(|n| n.value.clone())
+                        .ok_or_else(|| "Cannot evaluate tensor for save_npy".to_string())?;
+                    let dtype = dtype.as_deref().and_then(NpyDtype::parse).unwrap_or(NpyDtype::F64);
+                    save_npy_file(&tensor_val, path, dtype)?;
+                    last_node = Some(tensor_id);
+                }
+                Statement::LoadNpz { name, path } => {
+                    for (variable, tensor) in npz_bindings(name, path)? {
+                        let node_id = self.add_loaded_tensor(tensor.data, tensor.shape)?;
+                        variables.insert(variable, node_id);
+                        last_node = Some(node_id);
+                    }
+                }
+                Statement::SaveNpz { arrays, path, dtype } => {
+                    let mut array_map = Vec::new();
+                    for (array_name, array_expr) in arrays {
+                        let array_id = self.build_from_expression_with_functions(array_expr, variables, functions)?;
+                        self.forward_pass()?;
+                        let array_val = self.get_node(array_id)
+                            .and_then(|n| n.value.clone())
+                            .ok_or_else(|| format!("Cannot evaluate array '{}' for save_npz", array_name))?;
+                        array_map.push((array_name.clone(), array_val));
+                    }
+                    let dtype = dtype.as_deref().and_then(NpyDtype::parse).unwrap_or(NpyDtype::F64);
+                    save_npz_file(&array_map, path, dtype)?;
+                }
+                Statement::SaveSafetensors { tensors, path, dtype, metadata } => {
                     // Evaluate all tensors and save to Safetensors format
                     let mut tensor_map = Vec::new();
                     for (tensor_name, tensor_expr) in tensors {
@@ -806,16 +1928,21 @@ impl ComputationalGraph {
                             .ok_or_else(|| format!("Cannot evaluate tensor '{}' for save_safetensors", tensor_name))?;
                         tensor_map.push((tensor_name.clone(), tensor_val));
                     }
-                    save_safetensors_file(&tensor_map, path)?;
+                    let dtype = dtype.as_deref().and_then(SafetensorsDtype::from_name).unwrap_or(SafetensorsDtype::F64);
+                    save_safetensors_file_with(&tensor_map, path, dtype, metadata)?;
                     // save_safetensors doesn't produce a value
                 }
-                Statement::BatchLoop { item_name, index_name, data, batch_size, body } => {
-                    // Evaluate data tensor and batch size
-                    let data_id = self.build_from_expression_with_functions(data, variables, functions)?;
-                    self.forward_pass()?;
-                    let data_val = self.get_node(data_id)
-                        .and_then(|n| n.value.clone())
-                        .ok_or_else(|| "Cannot evaluate data for batch loop".to_string())?;
+                Statement::BatchLoop { item_names, index_name, epoch_name, data, batch_size, options, body } => {
+                    // Evaluate the data tensors and batch size
+                    let mut sources = Vec::new();
+                    for expr in data {
+                        let data_id = self.build_from_expression_with_functions(expr, variables, functions)?;
+                        self.forward_pass()?;
+                        let data_val = self.get_node(data_id)
+                            .and_then(|n| n.value.clone())
+                            .ok_or_else(|| "Cannot evaluate data for batch loop".to_string())?;
+                        sources.push(BatchSource::from_value(data_val));
+                    }
                     
                     let batch_size_id = self.build_from_expression_with_functions(batch_size, variables, functions)?;
                     self.forward_pass()?;
@@ -827,57 +1954,69 @@ impl ComputationalGraph {
                     if batch_size_val == 0 {
                         return Err("Batch size cannot be zero".to_string());
                     }
-                    
-                    // Get tensor data
-                    let (tensor_data, tensor_shape) = match &data_val {
-                        Value::Tensor(t) => (t.data.clone(), t.shape.clone()),
-                        Value::Scalar(s) => (vec![*s], vec![1]),
+                    let epochs = match &options.epochs {
+                        Some(expr) => {
+                            let epochs_id = self.build_from_expression_with_functions(expr, variables, functions)?;
+                            self.forward_pass()?;
+                            match self.get_node(epochs_id).and_then(|n| n.value.clone()).and_then(|v| v.as_scalar()) {
+                                Some(e) if e >= 1.0 && e.fract() == 0.0 => Some(e as usize),
+                                _ => return Err("Batch loop epochs must be a positive integer".to_string()),
+                            }
+                        }
+                        None => None,
                     };
+                    let shuffle = options.shuffle.then(|| options.seed.unwrap_or_else(|| self.draw_seed()));
+                    let schedule = BatchSource::schedule(&sources, shuffle, options.drop_last, epochs)?;
                     
-                    let num_samples = if tensor_shape.is_empty() { 1 } else { tensor_shape[0] };
-                    let num_batches = (num_samples + batch_size_val - 1) / batch_size_val;
-                    
-                    // Iterate over batches
-                    for batch_idx in 0..num_batches {
-                        let start = batch_idx * batch_size_val;
-                        let end = (start + batch_size_val).min(num_samples);
-                        
-                        // Extract batch data
-                        let batch_data = if tensor_shape.len() == 1 {
-                            tensor_data[start..end].to_vec()
-                        } else {
-                            // For multi-dimensional tensors, slice along first dimension
-                            let row_size: usize = tensor_shape[1..].iter().product();
-                            tensor_data[start * row_size..end * row_size].to_vec()
-                        };
-                        
-                        let batch_shape = if tensor_shape.len() == 1 {
-                            vec![end - start]
-                        } else {
-                            let mut shape = vec![end - start];
-                            shape.extend(&tensor_shape[1..]);
-                            shape
-                        };
-                        
-                        // Create batch tensor node
-                        let batch_node_id = self.add_constant_tensor(batch_data, batch_shape)?;
-                        variables.insert(item_name.clone(), batch_node_id);
-                        
-                        // Optionally set index variable
-                        if let Some(idx_name) = index_name {
-                            let idx_node_id = self.add_constant(batch_idx as f64);
-                            variables.insert(idx_name.clone(), idx_node_id);
+                    for epoch in 0..schedule.epochs {
+                        if let Some(epoch_name) = epoch_name {
+                            let epoch_node_id = self.add_constant(epoch as f64);
+                            variables.insert(epoch_name.clone(), epoch_node_id);
                         }
-                        
-                        // Execute batch body
-                        let result = self.inline_function_body(body, variables, functions)?;
-                        last_node = Some(result);
+                        for (batch_idx, rows) in schedule.batches(epoch, batch_size_val).iter().enumerate() {
+                            // Create one batch tensor node per source, all with the same rows
+                            for (item_name, source) in item_names.iter().zip(&sources) {
+                                let batch = source.read_rows(rows)?;
+                                let batch_node_id = self.add_constant_tensor(batch.data, batch.shape)?;
+                                variables.insert(item_name.clone(), batch_node_id);
+                            }
+                            
+                            // Optionally set index variable
+                            if let Some(idx_name) = index_name {
+                                let idx_node_id = self.add_constant(batch_idx as f64);
+                                variables.insert(idx_name.clone(), idx_node_id);
+                            }
+                            
+                            // Execute batch body
+                            let result = self.inline_function_body(body, variables, functions)?;
+                            last_node = Some(result);
+                        }
+                    }
+                }
+                Statement::Checkpoint { .. } | Statement::Resume { .. } => {
+                    return Err("checkpoint and resume are not supported inside user functions".to_string());
+                }
+                Statement::Dataset { .. } => {
+                    return Err("dataset is not supported inside user functions".to_string());
+                }
+                Statement::ImportOnnx { name, path, inputs } => {
+                    let input_ids = inputs.iter()
+                        .map(|e| self.build_from_expression_with_functions(e, variables, functions))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    for (variable, node_id) in crate::onnx::import_onnx_file(self, path, name, &input_ids, variables)? {
+                        variables.insert(variable, node_id);
                     }
+                    last_node = variables.get(name).copied();
                 }
                 Statement::ResetOptimizer => {
                     // reset_optimizer is handled at runtime by main.rs, not in the graph
                     // In function context, this is a no-op
                 }
+                Statement::Seed(expr) => {
+                    let seed_id = self.build_from_expression_with_functions(expr, variables, functions)?;
+                    let value = self.evaluate_node(seed_id)?;
+                    self.reseed(seed_value(value)?);
+                }
             }
         }
 
@@ -895,18 +2034,17 @@ impl ComputationalGraph {
     /// Reallocate a learnable tensor in-place with a new shape (preserves data)
     /// New slots are initialized with small random values to break symmetry
     pub fn realloc_learnable_tensor_by_id(&mut self, id: NodeId, new_shape: Vec<usize>) -> Result<NodeId, String> {
-        let node = self.get_node_mut(id).ok_or_else(|| "Invalid node id for learnable realloc".to_string())?;
+        let node = self.nodes.get_mut(&id).ok_or_else(|| "Invalid node id for learnable realloc".to_string())?;
         match (&node.node_type, node.value.clone()) {
             (NodeType::Learnable(_), Some(Value::Tensor(t))) => {
                 let new_size: usize = new_shape.iter().product();
                 let old_size = t.data.len();
                 
                 // Initialize new slots with small random values to break symmetry
-                let mut rng = rand::thread_rng();
                 let init_std = 0.3;  // Small std for new weights
                 let normal = Normal::new(0.0, init_std).unwrap();
                 let mut new_data: Vec<f64> = (0..new_size)
-                    .map(|_| normal.sample(&mut rng))
+                    .map(|_| normal.sample(&mut self.rng))
                     .collect();
                 
                 // Preserve existing data
@@ -927,10 +2065,90 @@ impl ComputationalGraph {
         &self.nodes
     }
 
+    /// Index the next node will get; nodes created later compare greater
+    pub fn next_node_index(&self) -> usize {
+        self.next_id
+    }
+
+    /// Remove the nodes created at index `first` or later that none of `keep` depends on.
+    /// Learnables and heap allocations always stay. Returns the number of nodes removed.
+    pub fn prune_since(&mut self, first: usize, keep: impl IntoIterator<Item = NodeId>) -> usize {
+        let mut live: HashSet<NodeId> = HashSet::new();
+        let mut stack: Vec<NodeId> = keep.into_iter().chain(self.heap_allocations.values().copied()).collect();
+        stack.extend(self.nodes.values().filter(|n| matches!(n.node_type, NodeType::Learnable(_))).map(|n| n.id));
+        while let Some(id) = stack.pop() {
+            if id.index() >= first && live.insert(id) {
+                if let Some(node) = self.nodes.get(&id) {
+                    stack.extend(node.inputs.iter().copied());
+                }
+            }
+        }
+        let dead: Vec<NodeId> = self.nodes.keys().copied().filter(|id| id.index() >= first && !live.contains(id)).collect();
+        for id in &dead {
+            self.nodes.remove(id);
+            self.running_stats.remove(id);
+            self.batch_stats.remove(id);
+            self.dropout_masks.remove(id);
+            self.metric_names.remove(id);
+        }
+        dead.len()
+    }
+
+    /// Evaluate the graph for its side effects (prints) without disturbing training: the pass
+    /// runs in eval mode, and the RNG position, dropout masks, batch statistics and the values of
+    /// nodes at index `first` or later that had not been evaluated yet are put back afterwards
+    pub fn evaluate_detached(&mut self, first: usize) -> Result<(), String> {
+        let unevaluated: Vec<NodeId> = self.nodes.values()
+            .filter(|n| n.id.index() >= first && n.value.is_none())
+            .map(|n| n.id)
+            .collect();
+        self.detached(unevaluated, Self::forward_pass)
+    }
+
+    /// Value of `id`, evaluating its ancestors the way evaluate_detached evaluates the graph
+    pub fn evaluate_node_detached(&mut self, id: NodeId) -> Result<Option<Value>, String> {
+        let unevaluated: Vec<NodeId> = self.nodes.values().filter(|n| n.value.is_none()).map(|n| n.id).collect();
+        self.detached(unevaluated, |graph| graph.evaluate_node(id))
+    }
+
+    /// Run `pass` in eval mode, then put back the RNG position, dropout masks and batch
+    /// statistics, and clear the values of the `unevaluated` nodes
+    fn detached<T>(&mut self, unevaluated: Vec<NodeId>, pass: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        let (mode, rng) = (self.mode, self.rng.clone());
+        let (masks, batch_stats) = (self.dropout_masks.clone(), self.batch_stats.clone());
+        self.mode = ExecutionMode::Eval;
+        let result = pass(self);
+        self.mode = mode;
+        self.rng = rng;
+        (self.dropout_masks, self.batch_stats) = (masks, batch_stats);
+        for id in unevaluated {
+            if let Some(node) = self.nodes.get_mut(&id) {
+                node.value = None;
+            }
+        }
+        result
+    }
+
     pub fn learnables(&self) -> &[String] {
         &self.learnables
     }
 
+    /// Replace each name that is not a learnable but prefixes learnables (`net` for `net.W1`,
+    /// `net.b1`, as bound by import_onnx) with those learnables
+    pub fn expand_learnable_names(&self, names: &[String]) -> Vec<String> {
+        let mut expanded = Vec::new();
+        for name in names {
+            let prefix = format!("{}.", name);
+            let members: Vec<&String> = self.learnables.iter().filter(|l| l.starts_with(&prefix)).collect();
+            if self.learnables.contains(name) || members.is_empty() {
+                expanded.push(name.clone());
+            } else {
+                expanded.extend(members.into_iter().cloned());
+            }
+        }
+        expanded
+    }
+
     fn topological_order(&self) -> Result<Vec<NodeId>, String> {
         let mut indegree: HashMap<NodeId, usize> = HashMap::new();
         let mut adj: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
@@ -977,7 +2195,24 @@ impl ComputationalGraph {
 
     pub fn forward_pass(&mut self) -> Result<(), String> {
         let node_ids = self.topological_order()?;
+        self.evaluate_nodes(node_ids)
+    }
+
+    /// Evaluate `id` and the nodes it depends on, leaving the rest of the graph untouched
+    pub fn evaluate_node(&mut self, id: NodeId) -> Result<Option<Value>, String> {
+        let mut needed = HashSet::new();
+        let mut stack = vec![id];
+        while let Some(next) = stack.pop() {
+            if needed.insert(next) {
+                stack.extend(self.nodes.get(&next).map(|n| n.inputs.clone()).unwrap_or_default());
+            }
+        }
+        let order = self.topological_order()?.into_iter().filter(|n| needed.contains(n)).collect();
+        self.evaluate_nodes(order)?;
+        Ok(self.nodes.get(&id).and_then(|n| n.value.clone()))
+    }
 
+    fn evaluate_nodes(&mut self, node_ids: Vec<NodeId>) -> Result<(), String> {
         for node_id in node_ids {
             let node_type = self.nodes.get(&node_id).map(|n| &n.node_type).cloned();
             let inputs = self.nodes.get(&node_id).map(|n| n.inputs.clone()).unwrap_or_default();
@@ -1008,14 +2243,7 @@ impl ComputationalGraph {
                                 "add" | "sub" | "mul" | "div" => broadcast_binary(&left_val, &right_val, op.as_str())?,
                                 "mod" => left_val.map2(&right_val, |a, b| a % b)?,
                                 "pow" => left_val.map2(&right_val, |a, b| a.powf(b))?,
-                                "eq" => Value::Scalar(if (left_val.as_scalar().unwrap_or(f64::NAN) - right_val.as_scalar().unwrap_or(f64::NAN)).abs() < f64::EPSILON { 1.0 } else { 0.0 }),
-                                "ne" => Value::Scalar(if (left_val.as_scalar().unwrap_or(f64::NAN) - right_val.as_scalar().unwrap_or(f64::NAN)).abs() >= f64::EPSILON { 1.0 } else { 0.0 }),
-                                "lt" => Value::Scalar(if left_val.as_scalar().ok_or("Non-scalar left")? < right_val.as_scalar().ok_or("Non-scalar right")? { 1.0 } else { 0.0 }),
-                                "gt" => Value::Scalar(if left_val.as_scalar().ok_or("Non-scalar left")? > right_val.as_scalar().ok_or("Non-scalar right")? { 1.0 } else { 0.0 }),
-                                "le" => Value::Scalar(if left_val.as_scalar().ok_or("Non-scalar left")? <= right_val.as_scalar().ok_or("Non-scalar right")? { 1.0 } else { 0.0 }),
-                                "ge" => Value::Scalar(if left_val.as_scalar().ok_or("Non-scalar left")? >= right_val.as_scalar().ok_or("Non-scalar right")? { 1.0 } else { 0.0 }),
-                                "and" => Value::Scalar(if left_val.as_scalar().ok_or("Non-scalar left")? != 0.0 && right_val.as_scalar().ok_or("Non-scalar right")? != 0.0 { 1.0 } else { 0.0 }),
-                                "or" => Value::Scalar(if left_val.as_scalar().ok_or("Non-scalar left")? != 0.0 || right_val.as_scalar().ok_or("Non-scalar right")? != 0.0 { 1.0 } else { 0.0 }),
+                                "eq" | "ne" | "lt" | "gt" | "le" | "ge" | "and" | "or" => broadcast_binary(&left_val, &right_val, op.as_str())?,
                                 _ => return Err(format!("Unknown binary op: {}", op)),
                             };
 
@@ -1030,10 +2258,7 @@ impl ComputationalGraph {
 
                             let result = match op.as_str() {
                                 "neg" => val.map_unary(|v| -v)?,
-                                "not" => {
-                                    let s = val.as_scalar().ok_or("Non-scalar for not")?;
-                                    Value::Scalar(if s != 0.0 { 0.0 } else { 1.0 })
-                                }
+                                "not" => val.map_unary(|v| if v != 0.0 { 0.0 } else { 1.0 })?,
                                 _ => return Err(format!("Unknown unary op: {}", op)),
                             };
 
@@ -1042,6 +2267,9 @@ impl ComputationalGraph {
                             }
                         }
                     }
+                    // Sample-once nodes keep their first draw across forward passes
+                    NodeType::FunctionCall(name) if sampling_of(&name) == Some(Sampling::Once)
+                        && self.nodes.get(&node_id).is_some_and(|n| n.value.is_some()) => {}
                     NodeType::FunctionCall(name) => match name.as_str() {
                         "sigmoid" => {
                             if inputs.len() == 1 {
@@ -1070,6 +2298,18 @@ impl ComputationalGraph {
                             }
                             if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(val); }
                         }
+                        "log_metric" => {
+                            let name = self.metric_names.get(&node_id).cloned().ok_or("log_metric is created by log(value, \"name\")")?;
+                            let val = self.nodes.get(&inputs[0]).and_then(|n| n.value.clone()).ok_or("Missing argument")?;
+                            let Value::Scalar(v) = val else {
+                                return Err(format!("log('{}') expects a scalar; reduce the tensor first, e.g. mean(x)", name));
+                            };
+                            match self.metrics.iter_mut().find(|(n, _)| *n == name) {
+                                Some(entry) => entry.1 = v,
+                                None => self.metrics.push((name, v)),
+                            }
+                            if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(val); }
+                        }
                         "dot" => {
                             if inputs.len() != 2 { return Err("dot expects 2 arguments".to_string()); }
                             let a = self.nodes.get(&inputs[0]).and_then(|n| n.value.clone()).ok_or("Missing arg a")?;
@@ -1219,12 +2459,23 @@ impl ComputationalGraph {
                             let res = v.map_unary(|x| x.ceil())?;
                             if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(res); }
                         }
+                        "current_lr" => {
+                            // current_lr() - learning rate of the running optimize step (0 outside one)
+                            if !inputs.is_empty() { return Err("current_lr expects 0 arguments".to_string()); }
+                            let lr = self.current_lr;
+                            if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(Value::Scalar(lr)); }
+                        }
+                        "grad_norm" => {
+                            // grad_norm() - pre-clip gradient norm of the previous optimize step
+                            if !inputs.is_empty() { return Err("grad_norm expects 0 arguments".to_string()); }
+                            let norm = self.grad_norm;
+                            if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(Value::Scalar(norm)); }
+                        }
                         // RNG functions for weight initialization
                         "rand" => {
                             // rand() - returns random f64 in [0, 1)
                             if inputs.len() != 0 { return Err("rand expects 0 arguments".to_string()); }
-                            let mut rng = rand::thread_rng();
-                            let val = rng.gen::<f64>();
+                            let val = self.rng.gen::<f64>();
                             if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(Value::Scalar(val)); }
                         }
                         "rand_uniform" => {
@@ -1234,8 +2485,7 @@ impl ComputationalGraph {
                             let max_val = self.nodes.get(&inputs[1]).and_then(|n| n.value.clone()).ok_or("Missing max")?;
                             let min = min_val.as_scalar().ok_or("rand_uniform min must be scalar")?;
                             let max = max_val.as_scalar().ok_or("rand_uniform max must be scalar")?;
-                            let mut rng = rand::thread_rng();
-                            let val = rng.gen_range(min..max);
+                            let val = self.rng.gen_range(min..max);
                             if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(Value::Scalar(val)); }
                         }
                         "rand_normal" => {
@@ -1246,8 +2496,7 @@ impl ComputationalGraph {
                             let mean = mean_val.as_scalar().ok_or("rand_normal mean must be scalar")?;
                             let std = std_val.as_scalar().ok_or("rand_normal std must be scalar")?;
                             let normal = Normal::new(mean, std).map_err(|e| format!("Invalid normal distribution: {}", e))?;
-                            let mut rng = rand::thread_rng();
-                            let val = normal.sample(&mut rng);
+                            let val = normal.sample(&mut self.rng);
                             if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(Value::Scalar(val)); }
                         }
                         "rand_tensor" => {
@@ -1261,8 +2510,7 @@ impl ComputationalGraph {
                                 shape.push(dim as usize);
                             }
                             let size: usize = shape.iter().product();
-                            let mut rng = rand::thread_rng();
-                            let data: Vec<f64> = (0..size).map(|_| rng.gen::<f64>()).collect();
+                            let data: Vec<f64> = (0..size).map(|_| self.rng.gen::<f64>()).collect();
                             let tensor = Tensor { data, shape };
                             if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(Value::Tensor(tensor)); }
                         }
@@ -1282,8 +2530,7 @@ impl ComputationalGraph {
                                 shape.push(dim as usize);
                             }
                             let size: usize = shape.iter().product();
-                            let mut rng = rand::thread_rng();
-                            let data: Vec<f64> = (0..size).map(|_| normal.sample(&mut rng)).collect();
+                            let data: Vec<f64> = (0..size).map(|_| normal.sample(&mut self.rng)).collect();
                             let tensor = Tensor { data, shape };
                             if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(Value::Tensor(tensor)); }
                         }
@@ -1304,8 +2551,7 @@ impl ComputationalGraph {
                                 shape.push(dim as usize);
                             }
                             let size: usize = shape.iter().product();
-                            let mut rng = rand::thread_rng();
-                            let data: Vec<f64> = (0..size).map(|_| rng.gen_range(-limit..limit)).collect();
+                            let data: Vec<f64> = (0..size).map(|_| self.rng.gen_range(-limit..limit)).collect();
                             let tensor = Tensor { data, shape };
                             if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(Value::Tensor(tensor)); }
                         }
@@ -1325,11 +2571,53 @@ impl ComputationalGraph {
                                 shape.push(dim as usize);
                             }
                             let size: usize = shape.iter().product();
-                            let mut rng = rand::thread_rng();
-                            let data: Vec<f64> = (0..size).map(|_| normal.sample(&mut rng)).collect();
+                            let data: Vec<f64> = (0..size).map(|_| normal.sample(&mut self.rng)).collect();
                             let tensor = Tensor { data, shape };
                             if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(Value::Tensor(tensor)); }
                         }
+                        "noise_uniform" | "noise_normal" => {
+                            let args = self.input_values(&inputs)?;
+                            let result = self.eval_noise(&name, &args)?;
+                            if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(result); }
+                        }
+                        "where" | "clip" | "maximum" | "minimum" | "max" | "min" | "sign" => {
+                            let mut args = Vec::with_capacity(inputs.len());
+                            for &inp in &inputs {
+                                args.push(self.nodes.get(&inp).and_then(|n| n.value.clone()).ok_or("Missing argument")?);
+                            }
+                            let result = eval_select_op(&name, &args)?;
+                            if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(result); }
+                        }
+                        "softmax" | "log_softmax" | "cross_entropy" | "binary_cross_entropy" | "mse" | "huber"
+                        | "leaky_relu" | "gelu" | "elu" | "softplus" | "swish" => {
+                            let mut args = Vec::with_capacity(inputs.len());
+                            for &inp in &inputs {
+                                args.push(self.nodes.get(&inp).and_then(|n| n.value.clone()).ok_or("Missing argument")?);
+                            }
+                            let result = eval_nn_op(&name, &args)?;
+                            if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(result); }
+                        }
+                        "layer_norm" | "batch_norm" | "dropout" => {
+                            let args = self.input_values(&inputs)?;
+                            let result = self.eval_norm_op(node_id, &name, &args)?;
+                            if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(result); }
+                        }
+                        "conv1d" | "conv2d" | "max_pool2d" | "avg_pool2d" | "im2col" => {
+                            let mut args = Vec::with_capacity(inputs.len());
+                            for &inp in &inputs {
+                                args.push(self.nodes.get(&inp).and_then(|n| n.value.clone()).ok_or("Missing argument")?);
+                            }
+                            let result = eval_conv_op(&name, &args)?;
+                            if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(result); }
+                        }
+                        "reshape" | "transpose" | "concat" | "stack" | "split" | "slice" | "select" | "squeeze" | "unsqueeze" => {
+                            let mut args = Vec::with_capacity(inputs.len());
+                            for &inp in &inputs {
+                                args.push(self.nodes.get(&inp).and_then(|n| n.value.clone()).ok_or("Missing argument")?);
+                            }
+                            let result = eval_shape_op(&name, &args)?;
+                            if let Some(node) = self.nodes.get_mut(&node_id) { node.value = Some(result); }
+                        }
                         "index" => {
                             if inputs.len() < 2 { return Err("index expects at least target and one index".to_string()); }
                             let target_val = self.nodes.get(&inputs[0]).and_then(|n| n.value.clone()).ok_or("Missing target")?;
@@ -1382,14 +2670,28 @@ impl ComputationalGraph {
     pub fn print_structure(&self) {
         println!("=== Computational Graph ===");
         for (id, node) in &self.nodes {
+            let sampling = match &node.node_type {
+                NodeType::FunctionCall(name) => match sampling_of(name) {
+                    Some(Sampling::Once) => " [sample-once]",
+                    Some(Sampling::PerStep) => " [per-step]",
+                    None => "",
+                },
+                _ => "",
+            };
             println!(
-                "Node {:?}: {:?}, value: {:?}, gradients: {:?}, inputs: {:?}",
-                id, node.node_type, node.value, node.gradient, node.inputs
+                "Node {:?}: {:?}{}, value: {:?}, gradients: {:?}, inputs: {:?}",
+                id, node.node_type, sampling, node.value, node.gradient, node.inputs
             );
         }
     }
 
     pub fn backward_pass(&mut self, output_id: NodeId) -> Result<(), String> {
+        self.update_running_stats();
+        self.backprop(output_id)
+    }
+
+    /// Reverse sweep of `backward_pass`, without committing batch statistics
+    fn backprop(&mut self, output_id: NodeId) -> Result<(), String> {
         if let Some(node) = self.nodes.get_mut(&output_id) {
             node.gradient = Some(node.value.clone().map(|v| v.ones_like()).unwrap_or(Value::Scalar(1.0)));
         }
@@ -1707,7 +3009,7 @@ impl ComputationalGraph {
                                         if let Some(node) = self.nodes.get_mut(&inputs[0]) { node.gradient = Some(add_grad(node.gradient.clone(), local)?); }
                                     }
                                 }
-                                "floor" | "ceil" => {
+                                "floor" | "ceil" | "sign" => {
                                     let zero = match val {
                                         Some(Value::Scalar(_)) => Value::Scalar(0.0),
                                         Some(Value::Tensor(t)) => Value::Tensor(Tensor::zeros(t.shape.clone())),
@@ -1717,17 +3019,42 @@ impl ComputationalGraph {
                                         node.gradient = Some(add_grad(node.gradient.clone(), zero)?);
                                     }
                                 }
-                                "print" => {
-                                    // Pass-through gradient to the printed value
+                                "print" | "log_metric" => {
+                                    // Pass-through gradient to the printed or logged value
                                     if let Some(node) = self.nodes.get_mut(&inputs[0]) {
                                         node.gradient = Some(add_grad(node.gradient.clone(), gradient.clone())?);
                                     }
                                 }
+                                "transpose" | "squeeze" | "concat" | "stack" => {
+                                    self.backprop_multi(&name, &inputs, &gradient, shape_op_grads)?;
+                                }
+                                "softmax" | "log_softmax" | "leaky_relu" | "gelu" | "elu" | "softplus" | "swish" => {
+                                    self.backprop_multi(&name, &inputs, &gradient, nn_op_grads)?;
+                                }
+                                "layer_norm" | "batch_norm" => {
+                                    self.backprop_norm(node_id, &name, &inputs, &gradient)?;
+                                }
                                 _ => {}
                             }
                         } else {
                             // 2+ argument function calls
                             match name.as_str() {
+                                "reshape" | "transpose" | "concat" | "stack" | "split" | "slice" | "select" | "squeeze" | "unsqueeze" => {
+                                    self.backprop_multi(&name, &inputs, &gradient, shape_op_grads)?;
+                                }
+                                "where" | "clip" | "maximum" | "minimum" | "max" | "min" => {
+                                    self.backprop_multi(&name, &inputs, &gradient, select_op_grads)?;
+                                }
+                                "conv1d" | "conv2d" | "max_pool2d" | "avg_pool2d" | "im2col" => {
+                                    self.backprop_multi(&name, &inputs, &gradient, conv_op_grads)?;
+                                }
+                                "layer_norm" | "batch_norm" | "dropout" => {
+                                    self.backprop_norm(node_id, &name, &inputs, &gradient)?;
+                                }
+                                "softmax" | "log_softmax" | "cross_entropy" | "binary_cross_entropy" | "mse" | "huber"
+                                | "leaky_relu" | "elu" => {
+                                    self.backprop_multi(&name, &inputs, &gradient, nn_op_grads)?;
+                                }
                                 "index" => {
                                     // Gradient w.r.t. target tensor: scatter upstream scalar into the chosen index
                                     let target_val = self.nodes.get(&inputs[0]).and_then(|n| n.value.clone());
@@ -1862,44 +3189,216 @@ impl ComputationalGraph {
         Ok(())
     }
 
-    pub fn optimize_step(&mut self, learning_rate: f64) -> Result<(), String> {
-        let node_ids: Vec<NodeId> = self.nodes.keys().copied().collect();
+    /// Route the upstream gradient of a multi-argument builtin back to its inputs, using `rule`
+    /// to compute one optional local gradient per argument
+    fn backprop_multi(&mut self, name: &str, inputs: &[NodeId], gradient: &Value, rule: GradRule) -> Result<(), String> {
+        let args = self.input_values(inputs)?;
+        let grads = rule(name, &args, gradient)?;
+        self.accumulate_grads(inputs, grads)
+    }
 
-        for node_id in node_ids {
-            if let Some(node) = self.nodes.get(&node_id) {
-                if let NodeType::Learnable(_) = &node.node_type {
-                    if let (Some(value), Some(gradient)) = (node.value.clone(), node.gradient.clone()) {
-                        let updated = match (value, gradient) {
-                            (Value::Scalar(v), Value::Scalar(g)) => Value::Scalar(v - learning_rate * g),
-                            (Value::Tensor(v), Value::Tensor(g)) => {
-                                if v.shape != g.shape {
-                                    return Err("Gradient/value tensor shape mismatch".to_string());
-                                }
-                                let data = v.data.iter().zip(g.data.iter()).map(|(v, g)| v - learning_rate * g).collect();
-                                Value::Tensor(Tensor { data, shape: v.shape })
-                            }
-                            _ => return Err("Mixed scalar/tensor optimization not supported".to_string()),
-                        };
+    /// Like `backprop_multi` for layer_norm/batch_norm/dropout, whose gradients depend on the
+    /// execution mode and on state saved by the forward pass
+    fn backprop_norm(&mut self, node_id: NodeId, name: &str, inputs: &[NodeId], gradient: &Value) -> Result<(), String> {
+        let args = self.input_values(inputs)?;
+        let grads = self.norm_op_grads(node_id, name, &args, gradient)?;
+        self.accumulate_grads(inputs, grads)
+    }
 
-                        if let Some(node) = self.nodes.get_mut(&node_id) {
-                            let zero = updated.zeros_like();
-                            node.value = Some(updated);
-                            node.gradient = Some(zero);
-                        }
-                    }
+    fn input_values(&self, inputs: &[NodeId]) -> Result<Vec<Value>, String> {
+        let mut args = Vec::with_capacity(inputs.len());
+        for inp in inputs {
+            args.push(self.nodes.get(inp).and_then(|n| n.value.clone()).ok_or("Missing argument")?);
+        }
+        Ok(args)
+    }
+
+    fn accumulate_grads(&mut self, inputs: &[NodeId], grads: Vec<Option<Value>>) -> Result<(), String> {
+        for (inp, local) in inputs.iter().zip(grads) {
+            if let Some(local) = local {
+                if let Some(node) = self.nodes.get_mut(inp) {
+                    node.gradient = Some(add_grad(node.gradient.clone(), local)?);
                 }
             }
         }
-
         Ok(())
     }
 
-    /// Perform one Adam optimization step
-    /// Adam: Adaptive Moment Estimation
-    /// m_t = β1 * m_{t-1} + (1 - β1) * g_t  (first moment / momentum)
-    /// v_t = β2 * v_{t-1} + (1 - β2) * g_t²  (second moment / squared gradient)
-    /// m̂_t = m_t / (1 - β1^t)  (bias-corrected first moment)
-    /// v̂_t = v_t / (1 - β2^t)  (bias-corrected second moment)
+    /// Draw noise_uniform(min, max, dims...) / noise_normal(mean, std, dims...); a scalar when no
+    /// dims are given
+    fn eval_noise(&mut self, name: &str, args: &[Value]) -> Result<Value, String> {
+        let (a, b, shape) = noise_params(name, args)?;
+        let draw = |rng: &mut ChaCha12Rng| -> Result<f64, String> {
+            if name == "noise_uniform" {
+                Ok(rng.gen_range(a..b))
+            } else {
+                let normal = Normal::new(a, b).map_err(|e| format!("Invalid normal distribution: {}", e))?;
+                Ok(normal.sample(rng))
+            }
+        };
+        match shape {
+            None => Ok(Value::Scalar(draw(&mut self.rng)?)),
+            Some(shape) => {
+                let data = (0..shape_product(&shape)).map(|_| draw(&mut self.rng)).collect::<Result<Vec<_>, _>>()?;
+                Ok(Value::Tensor(Tensor { data, shape }))
+            }
+        }
+    }
+
+    /// Forward evaluation of layer_norm, batch_norm and dropout. In train mode batch_norm
+    /// normalizes with batch statistics and updates its running statistics, and dropout samples
+    /// a fresh keep-mask; in eval mode batch_norm uses the running statistics and dropout is the
+    /// identity.
+    fn eval_norm_op(&mut self, node_id: NodeId, name: &str, args: &[Value]) -> Result<Value, String> {
+        check_norm_arity(name, args)?;
+        if name == "dropout" {
+            let p = dropout_rate(&args[1])?;
+            if !self.is_training() || p == 0.0 {
+                self.dropout_masks.remove(&node_id);
+                return Ok(args[0].clone());
+            }
+            let x = tensor_of(&args[0]);
+            let mask: Vec<f64> = (0..x.data.len())
+                .map(|_| if self.rng.gen::<f64>() < p { 0.0 } else { 1.0 / (1.0 - p) })
+                .collect();
+            let data = x.data.iter().zip(mask.iter()).map(|(v, m)| v * m).collect();
+            self.dropout_masks.insert(node_id, mask);
+            return Ok(value_of(Tensor { data, shape: x.shape }));
+        }
+
+        let x = tensor_of(&args[0]);
+        let layout = NormLayout::new(name, &x.shape)?;
+        let (gamma, beta) = (affine_param(args, 1, &layout, 1.0)?, affine_param(args, 2, &layout, 0.0)?);
+        let (momentum, eps) = norm_hyperparams(name, args)?;
+        let (mean, var) = if name == "batch_norm" && !self.is_training() {
+            self.running_stats.get(&self.stats_key(node_id)).cloned()
+                .unwrap_or_else(|| (vec![0.0; layout.groups], vec![1.0; layout.groups]))
+        } else {
+            let (mean, var) = group_stats(&x.data, &layout);
+            if name == "batch_norm" {
+                let count = x.data.len() / layout.groups;
+                let unbiased = if count > 1 { count as f64 / (count - 1) as f64 } else { 1.0 };
+                let var_unbiased = var.iter().map(|v| v * unbiased).collect();
+                self.batch_stats.insert(self.stats_key(node_id), (mean.clone(), var_unbiased, momentum));
+            }
+            (mean, var)
+        };
+        let data = x.data.iter().enumerate().map(|(i, v)| {
+            let (g, f) = (layout.group(i), layout.feature(i));
+            (v - mean[g]) / (var[g] + eps).sqrt() * gamma[f] + beta[f]
+        }).collect();
+        Ok(Value::Tensor(Tensor { data, shape: x.shape }))
+    }
+
+    /// Gradients of layer_norm, batch_norm and dropout matching the statistics and mask used by
+    /// the last forward pass. Hyperparameters receive no gradient.
+    fn norm_op_grads(&self, node_id: NodeId, name: &str, args: &[Value], gradient: &Value) -> Result<Vec<Option<Value>>, String> {
+        check_norm_arity(name, args)?;
+        let x = tensor_of(&args[0]);
+        let g = upstream_data(gradient, x.data.len(), name)?;
+        let mut grads: Vec<Option<Value>> = vec![None; args.len()];
+        if name == "dropout" {
+            let dx = match self.dropout_masks.get(&node_id) {
+                Some(mask) if self.is_training() => g.iter().zip(mask.iter()).map(|(g, m)| g * m).collect(),
+                _ => g,
+            };
+            grads[0] = Some(value_of(Tensor { data: dx, shape: x.shape }));
+            return Ok(grads);
+        }
+
+        let layout = NormLayout::new(name, &x.shape)?;
+        let gamma = affine_param(args, 1, &layout, 1.0)?;
+        let (_, eps) = norm_hyperparams(name, args)?;
+        let frozen = name == "batch_norm" && !self.is_training();
+        let (mean, var) = if frozen {
+            self.running_stats.get(&self.stats_key(node_id)).cloned()
+                .unwrap_or_else(|| (vec![0.0; layout.groups], vec![1.0; layout.groups]))
+        } else {
+            group_stats(&x.data, &layout)
+        };
+        let inv_std: Vec<f64> = var.iter().map(|v| 1.0 / (v + eps).sqrt()).collect();
+        let xhat: Vec<f64> = x.data.iter().enumerate().map(|(i, v)| (v - mean[layout.group(i)]) * inv_std[layout.group(i)]).collect();
+        let dxhat: Vec<f64> = g.iter().enumerate().map(|(i, g)| g * gamma[layout.feature(i)]).collect();
+
+        let dx: Vec<f64> = if frozen {
+            // Running statistics are constants
+            dxhat.iter().enumerate().map(|(i, d)| d * inv_std[layout.group(i)]).collect()
+        } else {
+            // dx = inv_std * (dxhat - mean(dxhat) - xhat * mean(dxhat * xhat)) per group
+            let mut sum_d = vec![0.0; layout.groups];
+            let mut sum_dx = vec![0.0; layout.groups];
+            for i in 0..x.data.len() {
+                sum_d[layout.group(i)] += dxhat[i];
+                sum_dx[layout.group(i)] += dxhat[i] * xhat[i];
+            }
+            let m = (x.data.len() / layout.groups) as f64;
+            (0..x.data.len()).map(|i| {
+                let k = layout.group(i);
+                inv_std[k] * (dxhat[i] - sum_d[k] / m - xhat[i] * sum_dx[k] / m)
+            }).collect()
+        };
+        grads[0] = Some(Value::Tensor(Tensor { data: dx, shape: x.shape.clone() }));
+
+        let mut dgamma = vec![0.0; layout.dim];
+        let mut dbeta = vec![0.0; layout.dim];
+        for i in 0..x.data.len() {
+            dgamma[layout.feature(i)] += g[i] * xhat[i];
+            dbeta[layout.feature(i)] += g[i];
+        }
+        for (k, d) in [(1, dgamma), (2, dbeta)] {
+            if let Some(arg) = args.get(k) {
+                grads[k] = Some(match arg {
+                    Value::Scalar(_) => Value::Scalar(d.iter().sum()),
+                    Value::Tensor(t) => Value::Tensor(Tensor { data: d, shape: t.shape.clone() }),
+                });
+            }
+        }
+        Ok(grads)
+    }
+
+    pub fn optimize_step(&mut self, learning_rate: f64) -> Result<(), String> {
+        self.optimize_step_sgd(&OptimizerConfig::sgd(learning_rate))
+    }
+
+    /// Plain gradient descent over the learnables `config` updates
+    fn optimize_step_sgd(&mut self, config: &OptimizerConfig) -> Result<(), String> {
+        let node_ids: Vec<NodeId> = self.nodes.keys().copied().collect();
+
+        for node_id in node_ids {
+            if let Some(node) = self.nodes.get(&node_id) {
+                if let Some(learning_rate) = self.update_lr(node, config) {
+                    if let (Some(value), Some(gradient)) = (node.value.clone(), node.gradient.clone()) {
+                        let updated = match (value, gradient) {
+                            (Value::Scalar(v), Value::Scalar(g)) => Value::Scalar(v - learning_rate * g),
+                            (Value::Tensor(v), Value::Tensor(g)) => {
+                                if v.shape != g.shape {
+                                    return Err("Gradient/value tensor shape mismatch".to_string());
+                                }
+                                let data = v.data.iter().zip(g.data.iter()).map(|(v, g)| v - learning_rate * g).collect();
+                                Value::Tensor(Tensor { data, shape: v.shape })
+                            }
+                            _ => return Err("Mixed scalar/tensor optimization not supported".to_string()),
+                        };
+
+                        if let Some(node) = self.nodes.get_mut(&node_id) {
+                            let zero = updated.zeros_like();
+                            node.value = Some(updated);
+                            node.gradient = Some(zero);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Perform one Adam optimization step
+    /// Adam: Adaptive Moment Estimation
+    /// m_t = β1 * m_{t-1} + (1 - β1) * g_t  (first moment / momentum)
+    /// v_t = β2 * v_{t-1} + (1 - β2) * g_t²  (second moment / squared gradient)
+    /// m̂_t = m_t / (1 - β1^t)  (bias-corrected first moment)
+    /// v̂_t = v_t / (1 - β2^t)  (bias-corrected second moment)
     /// θ_t = θ_{t-1} - lr * m̂_t / (√v̂_t + ε)
     pub fn optimize_step_adam(
         &mut self,
@@ -1908,7 +3407,6 @@ impl ComputationalGraph {
     ) -> Result<(), String> {
         state.t += 1;
         let t = state.t as f64;
-        let lr = config.learning_rate;
         let beta1 = config.beta1;
         let beta2 = config.beta2;
         let epsilon = config.epsilon;
@@ -1921,42 +3419,12 @@ impl ComputationalGraph {
 
         for node_id in node_ids {
             if let Some(node) = self.nodes.get(&node_id) {
-                if let NodeType::Learnable(_) = &node.node_type {
+                if let Some(lr) = self.update_lr(node, config) {
                     if let (Some(value), Some(gradient)) = (node.value.clone(), node.gradient.clone()) {
                         // Get or initialize m and v for this parameter
                         // Handle shape mismatch after realloc: extend m/v with zeros for new slots
-                        let m = {
-                            let existing = state.m.entry(node_id).or_insert_with(|| gradient.zeros_like());
-                            match (existing, &gradient) {
-                                (Value::Tensor(m_t), Value::Tensor(g_t)) if m_t.data.len() != g_t.data.len() => {
-                                    // Shape changed (realloc): copy old values, pad with zeros
-                                    let mut new_data = vec![0.0; g_t.data.len()];
-                                    for (i, &v) in m_t.data.iter().enumerate().take(new_data.len()) {
-                                        new_data[i] = v;
-                                    }
-                                    let resized = Value::Tensor(Tensor { data: new_data, shape: g_t.shape.clone() });
-                                    state.m.insert(node_id, resized.clone());
-                                    resized
-                                }
-                                _ => state.m.get(&node_id).cloned().unwrap_or_else(|| gradient.zeros_like())
-                            }
-                        };
-                        let v = {
-                            let existing = state.v.entry(node_id).or_insert_with(|| gradient.zeros_like());
-                            match (existing, &gradient) {
-                                (Value::Tensor(v_t), Value::Tensor(g_t)) if v_t.data.len() != g_t.data.len() => {
-                                    // Shape changed (realloc): copy old values, pad with zeros
-                                    let mut new_data = vec![0.0; g_t.data.len()];
-                                    for (i, &val) in v_t.data.iter().enumerate().take(new_data.len()) {
-                                        new_data[i] = val;
-                                    }
-                                    let resized = Value::Tensor(Tensor { data: new_data, shape: g_t.shape.clone() });
-                                    state.v.insert(node_id, resized.clone());
-                                    resized
-                                }
-                                _ => state.v.get(&node_id).cloned().unwrap_or_else(|| gradient.zeros_like())
-                            }
-                        };
+                        let m = resized_moment(&mut state.m, node_id, &gradient);
+                        let v = resized_moment(&mut state.v, node_id, &gradient);
 
                         let (updated, new_m, new_v) = match (value, gradient, m, v) {
                             (Value::Scalar(param), Value::Scalar(g), Value::Scalar(m_val), Value::Scalar(v_val)) => {
@@ -2036,7 +3504,6 @@ impl ComputationalGraph {
         state: &mut OptimizerState,
         config: &OptimizerConfig,
     ) -> Result<(), String> {
-        let lr = config.learning_rate;
         let beta = config.beta2; // RMSprop uses beta2 as decay rate
         let epsilon = config.epsilon;
 
@@ -2044,7 +3511,7 @@ impl ComputationalGraph {
 
         for node_id in node_ids {
             if let Some(node) = self.nodes.get(&node_id) {
-                if let NodeType::Learnable(_) = &node.node_type {
+                if let Some(lr) = self.update_lr(node, config) {
                     if let (Some(value), Some(gradient)) = (node.value.clone(), node.gradient.clone()) {
                         // Get or initialize v for this parameter
                         let v = state.v.entry(node_id).or_insert_with(|| gradient.zeros_like()).clone();
@@ -2103,17 +3570,348 @@ impl ComputationalGraph {
         Ok(())
     }
 
+    /// Apply a per-element update rule `(lr, param, grad, m, v) -> (param, m, v)` to every
+    /// learnable `config` updates, with m/v resized (zero-padded) after realloc like `optimize_step_adam`
+    fn optimize_step_elementwise(
+        &mut self,
+        state: &mut OptimizerState,
+        config: &OptimizerConfig,
+        rule: &ElementwiseRule,
+    ) -> Result<(), String> {
+        let node_ids: Vec<NodeId> = self.nodes.keys().copied().collect();
+
+        for node_id in node_ids {
+            let Some(node) = self.nodes.get(&node_id) else { continue };
+            let Some(lr) = self.update_lr(node, config) else { continue };
+            let (Some(value), Some(gradient)) = (node.value.clone(), node.gradient.clone()) else { continue };
+            let m = resized_moment(&mut state.m, node_id, &gradient);
+            let v = resized_moment(&mut state.v, node_id, &gradient);
+
+            let (updated, new_m, new_v) = match (value, gradient, m, v) {
+                (Value::Scalar(p), Value::Scalar(g), Value::Scalar(m), Value::Scalar(v)) => {
+                    let (p, m, v) = rule(lr, p, g, m, v);
+                    (Value::Scalar(p), Value::Scalar(m), Value::Scalar(v))
+                }
+                (Value::Tensor(p), Value::Tensor(g), Value::Tensor(m), Value::Tensor(v)) => {
+                    if p.shape != g.shape {
+                        return Err("Gradient/value tensor shape mismatch".to_string());
+                    }
+                    let len = p.data.len();
+                    let (mut new_p, mut new_m, mut new_v) = (Vec::with_capacity(len), Vec::with_capacity(len), Vec::with_capacity(len));
+                    for i in 0..len {
+                        let (p_i, m_i, v_i) = rule(lr, p.data[i], g.data[i], m.data[i], v.data[i]);
+                        new_p.push(p_i);
+                        new_m.push(m_i);
+                        new_v.push(v_i);
+                    }
+                    (
+                        Value::Tensor(Tensor { data: new_p, shape: p.shape.clone() }),
+                        Value::Tensor(Tensor { data: new_m, shape: p.shape.clone() }),
+                        Value::Tensor(Tensor { data: new_v, shape: p.shape }),
+                    )
+                }
+                _ => return Err("Mixed scalar/tensor optimization not supported".to_string()),
+            };
+
+            state.m.insert(node_id, new_m);
+            state.v.insert(node_id, new_v);
+            if let Some(node) = self.nodes.get_mut(&node_id) {
+                let zero = updated.zeros_like();
+                node.value = Some(updated);
+                node.gradient = Some(zero);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// One step of the momentum-family and adaptive optimizers that share `optimize_step_elementwise`:
+    ///
+    /// - Momentum:  m = μ m + g;  θ -= lr m
+    /// - Nesterov:  m = μ m + g;  θ -= lr (g + μ m)
+    /// - AdamW:     Adam moments;  θ -= lr (m̂ / (√v̂ + ε) + λ θ)
+    /// - Adagrad:   v += g²;  θ -= lr g / (√v + ε)
+    /// - Adadelta:  v = ρ v + (1-ρ) g²;  Δ = -√(m + ε) / √(v + ε) g;  m = ρ m + (1-ρ) Δ²;  θ += lr Δ
+    /// - Lion:      θ -= lr (sign(β1 m + (1-β1) g) + λ θ);  m = β2 m + (1-β2) g
+    pub fn optimize_step_extended(
+        &mut self,
+        state: &mut OptimizerState,
+        config: &OptimizerConfig,
+    ) -> Result<(), String> {
+        state.t += 1;
+        let OptimizerConfig { beta1, beta2, epsilon, momentum: mu, weight_decay: wd, .. } = *config;
+        let bias_correction1 = 1.0 - beta1.powi(state.t as i32);
+        let bias_correction2 = 1.0 - beta2.powi(state.t as i32);
+
+        let rule: Box<ElementwiseRule> = match config.optimizer_type {
+            OptimizerType::Momentum => Box::new(move |lr, p, g, m, v| {
+                let m = mu * m + g;
+                (p - lr * m, m, v)
+            }),
+            OptimizerType::Nesterov => Box::new(move |lr, p, g, m, v| {
+                let m = mu * m + g;
+                (p - lr * (g + mu * m), m, v)
+            }),
+            OptimizerType::AdamW => Box::new(move |lr, p, g, m, v| {
+                let m = beta1 * m + (1.0 - beta1) * g;
+                let v = beta2 * v + (1.0 - beta2) * g * g;
+                let step = (m / bias_correction1) / ((v / bias_correction2).sqrt() + epsilon);
+                (p - lr * (step + wd * p), m, v)
+            }),
+            OptimizerType::Adagrad => Box::new(move |lr, p, g, m, v| {
+                let v = v + g * g;
+                (p - lr * g / (v.sqrt() + epsilon), m, v)
+            }),
+            OptimizerType::Adadelta => Box::new(move |lr, p, g, m, v| {
+                let v = beta2 * v + (1.0 - beta2) * g * g;
+                let delta = -((m + epsilon).sqrt() / (v + epsilon).sqrt()) * g;
+                let m = beta2 * m + (1.0 - beta2) * delta * delta;
+                (p + lr * delta, m, v)
+            }),
+            OptimizerType::Lion => Box::new(move |lr, p, g, m, v| {
+                let c = beta1 * m + (1.0 - beta1) * g;
+                let sign = if c > 0.0 { 1.0 } else if c < 0.0 { -1.0 } else { 0.0 };
+                (p - lr * (sign + wd * p), beta2 * m + (1.0 - beta2) * g, v)
+            }),
+            other => return Err(format!("{:?} is not handled by optimize_step_extended", other)),
+        };
+        self.optimize_step_elementwise(state, config, rule.as_ref())
+    }
+
     /// Unified optimize step that dispatches to the appropriate optimizer
     pub fn optimize_step_with_config(
         &mut self,
         state: &mut OptimizerState,
         config: &OptimizerConfig,
     ) -> Result<(), String> {
+        let config = &config.scheduled(state);
+        if !config.optimizer_type.is_second_order() {
+            let norm = self.apply_grad_transforms(config)?;
+            if !norm.is_finite() && !config.grad_transforms.is_empty() {
+                return Err(format!("Gradient norm is {} at step {}; parameters left unchanged", norm, state.t + 1));
+            }
+        }
         match config.optimizer_type {
-            OptimizerType::SGD => self.optimize_step(config.learning_rate),
+            OptimizerType::SGD => {
+                self.optimize_step_sgd(config)?;
+                state.t += 1;
+                Ok(())
+            }
             OptimizerType::Adam => self.optimize_step_adam(state, config),
-            OptimizerType::RMSprop => self.optimize_step_rmsprop(state, config),
+            OptimizerType::RMSprop => {
+                self.optimize_step_rmsprop(state, config)?;
+                state.t += 1;
+                Ok(())
+            }
+            OptimizerType::Momentum
+            | OptimizerType::Nesterov
+            | OptimizerType::AdamW
+            | OptimizerType::Adagrad
+            | OptimizerType::Adadelta
+            | OptimizerType::Lion => self.optimize_step_extended(state, config),
+            OptimizerType::LBFGS | OptimizerType::NewtonCG => Err(format!(
+                "{:?} re-evaluates the objective; use optimize_step_second_order",
+                config.optimizer_type
+            )),
+        }
+    }
+
+    /// Apply `config.grad_transforms` in order to the gradients of the learnables `config`
+    /// updates and return (and record for `grad_norm()`) their global L2 norm before any transform
+    pub fn apply_grad_transforms(&mut self, config: &OptimizerConfig) -> Result<f64, String> {
+        let ids = self.trainable_ids(config);
+        let name_of = |node: &Node| match &node.node_type {
+            NodeType::Learnable(name) => name.clone(),
+            _ => String::new(),
+        };
+        let names: Vec<String> = ids.iter().map(|id| name_of(&self.nodes[id])).collect();
+        let grad_norm_of = |graph: &Self, targets: &[NodeId]| -> f64 {
+            targets.iter()
+                .filter_map(|id| graph.nodes.get(id).and_then(|n| n.gradient.as_ref()))
+                .map(|g| match g {
+                    Value::Scalar(s) => s * s,
+                    Value::Tensor(t) => t.data.iter().map(|x| x * x).sum(),
+                })
+                .sum::<f64>()
+                .sqrt()
+        };
+
+        let norm = grad_norm_of(self, &ids);
+        self.grad_norm = norm;
+
+        for transform in &config.grad_transforms {
+            if let Some(missing) = transform.params.iter().find(|p| !names.contains(p)) {
+                return Err(format!("Gradient transform refers to '{}', which is not a learnable this step updates", missing));
+            }
+            let targets: Vec<NodeId> = ids.iter().zip(&names)
+                .filter(|(_, name)| transform.params.is_empty() || transform.params.contains(name))
+                .map(|(id, _)| *id)
+                .collect();
+            let scale = match transform.kind {
+                GradTransformKind::ClipNorm(max_norm) => {
+                    let norm = grad_norm_of(self, &targets);
+                    if norm <= max_norm {
+                        continue;
+                    }
+                    max_norm / norm
+                }
+                _ => 1.0,
+            };
+            let rng = &mut self.rng;
+            for id in &targets {
+                let Some(node) = self.nodes.get_mut(id) else { continue };
+                let (Some(value), Some(gradient)) = (node.value.as_ref(), node.gradient.as_mut()) else { continue };
+                let pairs: Vec<(f64, &mut f64)> = match (value, gradient) {
+                    (Value::Scalar(p), Value::Scalar(g)) => vec![(*p, g)],
+                    (Value::Tensor(p), Value::Tensor(g)) if p.shape == g.shape => {
+                        p.data.iter().copied().zip(g.data.iter_mut()).collect()
+                    }
+                    _ => return Err("Gradient/value shape mismatch in gradient transform".to_string()),
+                };
+                for (p, g) in pairs {
+                    *g = match transform.kind {
+                        GradTransformKind::ClipValue(max) => g.clamp(-max, max),
+                        GradTransformKind::ClipNorm(_) => *g * scale,
+                        GradTransformKind::L1(weight) => *g + weight * if p > 0.0 { 1.0 } else if p < 0.0 { -1.0 } else { 0.0 },
+                        GradTransformKind::L2(weight) => *g + weight * p,
+                        GradTransformKind::Noise(std) => *g + std * rng.sample::<f64, _>(StandardNormal),
+                    };
+                }
+            }
+        }
+        Ok(norm)
+    }
+
+    /// Learnable names with the node that holds each; a name declared twice maps to its
+    /// latest node
+    pub fn learnables_by_name(&self) -> Vec<(String, NodeId)> {
+        let mut named: Vec<(String, NodeId)> = Vec::new();
+        for id in self.learnable_ids() {
+            let NodeType::Learnable(name) = &self.nodes[&id].node_type else { continue };
+            named.retain(|(other, _)| other != name);
+            named.push((name.clone(), id));
+        }
+        named
+    }
+
+    /// Learnable node ids in creation order; the layout used by the flat-parameter API
+    pub fn learnable_ids(&self) -> Vec<NodeId> {
+        let mut ids: Vec<NodeId> = self.nodes.values()
+            .filter(|n| matches!(n.node_type, NodeType::Learnable(_)))
+            .map(|n| n.id)
+            .collect();
+        ids.sort_by_key(|id| id.index());
+        ids
+    }
+
+    /// Concatenate the values of `ids` into one vector
+    pub fn flat_params(&self, ids: &[NodeId]) -> Vec<f64> {
+        let mut flat = Vec::new();
+        for id in ids {
+            match self.nodes.get(id).and_then(|n| n.value.as_ref()) {
+                Some(Value::Scalar(s)) => flat.push(*s),
+                Some(Value::Tensor(t)) => flat.extend_from_slice(&t.data),
+                None => {}
+            }
+        }
+        flat
+    }
+
+    /// Write a vector laid out like `flat_params(ids)` back into the learnables
+    pub fn set_flat_params(&mut self, ids: &[NodeId], flat: &[f64]) -> Result<(), String> {
+        let expected = self.flat_params(ids).len();
+        if flat.len() != expected {
+            return Err(format!("Flat parameter vector has {} values, expected {}", flat.len(), expected));
+        }
+        let mut offset = 0;
+        for id in ids {
+            let Some(value) = self.nodes.get_mut(id).and_then(|n| n.value.as_mut()) else { continue };
+            let slot = match value {
+                Value::Scalar(s) => std::slice::from_mut(s),
+                Value::Tensor(t) => t.data.as_mut_slice(),
+            };
+            slot.copy_from_slice(&flat[offset..offset + slot.len()]);
+            offset += slot.len();
+        }
+        Ok(())
+    }
+
+    /// Set the learnables `ids` to `params`, then run forward and backward from `objective`.
+    /// Returns the objective (summed if it is a tensor) and its gradient in the same layout.
+    /// Running statistics are not committed, so this can be called any number of times per step.
+    pub fn evaluate_at(&mut self, objective: NodeId, ids: &[NodeId], params: &[f64]) -> Result<(f64, Vec<f64>), String> {
+        self.set_flat_params(ids, params)?;
+        self.forward_pass()?;
+        self.reset_gradients();
+        self.backprop(objective)?;
+
+        let loss = match self.nodes.get(&objective).and_then(|n| n.value.as_ref()) {
+            Some(Value::Scalar(s)) => *s,
+            Some(Value::Tensor(t)) => t.data.iter().sum(),
+            None => return Err("Objective has no value".to_string()),
+        };
+        let mut grad = Vec::with_capacity(params.len());
+        for id in ids {
+            let Some(node) = self.nodes.get(id) else { continue };
+            match (&node.gradient, &node.value) {
+                (Some(Value::Scalar(g)), _) => grad.push(*g),
+                (Some(Value::Tensor(g)), _) => grad.extend_from_slice(&g.data),
+                (None, Some(Value::Scalar(_))) => grad.push(0.0),
+                (None, Some(Value::Tensor(t))) => grad.extend(std::iter::repeat_n(0.0, t.data.len())),
+                (None, None) => {}
+            }
+        }
+        if grad.len() != params.len() {
+            return Err("Gradient layout does not match the learnables".to_string());
+        }
+        Ok((loss, grad))
+    }
+
+    /// Closure over `evaluate_at` mapping flat parameters to (objective, gradient)
+    pub fn objective_closure<'a>(
+        &'a mut self,
+        objective: NodeId,
+        ids: &'a [NodeId],
+    ) -> impl FnMut(&[f64]) -> Result<(f64, Vec<f64>), String> + 'a {
+        move |params| self.evaluate_at(objective, ids, params)
+    }
+
+    /// One L-BFGS or Newton-CG step over the learnables `config` updates. The objective is
+    /// re-evaluated through `objective_closure` by the line search (and by the Hessian-vector
+    /// products of Newton-CG); per-step random nodes such as `dropout` are redrawn on each
+    /// evaluation, so the objective should be deterministic. Gradients are left reset.
+    pub fn optimize_step_second_order(
+        &mut self,
+        state: &mut OptimizerState,
+        config: &OptimizerConfig,
+        objective: NodeId,
+    ) -> Result<(), String> {
+        let config = &config.scheduled(state);
+        if !config.grad_transforms.is_empty() {
+            return Err(format!("{:?} does not support gradient transforms", config.optimizer_type));
         }
+        if !config.param_groups.is_empty() {
+            return Err(format!("{:?} does not support parameter groups", config.optimizer_type));
+        }
+        // Statistics from the forward pass at the current point count once per step
+        self.update_running_stats();
+        let ids = self.trainable_ids(config);
+        let x = self.flat_params(&ids);
+
+        let x_new = {
+            let mut f = self.objective_closure(objective, &ids);
+            match config.optimizer_type {
+                OptimizerType::LBFGS => lbfgs_step(&mut f, &x, config, &mut state.lbfgs_history)?,
+                OptimizerType::NewtonCG => newton_cg_step(&mut f, &x, config)?,
+                other => return Err(format!("{:?} is not a second-order optimizer", other)),
+            }
+        };
+
+        self.set_flat_params(&ids, &x_new)?;
+        self.batch_stats.clear();
+        self.reset_gradients();
+        state.t += 1;
+        Ok(())
     }
 
     pub fn reset_gradients(&mut self) {
@@ -2131,6 +3929,26 @@ impl Default for ComputationalGraph {
     }
 }
 
+/// Per-element optimizer update `(lr, param, grad, m, v) -> (param, m, v)`
+type ElementwiseRule = dyn Fn(f64, f64, f64, f64, f64) -> (f64, f64, f64);
+
+/// Optimizer moment for `id`, created as zeros or zero-padded/truncated to the gradient's size
+/// when the parameter was reallocated
+fn resized_moment(moments: &mut HashMap<NodeId, Value>, id: NodeId, gradient: &Value) -> Value {
+    let existing = moments.entry(id).or_insert_with(|| gradient.zeros_like());
+    if let (Value::Tensor(m_t), Value::Tensor(g_t)) = (&*existing, gradient) {
+        if m_t.data.len() != g_t.data.len() {
+            // Shape changed (realloc): copy old values, pad with zeros
+            let mut new_data = vec![0.0; g_t.data.len()];
+            for (i, &v) in m_t.data.iter().enumerate().take(new_data.len()) {
+                new_data[i] = v;
+            }
+            *existing = Value::Tensor(Tensor { data: new_data, shape: g_t.shape.clone() });
+        }
+    }
+    existing.clone()
+}
+
 fn add_grad(current: Option<Value>, delta: Value) -> Result<Value, String> {
     match (current, delta) {
         (None, d) => Ok(d),
@@ -2190,7 +4008,7 @@ fn shape_product(shape: &[usize]) -> usize {
     shape.iter().product()
 }
 
-fn broadcast_shapes(a: &[usize], b: &[usize]) -> Result<Vec<usize>, String> {
+pub(crate) fn broadcast_shapes(a: &[usize], b: &[usize]) -> Result<Vec<usize>, String> {
     let ra = a.len();
     let rb = b.len();
     let r = ra.max(rb);
@@ -2285,26 +4103,48 @@ fn reduce_to_shape(t: &Tensor, target_shape: &[usize]) -> Result<Tensor, String>
     Ok(Tensor { data: out_data, shape: out.to_vec() })
 }
 
+/// Scalar kernel shared by the elementwise binary ops; comparisons and logic ops yield 0/1
+fn binary_kernel(op: &str, x: f64, y: f64) -> f64 {
+    let truth = |b: bool| if b { 1.0 } else { 0.0 };
+    match op {
+        "add" => x + y,
+        "sub" => x - y,
+        "mul" => x * y,
+        "div" => x / y,
+        "eq" => truth(x == y),
+        "ne" => truth(x != y),
+        "lt" => truth(x < y),
+        "gt" => truth(x > y),
+        "le" => truth(x <= y),
+        "ge" => truth(x >= y),
+        "and" => truth(x != 0.0 && y != 0.0),
+        "or" => truth(x != 0.0 || y != 0.0),
+        "maximum" => x.max(y),
+        "minimum" => x.min(y),
+        _ => unreachable!(),
+    }
+}
+
 fn broadcast_binary(a: &Value, b: &Value, op: &str) -> Result<Value, String> {
     match (a, b) {
-        (Value::Scalar(x), Value::Scalar(y)) => Ok(Value::Scalar(match op { "add"=>x+y, "sub"=>x-y, "mul"=>x*y, "div"=>x/y, _=>unreachable!() })),
+        (Value::Scalar(x), Value::Scalar(y)) => Ok(Value::Scalar(binary_kernel(op, *x, *y))),
         (Value::Scalar(x), Value::Tensor(tb)) => {
-            let data = tb.data.iter().map(|&y| match op { "add"=>x + y, "sub"=>x - y, "mul"=>x * y, "div"=>x / y, _=>unreachable!() }).collect();
+            let data = tb.data.iter().map(|&y| binary_kernel(op, *x, y)).collect();
             Ok(Value::Tensor(Tensor { data, shape: tb.shape.clone() }))
         }
         (Value::Tensor(ta), Value::Scalar(y)) => {
-            let data = ta.data.iter().map(|&x| match op { "add"=>x + y, "sub"=>x - y, "mul"=>x * y, "div"=>x / y, _=>unreachable!() }).collect();
+            let data = ta.data.iter().map(|&x| binary_kernel(op, x, *y)).collect();
             Ok(Value::Tensor(Tensor { data, shape: ta.shape.clone() }))
         }
         (Value::Tensor(ta), Value::Tensor(tb)) => {
             let out_shape = broadcast_shapes(&ta.shape, &tb.shape)?;
             if ta.shape == out_shape && tb.shape == out_shape {
-                let data = ta.data.iter().zip(tb.data.iter()).map(|(&x,&y)| match op { "add"=>x+y, "sub"=>x-y, "mul"=>x*y, "div"=>x/y, _=>unreachable!() }).collect();
+                let data = ta.data.iter().zip(tb.data.iter()).map(|(&x,&y)| binary_kernel(op, x, y)).collect();
                 Ok(Value::Tensor(Tensor { data, shape: out_shape }))
             } else {
                 let a_exp = broadcast_to(ta, &out_shape)?;
                 let b_exp = broadcast_to(tb, &out_shape)?;
-                let data = a_exp.data.iter().zip(b_exp.data.iter()).map(|(&x,&y)| match op { "add"=>x+y, "sub"=>x-y, "mul"=>x*y, "div"=>x/y, _=>unreachable!() }).collect();
+                let data = a_exp.data.iter().zip(b_exp.data.iter()).map(|(&x,&y)| binary_kernel(op, x, y)).collect();
                 Ok(Value::Tensor(Tensor { data, shape: out_shape }))
             }
         }
@@ -2423,356 +4263,3085 @@ fn matmul_tensors_raw(a: &Tensor, b: &Tensor) -> Result<Tensor, String> {
 }
 
 // ============================================================================
-// File I/O Helper Functions
+// Shape Manipulation Helpers
 // ============================================================================
 
-/// Load a CSV file and return tensor data and shape
-/// Expects a numeric CSV (all values are f64)
-pub fn load_csv_file(path: &str) -> Result<(Vec<f64>, Vec<usize>), String> {
-    use std::fs::File;
-    use std::io::{BufRead, BufReader};
-    
-    let file = File::open(path).map_err(|e| format!("Cannot open CSV file '{}': {}", path, e))?;
-    let reader = BufReader::new(file);
-    
-    let mut data = Vec::new();
-    let mut num_cols: Option<usize> = None;
-    let mut num_rows = 0;
-    
-    for (line_num, line_result) in reader.lines().enumerate() {
-        let line = line_result.map_err(|e| format!("Error reading line {}: {}", line_num + 1, e))?;
-        let line = line.trim();
-        
-        // Skip empty lines and comments
-        if line.is_empty() || line.starts_with('#') {
-            continue;
-        }
-        
-        let values: Result<Vec<f64>, _> = line
-            .split(',')
-            .map(|s| s.trim().parse::<f64>())
-            .collect();
-        
-        let row_values = values.map_err(|e| format!("Error parsing line {}: {}", line_num + 1, e))?;
-        
-        // Validate column count consistency
-        match num_cols {
-            None => num_cols = Some(row_values.len()),
-            Some(expected) if expected != row_values.len() => {
-                return Err(format!(
-                    "Inconsistent column count at line {}: expected {}, got {}",
-                    line_num + 1, expected, row_values.len()
-                ));
-            }
-            _ => {}
-        }
-        
-        data.extend(row_values);
-        num_rows += 1;
-    }
-    
-    if num_rows == 0 {
-        return Err(format!("CSV file '{}' is empty or contains no data", path));
+/// View a value as a tensor; scalars become rank-0 tensors
+pub(crate) fn tensor_of(v: &Value) -> Tensor {
+    match v {
+        Value::Scalar(s) => Tensor { data: vec![*s], shape: Vec::new() },
+        Value::Tensor(t) => t.clone(),
     }
-    
-    let cols = num_cols.unwrap_or(1);
-    let shape = if cols == 1 {
-        vec![num_rows]  // 1D tensor for single column
-    } else {
-        vec![num_rows, cols]  // 2D tensor for multiple columns
-    };
-    
-    Ok((data, shape))
 }
 
-/// Save a tensor value to a CSV file
-pub fn save_csv_file(value: &Value, path: &str) -> Result<(), String> {
-    use std::fs::File;
-    use std::io::Write;
-    
-    let mut file = File::create(path).map_err(|e| format!("Cannot create CSV file '{}': {}", path, e))?;
-    
-    match value {
-        Value::Scalar(s) => {
-            writeln!(file, "{}", s).map_err(|e| format!("Error writing to CSV: {}", e))?;
-        }
-        Value::Tensor(t) => {
-            if t.shape.len() == 1 {
-                // 1D tensor: write each element on a new line
-                for val in &t.data {
-                    writeln!(file, "{}", val).map_err(|e| format!("Error writing to CSV: {}", e))?;
-                }
-            } else if t.shape.len() == 2 {
-                // 2D tensor: write each row as a CSV line
-                let cols = t.shape[1];
-                for row in t.data.chunks(cols) {
-                    let line: Vec<String> = row.iter().map(|v| v.to_string()).collect();
-                    writeln!(file, "{}", line.join(",")).map_err(|e| format!("Error writing to CSV: {}", e))?;
-                }
-            } else {
-                // Higher dimensional: flatten to 2D (first dim x rest)
-                let first_dim = t.shape[0];
-                let rest: usize = t.shape[1..].iter().product();
-                for row in 0..first_dim {
-                    let start = row * rest;
-                    let end = start + rest;
-                    let line: Vec<String> = t.data[start..end].iter().map(|v| v.to_string()).collect();
-                    writeln!(file, "{}", line.join(",")).map_err(|e| format!("Error writing to CSV: {}", e))?;
-                }
-            }
-        }
-    }
-    
-    Ok(())
+/// Turn a tensor back into a value; rank-0 tensors collapse to scalars
+fn value_of(t: Tensor) -> Value {
+    if t.shape.is_empty() { Value::Scalar(t.data[0]) } else { Value::Tensor(t) }
 }
 
-/// Load tensors from a Safetensors file
-/// Returns a map of tensor names to (data, shape)
-pub fn load_safetensors_file(path: &str) -> Result<Vec<(String, (Vec<f64>, Vec<usize>))>, String> {
-    use std::fs::File;
-    use std::io::Read;
-    
-    let mut file = File::open(path).map_err(|e| format!("Cannot open safetensors file '{}': {}", path, e))?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).map_err(|e| format!("Error reading safetensors file: {}", e))?;
-    
-    // Parse safetensors format
-    // The format is: 8 bytes header size (little endian u64), then JSON header, then binary data
-    if buffer.len() < 8 {
-        return Err("Invalid safetensors file: too short".to_string());
-    }
-    
-    let header_size = u64::from_le_bytes([
-        buffer[0], buffer[1], buffer[2], buffer[3],
-        buffer[4], buffer[5], buffer[6], buffer[7],
-    ]) as usize;
-    
-    if buffer.len() < 8 + header_size {
-        return Err("Invalid safetensors file: header size mismatch".to_string());
+fn scalar_to_int(v: &Value, what: &str) -> Result<i64, String> {
+    let s = v.as_scalar().ok_or_else(|| format!("{} must be a scalar", what))?;
+    if !s.is_finite() || s.fract() != 0.0 {
+        return Err(format!("{} must be an integer, got {}", what, s));
     }
-    
-    let header_json = std::str::from_utf8(&buffer[8..8 + header_size])
-        .map_err(|e| format!("Invalid UTF-8 in safetensors header: {}", e))?;
-    
-    // Parse header JSON manually (simplified parser for safetensors format)
-    let tensors = parse_safetensors_header(header_json, &buffer[8 + header_size..])?;
-    
-    Ok(tensors)
+    Ok(s as i64)
 }
 
-/// Parse safetensors header JSON and extract tensor data
-fn parse_safetensors_header(header: &str, data: &[u8]) -> Result<Vec<(String, (Vec<f64>, Vec<usize>))>, String> {
-    // Simple JSON parsing for safetensors format
-    // Expected format: { "tensor_name": { "dtype": "F64", "shape": [dim1, dim2], "data_offsets": [start, end] }, ... }
-    
-    let header = header.trim();
-    if !header.starts_with('{') || !header.ends_with('}') {
-        return Err("Invalid safetensors header: not a JSON object".to_string());
+/// Resolve a (possibly negative) axis argument against a tensor rank
+pub(crate) fn resolve_axis(v: &Value, rank: usize, op: &str) -> Result<usize, String> {
+    let a = scalar_to_int(v, &format!("{} axis", op))?;
+    let resolved = if a < 0 { a + rank as i64 } else { a };
+    if resolved < 0 || resolved >= rank as i64 {
+        return Err(format!("{} axis {} out of range for rank {}", op, a, rank));
     }
-    
-    let mut result = Vec::new();
-    
-    // Use serde_json if available, otherwise use simple parsing
-    #[cfg(feature = "serde_json")]
-    {
-        use serde_json::Value as JsonValue;
-        let parsed: serde_json::Map<String, JsonValue> = serde_json::from_str(header)
-            .map_err(|e| format!("Error parsing safetensors header: {}", e))?;
-        
-        for (name, tensor_info) in parsed {
-            if name == "__metadata__" { continue; }
-            
-            let obj = tensor_info.as_object()
-                .ok_or_else(|| format!("Invalid tensor info for '{}'", name))?;
-            
-            let dtype = obj.get("dtype")
-                .and_then(|v| v.as_str())
-                .ok_or_else(|| format!("Missing dtype for tensor '{}'", name))?;
-            
-            let shape: Vec<usize> = obj.get("shape")
-                .and_then(|v| v.as_array())
-                .ok_or_else(|| format!("Missing shape for tensor '{}'", name))?
-                .iter()
-                .filter_map(|v| v.as_u64().map(|n| n as usize))
-                .collect();
-            
-            let offsets: Vec<usize> = obj.get("data_offsets")
-                .and_then(|v| v.as_array())
-                .ok_or_else(|| format!("Missing data_offsets for tensor '{}'", name))?
-                .iter()
-                .filter_map(|v| v.as_u64().map(|n| n as usize))
-                .collect();
-            
-            if offsets.len() != 2 {
-                return Err(format!("Invalid data_offsets for tensor '{}'", name));
+    Ok(resolved as usize)
+}
+
+/// Resolve a (possibly negative) position along a dimension, clamped to [0, dim]
+fn resolve_bound(v: &Value, dim: usize, what: &str) -> Result<usize, String> {
+    let i = scalar_to_int(v, what)?;
+    let resolved = if i < 0 { i + dim as i64 } else { i };
+    Ok(resolved.clamp(0, dim as i64) as usize)
+}
+
+/// Sizes of the dimensions before and after `axis`
+fn outer_inner(shape: &[usize], axis: usize) -> (usize, usize) {
+    (shape_product(&shape[..axis]), shape_product(&shape[axis + 1..]))
+}
+
+fn permute_tensor(t: &Tensor, perm: &[usize]) -> Tensor {
+    let out_shape: Vec<usize> = perm.iter().map(|&p| t.shape[p]).collect();
+    let in_strides = compute_strides(&t.shape);
+    let mut data = vec![0.0; t.data.len()];
+    for (lin, slot) in data.iter_mut().enumerate() {
+        let out_idx = indices_from_linear(lin, &out_shape);
+        let off: usize = out_idx.iter().zip(perm.iter()).map(|(i, &p)| i * in_strides[p]).sum();
+        *slot = t.data[off];
+    }
+    Tensor { data, shape: out_shape }
+}
+
+fn concat_tensors(tensors: &[Tensor], axis: usize) -> Result<Tensor, String> {
+    let first = &tensors[0];
+    for t in &tensors[1..] {
+        let compatible = t.shape.len() == first.shape.len()
+            && t.shape.iter().zip(first.shape.iter()).enumerate().all(|(i, (a, b))| i == axis || a == b);
+        if !compatible {
+            return Err(format!("concat shape mismatch: {:?} vs {:?} along axis {}", first.shape, t.shape, axis));
+        }
+    }
+    let (outer, inner) = outer_inner(&first.shape, axis);
+    let mut shape = first.shape.clone();
+    shape[axis] = tensors.iter().map(|t| t.shape[axis]).sum();
+    let mut data = Vec::with_capacity(shape_product(&shape));
+    for o in 0..outer {
+        for t in tensors {
+            let block = t.shape[axis] * inner;
+            data.extend_from_slice(&t.data[o * block..(o + 1) * block]);
+        }
+    }
+    Ok(Tensor { data, shape })
+}
+
+fn slice_tensor(t: &Tensor, axis: usize, start: usize, end: usize) -> Tensor {
+    let (outer, inner) = outer_inner(&t.shape, axis);
+    let dim = t.shape[axis];
+    let mut data = Vec::with_capacity(outer * (end - start) * inner);
+    for o in 0..outer {
+        data.extend_from_slice(&t.data[(o * dim + start) * inner..(o * dim + end) * inner]);
+    }
+    let mut shape = t.shape.clone();
+    shape[axis] = end - start;
+    Tensor { data, shape }
+}
+
+/// Inverse of slice_tensor: place `part` at `start` along `axis` in a zero tensor of `shape`
+fn embed_slice(part: &Tensor, shape: &[usize], axis: usize, start: usize) -> Tensor {
+    let (outer, inner) = outer_inner(shape, axis);
+    let dim = shape[axis];
+    let len = part.shape[axis];
+    let mut data = vec![0.0; shape_product(shape)];
+    for o in 0..outer {
+        let dst = (o * dim + start) * inner;
+        data[dst..dst + len * inner].copy_from_slice(&part.data[o * len * inner..(o + 1) * len * inner]);
+    }
+    Tensor { data, shape: shape.to_vec() }
+}
+
+fn reshape_dims(t: &Tensor, dims: &[Value]) -> Result<Vec<usize>, String> {
+    if dims.is_empty() { return Err("reshape expects at least one dimension".to_string()); }
+    let mut shape = Vec::with_capacity(dims.len());
+    let mut inferred = None;
+    for (i, d) in dims.iter().enumerate() {
+        match scalar_to_int(d, "reshape dimension")? {
+            -1 if inferred.is_none() => { inferred = Some(i); shape.push(1); }
+            -1 => return Err("reshape can only infer one dimension".to_string()),
+            n if n < 1 => return Err(format!("reshape dimension must be positive, got {}", n)),
+            n => shape.push(n as usize),
+        }
+    }
+    if let Some(i) = inferred {
+        let known = shape_product(&shape);
+        if !t.data.len().is_multiple_of(known) {
+            return Err(format!("cannot reshape {} elements into {:?}", t.data.len(), dims));
+        }
+        shape[i] = t.data.len() / known;
+    }
+    if shape_product(&shape) != t.data.len() {
+        return Err(format!("cannot reshape tensor of shape {:?} into {:?}", t.shape, shape));
+    }
+    Ok(shape)
+}
+
+pub(crate) fn transpose_perm(t: &Tensor, axes: &[Value]) -> Result<Vec<usize>, String> {
+    let rank = t.shape.len();
+    if axes.is_empty() {
+        // Default: reverse the axes (swap rows/columns for matrices)
+        return Ok((0..rank).rev().collect());
+    }
+    if axes.len() != rank {
+        return Err(format!("transpose expects {} axes for a rank-{} tensor", rank, rank));
+    }
+    let mut perm = Vec::with_capacity(rank);
+    for a in axes {
+        let p = resolve_axis(a, rank, "transpose")?;
+        if perm.contains(&p) { return Err("transpose axes must be a permutation".to_string()); }
+        perm.push(p);
+    }
+    Ok(perm)
+}
+
+/// Split concat/stack arguments into tensors and an optional trailing axis scalar
+fn concat_args(op: &str, args: &[Value]) -> Result<(Vec<Tensor>, Option<Value>), String> {
+    let (tensor_args, axis) = match args.last() {
+        Some(Value::Scalar(_)) if args.len() > 1 => (&args[..args.len() - 1], args.last().cloned()),
+        _ => (args, None),
+    };
+    let mut tensors = Vec::with_capacity(tensor_args.len());
+    for a in tensor_args {
+        match a {
+            Value::Tensor(t) => tensors.push(t.clone()),
+            Value::Scalar(_) => return Err(format!("{} expects tensors followed by an optional axis", op)),
+        }
+    }
+    if tensors.is_empty() { return Err(format!("{} expects at least one tensor", op)); }
+    Ok((tensors, axis))
+}
+
+/// Axis and [start, end) range selected by slice(x, axis, start[, end])
+fn slice_range(t: &Tensor, args: &[Value]) -> Result<(usize, usize, usize), String> {
+    if args.len() < 3 || args.len() > 4 { return Err("slice expects (x, axis, start[, end])".to_string()); }
+    let axis = resolve_axis(&args[1], t.shape.len(), "slice")?;
+    let dim = t.shape[axis];
+    let start = resolve_bound(&args[2], dim, "slice start")?;
+    let end = match args.get(3) { Some(e) => resolve_bound(e, dim, "slice end")?, None => dim };
+    if start >= end { return Err(format!("slice {}:{} is empty for dimension of size {}", start, end, dim)); }
+    Ok((axis, start, end))
+}
+
+/// Axis and [start, end) range of chunk i selected by split(x, sections, i[, axis])
+fn split_range(t: &Tensor, args: &[Value]) -> Result<(usize, usize, usize), String> {
+    if args.len() < 3 || args.len() > 4 { return Err("split expects (x, sections, i[, axis])".to_string()); }
+    let axis = resolve_axis(args.get(3).unwrap_or(&Value::Scalar(0.0)), t.shape.len(), "split")?;
+    let sections = scalar_to_int(&args[1], "split sections")?;
+    let dim = t.shape[axis];
+    if sections < 1 || !dim.is_multiple_of(sections as usize) {
+        return Err(format!("split cannot divide dimension of size {} into {} sections", dim, sections));
+    }
+    let chunk = dim / sections as usize;
+    let i = scalar_to_int(&args[2], "split index")?;
+    if i < 0 || i >= sections { return Err(format!("split index {} out of range for {} sections", i, sections)); }
+    Ok((axis, i as usize * chunk, (i as usize + 1) * chunk))
+}
+
+/// Axis and position selected by select(x, axis, i)
+fn select_pos(t: &Tensor, args: &[Value]) -> Result<(usize, usize), String> {
+    if args.len() != 3 { return Err("select expects (x, axis, i)".to_string()); }
+    let axis = resolve_axis(&args[1], t.shape.len(), "select")?;
+    let dim = t.shape[axis];
+    let i = scalar_to_int(&args[2], "select index")?;
+    let resolved = if i < 0 { i + dim as i64 } else { i };
+    if resolved < 0 || resolved >= dim as i64 {
+        return Err(format!("select index {} out of bounds for dimension of size {}", i, dim));
+    }
+    Ok((axis, resolved as usize))
+}
+
+/// Forward evaluation of the shape manipulation builtins
+fn eval_shape_op(name: &str, args: &[Value]) -> Result<Value, String> {
+    let x = args.first().map(tensor_of).ok_or_else(|| format!("{} expects at least 1 argument", name))?;
+    match name {
+        "reshape" => {
+            let shape = reshape_dims(&x, &args[1..])?;
+            Ok(value_of(Tensor { data: x.data, shape }))
+        }
+        "transpose" => {
+            let perm = transpose_perm(&x, &args[1..])?;
+            Ok(value_of(permute_tensor(&x, &perm)))
+        }
+        "concat" => {
+            let (tensors, axis) = concat_args(name, args)?;
+            let axis = resolve_axis(&axis.unwrap_or(Value::Scalar(0.0)), tensors[0].shape.len(), name)?;
+            Ok(Value::Tensor(concat_tensors(&tensors, axis)?))
+        }
+        "stack" => {
+            let (tensors, axis) = concat_args(name, args)?;
+            let axis = resolve_axis(&axis.unwrap_or(Value::Scalar(0.0)), tensors[0].shape.len() + 1, name)?;
+            let expanded: Vec<Tensor> = tensors.into_iter().map(|mut t| { t.shape.insert(axis, 1); t }).collect();
+            if expanded.iter().any(|t| t.shape != expanded[0].shape) {
+                return Err("stack expects tensors of identical shape".to_string());
+            }
+            Ok(Value::Tensor(concat_tensors(&expanded, axis)?))
+        }
+        "split" => {
+            let (axis, start, end) = split_range(&x, args)?;
+            Ok(Value::Tensor(slice_tensor(&x, axis, start, end)))
+        }
+        "slice" => {
+            let (axis, start, end) = slice_range(&x, args)?;
+            Ok(Value::Tensor(slice_tensor(&x, axis, start, end)))
+        }
+        "select" => {
+            let (axis, i) = select_pos(&x, args)?;
+            let mut t = slice_tensor(&x, axis, i, i + 1);
+            t.shape.remove(axis);
+            Ok(value_of(t))
+        }
+        "squeeze" => {
+            let mut shape = x.shape.clone();
+            match args.get(1) {
+                Some(a) => {
+                    let axis = resolve_axis(a, shape.len(), name)?;
+                    if shape[axis] != 1 { return Err(format!("squeeze axis {} has size {}, expected 1", axis, shape[axis])); }
+                    shape.remove(axis);
+                }
+                None => shape.retain(|&d| d != 1),
+            }
+            Ok(value_of(Tensor { data: x.data, shape }))
+        }
+        "unsqueeze" => {
+            let a = args.get(1).ok_or("unsqueeze expects (x, axis)")?;
+            let axis = resolve_axis(a, x.shape.len() + 1, name)?;
+            let mut shape = x.shape.clone();
+            shape.insert(axis, 1);
+            Ok(Value::Tensor(Tensor { data: x.data, shape }))
+        }
+        _ => Err(format!("Unknown shape op: {}", name)),
+    }
+}
+
+/// Gradients of the shape manipulation builtins w.r.t. each argument (None for axis/size arguments)
+fn shape_op_grads(name: &str, args: &[Value], gradient: &Value) -> Result<Vec<Option<Value>>, String> {
+    let out = tensor_of(&eval_shape_op(name, args)?);
+    let g = match gradient {
+        Value::Tensor(t) => Tensor { data: t.data.clone(), shape: out.shape.clone() },
+        Value::Scalar(s) => Tensor { data: vec![*s; out.data.len()], shape: out.shape.clone() },
+    };
+    let x = tensor_of(&args[0]);
+    // Reshape a flat gradient back onto the first argument (scalar inputs get a scalar gradient)
+    let onto_input = |data: Vec<f64>| -> Value {
+        value_of(Tensor { data, shape: x.shape.clone() })
+    };
+    let mut grads: Vec<Option<Value>> = vec![None; args.len()];
+    match name {
+        "reshape" | "squeeze" | "unsqueeze" => grads[0] = Some(onto_input(g.data)),
+        "transpose" => {
+            let perm = transpose_perm(&x, &args[1..])?;
+            let mut inverse = vec![0usize; perm.len()];
+            for (i, &p) in perm.iter().enumerate() { inverse[p] = i; }
+            grads[0] = Some(onto_input(permute_tensor(&g, &inverse).data));
+        }
+        "concat" => {
+            let (tensors, axis) = concat_args(name, args)?;
+            let axis = resolve_axis(&axis.unwrap_or(Value::Scalar(0.0)), tensors[0].shape.len(), name)?;
+            let mut offset = 0;
+            for (k, t) in tensors.iter().enumerate() {
+                let len = t.shape[axis];
+                grads[k] = Some(Value::Tensor(slice_tensor(&g, axis, offset, offset + len)));
+                offset += len;
+            }
+        }
+        "stack" => {
+            let (tensors, axis) = concat_args(name, args)?;
+            let axis = resolve_axis(&axis.unwrap_or(Value::Scalar(0.0)), tensors[0].shape.len() + 1, name)?;
+            for (k, t) in tensors.iter().enumerate() {
+                let part = slice_tensor(&g, axis, k, k + 1);
+                grads[k] = Some(Value::Tensor(Tensor { data: part.data, shape: t.shape.clone() }));
+            }
+        }
+        "split" | "slice" => {
+            let (axis, start, _) = if name == "split" { split_range(&x, args)? } else { slice_range(&x, args)? };
+            grads[0] = Some(Value::Tensor(embed_slice(&g, &x.shape, axis, start)));
+        }
+        "select" => {
+            let (axis, i) = select_pos(&x, args)?;
+            let mut shape = g.shape.clone();
+            shape.insert(axis, 1);
+            let part = Tensor { data: g.data, shape };
+            grads[0] = Some(Value::Tensor(embed_slice(&part, &x.shape, axis, i)));
+        }
+        _ => return Err(format!("Unknown shape op: {}", name)),
+    }
+    Ok(grads)
+}
+
+// ============================================================================
+// Elementwise Selection Helpers
+// ============================================================================
+
+/// Flat data of each argument plus the common output shape (None when all arguments are scalars)
+type BroadcastArgs = (Vec<Vec<f64>>, Option<Vec<usize>>);
+
+/// Broadcast every argument to a common shape
+fn broadcast_args(args: &[Value]) -> Result<BroadcastArgs, String> {
+    let mut shape: Option<Vec<usize>> = None;
+    for a in args {
+        if let Value::Tensor(t) = a {
+            shape = Some(match shape {
+                None => t.shape.clone(),
+                Some(s) => broadcast_shapes(&s, &t.shape)?,
+            });
+        }
+    }
+    let size = shape.as_deref().map(shape_product).unwrap_or(1);
+    let mut data = Vec::with_capacity(args.len());
+    for a in args {
+        data.push(match (a, &shape) {
+            (Value::Scalar(x), _) => vec![*x; size],
+            (Value::Tensor(t), Some(s)) => broadcast_to(t, s)?.data,
+            (Value::Tensor(_), None) => unreachable!(),
+        });
+    }
+    Ok((data, shape))
+}
+
+fn value_from_data(data: Vec<f64>, shape: &Option<Vec<usize>>) -> Value {
+    match shape {
+        None => Value::Scalar(data[0]),
+        Some(s) => Value::Tensor(Tensor { data, shape: s.clone() }),
+    }
+}
+
+/// Sum a gradient computed in the broadcast output shape back down to an argument's shape
+fn unbroadcast_grad(data: Vec<f64>, shape: &Option<Vec<usize>>, arg: &Value) -> Result<Value, String> {
+    match (arg, shape) {
+        (Value::Scalar(_), _) => Ok(Value::Scalar(data.iter().sum())),
+        (Value::Tensor(t), Some(s)) => Ok(Value::Tensor(reduce_to_shape(&Tensor { data, shape: s.clone() }, &t.shape)?)),
+        (Value::Tensor(_), None) => Err("Gradient shape mismatch".to_string()),
+    }
+}
+
+/// Forward evaluation of where/clip/maximum/minimum/sign
+fn eval_select_op(name: &str, args: &[Value]) -> Result<Value, String> {
+    let arity = match name { "where" | "clip" => 3, "sign" => 1, _ => 2 };
+    if args.len() != arity {
+        return Err(format!("{} expects {} arguments", name, arity));
+    }
+    match name {
+        "where" | "clip" => {
+            let (data, shape) = broadcast_args(args)?;
+            let out = (0..data[0].len()).map(|i| {
+                let (a, b, c) = (data[0][i], data[1][i], data[2][i]);
+                if name == "where" { if a != 0.0 { b } else { c } } else { a.max(b).min(c) }
+            }).collect();
+            Ok(value_from_data(out, &shape))
+        }
+        "maximum" | "max" => broadcast_binary(&args[0], &args[1], "maximum"),
+        "minimum" | "min" => broadcast_binary(&args[0], &args[1], "minimum"),
+        "sign" => args[0].map_unary(|x| if x > 0.0 { 1.0 } else if x < 0.0 { -1.0 } else { 0.0 }),
+        _ => Err(format!("Unknown selection op: {}", name)),
+    }
+}
+
+/// Sub-gradients of where/clip/maximum/minimum. Ties in maximum/minimum split the gradient
+/// evenly; clip passes the gradient to x inside [lo, hi] and to the active bound outside it.
+fn select_op_grads(name: &str, args: &[Value], gradient: &Value) -> Result<Vec<Option<Value>>, String> {
+    let (data, shape) = broadcast_args(args)?;
+    let size = data[0].len();
+    let g = upstream_data(gradient, size, name)?;
+    // Weight of the upstream gradient routed to each argument, per element
+    let weights: Vec<Vec<f64>> = match name {
+        "where" => vec![
+            vec![0.0; size],
+            (0..size).map(|i| if data[0][i] != 0.0 { 1.0 } else { 0.0 }).collect(),
+            (0..size).map(|i| if data[0][i] != 0.0 { 0.0 } else { 1.0 }).collect(),
+        ],
+        "clip" => vec![
+            (0..size).map(|i| if data[0][i] >= data[1][i] && data[0][i] <= data[2][i] { 1.0 } else { 0.0 }).collect(),
+            (0..size).map(|i| if data[0][i] < data[1][i] { 1.0 } else { 0.0 }).collect(),
+            (0..size).map(|i| if data[0][i] > data[2][i] && data[0][i] >= data[1][i] { 1.0 } else { 0.0 }).collect(),
+        ],
+        "maximum" | "max" | "minimum" | "min" => {
+            let prefer_a = |a: f64, b: f64| if name.starts_with("max") { a > b } else { a < b };
+            let wa: Vec<f64> = (0..size).map(|i| {
+                let (a, b) = (data[0][i], data[1][i]);
+                if a == b { 0.5 } else if prefer_a(a, b) { 1.0 } else { 0.0 }
+            }).collect();
+            let wb = wa.iter().map(|w| 1.0 - w).collect();
+            vec![wa, wb]
+        }
+        _ => return Err(format!("Unknown selection op: {}", name)),
+    };
+    let mut grads = Vec::with_capacity(args.len());
+    for (k, w) in weights.into_iter().enumerate() {
+        if name == "where" && k == 0 {
+            grads.push(None);
+            continue;
+        }
+        let local: Vec<f64> = g.iter().zip(w.iter()).map(|(g, w)| g * w).collect();
+        grads.push(Some(unbroadcast_grad(local, &shape, &args[k])?));
+    }
+    Ok(grads)
+}
+
+// ============================================================================
+// Neural Network Helpers
+// ============================================================================
+
+/// Flatten an upstream gradient to `size` elements, broadcasting a scalar
+fn upstream_data(gradient: &Value, size: usize, name: &str) -> Result<Vec<f64>, String> {
+    match gradient {
+        Value::Scalar(s) => Ok(vec![*s; size]),
+        Value::Tensor(t) if t.data.len() == size => Ok(t.data.clone()),
+        Value::Tensor(_) => Err(format!("{} gradient shape mismatch", name)),
+    }
+}
+
+fn stable_sigmoid(x: f64) -> f64 {
+    if x >= 0.0 {
+        1.0 / (1.0 + (-x).exp())
+    } else {
+        let e = x.exp();
+        e / (1.0 + e)
+    }
+}
+
+const GELU_C: f64 = 0.7978845608028654; // sqrt(2 / pi)
+
+/// Value and derivative of a pointwise activation at x
+fn activation_kernel(name: &str, x: f64, alpha: f64) -> (f64, f64) {
+    match name {
+        "leaky_relu" => if x > 0.0 { (x, 1.0) } else { (alpha * x, alpha) },
+        "elu" => if x > 0.0 { (x, 1.0) } else { (alpha * x.exp_m1(), alpha * x.exp()) },
+        "gelu" => {
+            // tanh approximation, matches the LLVM lowering
+            let t = (GELU_C * (x + 0.044715 * x * x * x)).tanh();
+            let du = GELU_C * (1.0 + 3.0 * 0.044715 * x * x);
+            (0.5 * x * (1.0 + t), 0.5 * (1.0 + t) + 0.5 * x * (1.0 - t * t) * du)
+        }
+        "softplus" => (x.max(0.0) + (-x.abs()).exp().ln_1p(), stable_sigmoid(x)),
+        "swish" => {
+            let s = stable_sigmoid(x);
+            (x * s, s + x * s * (1.0 - s))
+        }
+        _ => unreachable!("not an activation: {}", name),
+    }
+}
+
+fn activation_alpha(name: &str, args: &[Value]) -> Result<f64, String> {
+    match args.get(1) {
+        Some(a) => a.as_scalar().ok_or_else(|| format!("{} alpha must be a scalar", name)),
+        None => Ok(if name == "leaky_relu" { 0.01 } else { 1.0 }),
+    }
+}
+
+/// Stable log-softmax along `axis` (max-subtracted log-sum-exp)
+fn log_softmax_tensor(t: &Tensor, axis: usize) -> Tensor {
+    let dim = t.shape[axis];
+    let (outer, inner) = outer_inner(&t.shape, axis);
+    let mut out = vec![0.0; t.data.len()];
+    for o in 0..outer {
+        for i in 0..inner {
+            let at = |k: usize| (o * dim + k) * inner + i;
+            let max = (0..dim).map(|k| t.data[at(k)]).fold(f64::NEG_INFINITY, f64::max);
+            let lse = max + (0..dim).map(|k| (t.data[at(k)] - max).exp()).sum::<f64>().ln();
+            for k in 0..dim {
+                out[at(k)] = t.data[at(k)] - lse;
+            }
+        }
+    }
+    Tensor { data: out, shape: t.shape.clone() }
+}
+
+fn softmax_axis(name: &str, args: &[Value]) -> Result<(Tensor, usize), String> {
+    let t = match &args[0] {
+        Value::Tensor(t) if !t.shape.is_empty() => t.clone(),
+        _ => return Err(format!("{} expects a tensor", name)),
+    };
+    let axis = match args.get(1) {
+        Some(a) => resolve_axis(a, t.shape.len(), name)?,
+        None => t.shape.len() - 1,
+    };
+    Ok((t, axis))
+}
+
+/// Dense per-element targets for cross_entropy. Labels are either a distribution with the
+/// logits' shape or class indices, one per row of the last axis. Returns (targets, dense).
+fn cross_entropy_targets(logits: &Tensor, labels: &Value) -> Result<(Vec<f64>, bool), String> {
+    let classes = *logits.shape.last().ok_or("cross_entropy expects tensor logits")?;
+    let rows = logits.data.len() / classes;
+    let labels = tensor_of(labels);
+    if labels.shape == logits.shape {
+        return Ok((labels.data, true));
+    }
+    if labels.data.len() != rows || labels.shape.as_slice() != &logits.shape[..logits.shape.len() - 1] {
+        return Err(format!(
+            "cross_entropy labels must have shape {:?} (class indices) or {:?}, got {:?}",
+            &logits.shape[..logits.shape.len() - 1], logits.shape, labels.shape
+        ));
+    }
+    let mut dense = vec![0.0; logits.data.len()];
+    for (r, &c) in labels.data.iter().enumerate() {
+        if c.fract() != 0.0 || c < 0.0 || c as usize >= classes {
+            return Err(format!("cross_entropy label {} out of range for {} classes", c, classes));
+        }
+        dense[r * classes + c as usize] = 1.0;
+    }
+    Ok((dense, false))
+}
+
+const BCE_EPS: f64 = 1e-12;
+
+fn huber_delta(args: &[Value]) -> Result<f64, String> {
+    match args.get(2) {
+        Some(d) => d.as_scalar().ok_or_else(|| "huber delta must be a scalar".to_string()),
+        None => Ok(1.0),
+    }
+}
+
+fn check_nn_arity(name: &str, args: &[Value]) -> Result<(), String> {
+    let (min, max) = match name {
+        "leaky_relu" | "elu" | "softmax" | "log_softmax" => (1, 2),
+        "gelu" | "softplus" | "swish" => (1, 1),
+        "huber" => (2, 3),
+        _ => (2, 2),
+    };
+    if args.len() < min || args.len() > max {
+        let expected = if min == max { min.to_string() } else { format!("{} or {}", min, max) };
+        return Err(format!("{} expects {} arguments, got {}", name, expected, args.len()));
+    }
+    Ok(())
+}
+
+/// Forward evaluation of activations, softmax variants and losses. Losses average over elements
+/// (cross_entropy over rows).
+fn eval_nn_op(name: &str, args: &[Value]) -> Result<Value, String> {
+    check_nn_arity(name, args)?;
+    match name {
+        "leaky_relu" | "elu" | "gelu" | "softplus" | "swish" => {
+            let alpha = activation_alpha(name, args)?;
+            args[0].map_unary(|x| activation_kernel(name, x, alpha).0)
+        }
+        "softmax" | "log_softmax" => {
+            let (t, axis) = softmax_axis(name, args)?;
+            let mut out = log_softmax_tensor(&t, axis);
+            if name == "softmax" {
+                out.data.iter_mut().for_each(|v| *v = v.exp());
+            }
+            Ok(Value::Tensor(out))
+        }
+        "cross_entropy" => {
+            let logits = tensor_of(&args[0]);
+            let (targets, _) = cross_entropy_targets(&logits, &args[1])?;
+            let logp = log_softmax_tensor(&logits, logits.shape.len() - 1);
+            let rows = (logits.data.len() / logits.shape[logits.shape.len() - 1]) as f64;
+            let total: f64 = targets.iter().zip(logp.data.iter()).map(|(t, l)| t * l).sum();
+            Ok(Value::Scalar(-total / rows))
+        }
+        "binary_cross_entropy" | "mse" | "huber" => {
+            let (data, _) = broadcast_args(&args[..2])?;
+            let n = data[0].len() as f64;
+            let delta = huber_delta(args)?;
+            let total: f64 = data[0].iter().zip(data[1].iter()).map(|(&p, &t)| match name {
+                "binary_cross_entropy" => {
+                    let p = p.clamp(BCE_EPS, 1.0 - BCE_EPS);
+                    -(t * p.ln() + (1.0 - t) * (1.0 - p).ln())
+                }
+                "mse" => (p - t) * (p - t),
+                _ => {
+                    let d = (p - t).abs();
+                    if d <= delta { 0.5 * d * d } else { delta * (d - 0.5 * delta) }
+                }
+            }).sum();
+            Ok(Value::Scalar(total / n))
+        }
+        _ => Err(format!("Unknown neural network op: {}", name)),
+    }
+}
+
+/// Analytic gradients of the neural network builtins. Hyperparameters (alpha, axis, delta) and
+/// class-index labels receive no gradient.
+fn nn_op_grads(name: &str, args: &[Value], gradient: &Value) -> Result<Vec<Option<Value>>, String> {
+    check_nn_arity(name, args)?;
+    let mut grads: Vec<Option<Value>> = vec![None; args.len()];
+    match name {
+        "leaky_relu" | "elu" | "gelu" | "softplus" | "swish" => {
+            let x = tensor_of(&args[0]);
+            let alpha = activation_alpha(name, args)?;
+            let g = upstream_data(gradient, x.data.len(), name)?;
+            let data = x.data.iter().zip(g.iter()).map(|(&x, g)| g * activation_kernel(name, x, alpha).1).collect();
+            grads[0] = Some(value_of(Tensor { data, shape: x.shape }));
+        }
+        "softmax" | "log_softmax" => {
+            let (t, axis) = softmax_axis(name, args)?;
+            let g = upstream_data(gradient, t.data.len(), name)?;
+            let logp = log_softmax_tensor(&t, axis);
+            let dim = t.shape[axis];
+            let (outer, inner) = outer_inner(&t.shape, axis);
+            let mut dx = vec![0.0; t.data.len()];
+            for o in 0..outer {
+                for i in 0..inner {
+                    let at = |k: usize| (o * dim + k) * inner + i;
+                    if name == "softmax" {
+                        // dx = y * (g - sum(g * y))
+                        let dot: f64 = (0..dim).map(|k| g[at(k)] * logp.data[at(k)].exp()).sum();
+                        for k in 0..dim {
+                            dx[at(k)] = logp.data[at(k)].exp() * (g[at(k)] - dot);
+                        }
+                    } else {
+                        // dx = g - softmax * sum(g)
+                        let total: f64 = (0..dim).map(|k| g[at(k)]).sum();
+                        for k in 0..dim {
+                            dx[at(k)] = g[at(k)] - logp.data[at(k)].exp() * total;
+                        }
+                    }
+                }
+            }
+            grads[0] = Some(Value::Tensor(Tensor { data: dx, shape: t.shape }));
+        }
+        "cross_entropy" => {
+            let g = gradient.as_scalar().ok_or("Expected scalar gradient for cross_entropy")?;
+            let logits = tensor_of(&args[0]);
+            let (targets, dense) = cross_entropy_targets(&logits, &args[1])?;
+            let logp = log_softmax_tensor(&logits, logits.shape.len() - 1);
+            let rows = (logits.data.len() / logits.shape[logits.shape.len() - 1]) as f64;
+            let scale = g / rows;
+            let dlogits = logp.data.iter().zip(targets.iter()).map(|(l, t)| scale * (l.exp() - t)).collect();
+            grads[0] = Some(Value::Tensor(Tensor { data: dlogits, shape: logits.shape.clone() }));
+            if dense {
+                let dlabels = logp.data.iter().map(|l| -scale * l).collect();
+                grads[1] = Some(value_of(Tensor { data: dlabels, shape: logits.shape }));
+            }
+        }
+        "binary_cross_entropy" | "mse" | "huber" => {
+            let g = gradient.as_scalar().ok_or_else(|| format!("Expected scalar gradient for {}", name))?;
+            let (data, shape) = broadcast_args(&args[..2])?;
+            let scale = g / data[0].len() as f64;
+            let delta = huber_delta(args)?;
+            let mut dp = Vec::with_capacity(data[0].len());
+            let mut dt = Vec::with_capacity(data[0].len());
+            for (&p, &t) in data[0].iter().zip(data[1].iter()) {
+                let (a, b) = match name {
+                    "binary_cross_entropy" => {
+                        let p = p.clamp(BCE_EPS, 1.0 - BCE_EPS);
+                        ((p - t) / (p * (1.0 - p)), (1.0 - p).ln() - p.ln())
+                    }
+                    "mse" => (2.0 * (p - t), -2.0 * (p - t)),
+                    _ => {
+                        let d = (p - t).clamp(-delta, delta);
+                        (d, -d)
+                    }
+                };
+                dp.push(scale * a);
+                dt.push(scale * b);
+            }
+            grads[0] = Some(unbroadcast_grad(dp, &shape, &args[0])?);
+            grads[1] = Some(unbroadcast_grad(dt, &shape, &args[1])?);
+        }
+        _ => return Err(format!("Unknown neural network op: {}", name)),
+    }
+    Ok(grads)
+}
+
+// ============================================================================
+// Convolution and Pooling Helpers
+// ============================================================================
+
+/// Geometry of a sliding-window op over an NCHW tensor. 1-D ops use H = KH = 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ConvGeom {
+    pub n: usize,
+    pub c: usize,
+    pub h: usize,
+    pub w: usize,
+    pub kh: usize,
+    pub kw: usize,
+    pub stride: (usize, usize),
+    pub pad: (usize, usize),
+    pub dil: (usize, usize),
+    pub oh: usize,
+    pub ow: usize,
+}
+
+impl ConvGeom {
+    fn new(dims: [usize; 4], kernel: (usize, usize), stride: (usize, usize), pad: (usize, usize), dil: (usize, usize), op: &str) -> Result<Self, String> {
+        let [n, c, h, w] = dims;
+        let (kh, kw) = kernel;
+        if kh == 0 || kw == 0 || stride.0 == 0 || stride.1 == 0 || dil.0 == 0 || dil.1 == 0 {
+            return Err(format!("{} kernel size, stride and dilation must be positive", op));
+        }
+        let span_h = dil.0 * (kh - 1) + 1;
+        let span_w = dil.1 * (kw - 1) + 1;
+        if h + 2 * pad.0 < span_h || w + 2 * pad.1 < span_w {
+            return Err(format!("{} kernel ({}, {}) is larger than the padded input ({}, {})", op, kh, kw, h + 2 * pad.0, w + 2 * pad.1));
+        }
+        let oh = (h + 2 * pad.0 - span_h) / stride.0 + 1;
+        let ow = (w + 2 * pad.1 - span_w) / stride.1 + 1;
+        Ok(Self { n, c, h, w, kh, kw, stride, pad, dil, oh, ow })
+    }
+
+    /// Input (row, col) read by output (oy, ox) at kernel tap (ky, kx), or None inside the padding
+    pub(crate) fn input_pos(&self, oy: usize, ox: usize, ky: usize, kx: usize) -> Option<(usize, usize)> {
+        let iy = (oy * self.stride.0 + ky * self.dil.0).checked_sub(self.pad.0)?;
+        let ix = (ox * self.stride.1 + kx * self.dil.1).checked_sub(self.pad.1)?;
+        if iy < self.h && ix < self.w { Some((iy, ix)) } else { None }
+    }
+
+    fn input_index(&self, n: usize, c: usize, iy: usize, ix: usize) -> usize {
+        ((n * self.c + c) * self.h + iy) * self.w + ix
+    }
+}
+
+fn optional_int(args: &[Value], i: usize, default: usize, what: &str) -> Result<usize, String> {
+    match args.get(i) {
+        None => Ok(default),
+        Some(v) => {
+            let k = scalar_to_int(v, what)?;
+            usize::try_from(k).map_err(|_| format!("{} must be non-negative, got {}", what, k))
+        }
+    }
+}
+
+fn conv_arity(op: &str, args: &[Value]) -> Result<(), String> {
+    let (min, max) = match op {
+        "conv1d" | "conv2d" => (2, 5),
+        "im2col" => (3, 6),
+        _ => (2, 4),
+    };
+    if args.len() < min || args.len() > max {
+        return Err(format!("{} expects {} to {} arguments, got {}", op, min, max, args.len()));
+    }
+    Ok(())
+}
+
+fn rank_checked<'a>(v: &'a Value, rank: usize, op: &str, what: &str) -> Result<&'a Tensor, String> {
+    match v {
+        Value::Tensor(t) if t.shape.len() == rank => Ok(t),
+        _ => Err(format!("{} expects {} to be a rank-{} tensor", op, what, rank)),
+    }
+}
+
+/// Resolve the sliding-window geometry of a conv/pool/im2col call from its arguments:
+/// conv1d/conv2d(x, w, stride, padding, dilation), max_pool2d/avg_pool2d(x, kernel, stride, padding)
+/// and im2col(x, kh, kw, stride, padding, dilation). Trailing arguments are optional.
+pub(crate) fn conv_geometry(op: &str, args: &[Value]) -> Result<ConvGeom, String> {
+    conv_arity(op, args)?;
+    match op {
+        "conv1d" => {
+            let x = rank_checked(&args[0], 3, op, "input [N, C, L]")?;
+            let w = rank_checked(&args[1], 3, op, "weight [F, C, K]")?;
+            if w.shape[1] != x.shape[1] {
+                return Err(format!("conv1d channel mismatch: input has {}, weight expects {}", x.shape[1], w.shape[1]));
+            }
+            let stride = optional_int(args, 2, 1, "conv1d stride")?;
+            let pad = optional_int(args, 3, 0, "conv1d padding")?;
+            let dil = optional_int(args, 4, 1, "conv1d dilation")?;
+            ConvGeom::new([x.shape[0], x.shape[1], 1, x.shape[2]], (1, w.shape[2]), (1, stride), (0, pad), (1, dil), op)
+        }
+        "conv2d" => {
+            let x = rank_checked(&args[0], 4, op, "input [N, C, H, W]")?;
+            let w = rank_checked(&args[1], 4, op, "weight [F, C, KH, KW]")?;
+            if w.shape[1] != x.shape[1] {
+                return Err(format!("conv2d channel mismatch: input has {}, weight expects {}", x.shape[1], w.shape[1]));
+            }
+            let stride = optional_int(args, 2, 1, "conv2d stride")?;
+            let pad = optional_int(args, 3, 0, "conv2d padding")?;
+            let dil = optional_int(args, 4, 1, "conv2d dilation")?;
+            let s = &x.shape;
+            ConvGeom::new([s[0], s[1], s[2], s[3]], (w.shape[2], w.shape[3]), (stride, stride), (pad, pad), (dil, dil), op)
+        }
+        "im2col" => {
+            let x = rank_checked(&args[0], 4, op, "input [N, C, H, W]")?;
+            let kh = optional_int(args, 1, 1, "im2col kernel height")?;
+            let kw = optional_int(args, 2, 1, "im2col kernel width")?;
+            let stride = optional_int(args, 3, 1, "im2col stride")?;
+            let pad = optional_int(args, 4, 0, "im2col padding")?;
+            let dil = optional_int(args, 5, 1, "im2col dilation")?;
+            let s = &x.shape;
+            ConvGeom::new([s[0], s[1], s[2], s[3]], (kh, kw), (stride, stride), (pad, pad), (dil, dil), op)
+        }
+        "max_pool2d" | "avg_pool2d" => {
+            let x = rank_checked(&args[0], 4, op, "input [N, C, H, W]")?;
+            let k = optional_int(args, 1, 1, &format!("{} kernel size", op))?;
+            let stride = optional_int(args, 2, k, &format!("{} stride", op))?;
+            let pad = optional_int(args, 3, 0, &format!("{} padding", op))?;
+            if 2 * pad > k {
+                return Err(format!("{} padding must be at most half the kernel size", op));
+            }
+            let s = &x.shape;
+            ConvGeom::new([s[0], s[1], s[2], s[3]], (k, k), (stride, stride), (pad, pad), (1, 1), op)
+        }
+        _ => Err(format!("Unknown convolution op: {}", op)),
+    }
+}
+
+/// Unfold sliding windows into columns: [N, C*KH*KW, OH*OW]
+fn im2col_data(x: &[f64], g: &ConvGeom) -> Vec<f64> {
+    let (rows, cols) = (g.c * g.kh * g.kw, g.oh * g.ow);
+    let mut out = vec![0.0; g.n * rows * cols];
+    for n in 0..g.n {
+        for c in 0..g.c {
+            for ky in 0..g.kh {
+                for kx in 0..g.kw {
+                    let row = (c * g.kh + ky) * g.kw + kx;
+                    for oy in 0..g.oh {
+                        for ox in 0..g.ow {
+                            if let Some((iy, ix)) = g.input_pos(oy, ox, ky, kx) {
+                                out[(n * rows + row) * cols + oy * g.ow + ox] = x[g.input_index(n, c, iy, ix)];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Adjoint of `im2col_data`: accumulate columns back into an NCHW gradient
+fn col2im_data(cols_data: &[f64], g: &ConvGeom) -> Vec<f64> {
+    let (rows, cols) = (g.c * g.kh * g.kw, g.oh * g.ow);
+    let mut dx = vec![0.0; g.n * g.c * g.h * g.w];
+    for n in 0..g.n {
+        for c in 0..g.c {
+            for ky in 0..g.kh {
+                for kx in 0..g.kw {
+                    let row = (c * g.kh + ky) * g.kw + kx;
+                    for oy in 0..g.oh {
+                        for ox in 0..g.ow {
+                            if let Some((iy, ix)) = g.input_pos(oy, ox, ky, kx) {
+                                dx[g.input_index(n, c, iy, ix)] += cols_data[(n * rows + row) * cols + oy * g.ow + ox];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    dx
+}
+
+fn conv_output_shape(op: &str, g: &ConvGeom, filters: usize) -> Vec<usize> {
+    match op {
+        "conv1d" => vec![g.n, filters, g.ow],
+        "conv2d" => vec![g.n, filters, g.oh, g.ow],
+        "im2col" => vec![g.n, g.c * g.kh * g.kw, g.oh * g.ow],
+        _ => vec![g.n, g.c, g.oh, g.ow],
+    }
+}
+
+/// Forward evaluation of conv1d/conv2d (via im2col), max/avg pooling and im2col
+fn eval_conv_op(op: &str, args: &[Value]) -> Result<Value, String> {
+    let g = conv_geometry(op, args)?;
+    let x = tensor_of(&args[0]);
+    match op {
+        "conv1d" | "conv2d" => {
+            let w = tensor_of(&args[1]);
+            let f = w.shape[0];
+            let (rows, cols) = (g.c * g.kh * g.kw, g.oh * g.ow);
+            let unfolded = im2col_data(&x.data, &g);
+            let mut out = vec![0.0; g.n * f * cols];
+            for n in 0..g.n {
+                for fi in 0..f {
+                    for r in 0..rows {
+                        let wv = w.data[fi * rows + r];
+                        let src = &unfolded[(n * rows + r) * cols..(n * rows + r + 1) * cols];
+                        let dst = &mut out[(n * f + fi) * cols..(n * f + fi + 1) * cols];
+                        dst.iter_mut().zip(src).for_each(|(o, v)| *o += wv * v);
+                    }
+                }
+            }
+            Ok(Value::Tensor(Tensor { data: out, shape: conv_output_shape(op, &g, f) }))
+        }
+        "im2col" => Ok(Value::Tensor(Tensor { data: im2col_data(&x.data, &g), shape: conv_output_shape(op, &g, 0) })),
+        _ => {
+            let mut out = Vec::with_capacity(g.n * g.c * g.oh * g.ow);
+            for n in 0..g.n {
+                for c in 0..g.c {
+                    for oy in 0..g.oh {
+                        for ox in 0..g.ow {
+                            let window = (0..g.kh).flat_map(|ky| (0..g.kw).map(move |kx| (ky, kx)))
+                                .filter_map(|(ky, kx)| g.input_pos(oy, ox, ky, kx))
+                                .map(|(iy, ix)| x.data[g.input_index(n, c, iy, ix)]);
+                            out.push(if op == "max_pool2d" {
+                                window.fold(f64::NEG_INFINITY, f64::max)
+                            } else {
+                                // Padding counts towards the divisor, as with zero-padded inputs
+                                window.sum::<f64>() / (g.kh * g.kw) as f64
+                            });
+                        }
+                    }
+                }
+            }
+            Ok(Value::Tensor(Tensor { data: out, shape: conv_output_shape(op, &g, 0) }))
+        }
+    }
+}
+
+/// Gradients of the convolution ops. Max pooling routes each window's gradient to its first
+/// maximal element; integer hyperparameters receive no gradient.
+fn conv_op_grads(op: &str, args: &[Value], gradient: &Value) -> Result<Vec<Option<Value>>, String> {
+    let g = conv_geometry(op, args)?;
+    let x = tensor_of(&args[0]);
+    let mut grads: Vec<Option<Value>> = vec![None; args.len()];
+    match op {
+        "conv1d" | "conv2d" => {
+            let w = tensor_of(&args[1]);
+            let f = w.shape[0];
+            let (rows, cols) = (g.c * g.kh * g.kw, g.oh * g.ow);
+            let gy = upstream_data(gradient, g.n * f * cols, op)?;
+            let unfolded = im2col_data(&x.data, &g);
+            let mut dw = vec![0.0; w.data.len()];
+            let mut dcols = vec![0.0; unfolded.len()];
+            for n in 0..g.n {
+                for fi in 0..f {
+                    let grow = &gy[(n * f + fi) * cols..(n * f + fi + 1) * cols];
+                    for r in 0..rows {
+                        let base = (n * rows + r) * cols;
+                        dw[fi * rows + r] += grow.iter().zip(&unfolded[base..base + cols]).map(|(a, b)| a * b).sum::<f64>();
+                        let wv = w.data[fi * rows + r];
+                        dcols[base..base + cols].iter_mut().zip(grow).for_each(|(d, gv)| *d += wv * gv);
+                    }
+                }
+            }
+            grads[0] = Some(Value::Tensor(Tensor { data: col2im_data(&dcols, &g), shape: x.shape.clone() }));
+            grads[1] = Some(Value::Tensor(Tensor { data: dw, shape: w.shape }));
+        }
+        "im2col" => {
+            let gy = upstream_data(gradient, g.n * g.c * g.kh * g.kw * g.oh * g.ow, op)?;
+            grads[0] = Some(Value::Tensor(Tensor { data: col2im_data(&gy, &g), shape: x.shape.clone() }));
+        }
+        _ => {
+            let gy = upstream_data(gradient, g.n * g.c * g.oh * g.ow, op)?;
+            let mut dx = vec![0.0; x.data.len()];
+            let area = (g.kh * g.kw) as f64;
+            let mut out_idx = 0;
+            for n in 0..g.n {
+                for c in 0..g.c {
+                    for oy in 0..g.oh {
+                        for ox in 0..g.ow {
+                            let taps = (0..g.kh).flat_map(|ky| (0..g.kw).map(move |kx| (ky, kx)))
+                                .filter_map(|(ky, kx)| g.input_pos(oy, ox, ky, kx))
+                                .map(|(iy, ix)| g.input_index(n, c, iy, ix));
+                            if op == "max_pool2d" {
+                                let mut best: Option<usize> = None;
+                                for i in taps {
+                                    if best.is_none_or(|b| x.data[i] > x.data[b]) {
+                                        best = Some(i);
+                                    }
+                                }
+                                if let Some(b) = best {
+                                    dx[b] += gy[out_idx];
+                                }
+                            } else {
+                                for i in taps {
+                                    dx[i] += gy[out_idx] / area;
+                                }
+                            }
+                            out_idx += 1;
+                        }
+                    }
+                }
+            }
+            grads[0] = Some(Value::Tensor(Tensor { data: dx, shape: x.shape.clone() }));
+        }
+    }
+    Ok(grads)
+}
+
+// ============================================================================
+// Random Number Helpers
+// ============================================================================
+
+/// When a random builtin draws its values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+    /// Drawn on the first forward pass and kept afterwards (initializers); baked into compiled code
+    Once,
+    /// Redrawn on every forward pass, e.g. every optimization step (noise, dropout); drawn at
+    /// runtime by compiled code
+    PerStep,
+}
+
+/// Sampling behaviour of a builtin, or None if it is deterministic
+pub fn sampling_of(name: &str) -> Option<Sampling> {
+    match name {
+        "rand" | "rand_uniform" | "rand_normal" | "rand_tensor" | "rand_normal_tensor" | "xavier_init" | "he_init" => Some(Sampling::Once),
+        "noise_uniform" | "noise_normal" | "dropout" => Some(Sampling::PerStep),
+        _ => None,
+    }
+}
+
+/// (min, max) or (mean, std) and the optional shape of a noise_uniform / noise_normal call
+pub(crate) fn noise_params(name: &str, args: &[Value]) -> Result<(f64, f64, Option<Vec<usize>>), String> {
+    if args.len() < 2 {
+        return Err(format!("{} expects at least 2 arguments ({}, dims...)", name, if name == "noise_uniform" { "min, max" } else { "mean, std" }));
+    }
+    let a = args[0].as_scalar().ok_or_else(|| format!("{} parameters must be scalars", name))?;
+    let b = args[1].as_scalar().ok_or_else(|| format!("{} parameters must be scalars", name))?;
+    if name == "noise_uniform" && a >= b {
+        return Err(format!("noise_uniform expects min < max, got {} and {}", a, b));
+    }
+    if name == "noise_normal" && b < 0.0 {
+        return Err(format!("noise_normal std must be non-negative, got {}", b));
+    }
+    if args.len() == 2 {
+        return Ok((a, b, None));
+    }
+    let mut shape = Vec::with_capacity(args.len() - 2);
+    for dim in &args[2..] {
+        let d = dim.as_scalar().ok_or("dimensions must be scalars")?;
+        if d < 1.0 || d.fract() != 0.0 { return Err("dimensions must be positive integers".to_string()); }
+        shape.push(d as usize);
+    }
+    Ok((a, b, Some(shape)))
+}
+
+/// Validate the argument of a `seed(...)` statement or `--seed` flag
+pub fn seed_value(value: Option<Value>) -> Result<u64, String> {
+    match value.as_ref().and_then(Value::as_scalar) {
+        Some(s) if s >= 0.0 && s.fract() == 0.0 && s <= u64::MAX as f64 => Ok(s as u64),
+        Some(s) => Err(format!("seed must be a non-negative integer, got {}", s)),
+        None => Err("seed expects a scalar argument".to_string()),
+    }
+}
+
+// ============================================================================
+// Normalization Helpers
+// ============================================================================
+
+/// How layer_norm and batch_norm group elements. Statistics are computed per group; the affine
+/// parameters gamma/beta are indexed per feature.
+#[derive(Debug, Clone, Copy)]
+struct NormLayout {
+    /// batch_norm: groups are channels (axis 1); layer_norm: groups are rows of the last axis
+    per_channel: bool,
+    /// Number of features (channels, or the size of the last axis)
+    dim: usize,
+    /// Elements per channel within one sample (batch_norm only)
+    inner: usize,
+    groups: usize,
+}
+
+impl NormLayout {
+    fn new(name: &str, shape: &[usize]) -> Result<Self, String> {
+        if name == "batch_norm" {
+            if shape.len() < 2 {
+                return Err(format!("batch_norm expects a tensor of rank >= 2 ([N, C, ...]), got shape {:?}", shape));
+            }
+            let inner = shape_product(&shape[2..]);
+            Ok(Self { per_channel: true, dim: shape[1], inner, groups: shape[1] })
+        } else {
+            let dim = *shape.last().ok_or("layer_norm expects a tensor")?;
+            Ok(Self { per_channel: false, dim, inner: 1, groups: shape_product(shape) / dim.max(1) })
+        }
+    }
+
+    fn group(&self, i: usize) -> usize {
+        if self.per_channel { (i / self.inner) % self.dim } else { i / self.dim }
+    }
+
+    fn feature(&self, i: usize) -> usize {
+        if self.per_channel { self.group(i) } else { i % self.dim }
+    }
+}
+
+fn check_norm_arity(name: &str, args: &[Value]) -> Result<(), String> {
+    let ok = match name {
+        "dropout" => args.len() == 2,
+        "layer_norm" => matches!(args.len(), 1 | 3 | 4),
+        _ => matches!(args.len(), 1 | 3 | 4 | 5),
+    };
+    if ok {
+        return Ok(());
+    }
+    Err(match name {
+        "dropout" => "dropout expects 2 arguments (x, p)".to_string(),
+        "layer_norm" => "layer_norm expects (x), (x, gamma, beta) or (x, gamma, beta, eps)".to_string(),
+        _ => "batch_norm expects (x), (x, gamma, beta), optionally followed by momentum and eps".to_string(),
+    })
+}
+
+fn dropout_rate(v: &Value) -> Result<f64, String> {
+    let p = v.as_scalar().ok_or("dropout probability must be a scalar")?;
+    if !(0.0..1.0).contains(&p) {
+        return Err(format!("dropout probability must be in [0, 1), got {}", p));
+    }
+    Ok(p)
+}
+
+/// (momentum, eps) of a normalization call; momentum only applies to batch_norm
+fn norm_hyperparams(name: &str, args: &[Value]) -> Result<(f64, f64), String> {
+    let scalar = |i: usize, default: f64, what: &str| -> Result<f64, String> {
+        match args.get(i) {
+            Some(v) => v.as_scalar().ok_or_else(|| format!("{} {} must be a scalar", name, what)),
+            None => Ok(default),
+        }
+    };
+    if name == "batch_norm" {
+        Ok((scalar(3, 0.1, "momentum")?, scalar(4, 1e-5, "eps")?))
+    } else {
+        Ok((0.0, scalar(3, 1e-5, "eps")?))
+    }
+}
+
+/// gamma (idx 1) or beta (idx 2) expanded to one value per feature
+fn affine_param(args: &[Value], idx: usize, layout: &NormLayout, default: f64) -> Result<Vec<f64>, String> {
+    match args.get(idx) {
+        None => Ok(vec![default; layout.dim]),
+        Some(Value::Scalar(s)) => Ok(vec![*s; layout.dim]),
+        Some(Value::Tensor(t)) if t.data.len() == layout.dim => Ok(t.data.clone()),
+        Some(Value::Tensor(t)) => Err(format!(
+            "normalization {} must have {} elements, got shape {:?}",
+            if idx == 1 { "gamma" } else { "beta" }, layout.dim, t.shape
+        )),
+    }
+}
+
+/// Per-group mean and biased variance
+fn group_stats(x: &[f64], layout: &NormLayout) -> (Vec<f64>, Vec<f64>) {
+    let count = (x.len() / layout.groups.max(1)) as f64;
+    let mut mean = vec![0.0; layout.groups];
+    for (i, v) in x.iter().enumerate() {
+        mean[layout.group(i)] += v / count;
+    }
+    let mut var = vec![0.0; layout.groups];
+    for (i, v) in x.iter().enumerate() {
+        let d = v - mean[layout.group(i)];
+        var[layout.group(i)] += d * d / count;
+    }
+    (mean, var)
+}
+
+// ============================================================================
+// Second-Order Optimizer Helpers
+// ============================================================================
+
+/// Objective re-evaluated by the line search: flat parameters -> (loss, gradient)
+type Objective<'a> = dyn FnMut(&[f64]) -> Result<(f64, Vec<f64>), String> + 'a;
+
+/// Sufficient-decrease and curvature constants of the strong Wolfe conditions
+const WOLFE_C1: f64 = 1e-4;
+const WOLFE_C2: f64 = 0.9;
+/// Objective evaluations allowed in each of the bracketing and zoom phases
+const LINE_SEARCH_MAX_EVALS: usize = 25;
+/// Upper bound on conjugate-gradient iterations per Newton-CG step
+const NEWTON_CG_MAX_ITERS: usize = 100;
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// x + alpha * d
+fn axpy(x: &[f64], alpha: f64, d: &[f64]) -> Vec<f64> {
+    x.iter().zip(d).map(|(x, d)| x + alpha * d).collect()
+}
+
+/// A point accepted by the line search: step length, objective and gradient there
+struct LineSearchPoint {
+    alpha: f64,
+    loss: f64,
+    grad: Vec<f64>,
+}
+
+/// Minimizer of the cubic interpolating (a1, f1, d1) and (a2, f2, d2), safeguarded to the
+/// middle 80% of the interval (bisection otherwise)
+fn cubic_step(a1: f64, f1: f64, d1: f64, a2: f64, f2: f64, d2: f64) -> f64 {
+    let (lo, hi) = if a1 < a2 { (a1, a2) } else { (a2, a1) };
+    let margin = 0.1 * (hi - lo);
+    let t1 = d1 + d2 - 3.0 * (f1 - f2) / (a1 - a2);
+    let disc = t1 * t1 - d1 * d2;
+    if disc >= 0.0 {
+        let t2 = (a2 - a1).signum() * disc.sqrt();
+        let a = a2 - (a2 - a1) * (d2 + t2 - t1) / (d2 - d1 + 2.0 * t2);
+        if a.is_finite() && a >= lo + margin && a <= hi - margin {
+            return a;
+        }
+    }
+    0.5 * (lo + hi)
+}
+
+/// Strong Wolfe line search along `d` (Nocedal & Wright, Algorithms 3.5 and 3.6). Returns None
+/// when no step decreasing the objective was found.
+fn wolfe_line_search(
+    f: &mut Objective,
+    x: &[f64],
+    loss0: f64,
+    grad0: &[f64],
+    d: &[f64],
+    alpha0: f64,
+) -> Result<Option<LineSearchPoint>, String> {
+    let slope0 = dot(grad0, d);
+    let armijo = |alpha: f64, loss: f64| loss.is_finite() && loss <= loss0 + WOLFE_C1 * alpha * slope0;
+    let curvature = |slope: f64| slope.abs() <= -WOLFE_C2 * slope0;
+
+    // Bracketing phase: grow the step until it overshoots or satisfies both conditions
+    let mut prev = LineSearchPoint { alpha: 0.0, loss: loss0, grad: grad0.to_vec() };
+    let mut alpha = alpha0;
+    let mut evals = 0;
+    let (mut lo, mut hi) = loop {
+        if evals == LINE_SEARCH_MAX_EVALS {
+            return Ok(Some(prev).filter(|p| p.alpha > 0.0));
+        }
+        let (loss, grad) = f(&axpy(x, alpha, d))?;
+        let slope = dot(&grad, d);
+        let point = LineSearchPoint { alpha, loss, grad };
+        if !armijo(alpha, loss) || (evals > 0 && loss >= prev.loss) {
+            break (prev, point);
+        }
+        if curvature(slope) {
+            return Ok(Some(point));
+        }
+        if slope >= 0.0 {
+            break (point, prev);
+        }
+        evals += 1;
+        alpha *= 2.0;
+        prev = point;
+    };
+
+    // Zoom phase: shrink [lo, hi] keeping `lo` the best point satisfying sufficient decrease
+    for _ in 0..LINE_SEARCH_MAX_EVALS {
+        let (lo_slope, hi_slope) = (dot(&lo.grad, d), dot(&hi.grad, d));
+        let alpha = if hi.loss.is_finite() {
+            cubic_step(lo.alpha, lo.loss, lo_slope, hi.alpha, hi.loss, hi_slope)
+        } else {
+            0.5 * (lo.alpha + hi.alpha)
+        };
+        let (loss, grad) = f(&axpy(x, alpha, d))?;
+        let slope = dot(&grad, d);
+        let point = LineSearchPoint { alpha, loss, grad };
+        if !armijo(alpha, loss) || loss >= lo.loss {
+            hi = point;
+        } else {
+            if curvature(slope) {
+                return Ok(Some(point));
+            }
+            if slope * (hi.alpha - lo.alpha) >= 0.0 {
+                hi = lo;
+            }
+            lo = point;
+        }
+        if (hi.alpha - lo.alpha).abs() < 1e-12 * lo.alpha.abs().max(1.0) {
+            break;
+        }
+    }
+    Ok(Some(lo).filter(|p| p.alpha > 0.0))
+}
+
+/// L-BFGS two-loop recursion: approximate -H⁻¹ g from the stored correction pairs
+fn lbfgs_direction(grad: &[f64], history: &VecDeque<(Vec<f64>, Vec<f64>)>) -> Vec<f64> {
+    let mut q = grad.to_vec();
+    let mut alphas = Vec::with_capacity(history.len());
+    for (s, y) in history.iter().rev() {
+        let rho = 1.0 / dot(y, s);
+        let a = rho * dot(s, &q);
+        q = axpy(&q, -a, y);
+        alphas.push((rho, a));
+    }
+    // Initial Hessian approximation gamma * I from the most recent pair
+    let gamma = history.back().map(|(s, y)| dot(s, y) / dot(y, y)).unwrap_or(1.0);
+    let mut r: Vec<f64> = q.iter().map(|v| gamma * v).collect();
+    for ((s, y), (rho, a)) in history.iter().zip(alphas.into_iter().rev()) {
+        let b = rho * dot(y, &r);
+        r = axpy(&r, a - b, s);
+    }
+    r.iter().map(|v| -v).collect()
+}
+
+fn lbfgs_step(
+    f: &mut Objective,
+    x: &[f64],
+    config: &OptimizerConfig,
+    history: &mut VecDeque<(Vec<f64>, Vec<f64>)>,
+) -> Result<Vec<f64>, String> {
+    // A realloc changes the parameter layout and invalidates the curvature pairs
+    if history.front().is_some_and(|(s, _)| s.len() != x.len()) {
+        history.clear();
+    }
+    let (loss, grad) = f(x)?;
+    if grad.iter().all(|g| *g == 0.0) {
+        return Ok(x.to_vec());
+    }
+
+    let mut d = lbfgs_direction(&grad, history);
+    let slope = dot(&grad, &d);
+    if slope.is_nan() || slope >= 0.0 {
+        history.clear();
+        d = grad.iter().map(|g| -g).collect();
+    }
+    // Scale the first steepest-descent step so it does not overshoot wildly
+    let alpha0 = if history.is_empty() {
+        config.learning_rate * (1.0 / grad.iter().map(|g| g.abs()).sum::<f64>()).min(1.0)
+    } else {
+        config.learning_rate
+    };
+
+    let Some(point) = wolfe_line_search(f, x, loss, &grad, &d, alpha0)? else {
+        history.clear();
+        return Ok(x.to_vec());
+    };
+    let s: Vec<f64> = d.iter().map(|v| point.alpha * v).collect();
+    let y: Vec<f64> = point.grad.iter().zip(&grad).map(|(a, b)| a - b).collect();
+    if dot(&s, &y) > 1e-10 {
+        history.push_back((s, y));
+        while history.len() > config.history_size.max(1) {
+            history.pop_front();
+        }
+    }
+    Ok(axpy(x, point.alpha, &d))
+}
+
+/// Hessian-vector product by central differences of the reverse-mode gradient; zero for a
+/// zero direction
+fn hessian_vector_product(f: &mut Objective, x: &[f64], v: &[f64]) -> Result<Vec<f64>, String> {
+    let v_norm = dot(v, v).sqrt();
+    if v_norm == 0.0 {
+        return Ok(vec![0.0; v.len()]);
+    }
+    let x_norm = dot(x, x).sqrt();
+    let eps = f64::EPSILON.sqrt() * (1.0 + x_norm) / v_norm;
+    let (_, g_plus) = f(&axpy(x, eps, v))?;
+    let (_, g_minus) = f(&axpy(x, -eps, v))?;
+    Ok(g_plus.iter().zip(&g_minus).map(|(a, b)| (a - b) / (2.0 * eps)).collect())
+}
+
+/// Truncated conjugate gradient on H d = -g, stopping early on negative curvature
+fn newton_cg_direction(f: &mut Objective, x: &[f64], grad: &[f64]) -> Result<Vec<f64>, String> {
+    let g_norm = dot(grad, grad).sqrt();
+    let tolerance = g_norm.sqrt().min(0.5) * g_norm;
+    let mut d = vec![0.0; x.len()];
+    let mut r: Vec<f64> = grad.iter().map(|g| -g).collect();
+    let mut p = r.clone();
+    let mut rr = dot(&r, &r);
+
+    for _ in 0..(2 * x.len()).min(NEWTON_CG_MAX_ITERS) {
+        let hp = hessian_vector_product(f, x, &p)?;
+        let curvature = dot(&p, &hp);
+        if curvature <= 0.0 {
+            break;
+        }
+        let alpha = rr / curvature;
+        d = axpy(&d, alpha, &p);
+        r = axpy(&r, -alpha, &hp);
+        let rr_next = dot(&r, &r);
+        if rr_next.sqrt() < tolerance {
+            break;
+        }
+        p = axpy(&r, rr_next / rr, &p);
+        rr = rr_next;
+    }
+
+    // Negative curvature before any progress: fall back to steepest descent
+    if d.iter().all(|v| *v == 0.0) {
+        d = grad.iter().map(|g| -g).collect();
+    }
+    Ok(d)
+}
+
+fn newton_cg_step(f: &mut Objective, x: &[f64], config: &OptimizerConfig) -> Result<Vec<f64>, String> {
+    let (loss, grad) = f(x)?;
+    if grad.iter().all(|g| *g == 0.0) {
+        return Ok(x.to_vec());
+    }
+    let d = newton_cg_direction(f, x, &grad)?;
+    let alpha0 = config.learning_rate;
+    match wolfe_line_search(f, x, loss, &grad, &d, alpha0)? {
+        Some(point) => Ok(axpy(x, point.alpha, &d)),
+        None => Ok(x.to_vec()),
+    }
+}
+
+// ============================================================================
+// Training Checkpoints
+// ============================================================================
+
+/// Everything needed to continue training: learnable values, optimizer state and the RNG
+/// position, keyed by learnable name so that a checkpoint outlives the `NodeId`s of one run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub params: Vec<(String, Value)>,
+    /// First and second moment estimates by learnable name
+    pub m: Vec<(String, Value)>,
+    pub v: Vec<(String, Value)>,
+    pub lbfgs_history: Vec<(Vec<f64>, Vec<f64>)>,
+    /// `OptimizerState::t`; with the plateau counters it fixes the schedule position
+    pub step: usize,
+    pub plateau_best: Option<f64>,
+    pub plateau_wait: usize,
+    pub plateau_reductions: usize,
+    /// `ComputationalGraph::rng_state()`
+    pub rng: ([u8; 32], u64, u128),
+    /// Optimize loops finished, and steps taken in the next one, when the checkpoint was written
+    pub loops_done: usize,
+    pub loop_step: usize,
+    /// Stopping-criteria progress of the loop the checkpoint was written in
+    pub stop: Option<StopProgress>,
+    /// batch_norm running (mean, var), keyed by the learnable gamma's name or `#<node index>`
+    pub running_stats: Vec<(String, Vec<f64>, Vec<f64>)>,
+}
+
+/// Version stored in the `noma.checkpoint` tensor
+const CHECKPOINT_VERSION: f64 = 1.0;
+
+impl Checkpoint {
+    pub fn capture(graph: &ComputationalGraph, state: &OptimizerState, monitor: Option<&StopMonitor>, loops_done: usize, loop_step: usize) -> Self {
+        let named = graph.learnables_by_name();
+        let values = |map: &HashMap<NodeId, Value>| -> Vec<(String, Value)> {
+            named.iter().filter_map(|(name, id)| map.get(id).map(|v| (name.clone(), v.clone()))).collect()
+        };
+        let params = named.iter()
+            .filter_map(|(name, id)| graph.nodes[id].value.clone().map(|v| (name.clone(), v)))
+            .collect();
+        Checkpoint {
+            params,
+            m: values(&state.m),
+            v: values(&state.v),
+            lbfgs_history: state.lbfgs_history.iter().cloned().collect(),
+            step: state.t,
+            plateau_best: state.plateau_best,
+            plateau_wait: state.plateau_wait,
+            plateau_reductions: state.plateau_reductions,
+            rng: graph.rng_state(),
+            loops_done,
+            loop_step,
+            stop: monitor.map(StopMonitor::progress),
+            running_stats: graph.running_stats_by_name(),
+        }
+    }
+
+    /// Write the checkpoint as a safetensors file of F64 tensors: `param.<name>`, `m.<name>`,
+    /// `v.<name>`, `lbfgs.s.<i>`, `lbfgs.y.<i>`, `bn.mean.<key>`, `bn.var.<key>`, the counters in
+    /// `noma.checkpoint` and `noma.rng`, and the stopping progress in `noma.stop*`
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let vector = |data: Vec<f64>| Value::Tensor(Tensor { shape: vec![data.len()], data });
+        let (key, stream, word_pos) = self.rng;
+        let mut rng: Vec<f64> = key.iter().map(|b| *b as f64).collect();
+        rng.extend([stream as u32, (stream >> 32) as u32].map(f64::from));
+        rng.extend((0..4).map(|i| f64::from((word_pos >> (32 * i)) as u32)));
+
+        let mut tensors = vec![
+            ("noma.checkpoint".to_string(), vector(vec![
+                CHECKPOINT_VERSION,
+                self.loops_done as f64,
+                self.loop_step as f64,
+                self.step as f64,
+                self.plateau_best.unwrap_or(f64::NAN),
+                self.plateau_wait as f64,
+                self.plateau_reductions as f64,
+            ])),
+            ("noma.rng".to_string(), vector(rng)),
+        ];
+        for (prefix, values) in [("param", &self.params), ("m", &self.m), ("v", &self.v)] {
+            tensors.extend(values.iter().map(|(name, value)| (format!("{}.{}", prefix, name), value.clone())));
+        }
+        for (i, (s, y)) in self.lbfgs_history.iter().enumerate() {
+            tensors.push((format!("lbfgs.s.{}", i), vector(s.clone())));
+            tensors.push((format!("lbfgs.y.{}", i), vector(y.clone())));
+        }
+        for (key, mean, var) in &self.running_stats {
+            tensors.push((format!("bn.mean.{}", key), vector(mean.clone())));
+            tensors.push((format!("bn.var.{}", key), vector(var.clone())));
+        }
+        if let Some(stop) = &self.stop {
+            let loss_at_best = stop.best_params.as_ref().map_or(f64::NAN, |(_, loss)| *loss);
+            tensors.push(("noma.stop".to_string(), vector(vec![stop.best_loss, stop.best_monitored, stop.wait as f64, loss_at_best])));
+            if !stop.losses.is_empty() {
+                tensors.push(("noma.stop.losses".to_string(), vector(stop.losses.clone())));
+            }
+            if let Some((params, _)) = &stop.best_params {
+                tensors.push(("noma.stop.best_params".to_string(), vector(params.clone())));
+            }
+        }
+        save_safetensors_file(&tensors, path)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let tensors = load_safetensors_file(path)?;
+        let get = |name: &str| tensors.iter().find(|(n, _)| n == name).map(|(_, (data, _))| data.clone());
+        let invalid = |what: &str| format!("'{}' is not a NOMA checkpoint: {}", path, what);
+
+        let header = get("noma.checkpoint").ok_or_else(|| invalid("missing noma.checkpoint"))?;
+        let [version, loops_done, loop_step, step, plateau_best, plateau_wait, plateau_reductions] = header[..] else {
+            return Err(invalid("malformed noma.checkpoint"));
+        };
+        if version != CHECKPOINT_VERSION {
+            return Err(format!("Checkpoint '{}' has version {}, expected {}", path, version, CHECKPOINT_VERSION));
+        }
+        let rng = get("noma.rng").ok_or_else(|| invalid("missing noma.rng"))?;
+        if rng.len() != 38 {
+            return Err(invalid("malformed noma.rng"));
+        }
+        let mut key = [0u8; 32];
+        for (k, v) in key.iter_mut().zip(&rng) {
+            *k = *v as u8;
+        }
+        let stream = rng[32] as u64 | (rng[33] as u64) << 32;
+        let word_pos = (0..4).fold(0u128, |acc, i| acc | (rng[34 + i] as u128) << (32 * i));
+
+        let with_prefix = |prefix: &str| -> Vec<(String, Value)> {
+            tensors.iter()
+                .filter_map(|(name, (data, shape))| {
+                    let name = name.strip_prefix(prefix)?;
+                    Some((name.to_string(), Value::Tensor(Tensor { data: data.clone(), shape: shape.clone() })))
+                })
+                .collect()
+        };
+        let mut lbfgs_history = Vec::new();
+        while let (Some(s), Some(y)) = (get(&format!("lbfgs.s.{}", lbfgs_history.len())), get(&format!("lbfgs.y.{}", lbfgs_history.len()))) {
+            lbfgs_history.push((s, y));
+        }
+        let stop = match get("noma.stop").as_deref() {
+            Some(&[best_loss, best_monitored, wait, loss_at_best]) => Some(StopProgress {
+                losses: get("noma.stop.losses").unwrap_or_default(),
+                best_loss,
+                best_monitored,
+                wait: wait as usize,
+                best_params: get("noma.stop.best_params").map(|params| (params, loss_at_best)),
+            }),
+            Some(_) => return Err(invalid("malformed noma.stop")),
+            None => None,
+        };
+        let running_stats = tensors.iter()
+            .filter_map(|(name, (mean, _))| {
+                let key = name.strip_prefix("bn.mean.")?;
+                Some((key.to_string(), mean.clone(), get(&format!("bn.var.{}", key))?))
+            })
+            .collect();
+
+        Ok(Checkpoint {
+            params: with_prefix("param."),
+            m: with_prefix("m."),
+            v: with_prefix("v."),
+            lbfgs_history,
+            step: step as usize,
+            plateau_best: if plateau_best.is_nan() { None } else { Some(plateau_best) },
+            plateau_wait: plateau_wait as usize,
+            plateau_reductions: plateau_reductions as usize,
+            rng: (key, stream, word_pos),
+            loops_done: loops_done as usize,
+            loop_step: loop_step as usize,
+            stop,
+            running_stats,
+        })
+    }
+
+    /// Put the learnables, `state`, the batch_norm running statistics and the graph RNG back to
+    /// the checkpointed values. The stopping progress is resumed by the optimize loop. A scalar
+    /// learnable takes a one-element saved value; a tensor learnable takes the saved shape.
+    /// Returns the saved parameters that are not learnables of `graph`.
+    pub fn restore(&self, graph: &mut ComputationalGraph, state: &mut OptimizerState) -> Result<Vec<String>, String> {
+        let named: HashMap<String, NodeId> = graph.learnables_by_name().into_iter().collect();
+        let as_kind_of = |current: &Option<Value>, name: &str, saved: &Value| -> Result<Value, String> {
+            match (current, saved) {
+                (Some(Value::Scalar(_)), Value::Tensor(t)) if t.data.len() == 1 => Ok(Value::Scalar(t.data[0])),
+                (Some(Value::Scalar(_)), Value::Tensor(t)) => {
+                    Err(format!("Checkpoint has shape {:?} for scalar learnable '{}'", t.shape, name))
+                }
+                _ => Ok(saved.clone()),
+            }
+        };
+
+        let mut skipped = Vec::new();
+        for (name, saved) in &self.params {
+            let Some(id) = named.get(name) else {
+                skipped.push(name.clone());
+                continue;
+            };
+            let node = graph.nodes.get_mut(id).ok_or("Invalid learnable id")?;
+            let value = as_kind_of(&node.value, name, saved)?;
+            node.gradient = Some(value.zeros_like());
+            node.value = Some(value);
+        }
+
+        state.reset();
+        for (target, saved) in [(&mut state.m, &self.m), (&mut state.v, &self.v)] {
+            for (name, value) in saved {
+                if let Some(id) = named.get(name) {
+                    target.insert(*id, as_kind_of(&graph.nodes[id].value, name, value)?);
+                }
+            }
+        }
+        state.lbfgs_history = self.lbfgs_history.iter().cloned().collect();
+        state.t = self.step;
+        state.plateau_best = self.plateau_best;
+        state.plateau_wait = self.plateau_wait;
+        state.plateau_reductions = self.plateau_reductions;
+        for (key, mean, var) in &self.running_stats {
+            graph.set_running_stats_by_name(key, mean.clone(), var.clone());
+        }
+        let (key, stream, word_pos) = self.rng;
+        graph.set_rng_state(key, stream, word_pos);
+        Ok(skipped)
+    }
+}
+
+// ============================================================================
+// File I/O Helper Functions
+// ============================================================================
+
+/// Load a CSV file and return tensor data and shape
+/// Expects a numeric CSV (all values are f64)
+pub fn load_csv_file(path: &str) -> Result<(Vec<f64>, Vec<usize>), String> {
+    let (features, _) = load_csv_with(path, &CsvOptions::default())?;
+    Ok((features.data, features.shape))
+}
+
+/// Split one CSV record into fields; `None` if a quoted field is still open at the end of
+/// `text`. Fields may be quoted with `"`, where `""` is a literal quote and delimiters and
+/// newlines are kept; unquoted fields are trimmed.
+fn split_csv_record(text: &str, delimiter: char) -> Result<Option<Vec<String>>, String> {
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    loop {
+        match chars.next() {
+            Some('"') if !quoted && field.trim().is_empty() => {
+                quoted = true;
+                field.clear();
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => field.push(c),
+                        None => return Ok(None),
+                    }
+                }
+            }
+            Some(c) if c == delimiter => {
+                fields.push(if quoted { std::mem::take(&mut field) } else { field.trim().to_string() });
+                field.clear();
+                quoted = false;
+            }
+            None => {
+                fields.push(if quoted { field } else { field.trim().to_string() });
+                return Ok(Some(fields));
+            }
+            Some(c) if quoted => {
+                if !c.is_whitespace() {
+                    return Err(format!("unexpected '{}' after a quoted field", c));
+                }
+            }
+            Some(c) => field.push(c),
+        }
+    }
+}
+
+/// Reads a CSV file record by record. Blank lines and lines starting with `#` are skipped.
+struct CsvRecords<R> {
+    reader: R,
+    delimiter: char,
+    /// Line number and byte offset of the next unread line
+    line: usize,
+    offset: u64,
+}
+
+impl<R: std::io::BufRead> CsvRecords<R> {
+    fn new(reader: R, delimiter: char) -> Self {
+        CsvRecords { reader, delimiter, line: 1, offset: 0 }
+    }
+
+    fn read_line(&mut self, text: &mut String) -> Result<bool, String> {
+        let read = self.reader.read_line(text).map_err(|e| format!("Error reading line {}: {}", self.line, e))?;
+        self.line += 1;
+        self.offset += read as u64;
+        Ok(read > 0)
+    }
+
+    /// Next record as (line number, byte offset, fields)
+    fn next_record(&mut self) -> Result<Option<(usize, u64, Vec<String>)>, String> {
+        let mut text = String::new();
+        loop {
+            let (line, offset) = (self.line, self.offset);
+            text.clear();
+            if !self.read_line(&mut text)? {
+                return Ok(None);
+            }
+            if text.trim().is_empty() || text.starts_with('#') {
+                continue;
+            }
+            loop {
+                match split_csv_record(&text, self.delimiter).map_err(|e| format!("Error parsing line {}: {}", line, e))? {
+                    Some(fields) => return Ok(Some((line, offset, fields))),
+                    None if !self.read_line(&mut text)? => {
+                        return Err(format!("Unterminated quoted field starting at line {}", line));
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
+/// Header names and the selected feature and label columns of a CSV file
+#[derive(Debug)]
+struct CsvLayout {
+    names: Vec<String>,
+    num_cols: usize,
+    columns: Vec<usize>,
+    labels: Vec<usize>,
+}
+
+impl CsvLayout {
+    /// Resolve the selections of `options` against the header `names` (if any) and the
+    /// field count of the first data record
+    fn resolve(path: &str, options: &CsvOptions, names: Vec<String>, first_record: &[String]) -> Result<Self, String> {
+        let num_cols = if options.header { names.len() } else { first_record.len() };
+        let resolve = |column: &CsvColumn| match column {
+            CsvColumn::Index(i) if *i < num_cols => Ok(*i),
+            CsvColumn::Index(i) => Err(format!("CSV column {} is out of range; '{}' has {} columns", i, path, num_cols)),
+            CsvColumn::Name(name) if !options.header => {
+                Err(format!("CSV column '{}' is selected by name, which needs header=true", name))
+            }
+            CsvColumn::Name(name) => names.iter().position(|n| n == name).ok_or_else(|| {
+                format!("CSV file '{}' has no column '{}'; it has: {}", path, name, names.join(", "))
+            }),
+        };
+        let labels = options.labels.iter().map(resolve).collect::<Result<Vec<_>, _>>()?;
+        let columns = if options.columns.is_empty() {
+            (0..num_cols).filter(|c| !labels.contains(c)).collect()
+        } else {
+            options.columns.iter().map(resolve).collect::<Result<Vec<_>, _>>()?
+        };
+        if columns.is_empty() {
+            return Err(format!("No feature columns left in '{}' after taking the labels", path));
+        }
+        Ok(CsvLayout { names, num_cols, columns, labels })
+    }
+
+    fn check_width(&self, line: usize, fields: &[String]) -> Result<(), String> {
+        if fields.len() != self.num_cols {
+            return Err(format!(
+                "Inconsistent column count at line {}: expected {}, got {}",
+                line, self.num_cols, fields.len()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Append the numbers of the `selected` columns of a record to `out`. Missing cells take
+    /// `na`, and are an error without it.
+    fn parse(&self, selected: &[usize], line: usize, fields: &[String], na: Option<f64>, out: &mut Vec<f64>) -> Result<(), String> {
+        for &c in selected {
+            let cell = fields[c].as_str();
+            let column = || self.names.get(c).map(|n| format!("'{}'", n)).unwrap_or_else(|| c.to_string());
+            let value = if cell.is_empty() || ["na", "n/a", "nan", "null"].contains(&cell.to_ascii_lowercase().as_str()) {
+                na.ok_or_else(|| format!(
+                    "Error parsing line {}: missing value in column {}; pass na=<value> (or na=nan) to fill missing cells",
+                    line, column()
+                ))?
+            } else {
+                cell.parse::<f64>().map_err(|_| {
+                    format!("Error parsing line {}: '{}' in column {} is not a number", line, cell, column())
+                })?
+            };
+            out.push(value);
+        }
+        Ok(())
+    }
+}
+
+/// Shape of `rows` records with `columns` selected columns: 1D for a single column
+fn csv_shape(rows: usize, columns: usize) -> Vec<usize> {
+    if columns == 1 { vec![rows] } else { vec![rows, columns] }
+}
+
+/// Load the columns selected by `options` from a CSV file. The second tensor holds the
+/// `labels` columns, if any; one column loads as a 1D tensor, several as [rows, columns].
+pub fn load_csv_with(path: &str, options: &CsvOptions) -> Result<(Tensor, Option<Tensor>), String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("Cannot open CSV file '{}': {}", path, e))?;
+    let mut reader = CsvRecords::new(std::io::BufReader::new(file), options.delimiter);
+    let names = if options.header {
+        reader.next_record()?.map(|(_, _, names)| names).unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    let mut layout = None;
+    let (mut features, mut labels, mut rows) = (Vec::new(), Vec::new(), 0);
+    while let Some((line, _, fields)) = reader.next_record()? {
+        if layout.is_none() {
+            layout = Some(CsvLayout::resolve(path, options, names.clone(), &fields)?);
+        }
+        let layout = layout.as_ref().expect("resolved on the first record");
+        layout.check_width(line, &fields)?;
+        layout.parse(&layout.columns, line, &fields, options.na, &mut features)?;
+        layout.parse(&layout.labels, line, &fields, options.na, &mut labels)?;
+        rows += 1;
+    }
+    let layout = layout.ok_or_else(|| format!("CSV file '{}' is empty or contains no data", path))?;
+    let features = Tensor { data: features, shape: csv_shape(rows, layout.columns.len()) };
+    let labels = (!layout.labels.is_empty()).then(|| Tensor { data: labels, shape: csv_shape(rows, layout.labels.len()) });
+    Ok((features, labels))
+}
+
+/// Save a tensor value to a CSV file
+pub fn save_csv_file(value: &Value, path: &str) -> Result<(), String> {
+    save_csv_file_with(value, path, &[])
+}
+
+/// Save a tensor value to a CSV file, preceded by a header row unless `header` is empty
+pub fn save_csv_file_with(value: &Value, path: &str, header: &[String]) -> Result<(), String> {
+    use std::fs::File;
+    use std::io::Write;
+    
+    let columns = match value {
+        Value::Tensor(t) if t.shape.len() >= 2 => t.shape[1..].iter().product(),
+        _ => 1,
+    };
+    if !header.is_empty() && header.len() != columns {
+        return Err(format!("save_csv header has {} names but the tensor has {} columns", header.len(), columns));
+    }
+
+    let mut file = File::create(path).map_err(|e| format!("Cannot create CSV file '{}': {}", path, e))?;
+    if !header.is_empty() {
+        let names: Vec<String> = header.iter().map(|name| {
+            if name.contains([',', '"', '\n', '\r']) || name.trim() != name {
+                format!("\"{}\"", name.replace('"', "\"\""))
+            } else {
+                name.clone()
+            }
+        }).collect();
+        writeln!(file, "{}", names.join(",")).map_err(|e| format!("Error writing to CSV: {}", e))?;
+    }
+    
+    match value {
+        Value::Scalar(s) => {
+            writeln!(file, "{}", s).map_err(|e| format!("Error writing to CSV: {}", e))?;
+        }
+        Value::Tensor(t) => {
+            if t.shape.len() == 1 {
+                // 1D tensor: write each element on a new line
+                for val in &t.data {
+                    writeln!(file, "{}", val).map_err(|e| format!("Error writing to CSV: {}", e))?;
+                }
+            } else if t.shape.len() == 2 {
+                // 2D tensor: write each row as a CSV line
+                let cols = t.shape[1];
+                for row in t.data.chunks(cols) {
+                    let line: Vec<String> = row.iter().map(|v| v.to_string()).collect();
+                    writeln!(file, "{}", line.join(",")).map_err(|e| format!("Error writing to CSV: {}", e))?;
+                }
+            } else {
+                // Higher dimensional: flatten to 2D (first dim x rest)
+                let first_dim = t.shape[0];
+                let rest: usize = t.shape[1..].iter().product();
+                for row in 0..first_dim {
+                    let start = row * rest;
+                    let end = start + rest;
+                    let line: Vec<String> = t.data[start..end].iter().map(|v| v.to_string()).collect();
+                    writeln!(file, "{}", line.join(",")).map_err(|e| format!("Error writing to CSV: {}", e))?;
+                }
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+/// Fields every optimize-loop log record starts with; `log(value, "name")` cannot use them
+pub const METRICS_LOG_FIELDS: &[&str] = &["step", "loop", "time", "lr", "grad_norm"];
+
+/// Writes optimize-loop telemetry (`noma run --log-file`): one record per logged step, as CSV
+/// when the path ends in `.csv` and as JSON lines otherwise
+pub struct MetricsLogger {
+    path: String,
+    writer: std::io::BufWriter<std::fs::File>,
+    csv: bool,
+    /// CSV columns, fixed by the first record
+    columns: Option<Vec<String>>,
+    every: usize,
+    started: std::time::Instant,
+}
+
+impl MetricsLogger {
+    /// Create (or truncate) `path`; loops log every `every`-th step and their last one
+    pub fn create(path: &str, every: usize) -> Result<Self, String> {
+        if every == 0 {
+            return Err("Log interval must be at least 1 step".to_string());
+        }
+        let file = std::fs::File::create(path).map_err(|e| format!("Cannot create log file '{}': {}", path, e))?;
+        Ok(MetricsLogger {
+            path: path.to_string(),
+            writer: std::io::BufWriter::new(file),
+            csv: path.to_ascii_lowercase().ends_with(".csv"),
+            columns: None,
+            every,
+            started: std::time::Instant::now(),
+        })
+    }
+
+    pub fn every(&self) -> usize {
+        self.every
+    }
+
+    /// Seconds since the log was created
+    pub fn elapsed(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+
+    /// Append one record. CSV columns come from the first record: later records leave missing
+    /// fields empty, and a field the header lacks is an error.
+    pub fn write(&mut self, record: &[(String, f64)]) -> Result<(), String> {
+        use std::io::Write;
+
+        let line = if self.csv {
+            let first = self.columns.is_none();
+            let columns = self.columns.get_or_insert_with(|| record.iter().map(|(name, _)| name.clone()).collect());
+            if let Some((name, _)) = record.iter().find(|(name, _)| !columns.contains(name)) {
+                return Err(format!(
+                    "Log file '{}': metric '{}' first appears after the CSV header was written; log it from the first loop or use a .jsonl file",
+                    self.path, name
+                ));
+            }
+            let row = columns.iter()
+                .map(|column| record.iter().find(|(name, _)| name == column).map(|(_, v)| v.to_string()).unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(",");
+            if first {
+                format!("{}\n{}", columns.join(","), row)
+            } else {
+                row
             }
-            
-            let tensor_data = extract_tensor_data(dtype, &data[offsets[0]..offsets[1]], &shape)?;
-            result.push((name, (tensor_data, shape)));
+        } else {
+            let fields: Vec<String> = record.iter()
+                .map(|(name, v)| format!("\"{}\":{}", json_escape(name), if v.is_finite() { v.to_string() } else { "null".to_string() }))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        };
+        writeln!(self.writer, "{}", line).map_err(|e| format!("Error writing log file '{}': {}", self.path, e))
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+        use std::io::Write;
+        self.writer.flush().map_err(|e| format!("Error writing log file '{}': {}", self.path, e))
+    }
+}
+
+/// Escape `s` for use inside a JSON string literal
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// (name, (data, shape)) of the arrays read from a tensor file, data in row-major order
+pub type NamedArrays = Vec<(String, (Vec<f64>, Vec<usize>))>;
+
+/// Element type of a safetensors tensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SafetensorsDtype {
+    F64,
+    F32,
+    F16,
+    BF16,
+    I64,
+    I32,
+    I16,
+    I8,
+    U64,
+    U32,
+    U16,
+    U8,
+    Bool,
+}
+
+impl SafetensorsDtype {
+    const ALL: [SafetensorsDtype; 13] = [
+        Self::F64, Self::F32, Self::F16, Self::BF16, Self::I64, Self::I32, Self::I16,
+        Self::I8, Self::U64, Self::U32, Self::U16, Self::U8, Self::Bool,
+    ];
+
+    /// Dtype for its header name ("F32", "BF16", "BOOL", ...)
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|dtype| dtype.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::F64 => "F64",
+            Self::F32 => "F32",
+            Self::F16 => "F16",
+            Self::BF16 => "BF16",
+            Self::I64 => "I64",
+            Self::I32 => "I32",
+            Self::I16 => "I16",
+            Self::I8 => "I8",
+            Self::U64 => "U64",
+            Self::U32 => "U32",
+            Self::U16 => "U16",
+            Self::U8 => "U8",
+            Self::Bool => "BOOL",
+        }
+    }
+
+    /// Bytes per element
+    pub fn size(self) -> usize {
+        match self {
+            Self::F64 | Self::I64 | Self::U64 => 8,
+            Self::F32 | Self::I32 | Self::U32 => 4,
+            Self::F16 | Self::BF16 | Self::I16 | Self::U16 => 2,
+            Self::I8 | Self::U8 | Self::Bool => 1,
         }
     }
-    
-    #[cfg(not(feature = "serde_json"))]
-    {
-        // Simplified parsing without serde_json
-        // This handles basic safetensors files
-        let content = &header[1..header.len()-1]; // Remove { }
-        
-        for part in content.split("},") {
-            let part = part.trim();
-            if part.is_empty() { continue; }
-            
-            // Find tensor name
-            let name_end = part.find(':').ok_or("Invalid header format")?;
-            let name = part[..name_end].trim().trim_matches('"').to_string();
-            
-            if name == "__metadata__" { continue; }
-            
-            let info = &part[name_end + 1..];
-            let info = info.trim_start_matches('{').trim_end_matches('}').trim();
-            
-            // Parse dtype
-            let dtype = extract_json_string(info, "dtype").unwrap_or("F64".to_string());
-            
-            // Parse shape
-            let shape = extract_json_array(info, "shape")?;
-            
-            // Parse data_offsets
-            let offsets = extract_json_array(info, "data_offsets")?;
-            
-            if offsets.len() != 2 {
-                return Err(format!("Invalid data_offsets for tensor '{}'", name));
+
+    /// Decode little-endian elements; `bytes.len()` must be a multiple of `size()`
+    pub fn decode(self, bytes: &[u8]) -> Vec<f64> {
+        fn le<const N: usize>(chunk: &[u8]) -> [u8; N] {
+            chunk.try_into().expect("chunks_exact yields N bytes")
+        }
+        let chunks = bytes.chunks_exact(self.size());
+        match self {
+            Self::F64 => chunks.map(|c| f64::from_le_bytes(le(c))).collect(),
+            Self::F32 => chunks.map(|c| f32::from_le_bytes(le(c)) as f64).collect(),
+            Self::F16 => chunks.map(|c| half::f16::from_le_bytes(le(c)).to_f64()).collect(),
+            Self::BF16 => chunks.map(|c| half::bf16::from_le_bytes(le(c)).to_f64()).collect(),
+            Self::I64 => chunks.map(|c| i64::from_le_bytes(le(c)) as f64).collect(),
+            Self::I32 => chunks.map(|c| i32::from_le_bytes(le(c)) as f64).collect(),
+            Self::I16 => chunks.map(|c| i16::from_le_bytes(le(c)) as f64).collect(),
+            Self::I8 => chunks.map(|c| c[0] as i8 as f64).collect(),
+            Self::U64 => chunks.map(|c| u64::from_le_bytes(le(c)) as f64).collect(),
+            Self::U32 => chunks.map(|c| u32::from_le_bytes(le(c)) as f64).collect(),
+            Self::U16 => chunks.map(|c| u16::from_le_bytes(le(c)) as f64).collect(),
+            Self::U8 => chunks.map(|c| c[0] as f64).collect(),
+            Self::Bool => chunks.map(|c| if c[0] != 0 { 1.0 } else { 0.0 }).collect(),
+        }
+    }
+
+    /// Encode values as little-endian elements. Floating-point dtypes round; integer dtypes
+    /// take only integral values in their range, and BOOL only 0 and 1.
+    pub fn encode(self, values: &[f64], tensor: &str) -> Result<Vec<u8>, String> {
+        fn int<T: TryFrom<i128>>(v: f64) -> Option<T> {
+            if v.fract() != 0.0 || v.abs() >= 2f64.powi(100) {
+                return None;
+            }
+            T::try_from(v as i128).ok()
+        }
+        let mut out = Vec::with_capacity(values.len() * self.size());
+        for &v in values {
+            let float = |bytes: &[u8], out: &mut Vec<u8>| {
+                out.extend_from_slice(bytes);
+                true
+            };
+            let written = match self {
+                Self::F64 => float(&v.to_le_bytes(), &mut out),
+                Self::F32 => float(&(v as f32).to_le_bytes(), &mut out),
+                Self::F16 => float(&half::f16::from_f64(v).to_le_bytes(), &mut out),
+                Self::BF16 => float(&half::bf16::from_f64(v).to_le_bytes(), &mut out),
+                Self::I64 => int::<i64>(v).map(|x| out.extend_from_slice(&x.to_le_bytes())).is_some(),
+                Self::I32 => int::<i32>(v).map(|x| out.extend_from_slice(&x.to_le_bytes())).is_some(),
+                Self::I16 => int::<i16>(v).map(|x| out.extend_from_slice(&x.to_le_bytes())).is_some(),
+                Self::I8 => int::<i8>(v).map(|x| out.extend_from_slice(&x.to_le_bytes())).is_some(),
+                Self::U64 => int::<u64>(v).map(|x| out.extend_from_slice(&x.to_le_bytes())).is_some(),
+                Self::U32 => int::<u32>(v).map(|x| out.extend_from_slice(&x.to_le_bytes())).is_some(),
+                Self::U16 => int::<u16>(v).map(|x| out.extend_from_slice(&x.to_le_bytes())).is_some(),
+                Self::U8 => int::<u8>(v).map(|x| out.push(x)).is_some(),
+                Self::Bool => (v == 0.0 || v == 1.0).then(|| out.push(v as u8)).is_some(),
+            };
+            if !written {
+                return Err(format!("Cannot write {} in tensor '{}' as {}", v, tensor, self.name()));
             }
-            
-            let tensor_data = extract_tensor_data(&dtype, &data[offsets[0]..offsets[1]], &shape)?;
-            result.push((name, (tensor_data, shape)));
         }
+        Ok(out)
     }
-    
-    Ok(result)
 }
 
-/// Extract JSON string value
-fn extract_json_string(json: &str, key: &str) -> Option<String> {
-    let key_pattern = format!("\"{}\"", key);
-    let start = json.find(&key_pattern)?;
-    let after_key = &json[start + key_pattern.len()..];
-    let colon_pos = after_key.find(':')?;
-    let value_start = &after_key[colon_pos + 1..].trim_start();
-    
-    if value_start.starts_with('"') {
-        let end = value_start[1..].find('"')?;
-        Some(value_start[1..1+end].to_string())
-    } else {
-        None
-    }
+/// One tensor of a safetensors header; `offsets` are relative to the start of the data
+#[derive(Debug, Clone, PartialEq)]
+pub struct SafetensorsEntry {
+    pub name: String,
+    pub dtype: SafetensorsDtype,
+    pub shape: Vec<usize>,
+    pub offsets: (usize, usize),
 }
 
-/// Extract JSON array of numbers
-fn extract_json_array(json: &str, key: &str) -> Result<Vec<usize>, String> {
-    let key_pattern = format!("\"{}\"", key);
-    let start = json.find(&key_pattern)
-        .ok_or_else(|| format!("Missing key '{}' in JSON", key))?;
-    let after_key = &json[start + key_pattern.len()..];
-    let bracket_start = after_key.find('[')
-        .ok_or_else(|| format!("Expected '[' after key '{}'", key))?;
-    let bracket_end = after_key.find(']')
-        .ok_or_else(|| format!("Expected ']' for key '{}'", key))?;
-    
-    let array_content = &after_key[bracket_start + 1..bracket_end];
-    
-    array_content
-        .split(',')
-        .map(|s| s.trim().parse::<usize>().map_err(|e| format!("Invalid number: {}", e)))
-        .collect()
+/// Parsed safetensors header: the tensors in data order, the `__metadata__` string map,
+/// and the file position where tensor data begins
+#[derive(Debug, Clone, PartialEq)]
+pub struct SafetensorsHeader {
+    pub tensors: Vec<SafetensorsEntry>,
+    pub metadata: Vec<(String, String)>,
+    pub data_start: usize,
 }
 
-/// Extract tensor data from binary buffer
-fn extract_tensor_data(dtype: &str, data: &[u8], shape: &[usize]) -> Result<Vec<f64>, String> {
-    let total_elements: usize = shape.iter().product();
-    
-    match dtype {
-        "F64" => {
-            if data.len() != total_elements * 8 {
-                return Err(format!("Data size mismatch for F64: expected {}, got {}", total_elements * 8, data.len()));
+/// Headers larger than this are rejected, as in the reference implementation
+const SAFETENSORS_MAX_HEADER: usize = 100_000_000;
+
+impl SafetensorsHeader {
+    /// Length of the JSON header, from the first 8 bytes of a file
+    pub fn json_len(prefix: &[u8]) -> Result<usize, String> {
+        let bytes: [u8; 8] = prefix.get(..8)
+            .and_then(|b| b.try_into().ok())
+            .ok_or("Invalid safetensors file: too short")?;
+        let len = u64::from_le_bytes(bytes);
+        if len > SAFETENSORS_MAX_HEADER as u64 {
+            return Err(format!("Invalid safetensors file: header of {} bytes is too large", len));
+        }
+        Ok(len as usize)
+    }
+
+    /// Parse the JSON header and check that the tensors exactly tile the `data_len` data bytes
+    pub fn parse(json: &[u8], data_len: usize) -> Result<Self, String> {
+        use serde_json::Value as Json;
+
+        let json = std::str::from_utf8(json).map_err(|e| format!("Invalid UTF-8 in safetensors header: {}", e))?;
+        let parsed: serde_json::Map<String, Json> = serde_json::from_str(json)
+            .map_err(|e| format!("Error parsing safetensors header: {}", e))?;
+
+        let mut tensors = Vec::new();
+        let mut metadata = Vec::new();
+        for (name, info) in parsed {
+            if name == "__metadata__" {
+                let entries = info.as_object().ok_or("Invalid safetensors header: __metadata__ is not an object")?;
+                for (key, value) in entries {
+                    let value = value.as_str()
+                        .ok_or_else(|| format!("Invalid safetensors header: metadata '{}' is not a string", key))?;
+                    metadata.push((key.clone(), value.to_string()));
+                }
+                continue;
             }
-            let mut result = Vec::with_capacity(total_elements);
-            for chunk in data.chunks(8) {
-                let bytes: [u8; 8] = chunk.try_into().map_err(|_| "Invalid F64 data")?;
-                result.push(f64::from_le_bytes(bytes));
+            let field = |key: &str| info.get(key).ok_or_else(|| format!("Missing {} for tensor '{}'", key, name));
+            let dims = |key: &str| -> Result<Vec<usize>, String> {
+                field(key)?.as_array()
+                    .and_then(|a| a.iter().map(|v| v.as_u64().map(|n| n as usize)).collect::<Option<Vec<_>>>())
+                    .ok_or_else(|| format!("Invalid {} for tensor '{}'", key, name))
+            };
+            let dtype_name = field("dtype")?.as_str().ok_or_else(|| format!("Invalid dtype for tensor '{}'", name))?;
+            let dtype = SafetensorsDtype::from_name(dtype_name)
+                .ok_or_else(|| format!("Unsupported dtype '{}' for tensor '{}'", dtype_name, name))?;
+            let shape = dims("shape")?;
+            let [start, end] = dims("data_offsets")?[..] else {
+                return Err(format!("Invalid data_offsets for tensor '{}'", name));
+            };
+            let expected = shape.iter().try_fold(dtype.size(), |n, &d| n.checked_mul(d));
+            if start > end || Some(end - start) != expected {
+                return Err(format!(
+                    "Tensor '{}' has data_offsets [{}, {}], which do not match shape {:?} of {}",
+                    name, start, end, shape, dtype.name()
+                ));
             }
-            Ok(result)
+            tensors.push(SafetensorsEntry { name, dtype, shape, offsets: (start, end) });
         }
-        "F32" => {
-            if data.len() != total_elements * 4 {
-                return Err(format!("Data size mismatch for F32: expected {}, got {}", total_elements * 4, data.len()));
+
+        tensors.sort_by_key(|t| t.offsets);
+        let mut position = 0;
+        for tensor in &tensors {
+            if tensor.offsets.0 != position {
+                return Err(format!("Invalid safetensors file: tensor '{}' does not start where the previous one ends", tensor.name));
             }
-            let mut result = Vec::with_capacity(total_elements);
-            for chunk in data.chunks(4) {
-                let bytes: [u8; 4] = chunk.try_into().map_err(|_| "Invalid F32 data")?;
-                result.push(f32::from_le_bytes(bytes) as f64);
+            position = tensor.offsets.1;
+        }
+        if position != data_len {
+            return Err(format!("Invalid safetensors file: tensors cover {} of {} data bytes", position, data_len));
+        }
+        Ok(SafetensorsHeader { tensors, metadata, data_start: 8 + json.len() })
+    }
+}
+
+/// A safetensors file opened for lazy reading. The header is parsed up front and the file
+/// is memory-mapped; a tensor is only read and converted to f64 when it is requested.
+#[derive(Debug)]
+pub struct SafetensorsFile {
+    path: String,
+    header: SafetensorsHeader,
+    mmap: memmap2::Mmap,
+}
+
+impl SafetensorsFile {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let file = std::fs::File::open(path).map_err(|e| format!("Cannot open safetensors file '{}': {}", path, e))?;
+        let len = file.metadata().map_err(|e| format!("Cannot open safetensors file '{}': {}", path, e))?.len();
+        if len < 8 {
+            return Err(format!("Invalid safetensors file: too short ({})", path));
+        }
+        // SAFETY: the mapping is read-only; like every mmap reader we rely on the file not
+        // being truncated while it is open
+        let mmap = unsafe { memmap2::Mmap::map(&file) }
+            .map_err(|e| format!("Cannot map safetensors file '{}': {}", path, e))?;
+        let json_len = SafetensorsHeader::json_len(&mmap)?;
+        let json = mmap.get(8..8 + json_len).ok_or("Invalid safetensors file: header size mismatch")?;
+        let header = SafetensorsHeader::parse(json, mmap.len() - 8 - json_len)
+            .map_err(|e| format!("{} ({})", e, path))?;
+        Ok(SafetensorsFile { path: path.to_string(), header, mmap })
+    }
+
+    /// Tensor entries in file order
+    pub fn entries(&self) -> &[SafetensorsEntry] {
+        &self.header.tensors
+    }
+
+    pub fn metadata(&self) -> &[(String, String)] {
+        &self.header.metadata
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&SafetensorsEntry> {
+        self.header.tensors.iter().find(|t| t.name == name)
+    }
+
+    /// Raw little-endian bytes of a tensor, borrowed from the mapping
+    pub fn bytes(&self, entry: &SafetensorsEntry) -> &[u8] {
+        let start = self.header.data_start;
+        &self.mmap[start + entry.offsets.0..start + entry.offsets.1]
+    }
+
+    /// Decode the tensor called `name` to f64. BOOL elements must be 0 or 1.
+    pub fn tensor(&self, name: &str) -> Result<Tensor, String> {
+        let entry = self.entry(name).ok_or_else(|| {
+            let mut names: Vec<&str> = self.header.tensors.iter().map(|t| t.name.as_str()).collect();
+            names.sort();
+            format!("No tensor '{}' in safetensors file '{}' (it has: {})", name, self.path, names.join(", "))
+        })?;
+        let bytes = self.bytes(entry);
+        if entry.dtype == SafetensorsDtype::Bool {
+            if let Some(b) = bytes.iter().find(|&&b| b > 1) {
+                return Err(format!("Invalid BOOL value {} in tensor '{}' of '{}'", b, name, self.path));
             }
-            Ok(result)
         }
-        "F16" | "BF16" => {
-            // For F16/BF16, we'd need proper half-float conversion
-            // For now, return an error suggesting F32/F64
-            Err(format!("Dtype '{}' not fully supported. Please convert to F32 or F64.", dtype))
+        Ok(Tensor { data: entry.dtype.decode(bytes), shape: entry.shape.clone() })
+    }
+
+    /// Like `tensor`, but in a NOMA checkpoint, where learnables are stored as `param.<name>`,
+    /// the learnable name works as well
+    pub fn tensor_or_param(&self, name: &str) -> Result<Tensor, String> {
+        let checkpoint_name = format!("param.{}", name);
+        if self.entry(name).is_none() && self.entry(&checkpoint_name).is_some() {
+            self.tensor(&checkpoint_name)
+        } else {
+            self.tensor(name)
         }
-        _ => Err(format!("Unsupported dtype: {}", dtype)),
     }
 }
 
-/// Save tensors to a Safetensors file
+/// Load tensors from a Safetensors file
+/// Returns (name, (data, shape)) in file order; every dtype is converted to f64
+pub fn load_safetensors_file(path: &str) -> Result<NamedArrays, String> {
+    let file = SafetensorsFile::open(path)?;
+    file.entries().iter()
+        .map(|entry| file.tensor(&entry.name).map(|t| (entry.name.clone(), (t.data, t.shape))))
+        .collect()
+}
+
+/// The `__metadata__` entries of a Safetensors file
+pub fn load_safetensors_metadata(path: &str) -> Result<Vec<(String, String)>, String> {
+    Ok(SafetensorsFile::open(path)?.header.metadata)
+}
+
+/// Load the tensor called `name` from a safetensors file, decoding no other tensor. In a NOMA
+/// checkpoint, where learnables are stored as `param.<name>`, the learnable name works as well.
+pub fn load_safetensors_tensor(path: &str, name: &str) -> Result<(Vec<f64>, Vec<usize>), String> {
+    let tensor = SafetensorsFile::open(path)?.tensor_or_param(name)?;
+    Ok((tensor.data, tensor.shape))
+}
+
+/// Variables bound by `load_safetensors name = "path";`: `name.<tensor>` for every tensor,
+/// plus `name` itself when the file holds a single tensor. A NOMA checkpoint binds only its
+/// learnables, as `name.<learnable>`, without decoding the optimizer state.
+pub fn safetensors_bindings(name: &str, path: &str) -> Result<Vec<(String, Tensor)>, String> {
+    let file = SafetensorsFile::open(path)?;
+    let is_checkpoint = file.entry("noma.checkpoint").is_some();
+    let selected: Vec<(&str, &str)> = file.entries().iter()
+        .filter_map(|t| if is_checkpoint {
+            t.name.strip_prefix("param.").map(|short| (short, t.name.as_str()))
+        } else {
+            Some((t.name.as_str(), t.name.as_str()))
+        })
+        .collect();
+    if selected.is_empty() {
+        return Err(format!("No tensors found in safetensors file: {}", path));
+    }
+    let mut bindings = Vec::new();
+    for (short, stored) in &selected {
+        bindings.push((format!("{}.{}", name, short), file.tensor(stored)?));
+    }
+    if let [(_, only)] = &bindings[..] {
+        bindings.push((name.to_string(), only.clone()));
+    }
+    Ok(bindings)
+}
+
+/// Save tensors to a Safetensors file as F64
 pub fn save_safetensors_file(tensors: &[(String, Value)], path: &str) -> Result<(), String> {
-    use std::fs::File;
-    use std::io::Write;
-    
-    // Build header and data
-    let mut header_parts = Vec::new();
+    save_safetensors_file_with(tensors, path, SafetensorsDtype::F64, &[])
+}
+
+/// Save tensors to a Safetensors file in `dtype`, with `metadata` as its `__metadata__`
+pub fn save_safetensors_file_with(
+    tensors: &[(String, Value)],
+    path: &str,
+    dtype: SafetensorsDtype,
+    metadata: &[(String, String)],
+) -> Result<(), String> {
+    use serde_json::{json, Map, Value as Json};
+
+    let mut header = Map::new();
+    if !metadata.is_empty() {
+        let entries = metadata.iter().map(|(k, v)| (k.clone(), Json::String(v.clone()))).collect();
+        header.insert("__metadata__".to_string(), Json::Object(entries));
+    }
     let mut tensor_data = Vec::new();
-    
     for (name, value) in tensors {
         let (data, shape) = match value {
             Value::Scalar(s) => (vec![*s], vec![1usize]),
             Value::Tensor(t) => (t.data.clone(), t.shape.clone()),
         };
-        
-        let start_offset = tensor_data.len();
-        
-        // Convert to F64 bytes (little endian)
-        for val in &data {
-            tensor_data.extend_from_slice(&val.to_le_bytes());
+        if name == "__metadata__" || header.contains_key(name) {
+            return Err(format!("Tensor name '{}' is reserved or given more than once in safetensors file '{}'", name, path));
+        }
+        let start = tensor_data.len();
+        tensor_data.extend(dtype.encode(&data, name)?);
+        header.insert(name.clone(), json!({
+            "dtype": dtype.name(),
+            "shape": shape,
+            "data_offsets": [start, tensor_data.len()],
+        }));
+    }
+
+    // Pad the header with spaces so that tensor data starts 8-byte aligned
+    let mut header_json = Json::Object(header).to_string();
+    while !header_json.len().is_multiple_of(8) {
+        header_json.push(' ');
+    }
+    let mut bytes = (header_json.len() as u64).to_le_bytes().to_vec();
+    bytes.extend_from_slice(header_json.as_bytes());
+    bytes.extend_from_slice(&tensor_data);
+    std::fs::write(path, bytes).map_err(|e| format!("Cannot create safetensors file '{}': {}", path, e))
+}
+
+/// Element type of a NumPy array, from its `descr` ("<f8", ">i4", "|u1", "|b1", ...)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NpyDtype {
+    pub element: SafetensorsDtype,
+    pub big_endian: bool,
+}
+
+impl NpyDtype {
+    pub const F64: NpyDtype = NpyDtype { element: SafetensorsDtype::F64, big_endian: false };
+
+    const KINDS: [(&'static str, SafetensorsDtype); 12] = [
+        ("f8", SafetensorsDtype::F64), ("f4", SafetensorsDtype::F32), ("f2", SafetensorsDtype::F16),
+        ("i8", SafetensorsDtype::I64), ("i4", SafetensorsDtype::I32), ("i2", SafetensorsDtype::I16),
+        ("i1", SafetensorsDtype::I8), ("u8", SafetensorsDtype::U64), ("u4", SafetensorsDtype::U32),
+        ("u2", SafetensorsDtype::U16), ("u1", SafetensorsDtype::U8), ("b1", SafetensorsDtype::Bool),
+    ];
+
+    /// Dtype for a NumPy type string; without a byte-order character the native order is used
+    pub fn parse(descr: &str) -> Option<Self> {
+        let (big_endian, kind) = match descr.as_bytes().first()? {
+            b'<' | b'|' => (false, &descr[1..]),
+            b'>' => (true, &descr[1..]),
+            b'=' => (cfg!(target_endian = "big"), &descr[1..]),
+            _ => (cfg!(target_endian = "big"), descr),
+        };
+        let element = Self::KINDS.iter().find(|(name, _)| *name == kind)?.1;
+        Some(NpyDtype { element, big_endian: big_endian && element.size() > 1 })
+    }
+
+    pub fn descr(self) -> String {
+        let kind = Self::KINDS.iter().find(|(_, e)| *e == self.element).map_or("f8", |(name, _)| name);
+        let order = match (self.element.size(), self.big_endian) {
+            (1, _) => '|',
+            (_, true) => '>',
+            (_, false) => '<',
+        };
+        format!("{}{}", order, kind)
+    }
+
+    fn decode(self, bytes: &[u8]) -> Vec<f64> {
+        if !self.big_endian {
+            return self.element.decode(bytes);
+        }
+        let swapped: Vec<u8> = bytes.chunks_exact(self.element.size()).flat_map(|c| c.iter().rev().copied()).collect();
+        self.element.decode(&swapped)
+    }
+
+    fn encode(self, values: &[f64], array: &str) -> Result<Vec<u8>, String> {
+        let mut bytes = self.element.encode(values, array)?;
+        if self.big_endian {
+            bytes.chunks_exact_mut(self.element.size()).for_each(|c| c.reverse());
+        }
+        Ok(bytes)
+    }
+}
+
+/// The header dict of a .npy file: {'descr': '<f8', 'fortran_order': False, 'shape': (3, 4), }
+fn parse_npy_header(header: &str) -> Result<(NpyDtype, bool, Vec<usize>), String> {
+    let mut chars = header.trim().chars().peekable();
+    let skip_ws = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+    let quoted = |chars: &mut std::iter::Peekable<std::str::Chars>| -> Result<String, String> {
+        let quote = chars.next().filter(|c| *c == '\'' || *c == '"').ok_or("expected a quoted string")?;
+        let text: String = chars.by_ref().take_while(|c| *c != quote).collect();
+        Ok(text)
+    };
+
+    let (mut descr, mut fortran_order, mut shape) = (None, None, None);
+    if chars.next() != Some('{') {
+        return Err("expected a dict".to_string());
+    }
+    loop {
+        skip_ws(&mut chars);
+        if chars.next_if_eq(&'}').is_some() {
+            break;
+        }
+        let key = quoted(&mut chars)?;
+        skip_ws(&mut chars);
+        if chars.next() != Some(':') {
+            return Err(format!("expected ':' after '{}'", key));
+        }
+        skip_ws(&mut chars);
+        match key.as_str() {
+            "descr" if chars.peek() == Some(&'[') => {
+                return Err("structured dtypes are not supported".to_string());
+            }
+            "descr" => {
+                let text = quoted(&mut chars)?;
+                descr = Some(NpyDtype::parse(&text).ok_or_else(|| format!("unsupported dtype '{}'", text))?);
+            }
+            "fortran_order" => {
+                let word: String = std::iter::from_fn(|| chars.next_if(|c| c.is_alphabetic())).collect();
+                fortran_order = Some(match word.as_str() {
+                    "True" => true,
+                    "False" => false,
+                    _ => return Err(format!("invalid fortran_order '{}'", word)),
+                });
+            }
+            "shape" => {
+                if chars.next() != Some('(') {
+                    return Err("shape must be a tuple".to_string());
+                }
+                let text: String = chars.by_ref().take_while(|c| *c != ')').collect();
+                let dims: Result<Vec<usize>, _> = text.split(',')
+                    .map(str::trim)
+                    .filter(|d| !d.is_empty())
+                    .map(|d| d.trim_end_matches('L').parse::<usize>())
+                    .collect();
+                shape = Some(dims.map_err(|_| format!("invalid shape ({})", text))?);
+            }
+            _ => return Err(format!("unexpected key '{}'", key)),
+        }
+        skip_ws(&mut chars);
+        chars.next_if_eq(&',');
+    }
+    match (descr, fortran_order, shape) {
+        (Some(d), Some(f), Some(s)) => Ok((d, f, s)),
+        _ => Err("missing descr, fortran_order or shape".to_string()),
+    }
+}
+
+/// Reorder column-major (Fortran) data to the row-major order of NOMA tensors
+fn fortran_to_c_order(data: &[f64], shape: &[usize]) -> Vec<f64> {
+    let mut strides = vec![1; shape.len()];
+    for k in 1..shape.len() {
+        strides[k] = strides[k - 1] * shape[k - 1];
+    }
+    let mut index = vec![0; shape.len()];
+    let mut out = Vec::with_capacity(data.len());
+    for _ in 0..data.len() {
+        out.push(data[index.iter().zip(&strides).map(|(i, s)| i * s).sum::<usize>()]);
+        for k in (0..shape.len()).rev() {
+            index[k] += 1;
+            if index[k] < shape[k] {
+                break;
+            }
+            index[k] = 0;
+        }
+    }
+    out
+}
+
+/// Dtype, Fortran order, shape and data offset of a .npy image, checking the data length
+fn npy_layout(bytes: &[u8], source: &str) -> Result<(NpyDtype, bool, Vec<usize>, usize), String> {
+    let invalid = |what: &str| format!("Invalid .npy data in '{}': {}", source, what);
+    if bytes.len() < 10 || !bytes.starts_with(b"\x93NUMPY") {
+        return Err(invalid("missing the \\x93NUMPY magic string"));
+    }
+    let (header_len, start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize, 12),
+        major => return Err(invalid(&format!("unsupported format version {}", major))),
+    };
+    let header = bytes.get(start..start + header_len).ok_or_else(|| invalid("truncated header"))?;
+    let header = std::str::from_utf8(header).map_err(|_| invalid("header is not text"))?;
+    let (dtype, fortran_order, shape) = parse_npy_header(header).map_err(|e| invalid(&e))?;
+
+    let data_len = bytes.len() - start - header_len;
+    let count: usize = shape.iter().product();
+    if data_len != count * dtype.element.size() {
+        return Err(invalid(&format!("shape {:?} of {} needs {} data bytes, found {}",
+            shape, dtype.descr(), count * dtype.element.size(), data_len)));
+    }
+    Ok((dtype, fortran_order, shape, start + header_len))
+}
+
+/// Decode a .npy file image; `source` names it in errors
+fn parse_npy(bytes: &[u8], source: &str) -> Result<(Vec<f64>, Vec<usize>), String> {
+    let (dtype, fortran_order, shape, data_start) = npy_layout(bytes, source)?;
+    let values = dtype.decode(&bytes[data_start..]);
+    let values = if fortran_order { fortran_to_c_order(&values, &shape) } else { values };
+    Ok((values, shape))
+}
+
+/// Encode a value as a C-order .npy file image
+fn npy_bytes(value: &Value, dtype: NpyDtype, name: &str) -> Result<Vec<u8>, String> {
+    let (data, shape): (&[f64], &[usize]) = match value {
+        Value::Scalar(s) => (std::slice::from_ref(s), &[]),
+        Value::Tensor(t) => (&t.data, &t.shape),
+    };
+    let dims: Vec<String> = shape.iter().map(|d| d.to_string()).collect();
+    let shape = match dims.len() {
+        1 => format!("({},)", dims[0]),
+        _ => format!("({})", dims.join(", ")),
+    };
+    let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", dtype.descr(), shape);
+    // Pad with spaces so the data starts 64-byte aligned; the header ends in a newline
+    while !(10 + header.len() + 1).is_multiple_of(64) {
+        header.push(' ');
+    }
+    header.push('\n');
+    let header_len = u16::try_from(header.len()).map_err(|_| format!("Shape of '{}' is too long for a .npy header", name))?;
+
+    let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+    bytes.extend_from_slice(&header_len.to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    bytes.extend(dtype.encode(data, name)?);
+    Ok(bytes)
+}
+
+/// Load a .npy file as (data, shape) in row-major order
+pub fn load_npy_file(path: &str) -> Result<(Vec<f64>, Vec<usize>), String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Cannot open .npy file '{}': {}", path, e))?;
+    parse_npy(&bytes, path)
+}
+
+/// Save a value as a .npy file in `dtype`
+pub fn save_npy_file(value: &Value, path: &str, dtype: NpyDtype) -> Result<(), String> {
+    let bytes = npy_bytes(value, dtype, path)?;
+    std::fs::write(path, bytes).map_err(|e| format!("Cannot create .npy file '{}': {}", path, e))
+}
+
+/// Load every array of a .npz archive as (name, (data, shape)), in archive order
+pub fn load_npz_file(path: &str) -> Result<NamedArrays, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Cannot open .npz file '{}': {}", path, e))?;
+    read_stored_zip(&bytes, path)?.into_iter()
+        .map(|(name, data)| {
+            let array = parse_npy(data, &format!("{}:{}", path, name))?;
+            Ok((name.trim_end_matches(".npy").to_string(), array))
+        })
+        .collect()
+}
+
+/// Load the array called `name` from a .npz archive, decoding no other array
+pub fn load_npz_array(path: &str, name: &str) -> Result<(Vec<f64>, Vec<usize>), String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Cannot open .npz file '{}': {}", path, e))?;
+    let entries = read_stored_zip(&bytes, path)?;
+    let (entry, data) = entries.iter()
+        .find(|(entry, _)| entry.trim_end_matches(".npy") == name)
+        .ok_or_else(|| {
+            let names: Vec<&str> = entries.iter().map(|(n, _)| n.trim_end_matches(".npy")).collect();
+            format!("No array '{}' in .npz file '{}' (it has: {})", name, path, names.join(", "))
+        })?;
+    parse_npy(data, &format!("{}:{}", path, entry))
+}
+
+/// Variables bound by `load_npz name = "path";`: `name.<array>` for every array, plus `name`
+/// itself when the archive holds a single array
+pub fn npz_bindings(name: &str, path: &str) -> Result<Vec<(String, Tensor)>, String> {
+    let arrays = load_npz_file(path)?;
+    if arrays.is_empty() {
+        return Err(format!("No arrays found in .npz file: {}", path));
+    }
+    let mut bindings: Vec<_> = arrays.into_iter()
+        .map(|(array, (data, shape))| (format!("{}.{}", name, array), Tensor { data, shape }))
+        .collect();
+    if let [(_, only)] = &bindings[..] {
+        bindings.push((name.to_string(), only.clone()));
+    }
+    Ok(bindings)
+}
+
+/// Save arrays as an uncompressed .npz archive, like `np.savez`
+pub fn save_npz_file(arrays: &[(String, Value)], path: &str, dtype: NpyDtype) -> Result<(), String> {
+    let mut entries = Vec::new();
+    for (name, value) in arrays {
+        if entries.iter().any(|(n, _): &(String, Vec<u8>)| *n == format!("{}.npy", name)) {
+            return Err(format!("Array '{}' is given more than once for .npz file '{}'", name, path));
+        }
+        entries.push((format!("{}.npy", name), npy_bytes(value, dtype, name)?));
+    }
+    let bytes = write_stored_zip(&entries).map_err(|e| format!("Cannot write .npz file '{}': {}", path, e))?;
+    std::fs::write(path, bytes).map_err(|e| format!("Cannot create .npz file '{}': {}", path, e))
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+/// Entries of a zip archive as (name, data). Only stored (uncompressed) entries are supported,
+/// which is what `np.savez` writes; Zip64 records are understood.
+fn read_stored_zip<'a>(bytes: &'a [u8], path: &str) -> Result<Vec<(String, &'a [u8])>, String> {
+    // Offsets and sizes come from the file, so every slice is bounds-checked without overflow
+    let invalid = |what: &str| format!("Invalid .npz file '{}': {}", path, what);
+    let span = |at: usize, len: usize| at.checked_add(len).and_then(|end| bytes.get(at..end));
+    let position = |value: u64| usize::try_from(value).map_err(|_| invalid("offset out of range"));
+    let field = |at: usize, len: usize| -> Result<u64, String> {
+        let b = span(at, len).ok_or_else(|| invalid("truncated zip record"))?;
+        Ok(b.iter().rev().fold(0u64, |acc, &x| acc << 8 | x as u64))
+    };
+    // A matching signature also proves that `at` lies inside the file, so fixed offsets from it
+    // cannot overflow
+    let signature = |at: usize, sig: u32| field(at, 4).is_ok_and(|s| s == sig as u64);
+
+    // End of central directory record, possibly followed by a comment
+    let eocd = (0..bytes.len().saturating_sub(21)).rev()
+        .take(0x10000 + 22)
+        .find(|&at| signature(at, 0x0605_4b50))
+        .ok_or_else(|| invalid("not a zip archive"))?;
+    let (mut count, mut directory) = (field(eocd + 10, 2)?, field(eocd + 16, 4)?);
+    if (count == 0xFFFF || directory == 0xFFFF_FFFF) && eocd >= 20 && signature(eocd - 20, 0x0706_4b50) {
+        let zip64 = position(field(eocd - 20 + 8, 8)?)?;
+        if !signature(zip64, 0x0606_4b50) {
+            return Err(invalid("bad Zip64 end of central directory"));
+        }
+        count = field(zip64 + 32, 8)?;
+        directory = field(zip64 + 48, 8)?;
+    }
+
+    let mut entries = Vec::new();
+    let mut at = position(directory)?;
+    for _ in 0..count {
+        if !signature(at, 0x0201_4b50) {
+            return Err(invalid("bad central directory entry"));
+        }
+        let method = field(at + 10, 2)?;
+        let crc = field(at + 16, 4)? as u32;
+        let (name_len, extra_len, comment_len) = (field(at + 28, 2)? as usize, field(at + 30, 2)? as usize, field(at + 32, 2)? as usize);
+        let name = span(at + 46, name_len).ok_or_else(|| invalid("truncated entry name"))?;
+        let name = String::from_utf8_lossy(name).to_string();
+
+        // Sizes and offset saturated at 0xFFFFFFFF are stored in the Zip64 extra field, in this order
+        let mut values = [field(at + 24, 4)?, field(at + 20, 4)?, field(at + 42, 4)?];
+        let mut extra = at + 46 + name_len;
+        let extra_end = extra + extra_len;
+        while extra + 4 <= extra_end {
+            let (id, len) = (field(extra, 2)?, field(extra + 2, 2)? as usize);
+            if id == 0x0001 {
+                let mut pos = extra + 4;
+                for value in values.iter_mut().filter(|v| **v == 0xFFFF_FFFF) {
+                    *value = field(pos, 8)?;
+                    pos += 8;
+                }
+            }
+            extra += 4 + len;
+        }
+        let [size, compressed, offset] = values;
+        if method != 0 {
+            return Err(format!(
+                "Entry '{}' of '{}' is compressed (np.savez_compressed); only uncompressed .npz files (np.savez) are supported",
+                name, path
+            ));
+        }
+        if compressed != size {
+            return Err(invalid(&format!("stored entry '{}' has mismatched sizes", name)));
+        }
+
+        let local = position(offset)?;
+        if !signature(local, 0x0403_4b50) {
+            return Err(invalid(&format!("bad local header for '{}'", name)));
+        }
+        let start = local + 30 + field(local + 26, 2)? as usize + field(local + 28, 2)? as usize;
+        let data = span(start, position(size)?).ok_or_else(|| invalid(&format!("truncated entry '{}'", name)))?;
+        if crc32(data) != crc {
+            return Err(invalid(&format!("CRC mismatch in entry '{}'", name)));
+        }
+        entries.push((name, data));
+        at = extra_end + comment_len;
+    }
+    Ok(entries)
+}
+
+/// A zip archive of stored (uncompressed) entries
+fn write_stored_zip(entries: &[(String, Vec<u8>)]) -> Result<Vec<u8>, String> {
+    let too_large = || "archive larger than 4 GiB".to_string();
+    let u32_of = |n: usize| u32::try_from(n).map_err(|_| too_large());
+    let mut out = Vec::new();
+    let mut directory = Vec::new();
+    for (name, data) in entries {
+        let offset = u32_of(out.len())?;
+        let (crc, size) = (crc32(data), u32_of(data.len())?);
+        // version 20, no flags, stored, DOS date 1980-01-01
+        let common = |record: &mut Vec<u8>| {
+            record.extend_from_slice(&20u16.to_le_bytes());
+            record.extend_from_slice(&0u16.to_le_bytes());
+            record.extend_from_slice(&0u16.to_le_bytes());
+            record.extend_from_slice(&0u16.to_le_bytes());
+            record.extend_from_slice(&0x21u16.to_le_bytes());
+            record.extend_from_slice(&crc.to_le_bytes());
+            record.extend_from_slice(&size.to_le_bytes());
+            record.extend_from_slice(&size.to_le_bytes());
+            record.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            record.extend_from_slice(&0u16.to_le_bytes());
+        };
+        out.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        common(&mut out);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(data);
+
+        directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&20u16.to_le_bytes());
+        common(&mut directory);
+        directory.extend_from_slice(&[0; 6]); // comment length, disk, internal attributes
+        directory.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+    }
+    let directory_offset = u32_of(out.len())?;
+    let count = u16::try_from(entries.len()).map_err(|_| "more than 65535 arrays".to_string())?;
+    out.extend_from_slice(&directory);
+    out.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    out.extend_from_slice(&[0; 4]); // disk numbers
+    out.extend_from_slice(&count.to_le_bytes());
+    out.extend_from_slice(&count.to_le_bytes());
+    out.extend_from_slice(&u32_of(directory.len())?.to_le_bytes());
+    out.extend_from_slice(&directory_offset.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    Ok(out)
+}
+
+// ============================================================================
+// Streaming Datasets
+// ============================================================================
+
+/// File formats a dataset can stream rows from, chosen by extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DatasetFormat {
+    Csv,
+    Npy,
+    Safetensors,
+}
+
+impl DatasetFormat {
+    fn of(path: &str) -> Self {
+        let lower = path.to_ascii_lowercase();
+        if lower.ends_with(".npy") {
+            DatasetFormat::Npy
+        } else if lower.ends_with(".safetensors") {
+            DatasetFormat::Safetensors
+        } else {
+            DatasetFormat::Csv
+        }
+    }
+}
+
+/// Where the rows of a dataset are read from
+enum DatasetSource {
+    /// Line number and byte offset of every data record; records are parsed when their rows are read
+    Csv { layout: CsvLayout, delimiter: char, na: Option<f64>, offsets: Vec<(usize, u64)> },
+    /// A memory-mapped C-order .npy file
+    Npy { mmap: memmap2::Mmap, dtype: NpyDtype, data_start: usize },
+    /// One tensor of a memory-mapped safetensors file
+    Safetensors { file: SafetensorsFile, entry: SafetensorsEntry },
+}
+
+/// Order in which a batch loop visits rows, epoch by epoch
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchSchedule {
+    pub rows: usize,
+    /// Seed of the per-epoch shuffle; `None` keeps file order
+    pub shuffle: Option<u64>,
+    pub drop_last: bool,
+    pub epochs: usize,
+}
+
+impl BatchSchedule {
+    /// One pass over `rows` rows in order
+    pub fn new(rows: usize) -> Self {
+        BatchSchedule { rows, shuffle: None, drop_last: false, epochs: 1 }
+    }
+
+    /// Row indices in the order `epoch` visits them: file order, or a permutation drawn from
+    /// the shuffle seed and the epoch number
+    pub fn order(&self, epoch: usize) -> Vec<usize> {
+        use rand::seq::SliceRandom;
+        let mut order: Vec<usize> = (0..self.rows).collect();
+        if let Some(seed) = self.shuffle {
+            let mut rng = ChaCha12Rng::seed_from_u64(seed);
+            rng.set_stream(epoch as u64);
+            order.shuffle(&mut rng);
+        }
+        order
+    }
+
+    /// Row indices of every batch of `epoch`, without the short last batch if `drop_last`
+    pub fn batches(&self, epoch: usize, batch_size: usize) -> Vec<Vec<usize>> {
+        self.order(epoch)
+            .chunks(batch_size)
+            .filter(|batch| !self.drop_last || batch.len() == batch_size)
+            .map(|batch| batch.to_vec())
+            .collect()
+    }
+}
+
+/// Rows of a file, read batch by batch instead of being loaded as one tensor. Opening a
+/// dataset only indexes its rows; `read_rows` decodes the requested rows on demand.
+pub struct Dataset {
+    path: String,
+    source: DatasetSource,
+    /// Shape of one row; empty for a single CSV column or a 1D array
+    row_shape: Vec<usize>,
+    schedule: BatchSchedule,
+}
+
+impl Dataset {
+    /// Reject options that do not apply to the file format of `path`
+    pub fn check_options(path: &str, options: &DatasetOptions) -> Result<(), String> {
+        let format = DatasetFormat::of(path);
+        if !options.csv.labels.is_empty() {
+            return Err("A dataset streams one tensor; declare a second dataset with columns=[...] for the labels \
+                        and batch both: batch (x, y) in (X, Y)".into());
+        }
+        if format != DatasetFormat::Csv && options.csv != CsvOptions::default() {
+            return Err("header, delimiter, columns and na apply only to CSV datasets".into());
+        }
+        if format != DatasetFormat::Safetensors && options.tensor.is_some() {
+            return Err("tensor=\"...\" applies only to .safetensors datasets".into());
+        }
+        Ok(())
+    }
+
+    /// Index the rows of `path`; `seed` drives the shuffle of every epoch
+    pub fn open(path: &str, options: &DatasetOptions, seed: u64) -> Result<Self, String> {
+        Self::check_options(path, options)?;
+        let (source, shape) = match DatasetFormat::of(path) {
+            DatasetFormat::Csv => Self::index_csv(path, &options.csv)?,
+            DatasetFormat::Npy => {
+                let file = std::fs::File::open(path).map_err(|e| format!("Cannot open .npy file '{}': {}", path, e))?;
+                // SAFETY: read-only mapping, as for safetensors files
+                let mmap = unsafe { memmap2::Mmap::map(&file) }.map_err(|e| format!("Cannot map .npy file '{}': {}", path, e))?;
+                let (dtype, fortran_order, shape, data_start) = npy_layout(&mmap, path)?;
+                if fortran_order && shape.len() > 1 {
+                    return Err(format!("Cannot stream rows of Fortran-order array '{}'; save it in C order", path));
+                }
+                (DatasetSource::Npy { mmap, dtype, data_start }, shape)
+            }
+            DatasetFormat::Safetensors => {
+                let file = SafetensorsFile::open(path)?;
+                let entry = match &options.tensor {
+                    Some(name) => file.entry(name).cloned(),
+                    None if file.entries().len() == 1 => Some(file.entries()[0].clone()),
+                    None => None,
+                };
+                let entry = entry.ok_or_else(|| {
+                    let mut names: Vec<&str> = file.entries().iter().map(|t| t.name.as_str()).collect();
+                    names.sort();
+                    format!("Pick the tensor of '{}' to stream with tensor=\"...\" (it has: {})", path, names.join(", "))
+                })?;
+                let shape = entry.shape.clone();
+                (DatasetSource::Safetensors { file, entry }, shape)
+            }
+        };
+        let (&rows, row_shape) = shape.split_first().ok_or_else(|| format!("Dataset '{}' is a scalar, which has no rows", path))?;
+        if rows == 0 {
+            return Err(format!("Dataset '{}' has no rows", path));
+        }
+        Ok(Dataset {
+            path: path.to_string(),
+            source,
+            row_shape: row_shape.to_vec(),
+            schedule: BatchSchedule {
+                rows,
+                shuffle: options.shuffle.then_some(seed),
+                drop_last: options.drop_last,
+                epochs: options.epochs,
+            },
+        })
+    }
+
+    /// Scan a CSV file once, checking every record and keeping only its byte offset
+    fn index_csv(path: &str, options: &CsvOptions) -> Result<(DatasetSource, Vec<usize>), String> {
+        let file = std::fs::File::open(path).map_err(|e| format!("Cannot open CSV file '{}': {}", path, e))?;
+        let mut reader = CsvRecords::new(std::io::BufReader::new(file), options.delimiter);
+        let names = if options.header {
+            reader.next_record()?.map(|(_, _, names)| names).unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        let (mut layout, mut offsets, mut row) = (None, Vec::new(), Vec::new());
+        while let Some((line, offset, fields)) = reader.next_record()? {
+            if layout.is_none() {
+                layout = Some(CsvLayout::resolve(path, options, names.clone(), &fields)?);
+            }
+            let layout = layout.as_ref().expect("resolved on the first record");
+            layout.check_width(line, &fields)?;
+            row.clear();
+            layout.parse(&layout.columns, line, &fields, options.na, &mut row)?;
+            offsets.push((line, offset));
+        }
+        let layout = layout.ok_or_else(|| format!("CSV file '{}' is empty or contains no data", path))?;
+        let shape = csv_shape(offsets.len(), layout.columns.len());
+        Ok((DatasetSource::Csv { layout, delimiter: options.delimiter, na: options.na, offsets }, shape))
+    }
+
+    pub fn rows(&self) -> usize {
+        self.schedule.rows
+    }
+
+    pub fn row_shape(&self) -> &[usize] {
+        &self.row_shape
+    }
+
+    /// Shuffle, drop-last and epochs declared with the dataset
+    pub fn schedule(&self) -> &BatchSchedule {
+        &self.schedule
+    }
+
+    /// Decode the given rows, in order, as a [rows.len(), row_shape...] tensor
+    pub fn read_rows(&self, rows: &[usize]) -> Result<Tensor, String> {
+        if let Some(row) = rows.iter().find(|&&row| row >= self.rows()) {
+            return Err(format!("Row {} is out of range; dataset '{}' has {} rows", row, self.path, self.rows()));
+        }
+        let width: usize = self.row_shape.iter().product();
+        let mut data = Vec::with_capacity(rows.len() * width);
+        match &self.source {
+            DatasetSource::Csv { layout, delimiter, na, offsets } => {
+                use std::io::Seek;
+                let file = std::fs::File::open(&self.path).map_err(|e| format!("Cannot open CSV file '{}': {}", self.path, e))?;
+                let mut reader = CsvRecords::new(std::io::BufReader::new(file), *delimiter);
+                let mut position = None;
+                for &row in rows {
+                    // Consecutive rows are read in one pass; any other row needs a seek
+                    if position != Some(row) {
+                        let (line, offset) = offsets[row];
+                        reader.reader.seek(std::io::SeekFrom::Start(offset))
+                            .map_err(|e| format!("Cannot seek in CSV file '{}': {}", self.path, e))?;
+                        (reader.line, reader.offset) = (line, offset);
+                    }
+                    let (line, _, fields) = reader.next_record()?
+                        .ok_or_else(|| format!("CSV file '{}' changed while it was being read", self.path))?;
+                    layout.parse(&layout.columns, line, &fields, *na, &mut data)?;
+                    position = Some(row + 1);
+                }
+            }
+            DatasetSource::Npy { mmap, dtype, data_start } => {
+                let row_bytes = width * dtype.element.size();
+                for &row in rows {
+                    let start = data_start + row * row_bytes;
+                    data.extend(dtype.decode(&mmap[start..start + row_bytes]));
+                }
+            }
+            DatasetSource::Safetensors { file, entry } => {
+                let row_bytes = width * entry.dtype.size();
+                let bytes = file.bytes(entry);
+                for &row in rows {
+                    data.extend(entry.dtype.decode(&bytes[row * row_bytes..(row + 1) * row_bytes]));
+                }
+            }
+        }
+        let mut shape = vec![rows.len()];
+        shape.extend(&self.row_shape);
+        Ok(Tensor { data, shape })
+    }
+}
+
+/// Rows a batch loop iterates over: a tensor evaluated before the loop, or a dataset
+/// streamed from its file
+pub enum BatchSource {
+    Tensor(Tensor),
+    Dataset(std::rc::Rc<Dataset>),
+}
+
+impl BatchSource {
+    /// A tensor source; a scalar is a single row
+    pub fn from_value(value: Value) -> Self {
+        match value {
+            Value::Tensor(t) => BatchSource::Tensor(t),
+            Value::Scalar(s) => BatchSource::Tensor(Tensor { data: vec![s], shape: vec![1] }),
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        match self {
+            BatchSource::Tensor(t) => t.shape.first().copied().unwrap_or(1),
+            BatchSource::Dataset(d) => d.rows(),
         }
-        
-        let end_offset = tensor_data.len();
-        
-        let shape_str: Vec<String> = shape.iter().map(|s| s.to_string()).collect();
-        header_parts.push(format!(
-            "\"{}\":{{\"dtype\":\"F64\",\"shape\":[{}],\"data_offsets\":[{},{}]}}",
-            name,
-            shape_str.join(","),
-            start_offset,
-            end_offset
-        ));
     }
-    
-    let header_json = format!("{{{}}}", header_parts.join(","));
-    let header_bytes = header_json.as_bytes();
-    let header_size = header_bytes.len() as u64;
-    
-    let mut file = File::create(path).map_err(|e| format!("Cannot create safetensors file '{}': {}", path, e))?;
-    
-    // Write header size (8 bytes, little endian)
-    file.write_all(&header_size.to_le_bytes()).map_err(|e| format!("Error writing safetensors: {}", e))?;
-    
-    // Write header JSON
-    file.write_all(header_bytes).map_err(|e| format!("Error writing safetensors: {}", e))?;
-    
-    // Write tensor data
-    file.write_all(&tensor_data).map_err(|e| format!("Error writing safetensors: {}", e))?;
-    
-    Ok(())
+
+    /// The given rows, in order, as a [rows.len(), row shape...] tensor
+    pub fn read_rows(&self, rows: &[usize]) -> Result<Tensor, String> {
+        match self {
+            BatchSource::Tensor(t) => {
+                let row_shape = t.shape.get(1..).unwrap_or(&[]);
+                let width: usize = row_shape.iter().product();
+                let mut data = Vec::with_capacity(rows.len() * width);
+                for &row in rows {
+                    data.extend_from_slice(&t.data[row * width..(row + 1) * width]);
+                }
+                let mut shape = vec![rows.len()];
+                shape.extend(row_shape);
+                Ok(Tensor { data, shape })
+            }
+            BatchSource::Dataset(d) => d.read_rows(rows),
+        }
+    }
+
+    /// Schedule of a loop over `sources`, which must have the same number of rows: the
+    /// settings of the first dataset among them, overridden by those of the loop header
+    pub fn schedule(sources: &[BatchSource], shuffle: Option<u64>, drop_last: bool, epochs: Option<usize>) -> Result<BatchSchedule, String> {
+        let rows = sources.first().map_or(0, BatchSource::rows);
+        if let Some(other) = sources.iter().map(BatchSource::rows).find(|&r| r != rows) {
+            return Err(format!("Batch loop sources have different numbers of rows: {} and {}", rows, other));
+        }
+        let mut schedule = sources.iter()
+            .find_map(|s| match s {
+                BatchSource::Dataset(d) => Some(d.schedule().clone()),
+                BatchSource::Tensor(_) => None,
+            })
+            .unwrap_or_else(|| BatchSchedule::new(rows));
+        schedule.shuffle = shuffle.or(schedule.shuffle);
+        schedule.drop_last |= drop_last;
+        schedule.epochs = epochs.unwrap_or(schedule.epochs);
+        Ok(schedule)
+    }
 }
 
 #[cfg(test)]
@@ -2954,4 +7523,1294 @@ mod tests {
             _ => panic!("expected scalar gradient"),
         }
     }
+
+    #[test]
+    fn test_shape_ops_forward() {
+        let mut graph = ComputationalGraph::new();
+        let t = graph.add_constant_tensor(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], vec![2, 3]).unwrap();
+        let three = graph.add_constant(3.0);
+        let neg_one = graph.add_constant(-1.0);
+        let one = graph.add_constant(1.0);
+        let r = graph.add_function_call("reshape".to_string(), vec![t, three, neg_one]);
+        let tr = graph.add_function_call("transpose".to_string(), vec![t]);
+        let c = graph.add_function_call("concat".to_string(), vec![t, t, one]);
+        let s = graph.add_function_call("slice".to_string(), vec![t, one, one]);
+
+        graph.forward_pass().unwrap();
+        let value = |id: NodeId| graph.get_node(id).and_then(|n| n.value.clone()).and_then(|v| v.as_tensor().cloned()).unwrap();
+        assert_eq!(value(r).shape, vec![3, 2]);
+        assert_eq!(value(tr).data, vec![1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+        assert_eq!(value(c).shape, vec![2, 6]);
+        assert_eq!(value(c).data[..6], [1.0, 2.0, 3.0, 1.0, 2.0, 3.0]);
+        assert_eq!(value(s).data, vec![2.0, 3.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn test_shape_ops_backward() {
+        // loss = sum(slice(concat(w, w), 0, 1, 3) * [1..6]) routes gradient back through both copies of w
+        let mut graph = ComputationalGraph::new();
+        let w = graph.add_learnable_tensor("w".to_string(), vec![1.0, 2.0, 3.0, 4.0], vec![2, 2]).unwrap();
+        let zero = graph.add_constant(0.0);
+        let one = graph.add_constant(1.0);
+        let three = graph.add_constant(3.0);
+        let c = graph.add_function_call("concat".to_string(), vec![w, w, zero]);
+        let s = graph.add_function_call("slice".to_string(), vec![c, zero, one, three]);
+        let k = graph.add_constant_tensor(vec![1.0, 2.0, 3.0, 4.0], vec![2, 2]).unwrap();
+        let m = graph.add_binary_op("mul", s, k);
+        let loss = graph.add_function_call("sum".to_string(), vec![m]);
+
+        graph.forward_pass().unwrap();
+        graph.backward_pass(loss).unwrap();
+        // rows 1 and 2 of [w; w] are w[1] and w[0]
+        if let Some(Value::Tensor(g)) = graph.get_node(w).and_then(|n| n.gradient.clone()) {
+            assert_eq!(g.data, vec![3.0, 4.0, 1.0, 2.0]);
+        } else {
+            panic!("expected tensor gradient");
+        }
+    }
+
+    #[test]
+    fn test_select_transpose_backward() {
+        let mut graph = ComputationalGraph::new();
+        let w = graph.add_learnable_tensor("w".to_string(), vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], vec![2, 3]).unwrap();
+        let t = graph.add_function_call("transpose".to_string(), vec![w]);
+        let axis = graph.add_constant(1.0);
+        let idx = graph.add_constant(-1.0);
+        let col = graph.add_function_call("select".to_string(), vec![t, axis, idx]);
+        let loss = graph.add_function_call("sum".to_string(), vec![col]);
+
+        graph.forward_pass().unwrap();
+        assert_eq!(graph.get_node(loss).and_then(|n| n.value.clone()), Some(Value::Scalar(15.0)));
+        graph.backward_pass(loss).unwrap();
+        if let Some(Value::Tensor(g)) = graph.get_node(w).and_then(|n| n.gradient.clone()) {
+            assert_eq!(g.data, vec![0.0, 0.0, 0.0, 1.0, 1.0, 1.0]);
+        } else {
+            panic!("expected tensor gradient");
+        }
+    }
+
+    #[test]
+    fn test_tensor_comparison_mask() {
+        let mut graph = ComputationalGraph::new();
+        let t = graph.add_constant_tensor(vec![-1.0, 0.0, 2.0], vec![3]).unwrap();
+        let zero = graph.add_constant(0.0);
+        let gt = graph.add_binary_op("gt", t, zero);
+        let eq = graph.add_binary_op("eq", t, zero);
+        let not = graph.add_unary_op("not", gt);
+
+        graph.forward_pass().unwrap();
+        let value = |id: NodeId| graph.get_node(id).and_then(|n| n.value.clone()).and_then(|v| v.as_tensor().cloned()).unwrap();
+        assert_eq!(value(gt).data, vec![0.0, 0.0, 1.0]);
+        assert_eq!(value(eq).data, vec![0.0, 1.0, 0.0]);
+        assert_eq!(value(not).data, vec![1.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn test_where_and_clip_backward() {
+        // loss = sum(where(x > 0, x * 3, clip(x, -0.5, 0.5)))
+        let mut graph = ComputationalGraph::new();
+        let x = graph.add_learnable_tensor("x".to_string(), vec![-1.0, -0.2, 0.3, 2.0], vec![4]).unwrap();
+        let zero = graph.add_constant(0.0);
+        let three = graph.add_constant(3.0);
+        let lo = graph.add_constant(-0.5);
+        let hi = graph.add_constant(0.5);
+        let mask = graph.add_binary_op("gt", x, zero);
+        let scaled = graph.add_binary_op("mul", x, three);
+        let clipped = graph.add_function_call("clip".to_string(), vec![x, lo, hi]);
+        let w = graph.add_function_call("where".to_string(), vec![mask, scaled, clipped]);
+        let loss = graph.add_function_call("sum".to_string(), vec![w]);
+
+        graph.forward_pass().unwrap();
+        assert_eq!(graph.get_node(loss).and_then(|n| n.value.clone()), Some(Value::Scalar(-0.5 - 0.2 + 0.9 + 6.0)));
+        graph.backward_pass(loss).unwrap();
+        if let Some(Value::Tensor(g)) = graph.get_node(x).and_then(|n| n.gradient.clone()) {
+            assert_eq!(g.data, vec![0.0, 1.0, 3.0, 3.0]);
+        } else {
+            panic!("expected tensor gradient");
+        }
+    }
+
+    #[test]
+    fn test_maximum_subgradient() {
+        let mut graph = ComputationalGraph::new();
+        let a = graph.add_learnable_tensor("a".to_string(), vec![1.0, 2.0, 3.0], vec![3]).unwrap();
+        let b = graph.add_learnable("b".to_string(), 2.0);
+        let m = graph.add_function_call("maximum".to_string(), vec![a, b]);
+        let loss = graph.add_function_call("sum".to_string(), vec![m]);
+
+        graph.forward_pass().unwrap();
+        assert_eq!(graph.get_node(loss).and_then(|n| n.value.clone()), Some(Value::Scalar(7.0)));
+        graph.backward_pass(loss).unwrap();
+        if let Some(Value::Tensor(g)) = graph.get_node(a).and_then(|n| n.gradient.clone()) {
+            assert_eq!(g.data, vec![0.0, 0.5, 1.0]);
+        } else {
+            panic!("expected tensor gradient");
+        }
+        assert_eq!(graph.get_node(b).and_then(|n| n.gradient.clone()), Some(Value::Scalar(1.5)));
+    }
+
+    #[test]
+    fn test_nn_ops_forward() {
+        let logits = Value::Tensor(Tensor { data: vec![1000.0, 1001.0, 0.0, 0.0], shape: vec![2, 2] });
+        let probs = eval_nn_op("softmax", &[logits.clone()]).unwrap();
+        let p = probs.as_tensor().unwrap();
+        assert!(p.data.iter().all(|v| v.is_finite()));
+        assert!((p.data[0] + p.data[1] - 1.0).abs() < 1e-12);
+        assert!((p.data[2] - 0.5).abs() < 1e-12);
+
+        // Class-index and one-hot labels give the same loss
+        let idx = Value::Tensor(Tensor { data: vec![1.0, 0.0], shape: vec![2] });
+        let onehot = Value::Tensor(Tensor { data: vec![0.0, 1.0, 1.0, 0.0], shape: vec![2, 2] });
+        let a = eval_nn_op("cross_entropy", &[logits.clone(), idx]).unwrap().as_scalar().unwrap();
+        let b = eval_nn_op("cross_entropy", &[logits, onehot]).unwrap().as_scalar().unwrap();
+        assert!((a - b).abs() < 1e-12);
+        assert!((a - 0.5 * ((1.0 + (-1.0f64).exp()).ln() + 2f64.ln())).abs() < 1e-9);
+
+        let sp = eval_nn_op("softplus", &[Value::Scalar(800.0)]).unwrap().as_scalar().unwrap();
+        assert_eq!(sp, 800.0);
+        let pred = Value::Tensor(Tensor { data: vec![0.0, 3.0], shape: vec![2] });
+        let huber = eval_nn_op("huber", &[pred, Value::Scalar(0.5)]).unwrap().as_scalar().unwrap();
+        assert!((huber - (0.125 + 2.0) / 2.0).abs() < 1e-12);
+        assert!(eval_nn_op("gelu", &[Value::Scalar(1.0), Value::Scalar(2.0)]).is_err());
+    }
+
+    #[test]
+    fn test_nn_ops_finite_difference() {
+        let x = Tensor { data: vec![0.3, -1.2, 2.0, 0.7, -0.4, 1.1], shape: vec![2, 3] };
+        let target = Tensor { data: vec![0.2, 0.5, 0.3, 0.9, 0.05, 0.05], shape: vec![2, 3] };
+        let probs = Tensor { data: vec![0.2, 0.6, 0.9, 0.4, 0.3, 0.7], shape: vec![2, 3] };
+        let weights = Tensor { data: vec![0.5, -1.0, 2.0, 1.5, 0.25, -0.75], shape: vec![2, 3] };
+        let cases: Vec<(&str, Vec<Value>)> = vec![
+            ("leaky_relu", vec![Value::Tensor(x.clone()), Value::Scalar(0.1)]),
+            ("elu", vec![Value::Tensor(x.clone())]),
+            ("gelu", vec![Value::Tensor(x.clone())]),
+            ("softplus", vec![Value::Tensor(x.clone())]),
+            ("swish", vec![Value::Tensor(x.clone())]),
+            ("softmax", vec![Value::Tensor(x.clone())]),
+            ("log_softmax", vec![Value::Tensor(x.clone()), Value::Scalar(0.0)]),
+            ("cross_entropy", vec![Value::Tensor(x.clone()), Value::Tensor(target.clone())]),
+            ("binary_cross_entropy", vec![Value::Tensor(probs), Value::Tensor(target.clone())]),
+            ("mse", vec![Value::Tensor(x.clone()), Value::Tensor(target.clone())]),
+            ("huber", vec![Value::Tensor(x.clone()), Value::Tensor(target), Value::Scalar(0.5)]),
+        ];
+        // Project outputs onto fixed weights so every op reduces to a scalar objective
+        let objective = |name: &str, args: &[Value]| -> f64 {
+            match eval_nn_op(name, args).unwrap() {
+                Value::Scalar(s) => s,
+                Value::Tensor(t) => t.data.iter().zip(weights.data.iter()).map(|(a, w)| a * w).sum(),
+            }
+        };
+        for (name, args) in cases {
+            let upstream = match eval_nn_op(name, &args).unwrap() {
+                Value::Scalar(_) => Value::Scalar(1.0),
+                Value::Tensor(_) => Value::Tensor(weights.clone()),
+            };
+            let grads = nn_op_grads(name, &args, &upstream).unwrap();
+            let Some(Value::Tensor(g)) = &grads[0] else { panic!("{}: expected tensor gradient", name) };
+            for i in 0..x.data.len() {
+                let eps = 1e-6;
+                let mut plus = args.clone();
+                let mut minus = args.clone();
+                if let Value::Tensor(t) = &mut plus[0] { t.data[i] += eps; }
+                if let Value::Tensor(t) = &mut minus[0] { t.data[i] -= eps; }
+                let numeric = (objective(name, &plus) - objective(name, &minus)) / (2.0 * eps);
+                assert!((numeric - g.data[i]).abs() < 1e-5, "{}: d/dx[{}] analytic {} vs numeric {}", name, i, g.data[i], numeric);
+            }
+        }
+    }
+
+    fn seq_tensor(shape: Vec<usize>, scale: f64) -> Tensor {
+        let size = shape.iter().product::<usize>();
+        // Distinct, non-monotone values so max pooling has no ties
+        let data = (0..size).map(|i| ((i * 7 % 11) as f64 - 5.0) * scale + i as f64 * 0.013).collect();
+        Tensor { data, shape }
+    }
+
+    #[test]
+    fn test_index_after_slice() {
+        let tokens = crate::lexer::Lexer::new("fn main() { return a[1:3][0] + a[1:3, 0]; }").tokenize().unwrap();
+        let program = crate::parser::Parser::new(tokens).parse().unwrap();
+        let crate::ast::Item::Function(main) = &program.items[0] else { panic!("expected main") };
+        let Statement::Return(Some(expr)) = &main.body[0] else { panic!("expected return") };
+        let mut graph = ComputationalGraph::new();
+        let a = graph.add_constant_tensor(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], vec![3, 2]).unwrap();
+        let out = graph.build_from_expression(expr, &HashMap::from([("a".to_string(), a)])).unwrap();
+        graph.forward_pass().unwrap();
+        // Row 0 of rows 1..3 is [3, 4]; column 0 of rows 1..3 is [3, 5]
+        assert_eq!(graph.get_node(out).unwrap().value, Some(Value::Tensor(Tensor { data: vec![6.0, 9.0], shape: vec![2] })));
+    }
+
+    #[test]
+    fn test_objective_closure_reevaluates_graph() {
+        // loss = sum(w * w) + 3 * s, with w = [1, -2] and s = 0.5
+        let mut graph = ComputationalGraph::new();
+        let w = graph.add_learnable_tensor("w".to_string(), vec![1.0, -2.0], vec![2]).unwrap();
+        let s = graph.add_learnable("s".to_string(), 0.5);
+        let sq = graph.add_binary_op("mul", w, w);
+        let sum = graph.add_function_call("sum".to_string(), vec![sq]);
+        let three = graph.add_constant(3.0);
+        let scaled = graph.add_binary_op("mul", three, s);
+        let loss = graph.add_binary_op("add", sum, scaled);
+
+        let ids = graph.learnable_ids();
+        assert_eq!(ids, vec![w, s]);
+        assert_eq!(graph.flat_params(&ids), vec![1.0, -2.0, 0.5]);
+
+        {
+            let mut f = graph.objective_closure(loss, &ids);
+            assert_eq!(f(&[1.0, -2.0, 0.5]).unwrap(), (6.5, vec![2.0, -4.0, 3.0]));
+            // The Hessian is diag(2, 2, 0); a zero direction gives zero, not NaN
+            let hv = hessian_vector_product(&mut f, &[1.0, -2.0, 0.5], &[0.0, 1.0, 1.0]).unwrap();
+            assert!(hv[0].abs() < 1e-6 && (hv[1] - 2.0).abs() < 1e-6 && hv[2].abs() < 1e-6, "{:?}", hv);
+            assert_eq!(hessian_vector_product(&mut f, &[1.0, -2.0, 0.5], &[0.0; 3]).unwrap(), vec![0.0; 3]);
+            // Gradients do not accumulate across evaluations
+            assert_eq!(f(&[3.0, 0.0, -1.0]).unwrap(), (6.0, vec![6.0, 0.0, 3.0]));
+            assert!(f(&[1.0, 2.0]).is_err());
+        }
+
+        assert_eq!(graph.flat_params(&ids), vec![3.0, 0.0, -1.0]);
+        assert!(graph.optimize_step_with_config(&mut OptimizerState::new(), &OptimizerConfig::lbfgs(1.0, 5)).is_err());
+    }
+
+    #[test]
+    fn test_learning_rate_schedules() {
+        let schedule = |name: &str, params: &[(&str, f64)]| OptimizerConfig {
+            schedule: LrSchedule::from_spec(&ScheduleSpec {
+                name: name.to_string(),
+                params: params.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+            }).unwrap(),
+            ..OptimizerConfig::sgd(1.0)
+        };
+        let lrs = |config: &OptimizerConfig, steps: usize| -> Vec<f64> {
+            let mut state = OptimizerState::new();
+            (0..steps).map(|t| { state.t = t; config.learning_rate_at(&state) }).collect()
+        };
+        let close = |a: &[f64], b: &[f64]| a.len() == b.len() && a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-12);
+
+        assert_eq!(lrs(&OptimizerConfig::sgd(0.5), 3), vec![0.5; 3]);
+        assert!(close(&lrs(&schedule("step", &[("step_size", 2.0), ("gamma", 0.5)]), 5), &[1.0, 1.0, 0.5, 0.5, 0.25]));
+        assert!(close(&lrs(&schedule("exponential", &[("gamma", 0.5)]), 3), &[1.0, 0.5, 0.25]));
+        // Warmup ramps linearly, then the schedule starts from its own step 0
+        assert!(close(&lrs(&schedule("exponential", &[("gamma", 0.5), ("warmup", 4.0)]), 6), &[0.25, 0.5, 0.75, 1.0, 1.0, 0.5]));
+        // Cosine restarts after 2 steps, then after 4 more (t_mult = 2)
+        let cosine = lrs(&schedule("cosine", &[("period", 2.0), ("t_mult", 2.0), ("min_lr", 0.2)]), 7);
+        assert!(close(&cosine, &[1.0, 0.6, 1.0, 1.0 - 0.8 * (0.5 - 0.5f64.sqrt() / 2.0), 0.6, 0.2 + 0.8 * (0.5 - 0.5f64.sqrt() / 2.0), 1.0]));
+        // The closed form agrees with walking the cycles one by one, and costs the same at any step
+        for (period, t_mult) in [(2.0, 2.0), (3.0, 1.5), (5.0, 1.0), (1.0, 3.0)] {
+            let walked: Vec<f64> = (0..2000).map(|t| {
+                let (mut pos, mut len) = (t as f64, period);
+                while pos >= len {
+                    pos -= len;
+                    len *= t_mult;
+                }
+                0.2 + 0.8 * (1.0 + (std::f64::consts::PI * pos / len).cos()) / 2.0
+            }).collect();
+            let config = schedule("cosine", &[("period", period), ("t_mult", t_mult), ("min_lr", 0.2)]);
+            assert!(close(&lrs(&config, 2000), &walked), "period {} t_mult {}", period, t_mult);
+            let mut state = OptimizerState::new();
+            state.t = usize::MAX / 2;
+            assert!((0.2..=1.0).contains(&config.learning_rate_at(&state)));
+        }
+
+        // Plateau: two non-improving steps beyond patience 1 divide the lr by 10, down to min_lr
+        let config = schedule("plateau", &[("patience", 1.0), ("min_lr", 0.005)]);
+        let mut state = OptimizerState::new();
+        let mut seen = Vec::new();
+        for loss in [5.0, 4.0, 4.0, 4.0, 3.0, 3.0, 3.0, 3.0, 3.0, 3.0, 3.0] {
+            seen.push(config.learning_rate_at(&state));
+            state.t += 1;
+            state.observe_loss(&config, loss);
+        }
+        assert!(close(&seen, &[1.0, 1.0, 1.0, 1.0, 0.1, 0.1, 0.1, 0.01, 0.01, 0.005, 0.005]));
+        state.reset();
+        assert_eq!(config.learning_rate_at(&state), 1.0);
+
+        // The scheduled rate is what optimize_step_with_config applies
+        let mut graph = ComputationalGraph::new();
+        let w = graph.add_learnable("w".to_string(), 1.0);
+        let loss = graph.add_binary_op("mul", w, w);
+        let config = schedule("step", &[("step_size", 1.0), ("gamma", 0.5)]);
+        let mut state = OptimizerState::new();
+        for _ in 0..2 {
+            graph.forward_pass().unwrap();
+            graph.backward_pass(loss).unwrap();
+            graph.optimize_step_with_config(&mut state, &config).unwrap();
+            graph.reset_gradients();
+        }
+        // w = 1 - 1 * 2 = -1, then -1 - 0.5 * (-2) = 0
+        assert_eq!(graph.get_node(w).unwrap().value, Some(Value::Scalar(0.0)));
+        assert_eq!(state.t, 2);
+    }
+
+    #[test]
+    fn test_grad_transforms() {
+        let transform = |name: &str, value: f64, targets: &[&str]| GradTransform::from_spec(&GradTransformSpec {
+            name: name.to_string(),
+            params: vec![(match name { "clip_value" => "max", "clip_norm" => "max_norm", "noise" => "std", _ => "weight" }.to_string(), value)],
+            targets: targets.iter().map(|t| t.to_string()).collect(),
+        }).unwrap();
+        // Gradients: a = [3, -4] (norm 5), b = 12 (norm 13 overall)
+        let setup = || {
+            let mut graph = ComputationalGraph::new();
+            let a = graph.add_learnable_tensor("a".to_string(), vec![1.0, -2.0], vec![2]).unwrap();
+            let b = graph.add_learnable("b".to_string(), -0.5);
+            graph.nodes.get_mut(&a).unwrap().gradient = Some(Value::Tensor(Tensor { data: vec![3.0, -4.0], shape: vec![2] }));
+            graph.nodes.get_mut(&b).unwrap().gradient = Some(Value::Scalar(12.0));
+            (graph, a, b)
+        };
+        let grad = |graph: &ComputationalGraph, id: NodeId| match graph.get_node(id).unwrap().gradient.clone().unwrap() {
+            Value::Scalar(s) => vec![s],
+            Value::Tensor(t) => t.data,
+        };
+        let close = |a: &[f64], b: &[f64]| a.len() == b.len() && a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-12);
+        let with = |grad_transforms: Vec<GradTransform>| OptimizerConfig { grad_transforms, ..Default::default() };
+
+        let (mut graph, a, b) = setup();
+        assert_eq!(graph.apply_grad_transforms(&with(vec![transform("clip_norm", 6.5, &[])])).unwrap(), 13.0);
+        assert_eq!(graph.grad_norm(), 13.0);
+        assert!(close(&grad(&graph, a), &[1.5, -2.0]) && close(&grad(&graph, b), &[6.0]));
+
+        // Per-parameter: only `a` is clipped (its own norm is 5), then L1/L2 in order
+        let (mut graph, a, b) = setup();
+        graph.apply_grad_transforms(&with(vec![
+            transform("clip_norm", 1.0, &["a"]),
+            transform("clip_value", 0.5, &[]),
+            transform("l2", 0.1, &["a"]),
+            transform("l1", 1.0, &["b"]),
+        ])).unwrap();
+        assert!(close(&grad(&graph, a), &[0.5 + 0.1, -0.5 - 0.2]) && close(&grad(&graph, b), &[0.5 - 1.0]));
+
+        // Noise comes from the seeded graph RNG
+        let noisy = |seed| {
+            let (mut graph, a, _) = setup();
+            graph.set_seed(seed);
+            graph.apply_grad_transforms(&with(vec![transform("noise", 0.1, &["a"])])).unwrap();
+            grad(&graph, a)
+        };
+        assert_eq!(noisy(7), noisy(7));
+        assert!(noisy(7) != vec![3.0, -4.0] && noisy(7).iter().zip([3.0, -4.0]).all(|(g, e)| (g - e).abs() < 1.0));
+
+        let (mut graph, _, _) = setup();
+        assert!(graph.apply_grad_transforms(&with(vec![transform("l2", 0.1, &["c"])])).unwrap_err().contains("'c', which is not a learnable"));
+        for (name, params) in [("clip", vec![("max", 1.0)]), ("clip_norm", vec![("max", 1.0)]), ("clip_value", vec![("max", 0.0)]), ("l2", vec![])] {
+            let spec = GradTransformSpec { name: name.to_string(), params: params.into_iter().map(|(k, v)| (k.to_string(), v)).collect(), targets: vec![] };
+            assert!(GradTransform::from_spec(&spec).is_err(), "{}", name);
+        }
+
+        // Non-finite gradients are rejected once a transform is configured
+        let (mut graph, a, _) = setup();
+        graph.nodes.get_mut(&a).unwrap().gradient = Some(Value::Tensor(Tensor { data: vec![f64::NAN, 0.0], shape: vec![2] }));
+        let config = OptimizerConfig { grad_transforms: vec![transform("clip_norm", 1.0, &[])], ..OptimizerConfig::sgd(0.1) };
+        assert!(graph.optimize_step_with_config(&mut OptimizerState::new(), &config).unwrap_err().contains("Gradient norm is NaN"));
+        assert_eq!(graph.get_node(a).unwrap().value, Some(Value::Tensor(Tensor { data: vec![1.0, -2.0], shape: vec![2] })));
+    }
+
+    #[test]
+    fn test_params_freeze_and_groups() {
+        // loss = a^2 + b^2 + c^2, every gradient is 2 * value
+        let setup = || {
+            let mut graph = ComputationalGraph::new();
+            let ids: Vec<NodeId> = ["a", "b", "c"].iter().map(|n| graph.add_learnable(n.to_string(), 1.0)).collect();
+            let squares: Vec<NodeId> = ids.iter().map(|id| graph.add_binary_op("mul", *id, *id)).collect();
+            let ab = graph.add_binary_op("add", squares[0], squares[1]);
+            let loss = graph.add_binary_op("add", ab, squares[2]);
+            (graph, loss)
+        };
+        let step = |graph: &mut ComputationalGraph, loss: NodeId, config: &OptimizerConfig, state: &mut OptimizerState| {
+            graph.forward_pass().unwrap();
+            graph.backward_pass(loss).unwrap();
+            graph.optimize_step_with_config(state, config).unwrap();
+            graph.reset_gradients();
+            let ids = graph.learnable_ids();
+            [0, 1, 2].map(|i| graph.get_node(ids[i]).unwrap().value.clone().unwrap().as_scalar().unwrap())
+        };
+        let targets = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        for optimizer_type in [OptimizerType::SGD, OptimizerType::Adam, OptimizerType::RMSprop, OptimizerType::Momentum] {
+            let (mut graph, loss) = setup();
+            let config = OptimizerConfig { optimizer_type, learning_rate: 0.1, params: targets(&["a", "b"]), ..Default::default() };
+            let values = step(&mut graph, loss, &config, &mut OptimizerState::new());
+            assert!(values[0] < 1.0 && values[1] < 1.0 && values[2] == 1.0, "{:?}: {:?}", optimizer_type, values);
+
+            graph.freeze("a").unwrap();
+            let again = step(&mut graph, loss, &config, &mut OptimizerState::new());
+            assert!(again[0] == values[0] && again[1] != values[1] && again[2] == 1.0, "{:?}: {:?}", optimizer_type, again);
+            graph.unfreeze("a").unwrap();
+            assert!(!graph.is_frozen("a"));
+        }
+
+        // SGD with a group: a uses lr 0.1, b lr 0.25, c untouched
+        let (mut graph, loss) = setup();
+        let config = OptimizerConfig {
+            learning_rate: 0.1,
+            params: targets(&["a", "b"]),
+            param_groups: vec![ParamGroup { params: targets(&["b"]), learning_rate: 0.25 }],
+            ..Default::default()
+        };
+        assert_eq!(step(&mut graph, loss, &config, &mut OptimizerState::new()), [0.8, 0.5, 1.0]);
+        // A schedule scales group rates too
+        let halved = OptimizerConfig { schedule: LrSchedule { kind: ScheduleKind::Exponential { gamma: 0.5 }, warmup: 0 }, ..config.clone() };
+        let mut state = OptimizerState { t: 1, ..OptimizerState::new() };
+        assert_eq!(halved.scheduled(&state).param_groups[0].learning_rate, 0.125);
+        let (mut graph, loss) = setup();
+        assert_eq!(step(&mut graph, loss, &halved, &mut state), [0.9, 0.75, 1.0]);
+
+        let (mut graph, _) = setup();
+        assert!(graph.freeze("d").is_err());
+        assert_eq!(config.learning_rate_for("c"), None);
+        assert_eq!(OptimizerConfig::default().learning_rate_for("c"), Some(0.01));
+        assert!(ParamGroup::from_spec(&ParamGroupSpec { params: vec![("lr".into(), 0.1)], targets: vec![] }).is_err());
+        assert!(ParamGroup::from_spec(&ParamGroupSpec { params: vec![("beta1".into(), 0.1)], targets: vec!["a".into()] }).is_err());
+    }
+
+    #[test]
+    fn test_stop_criteria() {
+        let spec = |name: &str, params: Vec<(&str, f64)>, monitor: Option<Expression>| StopSpec {
+            name: name.into(),
+            params: params.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+            monitor,
+        };
+        let on = Some(Expression::Identifier("val".into()));
+        assert_eq!(
+            StopCriterion::from_spec(&spec("early", vec![("patience", 3.0), ("restore_best", 1.0)], on.clone())).unwrap(),
+            StopCriterion::Early { patience: 3, min_delta: 0.0, restore_best: true }
+        );
+        assert_eq!(
+            StopCriterion::from_spec(&spec("rel_improvement", vec![("tol", 1e-6)], None)).unwrap(),
+            StopCriterion::RelImprovement { tol: 1e-6, window: 1 }
+        );
+        for (bad, message) in [
+            (spec("patience", vec![], None), "Unknown stop criterion 'patience'"),
+            (spec("early", vec![("patience", 3.0)], None), "stop early needs 'on'"),
+            (spec("grad_norm", vec![("tol", 1e-6)], on.clone()), "stop grad_norm does not take 'on'"),
+            (spec("early", vec![("patience", 2.5)], on.clone()), "patience must be a positive integer"),
+            (spec("time_limit", vec![("tol", 1.0)], None), "stop time_limit does not take 'tol'"),
+            (spec("time_limit", vec![], None), "stop time_limit needs seconds"),
+            (spec("grad_norm", vec![("tol", 0.0)], None), "stop grad_norm tol must be > 0"),
+        ] {
+            let err = StopCriterion::from_spec(&bad).unwrap_err();
+            assert!(err.contains(message), "{}", err);
+        }
+
+        // Patience counts steps without an improvement larger than min_delta
+        let id = NodeId::new(0);
+        let mut monitor = StopMonitor::new(
+            vec![StopCriterion::Early { patience: 2, min_delta: 0.1, restore_best: true }],
+            Some(id),
+        );
+        assert_eq!(monitor.before_step(1.0, Some(5.0)), None);
+        assert!(monitor.improved());
+        assert_eq!(monitor.before_step(1.0, Some(4.95)), None);
+        assert!(!monitor.improved());
+        assert_eq!(monitor.before_step(1.0, Some(f64::NAN)), Some(StopReason::Early));
+        assert_eq!((monitor.best(), monitor.restores_best()), (5.0, true));
+
+        // Relative change compares against the loss `window` steps back
+        let mut monitor = StopMonitor::new(vec![StopCriterion::RelImprovement { tol: 0.01, window: 2 }], None);
+        assert_eq!(monitor.before_step(10.0, None), None);
+        assert_eq!(monitor.before_step(9.0, None), None);
+        assert_eq!(monitor.before_step(9.95, None), Some(StopReason::RelImprovement));
+        assert_eq!(monitor.best(), 9.0);
+
+        let monitor = StopMonitor::new(
+            vec![StopCriterion::TimeLimit { seconds: 3600.0 }, StopCriterion::GradNorm { tol: 1e-3 }],
+            None,
+        );
+        assert!(monitor.needs_grad_norm());
+        assert_eq!(monitor.after_step(1e-2), None);
+        assert_eq!(monitor.after_step(1e-4), Some(StopReason::GradNorm));
+        assert_eq!((StopReason::GradNorm.code(), StopReason::GradNorm.converged()), (4.0, true));
+        assert!(!StopReason::Early.converged());
+    }
+
+    #[test]
+    fn test_metric_logging() {
+        let mut graph = ComputationalGraph::new();
+        let mut variables = HashMap::new();
+        variables.insert("w".to_string(), graph.add_learnable("w".to_string(), 2.0));
+        let call = |name: &str, args: Vec<Expression>| Expression::Call { name: name.into(), args };
+        let w = || Expression::Identifier("w".into());
+        let square = Expression::BinaryOp { left: Box::new(w()), op: BinaryOperator::Mul, right: Box::new(w()) };
+        let loss = graph.build_from_expression(&call("log", vec![square, Expression::StringLiteral("loss".into())]), &variables).unwrap();
+        graph.build_from_expression(&call("log", vec![w(), Expression::StringLiteral("w \"x\"".into())]), &variables).unwrap();
+        // One argument is still the natural logarithm
+        let ln = graph.build_from_expression(&call("log", vec![w()]), &variables).unwrap();
+        graph.forward_pass().unwrap();
+        assert_eq!(graph.metrics(), &[("loss".to_string(), 4.0), ("w \"x\"".to_string(), 2.0)]);
+        assert_eq!(graph.get_node(ln).unwrap().value, Some(Value::Scalar(2f64.ln())));
+        // The logged value passes its gradient through
+        graph.backward_pass(loss).unwrap();
+        assert_eq!(graph.get_node(variables["w"]).unwrap().gradient, Some(Value::Scalar(4.0)));
+
+        for (args, message) in [
+            (vec![w(), Expression::StringLiteral("step".into())], "reserved"),
+            (vec![w(), w()], "string literal"),
+        ] {
+            let err = graph.build_from_expression(&call("log", args), &variables).unwrap_err();
+            assert!(err.contains(message), "{}", err);
+        }
+
+        let dir = std::env::temp_dir();
+        let record = |step: f64, extra: &[(&str, f64)]| {
+            let mut r = vec![("step".to_string(), step), ("loss".to_string(), f64::NAN)];
+            r.extend(extra.iter().map(|(k, v)| (k.to_string(), *v)));
+            r
+        };
+        let jsonl = dir.join(format!("noma_metrics_{}.jsonl", std::process::id()));
+        let mut log = MetricsLogger::create(jsonl.to_str().unwrap(), 1).unwrap();
+        log.write(&record(1.0, &[("a\"b", 0.5)])).unwrap();
+        log.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&jsonl).unwrap(), "{\"step\":1,\"loss\":null,\"a\\\"b\":0.5}\n");
+
+        let csv = dir.join(format!("noma_metrics_{}.csv", std::process::id()));
+        let mut log = MetricsLogger::create(csv.to_str().unwrap(), 1).unwrap();
+        log.write(&record(1.0, &[("acc", 0.5)])).unwrap();
+        log.write(&record(2.0, &[])).unwrap();
+        assert!(log.write(&record(3.0, &[("new", 1.0)])).unwrap_err().contains("metric 'new' first appears"));
+        log.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&csv).unwrap(), "step,loss,acc\n1,NaN,0.5\n2,NaN,\n");
+        assert!(MetricsLogger::create(csv.to_str().unwrap(), 0).is_err());
+        let _ = std::fs::remove_file(jsonl);
+        let _ = std::fs::remove_file(csv);
+    }
+
+    #[test]
+    fn test_safetensors_fixtures() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("noma_fixture_{}.safetensors", std::process::id()));
+        let path = path.to_str().unwrap();
+        let write_fixture = |header: &str, data: &[u8]| {
+            let mut header = header.to_string();
+            while (8 + header.len()) % 8 != 0 {
+                header.push(' ');
+            }
+            let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+            bytes.extend_from_slice(header.as_bytes());
+            bytes.extend_from_slice(data);
+            std::fs::write(path, bytes).unwrap();
+        };
+        let fixture = |name: &str| format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+
+        // Written by the reference safetensors serializer: `__metadata__`, space padding, and
+        // data ordered by alignment rather than by name
+        let reference = fixture("reference_dtypes.safetensors");
+        let tensors = load_safetensors_file(&reference).unwrap();
+        assert_eq!(tensors, vec![
+            ("i".to_string(), (vec![-3.0, 7.0], vec![2, 1])),
+            ("pi".to_string(), (vec![std::f64::consts::PI], vec![])),
+            ("w".to_string(), (vec![0.25, -1.5, 3.0, 0.001f32 as f64], vec![2, 2])),
+            ("b".to_string(), (vec![1.0, -2.5], vec![2])),
+            ("h".to_string(), (vec![1.0, -2.0, 0.5], vec![3])),
+            ("layer.0/\"q\"é".to_string(), (vec![-1.0, 5.0], vec![2])),
+            ("u".to_string(), (vec![200.0], vec![])),
+            ("m".to_string(), (vec![1.0, 0.0, 1.0], vec![3])),
+        ]);
+        assert_eq!(load_safetensors_metadata(&reference).unwrap(), vec![("format".to_string(), "pt".to_string())]);
+
+        // Writing the tensors of a reference file gives the same tensors, bytes and metadata
+        let reference = SafetensorsFile::open(&fixture("reference_weights.safetensors")).unwrap();
+        let named: Vec<(String, Value)> = reference.entries().iter()
+            .map(|e| (e.name.clone(), Value::Tensor(reference.tensor(&e.name).unwrap())))
+            .collect();
+        save_safetensors_file_with(&named, path, SafetensorsDtype::F32, reference.metadata()).unwrap();
+        let written = SafetensorsFile::open(path).unwrap();
+        assert_eq!((written.entries(), written.metadata()), (reference.entries(), reference.metadata()));
+        assert_eq!(written.mmap[written.header.data_start..], reference.mmap[reference.header.data_start..]);
+        drop(written);
+
+        // Malformed files are rejected with the reason
+        for (header, message) in [
+            (r#"{"a":{"dtype":"F8_E4M3","shape":[2],"data_offsets":[0,2]}}"#, "Unsupported dtype 'F8_E4M3'"),
+            (r#"{"a":{"dtype":"F16","shape":[2],"data_offsets":[0,2]}}"#, "do not match shape [2] of F16"),
+            (r#"{"a":{"dtype":"U8","shape":[1],"data_offsets":[1,2]}}"#, "does not start where"),
+            (r#"{"a":{"dtype":"U8","shape":[1],"data_offsets":[0,1]}}"#, "cover 1 of 2 data bytes"),
+            (r#"{"a":{"dtype":"U8","shape":[2]}}"#, "Missing data_offsets"),
+            (r#"{"__metadata__":{"epoch":3}}"#, "metadata 'epoch' is not a string"),
+            (r#"{"a": "#, "Error parsing safetensors header"),
+        ] {
+            write_fixture(header, &[0, 0]);
+            let err = load_safetensors_file(path).unwrap_err();
+            assert!(err.contains(message), "{}", err);
+        }
+
+        // Writing: chosen dtype, escaped names, metadata, aligned data
+        let tensor = |data: Vec<f64>| Value::Tensor(Tensor { shape: vec![data.len()], data });
+        let metadata = vec![("format".to_string(), "pt".to_string()), ("note".to_string(), "a \"b\"".to_string())];
+        let named = vec![("w\"1\"".to_string(), tensor(vec![0.1, -3.0])), ("s".to_string(), Value::Scalar(2.0))];
+        save_safetensors_file_with(&named, path, SafetensorsDtype::BF16, &metadata).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(SafetensorsHeader::json_len(&bytes).unwrap() % 8, 0);
+        let loaded = load_safetensors_file(path).unwrap();
+        assert_eq!(loaded[0].0, "w\"1\"");
+        assert!((loaded[0].1 .0[0] - 0.1).abs() < 1e-3 && loaded[0].1 .0[1] == -3.0);
+        assert_eq!(loaded[1], ("s".to_string(), (vec![2.0], vec![1])));
+        assert_eq!(load_safetensors_metadata(path).unwrap(), metadata);
+
+        for dtype in [SafetensorsDtype::I32, SafetensorsDtype::U8, SafetensorsDtype::Bool, SafetensorsDtype::F16] {
+            save_safetensors_file_with(&[("x".to_string(), tensor(vec![0.0, 1.0]))], path, dtype, &[]).unwrap();
+            assert_eq!(load_safetensors_file(path).unwrap()[0].1 .0, vec![0.0, 1.0]);
+        }
+        for (dtype, value) in [(SafetensorsDtype::I32, 0.5), (SafetensorsDtype::U8, -1.0), (SafetensorsDtype::Bool, 2.0)] {
+            let err = save_safetensors_file_with(&[("x".to_string(), tensor(vec![value]))], path, dtype, &[]).unwrap_err();
+            assert!(err.contains(&format!("as {}", dtype.name())), "{}", err);
+        }
+        let duplicate = vec![("x".to_string(), Value::Scalar(1.0)), ("x".to_string(), Value::Scalar(2.0))];
+        assert!(save_safetensors_file(&duplicate, path).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_safetensors_lazy_loading() {
+        let path = std::env::temp_dir().join(format!("noma_lazy_{}.safetensors", std::process::id()));
+        let path = path.to_str().unwrap();
+        let tensor = |data: Vec<f64>| Value::Tensor(Tensor { shape: vec![data.len()], data });
+        let tensors = vec![("keep".to_string(), tensor(vec![1.0, 0.0, 1.0])), ("mask".to_string(), tensor(vec![0.0, 1.0]))];
+        save_safetensors_file_with(&tensors, path, SafetensorsDtype::Bool, &[("format".into(), "pt".into())]).unwrap();
+
+        // Corrupt the payload of `mask`: a BOOL byte must be 0 or 1
+        let mut bytes = std::fs::read(path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] = 7;
+        std::fs::write(path, &bytes).unwrap();
+
+        // Opening reads the header only, so the corrupt payload goes unnoticed until decoded
+        let file = SafetensorsFile::open(path).unwrap();
+        let names: Vec<&str> = file.entries().iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["keep", "mask"]);
+        assert_eq!(file.metadata(), &[("format".to_string(), "pt".to_string())]);
+        let entry = file.entry("mask").unwrap();
+        assert_eq!((entry.dtype, file.bytes(entry)), (SafetensorsDtype::Bool, &[0u8, 7][..]));
+
+        assert_eq!(file.tensor("keep").unwrap().data, vec![1.0, 0.0, 1.0]);
+        assert!(file.tensor("mask").unwrap_err().contains("Invalid BOOL value 7 in tensor 'mask'"));
+        assert!(file.tensor("layer9").unwrap_err().contains("it has: keep, mask"));
+        drop(file);
+        assert_eq!(load_safetensors_tensor(path, "keep").unwrap(), (vec![1.0, 0.0, 1.0], vec![3]));
+        assert!(load_safetensors_file(path).is_err());
+
+        // Statement lowering reads single tensors the same way
+        let mut graph = ComputationalGraph::new();
+        let load = |tensor: &str| Expression::Call {
+            name: "load_safetensors".to_string(),
+            args: vec![Expression::StringLiteral(path.to_string()), Expression::StringLiteral(tensor.to_string())],
+        };
+        let sum = Expression::BinaryOp { op: BinaryOperator::Add, left: Box::new(load("keep")), right: Box::new(load("keep")) };
+        let id = graph.build_from_expression_with_functions(&sum, &HashMap::new(), &FunctionRegistry::new()).unwrap();
+        graph.forward_pass().unwrap();
+        assert_eq!(graph.get_node(id).unwrap().value.clone().unwrap().as_tensor().unwrap().data, vec![2.0, 0.0, 2.0]);
+        assert!(graph.build_from_expression_with_functions(&load("mask"), &HashMap::new(), &FunctionRegistry::new()).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_npy_and_npz() {
+        let dir = std::env::temp_dir();
+        let path = |ext: &str| dir.join(format!("noma_numpy_{}.{}", std::process::id(), ext)).to_str().unwrap().to_string();
+        let (npy, npz) = (path("npy"), path("npz"));
+        // .npy images as NumPy writes them: padded header dict, then raw data
+        let npy_image = |version: u8, header: &str, data: &[u8]| {
+            let mut header = header.to_string();
+            let prefix = if version == 1 { 10 } else { 12 };
+            while (prefix + header.len() + 1) % 64 != 0 {
+                header.push(' ');
+            }
+            header.push('\n');
+            let mut bytes = vec![0x93, b'N', b'U', b'M', b'P', b'Y', version, 0];
+            match version {
+                1 => bytes.extend((header.len() as u16).to_le_bytes()),
+                _ => bytes.extend((header.len() as u32).to_le_bytes()),
+            }
+            bytes.extend(header.as_bytes());
+            bytes.extend(data);
+            bytes
+        };
+
+        // Fortran-order big-endian f4: [[1, 2, 3], [4, 5, 6]] stored column by column
+        let data: Vec<u8> = [1f32, 4.0, 2.0, 5.0, 3.0, 6.0].iter().flat_map(|v| v.to_be_bytes()).collect();
+        std::fs::write(&npy, npy_image(1, "{'descr': '>f4', 'fortran_order': True, 'shape': (2, 3), }", &data)).unwrap();
+        assert_eq!(load_npy_file(&npy).unwrap(), (vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], vec![2, 3]));
+        let data: Vec<u8> = [-1i64, 300].iter().flat_map(|v| v.to_le_bytes()).collect();
+        std::fs::write(&npy, npy_image(2, "{'descr': '<i8', 'fortran_order': False, 'shape': (2,), }", &data)).unwrap();
+        assert_eq!(load_npy_file(&npy).unwrap(), (vec![-1.0, 300.0], vec![2]));
+        std::fs::write(&npy, npy_image(1, "{'descr': '|b1', 'fortran_order': False, 'shape': (), }", &[1])).unwrap();
+        assert_eq!(load_npy_file(&npy).unwrap(), (vec![1.0], vec![]));
+        for (header, message) in [
+            ("{'descr': [('a', '<f8')], 'fortran_order': False, 'shape': (1,), }", "structured dtypes"),
+            ("{'descr': '<c16', 'fortran_order': False, 'shape': (1,), }", "unsupported dtype '<c16'"),
+            ("{'descr': '<f8', 'fortran_order': False, 'shape': (2,), }", "needs 16 data bytes, found 1"),
+        ] {
+            std::fs::write(&npy, npy_image(1, header, &[1])).unwrap();
+            let err = load_npy_file(&npy).unwrap_err();
+            assert!(err.contains(message), "{}", err);
+        }
+
+        // Round trips in the written dtype and byte order
+        let value = Value::Tensor(Tensor { data: vec![1.0, -2.0, 3.0, 4.0], shape: vec![2, 2] });
+        for descr in ["<f8", ">f8", "<f4", ">i4", "<i2", "|u1"] {
+            let dtype = NpyDtype::parse(descr).unwrap();
+            assert_eq!(dtype.descr(), descr);
+            let result = save_npy_file(&value, &npy, dtype).and_then(|_| load_npy_file(&npy));
+            match descr {
+                "|u1" => assert!(result.unwrap_err().contains("Cannot write -2")),
+                _ => assert_eq!(result.unwrap(), (vec![1.0, -2.0, 3.0, 4.0], vec![2, 2])),
+            }
+        }
+        save_npy_file(&Value::Scalar(2.5), &npy, NpyDtype::F64).unwrap();
+        let bytes = std::fs::read(&npy).unwrap();
+        assert!(std::str::from_utf8(&bytes[10..74]).unwrap().contains("'shape': ()"));
+        assert_eq!(load_npy_file(&npy).unwrap(), (vec![2.5], vec![]));
+
+        let arrays = vec![("W".to_string(), value.clone()), ("b".to_string(), Value::Scalar(0.5))];
+        save_npz_file(&arrays, &npz, NpyDtype::parse("<f4").unwrap()).unwrap();
+        assert_eq!(load_npz_file(&npz).unwrap(), vec![
+            ("W".to_string(), (vec![1.0, -2.0, 3.0, 4.0], vec![2, 2])),
+            ("b".to_string(), (vec![0.5], vec![])),
+        ]);
+        assert_eq!(load_npz_array(&npz, "b").unwrap(), (vec![0.5], vec![]));
+        assert!(load_npz_array(&npz, "c").unwrap_err().contains("it has: W, b"));
+        let names: Vec<String> = npz_bindings("d", &npz).unwrap().into_iter().map(|(n, _)| n).collect();
+        assert_eq!(names, ["d.W", "d.b"]);
+
+        // np.savez opens its entries with force_zip64: Zip64 local headers around the payload
+        let hex = |s: &str| (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect::<Vec<u8>>();
+        let payload = npy_image(1, "{'descr': '<u2', 'fortran_order': False, 'shape': (2,), }", &[7, 0, 0xFF, 0xFF]);
+        let mut archive = hex("504b03042d0000000000000021002fa24634ffffffffffffffff05001400782e6e70790100100084000000000000008400000000000000");
+        archive.extend(&payload);
+        archive.extend(hex("504b01022d032d0000000000000021002fa246348400000084000000050000000000000000000000800100000000782e6e7079504b0506000000000100010033000000bb0000000000"));
+        std::fs::write(&npz, &archive).unwrap();
+        assert_eq!(load_npz_file(&npz).unwrap(), vec![("x".to_string(), (vec![7.0, 65535.0], vec![2]))]);
+        // Corrupted data fails the CRC check; deflated entries are reported as such
+        let last = archive.len() - 100;
+        archive[last] ^= 1;
+        std::fs::write(&npz, &archive).unwrap();
+        assert!(load_npz_file(&npz).unwrap_err().contains("CRC mismatch"));
+        archive[last] ^= 1;
+        // compression method of the 51-byte central directory entry before the 22-byte end record
+        let method = archive.len() - 22 - 51 + 10;
+        archive[method] = 8;
+        std::fs::write(&npz, &archive).unwrap();
+        assert!(load_npz_file(&npz).unwrap_err().contains("np.savez_compressed"));
+        archive[method] = 0;
+        // A Zip64 end record that puts the central directory at the end of the address space
+        let eocd = archive.split_off(archive.len() - 22);
+        let zip64 = archive.len() as u64;
+        archive.extend(hex("504b06062c000000000000002d002d00000000000000000001000000000000000100000000000000"));
+        archive.extend(0x33u64.to_le_bytes());
+        archive.extend(u64::MAX.to_le_bytes());
+        archive.extend(hex("504b060700000000"));
+        archive.extend(zip64.to_le_bytes());
+        archive.extend(hex("01000000"));
+        archive.extend(&eocd[..16]);
+        archive.extend(hex("ffffffff0000"));
+        std::fs::write(&npz, &archive).unwrap();
+        assert!(load_npz_file(&npz).unwrap_err().contains("bad central directory entry"));
+        let _ = std::fs::remove_file(npy);
+        let _ = std::fs::remove_file(npz);
+    }
+
+    #[test]
+    fn test_load_safetensors_by_name() {
+        let path = std::env::temp_dir().join(format!("noma_named_{}.safetensors", std::process::id()));
+        let path = path.to_str().unwrap();
+        let tensor = |data: Vec<f64>, shape: Vec<usize>| Value::Tensor(Tensor { data, shape });
+        save_safetensors_file(&[
+            ("W1".to_string(), tensor(vec![1.0, 2.0, 3.0, 4.0], vec![2, 2])),
+            ("b1".to_string(), tensor(vec![0.5, -0.5], vec![2])),
+            ("W2".to_string(), tensor(vec![7.0, 8.0], vec![2, 1])),
+        ], path).unwrap();
+
+        // Every tensor is reachable by name, whatever the header order
+        let mut graph = ComputationalGraph::new();
+        let variables = HashMap::new();
+        let call = |name: &str| Expression::Call {
+            name: "load_safetensors".into(),
+            args: vec![Expression::StringLiteral(path.into()), Expression::StringLiteral(name.into())],
+        };
+        let b1 = graph.build_from_expression(&call("b1"), &variables).unwrap();
+        assert_eq!(graph.get_node(b1).unwrap().value, Some(tensor(vec![0.5, -0.5], vec![2])));
+        let err = graph.build_from_expression(&call("W3"), &variables).unwrap_err();
+        assert!(err.contains("No tensor 'W3'") && err.contains("W1, W2, b1"), "{}", err);
+
+        let mut bindings = safetensors_bindings("model", path).unwrap();
+        bindings.sort_by(|a, b| a.0.cmp(&b.0));
+        let names: Vec<&str> = bindings.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["model.W1", "model.W2", "model.b1"]);
+        assert_eq!(bindings[1].1, Tensor { data: vec![7.0, 8.0], shape: vec![2, 1] });
+
+        // A checkpoint exposes its learnables under their own names
+        let mut trained = ComputationalGraph::new();
+        trained.add_learnable("w".to_string(), 1.25);
+        Checkpoint::capture(&trained, &OptimizerState::new(), None, 1, 0).save(path).unwrap();
+        assert_eq!(load_safetensors_tensor(path, "w").unwrap(), (vec![1.25], vec![1]));
+        let bindings = safetensors_bindings("ckpt", path).unwrap();
+        let names: Vec<&str> = bindings.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["ckpt.w", "ckpt"]);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_csv_options() {
+        let path = std::env::temp_dir().join(format!("noma_csv_{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "id;\"x; 1\";x2;y\r\n\"a\"\"b\";1.5;;1\r\nc; 2 ;NA;0\r\n\r\n\"d\ne\";3;4;1\r\n").unwrap();
+
+        // Quoted names and fields keep delimiters, quotes and newlines; missing cells take `na`
+        let options = CsvOptions {
+            header: true,
+            delimiter: ';',
+            columns: vec![CsvColumn::Name("x; 1".into()), CsvColumn::Index(2)],
+            labels: vec![CsvColumn::Name("y".into())],
+            na: Some(-1.0),
+        };
+        let (features, labels) = load_csv_with(path, &options).unwrap();
+        assert_eq!(features, Tensor { data: vec![1.5, -1.0, 2.0, -1.0, 3.0, 4.0], shape: vec![3, 2] });
+        assert_eq!(labels, Some(Tensor { data: vec![1.0, 0.0, 1.0], shape: vec![3] }));
+
+        // Missing cells are an error without `na`; `na=nan` keeps them as NaN
+        let options = CsvOptions { columns: vec![CsvColumn::Index(2)], na: None, ..options };
+        let err = load_csv_with(path, &options).unwrap_err();
+        assert!(err.contains("line 2: missing value in column 'x2'"), "{}", err);
+        let (features, _) = load_csv_with(path, &CsvOptions { na: Some(f64::NAN), ..options.clone() }).unwrap();
+        assert!(features.data[0].is_nan() && features.data[2] == 4.0);
+        // The text column still has to be left out
+        let options = CsvOptions { columns: Vec::new(), ..options };
+        let err = load_csv_with(path, &options).unwrap_err();
+        assert!(err.contains("'a\"b' in column 'id' is not a number"), "{}", err);
+        let options = CsvOptions { columns: vec![CsvColumn::Name("z".into())], ..options };
+        assert!(load_csv_with(path, &options).unwrap_err().contains("no column 'z'; it has: id, x; 1, x2, y"));
+        let options = CsvOptions { header: false, ..options };
+        assert!(load_csv_with(path, &options).unwrap_err().contains("needs header=true"));
+
+        // save_csv writes the header before the rows, quoting names where needed
+        let value = Value::Tensor(Tensor { data: vec![1.0, 2.0, 3.0, 4.0], shape: vec![2, 2] });
+        save_csv_file_with(&value, path, &["a".into(), "b,c".into()]).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "a,\"b,c\"\n1,2\n3,4\n");
+        let options = CsvOptions { header: true, delimiter: ',', columns: Vec::new(), labels: vec![CsvColumn::Index(1)], na: None };
+        assert_eq!(load_csv_with(path, &options).unwrap(), (
+            Tensor { data: vec![1.0, 3.0], shape: vec![2] },
+            Some(Tensor { data: vec![2.0, 4.0], shape: vec![2] }),
+        ));
+        assert!(save_csv_file_with(&value, path, &["a".into()]).unwrap_err().contains("1 names but the tensor has 2 columns"));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_dataset_streaming() {
+        let dir = std::env::temp_dir();
+        let path = |ext: &str| dir.join(format!("noma_dataset_{}.{}", std::process::id(), ext)).to_str().unwrap().to_string();
+        let (csv, npy, st) = (path("csv"), path("npy"), path("safetensors"));
+        std::fs::write(&csv, "a,b,note\n1,10,x\n2,20,\"y\nz\"\n3,,w\n4,40,v\n5,50,u\n").unwrap();
+        let rows = Value::Tensor(Tensor { data: (1..=10).map(f64::from).collect(), shape: vec![5, 2] });
+        save_npy_file(&rows, &npy, NpyDtype::parse(">f4").unwrap()).unwrap();
+        save_safetensors_file(&[("x".into(), rows.clone()), ("y".into(), Value::Scalar(1.0))], &st).unwrap();
+
+        // CSV rows are indexed up front and parsed on demand, quoted line breaks included
+        let csv_options = CsvOptions { header: true, columns: vec![CsvColumn::Index(0), CsvColumn::Name("b".into())], na: Some(0.0), ..CsvOptions::default() };
+        let options = DatasetOptions { csv: csv_options, drop_last: true, epochs: 2, ..DatasetOptions::default() };
+        let dataset = Dataset::open(&csv, &options, 0).unwrap();
+        assert_eq!((dataset.rows(), dataset.row_shape(), dataset.schedule().epochs), (5, &[2][..], 2));
+        assert_eq!(dataset.schedule().batches(1, 2), vec![vec![0, 1], vec![2, 3]]);
+        assert_eq!(dataset.read_rows(&[2, 3, 0]).unwrap(), Tensor { data: vec![3.0, 0.0, 4.0, 40.0, 1.0, 10.0], shape: vec![3, 2] });
+        // Read errors name the file line, past the header and the quoted line break
+        std::fs::write(&csv, "a,b,note\n1,10,x\n2,20,\"y\nz\"\n3,,w\n4,4x,v\n5,50,u\n").unwrap();
+        for rows in [&[3][..], &[2, 3]] {
+            let err = dataset.read_rows(rows).unwrap_err();
+            assert!(err.contains("line 6: '4x' in column 'b'"), "{}", err);
+        }
+
+        // A seeded shuffle is reproducible and differs between epochs
+        let options = DatasetOptions { shuffle: true, ..DatasetOptions::default() };
+        let dataset = Dataset::open(&npy, &options, 7).unwrap();
+        let schedule = dataset.schedule();
+        assert_eq!(schedule.order(0), Dataset::open(&npy, &options, 7).unwrap().schedule().order(0));
+        assert_ne!((0..4).map(|e| schedule.order(e)).collect::<Vec<_>>(), vec![schedule.order(0); 4]);
+        assert_eq!(schedule.batches(0, 2).len(), 3);
+        assert_eq!(dataset.read_rows(&[4, 1]).unwrap(), Tensor { data: vec![9.0, 10.0, 3.0, 4.0], shape: vec![2, 2] });
+
+        let options = DatasetOptions { tensor: Some("x".into()), ..DatasetOptions::default() };
+        let dataset = Dataset::open(&st, &options, 0).unwrap();
+        assert_eq!(dataset.read_rows(&[3]).unwrap(), Tensor { data: vec![7.0, 8.0], shape: vec![1, 2] });
+        let err = Dataset::open(&st, &DatasetOptions::default(), 0).err().unwrap();
+        assert!(err.contains("tensor=\"...\" (it has: x, y)"), "{}", err);
+        let options = DatasetOptions { csv: CsvOptions { header: true, ..CsvOptions::default() }, ..DatasetOptions::default() };
+        assert!(Dataset::check_options(&npy, &options).unwrap_err().contains("only to CSV datasets"));
+        for file in [csv, npy, st] {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[test]
+    fn test_batch_sources_in_lockstep() {
+        let x = BatchSource::from_value(Value::Tensor(Tensor { data: (0..10).map(f64::from).collect(), shape: vec![5, 2] }));
+        let y = BatchSource::from_value(Value::Tensor(Tensor { data: vec![0.0, 10.0, 20.0, 30.0, 40.0], shape: vec![5] }));
+        let sources = [x, y];
+        let schedule = BatchSource::schedule(&sources, None, false, None).unwrap();
+        assert_eq!(schedule, BatchSchedule::new(5));
+        assert_eq!(schedule.batches(0, 2), vec![vec![0, 1], vec![2, 3], vec![4]]);
+
+        // The shuffle is shared by every source, reproducible, and new each epoch
+        let schedule = BatchSource::schedule(&sources, Some(9), true, Some(3)).unwrap();
+        assert_eq!((schedule.drop_last, schedule.epochs), (true, 3));
+        let batches = schedule.batches(1, 2);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches, BatchSource::schedule(&sources, Some(9), true, Some(3)).unwrap().batches(1, 2));
+        assert_ne!((0..4).map(|e| schedule.order(e)).collect::<Vec<_>>(), vec![schedule.order(0); 4]);
+        let rows = &batches[0];
+        let (xb, yb) = (sources[0].read_rows(rows).unwrap(), sources[1].read_rows(rows).unwrap());
+        assert_eq!((xb.shape, yb.shape.clone()), (vec![2, 2], vec![2]));
+        for (k, &row) in rows.iter().enumerate() {
+            assert_eq!(xb.data[2 * k], 2.0 * row as f64);
+            assert_eq!(yb.data[k], 10.0 * row as f64);
+        }
+
+        let short = BatchSource::from_value(Value::Tensor(Tensor { data: vec![1.0; 4], shape: vec![4] }));
+        let [x, _] = sources;
+        let err = BatchSource::schedule(&[x, short], None, false, None).unwrap_err();
+        assert!(err.contains("different numbers of rows: 5 and 4"), "{}", err);
+    }
+
+    #[test]
+    fn test_prune_since_keeps_live_nodes() {
+        let mut graph = ComputationalGraph::new();
+        let before = graph.add_constant(1.0);
+        let first = graph.next_node_index();
+        let w = graph.add_learnable("w".to_string(), 2.0);
+        let old_batch = graph.add_constant_tensor(vec![1.0, 2.0], vec![2]).unwrap();
+        let old_loss = graph.add_binary_op("mul", old_batch, w);
+        let batch = graph.add_constant_tensor(vec![3.0, 4.0], vec![2]).unwrap();
+        let loss = graph.add_binary_op("mul", batch, w);
+        assert_eq!(graph.prune_since(first, [loss]), 2);
+        for (id, kept) in [(before, true), (w, true), (old_batch, false), (old_loss, false), (batch, true), (loss, true)] {
+            assert_eq!(graph.get_node(id).is_some(), kept);
+        }
+        graph.forward_pass().unwrap();
+        assert_eq!(graph.get_node(loss).unwrap().value, Some(Value::Tensor(Tensor { data: vec![6.0, 8.0], shape: vec![2] })));
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let mut graph = ComputationalGraph::new();
+        graph.set_seed(7);
+        let w = graph.add_learnable("w".to_string(), 1.5);
+        let t = graph.add_learnable_tensor("t".to_string(), vec![1.0, 2.0, 3.0, 4.0], vec![2, 2]).unwrap();
+        let draw = graph.add_function_call("rand".to_string(), vec![]);
+        graph.forward_pass().unwrap();
+        let mut state = OptimizerState::new();
+        state.m.insert(w, Value::Scalar(0.25));
+        state.v.insert(t, Value::Tensor(Tensor { data: vec![0.1, 0.2, 0.3, 0.4], shape: vec![2, 2] }));
+        state.lbfgs_history.push_back((vec![1.0, 2.0], vec![3.0, 4.0]));
+        state.t = 12;
+        state.plateau_best = Some(0.5);
+
+        let path = std::env::temp_dir().join(format!("noma_checkpoint_{}.safetensors", std::process::id()));
+        let path = path.to_str().unwrap();
+        graph.running_stats.insert(t, (vec![0.5, 1.5], vec![2.0, 3.0]));
+        let mut monitor = StopMonitor::new(vec![StopCriterion::RelImprovement { tol: 1e-6, window: 3 }], None);
+        monitor.before_step(2.0, None);
+        monitor.before_step(1.0, None);
+        monitor.keep_best(vec![1.5, 1.0, 2.0, 3.0, 4.0], 1.0);
+        let checkpoint = Checkpoint::capture(&graph, &state, Some(&monitor), 2, 30);
+        checkpoint.save(path).unwrap();
+        let loaded = Checkpoint::load(path).unwrap();
+        let _ = std::fs::remove_file(path);
+        assert_eq!((loaded.loops_done, loaded.loop_step, loaded.step), (2, 30, 12));
+        assert_eq!(loaded.rng, checkpoint.rng);
+        assert_eq!(loaded.lbfgs_history, checkpoint.lbfgs_history);
+        assert_eq!(loaded.stop, Some(monitor.progress()));
+        assert_eq!(loaded.running_stats, [("t".to_string(), vec![0.5, 1.5], vec![2.0, 3.0])]);
+
+        // A fresh program creates the learnables in another order, so the ids differ
+        let mut other = ComputationalGraph::new();
+        let t2 = other.add_learnable_tensor("t".to_string(), vec![0.0; 4], vec![2, 2]).unwrap();
+        let w2 = other.add_learnable("w".to_string(), 0.0);
+        let draw2 = other.add_function_call("rand".to_string(), vec![]);
+        let mut restored = OptimizerState::new();
+        assert!(loaded.restore(&mut other, &mut restored).unwrap().is_empty());
+        assert_eq!(other.get_node(w2).unwrap().value, Some(Value::Scalar(1.5)));
+        assert_eq!(other.get_node(t2).unwrap().value, graph.get_node(t).unwrap().value);
+        assert_eq!(restored.m.get(&w2), Some(&Value::Scalar(0.25)));
+        assert_eq!(restored.v.get(&t2), state.v.get(&t));
+        assert_eq!((restored.t, restored.plateau_best), (12, Some(0.5)));
+        assert_eq!(other.running_stats(t2), Some(&(vec![0.5, 1.5], vec![2.0, 3.0])));
+
+        // Random draws continue where the checkpointed run left off
+        let next = graph.add_function_call("rand".to_string(), vec![]);
+        graph.forward_pass().unwrap();
+        other.forward_pass().unwrap();
+        assert_eq!(other.get_node(draw2).unwrap().value, graph.get_node(next).unwrap().value);
+        assert_ne!(other.get_node(draw2).unwrap().value, graph.get_node(draw).unwrap().value);
+
+        // Saved parameters without a learnable are reported, shape mismatches are errors
+        let mut extra = ComputationalGraph::new();
+        extra.add_learnable("w".to_string(), 0.0);
+        assert_eq!(loaded.restore(&mut extra, &mut OptimizerState::new()).unwrap(), vec!["t".to_string()]);
+        let mut scalar_t = ComputationalGraph::new();
+        scalar_t.add_learnable("t".to_string(), 0.0);
+        assert!(loaded.restore(&mut scalar_t, &mut OptimizerState::new()).unwrap_err().contains("scalar learnable 't'"));
+    }
+
+    #[test]
+    fn test_optimizer_state_survives_realloc() {
+        for optimizer_type in [OptimizerType::Momentum, OptimizerType::Nesterov, OptimizerType::AdamW,
+                               OptimizerType::Adagrad, OptimizerType::Adadelta, OptimizerType::Lion] {
+            let mut graph = ComputationalGraph::new();
+            let w = graph.add_learnable_tensor("w".to_string(), vec![1.0, -2.0], vec![2]).unwrap();
+            let sq = graph.add_binary_op("mul", w, w);
+            let loss = graph.add_function_call("sum".to_string(), vec![sq]);
+            let config = OptimizerConfig { optimizer_type, learning_rate: 0.1, ..Default::default() };
+            let mut state = OptimizerState::new();
+            for shape in [vec![2], vec![2], vec![4], vec![4]] {
+                if shape[0] != graph.get_node(w).unwrap().value.as_ref().unwrap().as_tensor().unwrap().data.len() {
+                    graph.realloc_learnable_tensor_by_id(w, shape).unwrap();
+                }
+                graph.forward_pass().unwrap();
+                // Downstream gradients still have the old shape after a realloc
+                graph.reset_gradients();
+                graph.backward_pass(loss).unwrap();
+                graph.optimize_step_with_config(&mut state, &config).unwrap();
+                graph.reset_gradients();
+            }
+            assert_eq!(state.m[&w].as_tensor().unwrap().data.len(), 4);
+            assert_eq!(state.v[&w].as_tensor().unwrap().data.len(), 4);
+            assert_eq!(state.t, 4);
+            let data = &graph.get_node(w).unwrap().value.as_ref().unwrap().as_tensor().unwrap().data;
+            assert!(data[0].abs() < 1.0, "{:?} did not move toward the minimum", optimizer_type);
+        }
+    }
+
+    #[test]
+    fn test_seeded_rng_is_reproducible() {
+        let sample = |seed: u64| -> Vec<f64> {
+            let mut graph = ComputationalGraph::new();
+            graph.set_seed(seed);
+            let r = graph.add_function_call("rand".to_string(), vec![]);
+            let fan_in = graph.add_constant(4.0);
+            let dim = graph.add_constant(3.0);
+            let he = graph.add_function_call("he_init".to_string(), vec![fan_in, dim]);
+            let w = graph.add_learnable_tensor("w".to_string(), vec![1.0], vec![1]).unwrap();
+            graph.forward_pass().unwrap();
+            graph.realloc_learnable_tensor_by_id(w, vec![3]).unwrap();
+            let mut out = vec![graph.get_node(r).unwrap().value.clone().unwrap().as_scalar().unwrap()];
+            for id in [he, w] {
+                out.extend(graph.get_node(id).unwrap().value.clone().unwrap().as_tensor().unwrap().data.clone());
+            }
+            out
+        };
+        assert_eq!(sample(42), sample(42));
+        assert_ne!(sample(42), sample(43));
+        assert_eq!(sample(42)[4], 1.0);
+        assert_eq!(seed_value(Some(Value::Scalar(7.0))), Ok(7));
+        assert!(seed_value(Some(Value::Scalar(-1.0))).is_err());
+        assert!(seed_value(Some(Value::Scalar(1.5))).is_err());
+    }
+
+    #[test]
+    fn test_sample_once_and_per_step_nodes() {
+        let mut graph = ComputationalGraph::new();
+        graph.set_seed(1);
+        let (zero, one, three) = (graph.add_constant(0.0), graph.add_constant(1.0), graph.add_constant(3.0));
+        let init = graph.add_function_call("rand_normal_tensor".to_string(), vec![zero, one, three]);
+        let noise = graph.add_function_call("noise_normal".to_string(), vec![zero, one, three]);
+        let scalar = graph.add_function_call("noise_uniform".to_string(), vec![zero, one]);
+        let value = |g: &ComputationalGraph, id: NodeId| g.get_node(id).unwrap().value.clone().unwrap();
+
+        graph.forward_pass().unwrap();
+        let (first_init, first_noise) = (value(&graph, init), value(&graph, noise));
+        assert_eq!(first_noise.as_tensor().unwrap().shape, vec![3]);
+        assert!(matches!(value(&graph, scalar), Value::Scalar(s) if (0.0..1.0).contains(&s)));
+        graph.forward_pass().unwrap();
+        assert_eq!(value(&graph, init), first_init);
+        assert_ne!(value(&graph, noise), first_noise);
+
+        assert_eq!(sampling_of("xavier_init"), Some(Sampling::Once));
+        assert_eq!(sampling_of("dropout"), Some(Sampling::PerStep));
+        assert_eq!(sampling_of("relu"), None);
+        assert!(noise_params("noise_uniform", &[Value::Scalar(1.0), Value::Scalar(0.0)]).is_err());
+    }
+
+    #[test]
+    fn test_evaluate_detached_leaves_training_state() {
+        let mut graph = ComputationalGraph::new();
+        graph.set_seed(3);
+        let x = graph.add_constant_tensor(vec![1.0, 2.0, 3.0, 6.0, 5.0, 10.0], vec![3, 2]).unwrap();
+        let gamma = graph.add_learnable_tensor("gamma".to_string(), vec![1.0, 1.0], vec![2]).unwrap();
+        let beta = graph.add_learnable_tensor("beta".to_string(), vec![0.0, 0.0], vec![2]).unwrap();
+        let bn = graph.add_function_call("batch_norm".to_string(), vec![x, gamma, beta]);
+        let p = graph.add_constant(0.5);
+        let drop = graph.add_function_call("dropout".to_string(), vec![bn, p]);
+        let total = graph.add_function_call("sum".to_string(), vec![drop]);
+        graph.forward_pass().unwrap();
+        let mut expected = graph.clone();
+        expected.backward_pass(total).unwrap();
+
+        let first = graph.next_node_index();
+        let (zero, one, three) = (graph.add_constant(0.0), graph.add_constant(1.0), graph.add_constant(3.0));
+        let noise = graph.add_function_call("noise_normal".to_string(), vec![zero, one, three]);
+        graph.evaluate_detached(first).unwrap();
+        assert_eq!(graph.mode(), ExecutionMode::Train);
+        assert_eq!(graph.rng_state(), expected.rng_state());
+        assert!(graph.get_node(noise).unwrap().value.is_none());
+        graph.backward_pass(total).unwrap();
+        assert_eq!(graph.running_stats(gamma), expected.running_stats(gamma));
+        assert_eq!(graph.get_node(gamma).unwrap().gradient, expected.get_node(gamma).unwrap().gradient);
+    }
+
+    #[test]
+    fn test_norm_ops_modes_and_running_stats() {
+        let mut graph = ComputationalGraph::new();
+        let x = graph.add_constant_tensor(vec![1.0, 2.0, 3.0, 6.0, 5.0, 10.0], vec![3, 2]).unwrap();
+        let gamma = graph.add_learnable_tensor("gamma".to_string(), vec![1.0, 1.0], vec![2]).unwrap();
+        let beta = graph.add_learnable_tensor("beta".to_string(), vec![0.0, 0.0], vec![2]).unwrap();
+        let bn = graph.add_function_call("batch_norm".to_string(), vec![x, gamma, beta]);
+        let ln = graph.add_function_call("layer_norm".to_string(), vec![x]);
+        let p = graph.add_constant(0.5);
+        let drop = graph.add_function_call("dropout".to_string(), vec![x, p]);
+        let total = graph.add_function_call("sum".to_string(), vec![bn]);
+
+        graph.forward_pass().unwrap();
+        let Some(Value::Tensor(t)) = graph.get_node(bn).unwrap().value.clone() else { panic!("expected tensor") };
+        assert!((t.data[0] + t.data[2] + t.data[4]).abs() < 1e-9);
+        assert!((t.data[4] - 1.5f64.sqrt()).abs() < 1e-4);
+        let Some(Value::Tensor(t)) = graph.get_node(ln).unwrap().value.clone() else { panic!("expected tensor") };
+        assert!((t.data[0] + 1.0).abs() < 1e-4 && (t.data[1] - 1.0).abs() < 1e-4);
+        let Some(Value::Tensor(t)) = graph.get_node(drop).unwrap().value.clone() else { panic!("expected tensor") };
+        for (d, v) in t.data.iter().zip([1.0, 2.0, 3.0, 6.0, 5.0, 10.0]) {
+            assert!(*d == 0.0 || (*d - 2.0 * v).abs() < 1e-12);
+        }
+
+        // Forward passes alone do not touch the running statistics; each training step does
+        graph.forward_pass().unwrap();
+        assert!(graph.running_stats(gamma).is_none());
+        graph.backward_pass(total).unwrap();
+        let (mean, var) = graph.running_stats(gamma).unwrap().clone();
+        assert!((mean[0] - 0.3).abs() < 1e-12 && (mean[1] - 0.6).abs() < 1e-12);
+        assert!((var[0] - 1.3).abs() < 1e-12 && (var[1] - 2.5).abs() < 1e-12);
+
+        // Eval mode normalizes with the frozen statistics and turns dropout into the identity
+        graph.set_mode(ExecutionMode::Eval);
+        graph.forward_pass().unwrap();
+        graph.backward_pass(total).unwrap();
+        assert_eq!(graph.running_stats(gamma).unwrap(), &(mean.clone(), var.clone()));
+        let Some(Value::Tensor(t)) = graph.get_node(bn).unwrap().value.clone() else { panic!("expected tensor") };
+        assert!((t.data[0] - (1.0 - 0.3) / (1.3f64 + 1e-5).sqrt()).abs() < 1e-12);
+        let Some(Value::Tensor(t)) = graph.get_node(drop).unwrap().value.clone() else { panic!("expected tensor") };
+        assert_eq!(t.data, vec![1.0, 2.0, 3.0, 6.0, 5.0, 10.0]);
+    }
+
+    #[test]
+    fn test_norm_ops_finite_difference() {
+        let id = NodeId::new(0);
+        let x = seq_tensor(vec![2, 2, 3], 0.4);
+        let gamma = Tensor { data: vec![1.5, -0.5], shape: vec![2] };
+        let beta = Tensor { data: vec![0.2, 0.1], shape: vec![2] };
+        let ln_gamma = Tensor { data: vec![0.5, 2.0, -1.0], shape: vec![3] };
+        let weights = seq_tensor(vec![2, 2, 3], 0.3);
+        let cases: Vec<(&str, ExecutionMode, Vec<Value>)> = vec![
+            ("layer_norm", ExecutionMode::Train, vec![Value::Tensor(x.clone())]),
+            ("layer_norm", ExecutionMode::Train, vec![Value::Tensor(x.clone()), Value::Tensor(ln_gamma), Value::Scalar(0.3)]),
+            ("batch_norm", ExecutionMode::Train, vec![Value::Tensor(x.clone()), Value::Tensor(gamma.clone()), Value::Tensor(beta.clone())]),
+            ("batch_norm", ExecutionMode::Eval, vec![Value::Tensor(x.clone()), Value::Tensor(gamma), Value::Tensor(beta)]),
+        ];
+        for (name, mode, args) in cases {
+            let mut graph = ComputationalGraph::new();
+            graph.set_mode(mode);
+            let mut objective = |args: &[Value]| -> f64 {
+                let Value::Tensor(t) = graph.eval_norm_op(id, name, args).unwrap() else { panic!("expected tensor") };
+                t.data.iter().zip(weights.data.iter()).map(|(a, w)| a * w).sum()
+            };
+            let mut numeric = Vec::new();
+            for k in 0..args.len() {
+                let len = match &args[k] { Value::Scalar(_) => 1, Value::Tensor(t) => t.data.len() };
+                let mut col = Vec::new();
+                for i in 0..len {
+                    let eps = 1e-6;
+                    let (mut plus, mut minus) = (args.clone(), args.clone());
+                    for (v, d) in [(&mut plus[k], eps), (&mut minus[k], -eps)] {
+                        match v { Value::Scalar(s) => *s += d, Value::Tensor(t) => t.data[i] += d }
+                    }
+                    col.push((objective(&plus) - objective(&minus)) / (2.0 * eps));
+                }
+                numeric.push(col);
+            }
+            graph.eval_norm_op(id, name, &args).unwrap();
+            let grads = graph.norm_op_grads(id, name, &args, &Value::Tensor(weights.clone())).unwrap();
+            for (k, col) in numeric.iter().enumerate() {
+                let analytic = match grads[k].clone().unwrap() {
+                    Value::Scalar(s) => vec![s],
+                    Value::Tensor(t) => t.data,
+                };
+                for (i, n) in col.iter().enumerate() {
+                    assert!((n - analytic[i]).abs() < 1e-5, "{} ({:?}): d/arg{}[{}] analytic {} vs numeric {}", name, mode, k, i, analytic[i], n);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_conv_ops_forward() {
+        // 1x1x3x3 input, 1x1x2x2 all-ones kernel: each output is a 2x2 window sum
+        let x = Value::Tensor(Tensor { data: (1..=9).map(|v| v as f64).collect(), shape: vec![1, 1, 3, 3] });
+        let w = Value::Tensor(Tensor { data: vec![1.0; 4], shape: vec![1, 1, 2, 2] });
+        let y = eval_conv_op("conv2d", &[x.clone(), w.clone()]).unwrap();
+        assert_eq!(y.as_tensor().unwrap().shape, vec![1, 1, 2, 2]);
+        assert_eq!(y.as_tensor().unwrap().data, vec![12.0, 16.0, 24.0, 28.0]);
+
+        // Padding 1 with stride 2 samples the corners of the padded image
+        let y = eval_conv_op("conv2d", &[x.clone(), w, Value::Scalar(2.0), Value::Scalar(1.0)]).unwrap();
+        assert_eq!(y.as_tensor().unwrap().data, vec![1.0, 5.0, 11.0, 28.0]);
+
+        let max = eval_conv_op("max_pool2d", &[x.clone(), Value::Scalar(2.0), Value::Scalar(1.0)]).unwrap();
+        assert_eq!(max.as_tensor().unwrap().data, vec![5.0, 6.0, 8.0, 9.0]);
+        let avg = eval_conv_op("avg_pool2d", &[x.clone(), Value::Scalar(3.0)]).unwrap();
+        assert_eq!(avg.as_tensor().unwrap().data, vec![5.0]);
+
+        let cols = eval_conv_op("im2col", &[x, Value::Scalar(2.0), Value::Scalar(2.0)]).unwrap();
+        let cols = cols.as_tensor().unwrap();
+        assert_eq!(cols.shape, vec![1, 4, 4]);
+        assert_eq!(&cols.data[..4], &[1.0, 2.0, 4.0, 5.0]);
+
+        let seq = Value::Tensor(Tensor { data: vec![1.0, 2.0, 3.0, 4.0, 5.0], shape: vec![1, 1, 5] });
+        let k = Value::Tensor(Tensor { data: vec![1.0, -1.0], shape: vec![1, 1, 2] });
+        let y = eval_conv_op("conv1d", &[seq, k, Value::Scalar(1.0), Value::Scalar(0.0), Value::Scalar(2.0)]).unwrap();
+        assert_eq!(y.as_tensor().unwrap().data, vec![-2.0, -2.0, -2.0]);
+
+        let bad = Value::Tensor(Tensor { data: vec![0.0; 8], shape: vec![1, 2, 2, 2] });
+        assert!(eval_conv_op("conv2d", &[bad, Value::Tensor(Tensor { data: vec![0.0; 4], shape: vec![1, 1, 2, 2] })]).is_err());
+    }
+
+    #[test]
+    fn test_conv_ops_finite_difference() {
+        let s = Value::Scalar;
+        let cases: Vec<(&str, Vec<Value>)> = vec![
+            ("conv2d", vec![Value::Tensor(seq_tensor(vec![2, 2, 5, 4], 0.1)), Value::Tensor(seq_tensor(vec![3, 2, 3, 2], 0.2))]),
+            ("conv2d", vec![Value::Tensor(seq_tensor(vec![1, 2, 6, 5], 0.1)), Value::Tensor(seq_tensor(vec![2, 2, 2, 2], 0.3)), s(2.0), s(1.0), s(2.0)]),
+            ("conv1d", vec![Value::Tensor(seq_tensor(vec![2, 3, 9], 0.1)), Value::Tensor(seq_tensor(vec![2, 3, 3], 0.2)), s(2.0), s(1.0), s(2.0)]),
+            ("max_pool2d", vec![Value::Tensor(seq_tensor(vec![1, 2, 5, 5], 0.1)), s(3.0), s(2.0), s(1.0)]),
+            ("avg_pool2d", vec![Value::Tensor(seq_tensor(vec![2, 1, 4, 6], 0.1)), s(2.0), s(1.0), s(1.0)]),
+            ("im2col", vec![Value::Tensor(seq_tensor(vec![1, 2, 4, 4], 0.1)), s(3.0), s(2.0), s(1.0), s(1.0), s(1.0)]),
+        ];
+        for (op, args) in cases {
+            let out = eval_conv_op(op, &args).unwrap();
+            let weights = seq_tensor(out.as_tensor().unwrap().shape.clone(), 0.5);
+            let objective = |args: &[Value]| -> f64 {
+                let y = eval_conv_op(op, args).unwrap();
+                y.as_tensor().unwrap().data.iter().zip(weights.data.iter()).map(|(a, w)| a * w).sum()
+            };
+            let grads = conv_op_grads(op, &args, &Value::Tensor(weights.clone())).unwrap();
+            // Check every tensor argument (input, and weights for convolutions)
+            for (k, arg) in args.iter().enumerate() {
+                let Value::Tensor(t) = arg else { continue };
+                let Some(Value::Tensor(g)) = &grads[k] else { panic!("{}: missing gradient for argument {}", op, k) };
+                for i in 0..t.data.len() {
+                    let eps = 1e-6;
+                    let mut plus = args.clone();
+                    let mut minus = args.clone();
+                    if let Value::Tensor(t) = &mut plus[k] { t.data[i] += eps; }
+                    if let Value::Tensor(t) = &mut minus[k] { t.data[i] -= eps; }
+                    let numeric = (objective(&plus) - objective(&minus)) / (2.0 * eps);
+                    assert!((numeric - g.data[i]).abs() < 1e-5, "{} arg {}[{}]: analytic {} vs numeric {}", op, k, i, g.data[i], numeric);
+                }
+            }
+        }
+    }
 }
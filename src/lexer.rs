@@ -196,6 +196,21 @@ impl Lexer {
             }
         }
 
+        // Read exponent if exists (1e-3, 2.5E+4); a bare 'e' is left for the next token
+        if !self.is_at_end() && matches!(self.peek(), 'e' | 'E') {
+            let digit_at = |pos: usize| pos < self.source.len() && self.source[pos].is_ascii_digit();
+            let sign = matches!(self.source.get(self.current + 1), Some('+') | Some('-'));
+            let digits_start = self.current + if sign { 2 } else { 1 };
+            if digit_at(digits_start) {
+                while self.current < digits_start {
+                    number.push(self.advance());
+                }
+                while !self.is_at_end() && self.peek().is_ascii_digit() {
+                    number.push(self.advance());
+                }
+            }
+        }
+
         number.parse::<f64>().map_err(|_| NomaError::InvalidNumber {
             line: start_line,
             column: start_column,
@@ -263,12 +278,22 @@ impl Lexer {
             "tensor" => TokenType::Tensor,
             "alloc" => TokenType::Alloc,
             "free" => TokenType::Free,
+            "freeze" => TokenType::Freeze,
+            "unfreeze" => TokenType::Unfreeze,
             "realloc" => TokenType::Realloc,
             "reset_optimizer" => TokenType::ResetOptimizer,
+            "seed" => TokenType::Seed,
             "load_csv" => TokenType::LoadCsv,
             "save_csv" => TokenType::SaveCsv,
             "load_safetensors" => TokenType::LoadSafetensors,
             "save_safetensors" => TokenType::SaveSafetensors,
+            "load_npy" => TokenType::LoadNpy,
+            "save_npy" => TokenType::SaveNpy,
+            "load_npz" => TokenType::LoadNpz,
+            "save_npz" => TokenType::SaveNpz,
+            "checkpoint" => TokenType::Checkpoint,
+            "resume" => TokenType::Resume,
+            "dataset" => TokenType::Dataset,
             "batch" => TokenType::Batch,
             "in" => TokenType::In,
             "as" => TokenType::As,
@@ -357,6 +382,19 @@ mod tests {
         assert_eq!(tokens[2].token_type, TokenType::Number(0.001));
     }
 
+    #[test]
+    fn test_exponent_numbers() {
+        let source = "1e-3 2.5E+4 6e2 3e x";
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.tokenize().unwrap();
+
+        assert_eq!(tokens[0].token_type, TokenType::Number(0.001));
+        assert_eq!(tokens[1].token_type, TokenType::Number(25000.0));
+        assert_eq!(tokens[2].token_type, TokenType::Number(600.0));
+        assert_eq!(tokens[3].token_type, TokenType::Number(3.0));
+        assert_eq!(tokens[4].token_type, TokenType::Identifier("e".into()));
+    }
+
     #[test]
     fn test_identifiers() {
         let source = "x my_var weight_matrix";
@@ -10,13 +10,15 @@ pub mod graph;
 pub mod llvm_codegen;
 pub mod ptx_codegen;
 pub mod nvptx_host;
+pub mod onnx;
 
 pub use lexer::Lexer;
 pub use token::{Token, TokenType};
 pub use error::NomaError;
-pub use ast::{Expression, Statement, Program, BinaryOperator, UnaryOperator, Item, FunctionDef};
+pub use ast::{Expression, Statement, Program, BinaryOperator, UnaryOperator, Item, FunctionDef, OptimizerSpec, ScheduleSpec, GradTransformSpec, ParamGroupSpec, StopSpec, CsvOptions, CsvColumn, DatasetOptions, BatchOptions};
 pub use parser::Parser;
-pub use graph::{ComputationalGraph, NodeId, NodeType, Tensor, Value, FunctionRegistry, UserFunction, OptimizerType, OptimizerConfig, OptimizerState, load_csv_file, save_csv_file, load_safetensors_file, save_safetensors_file};
+pub use graph::{ComputationalGraph, ExecutionMode, NodeId, Sampling, sampling_of, NodeType, Tensor, Value, FunctionRegistry, UserFunction, OptimizerType, OptimizerConfig, OptimizerState, LrSchedule, ScheduleKind, GradTransform, GradTransformKind, ParamGroup, StopCriterion, StopReason, StopMonitor, StopProgress, MetricsLogger, METRICS_LOG_FIELDS, Checkpoint, Dataset, BatchSchedule, BatchSource, load_csv_file, load_csv_with, save_csv_file, save_csv_file_with, seed_value, load_safetensors_file, load_safetensors_metadata, load_safetensors_tensor, safetensors_bindings, save_safetensors_file, save_safetensors_file_with, SafetensorsDtype, NamedArrays, NpyDtype, load_npy_file, save_npy_file, load_npz_file, load_npz_array, npz_bindings, save_npz_file, SafetensorsEntry, SafetensorsFile, SafetensorsHeader};
 pub use llvm_codegen::LLVMCodegen;
 pub use ptx_codegen::PTXCodegen;
 pub use nvptx_host::run_elementwise_kernel;
+pub use onnx::{export_onnx, import_onnx, import_onnx_file, OnnxModel, OnnxGraph, OnnxNode, OnnxTensor, OnnxValueInfo, OnnxAttribute, OnnxDim, OnnxDtype, ONNX_OPSET};
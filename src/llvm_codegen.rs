@@ -1,4 +1,4 @@
-use crate::graph::{ComputationalGraph, NodeId, NodeType, Value, Tensor};
+use crate::graph::{broadcast_shapes, conv_geometry, noise_params, ComputationalGraph, ConvGeom, NodeId, NodeType, Value, Tensor};
 use std::collections::{BTreeSet, HashMap};
 
 /// Represents a value in LLVM IR - either a scalar SSA value or a tensor descriptor
@@ -28,6 +28,8 @@ pub struct LLVMCodegen {
     global_constants: Vec<String>,
     /// Track which tensors need to be freed
     allocated_tensors: Vec<String>,
+    /// Stack slots hoisted into the entry block so loops do not grow the stack
+    entry_allocas: Vec<String>,
 }
 
 impl LLVMCodegen {
@@ -39,6 +41,7 @@ impl LLVMCodegen {
             extern_decls: BTreeSet::new(),
             global_constants: Vec::new(),
             allocated_tensors: Vec::new(),
+            entry_allocas: Vec::new(),
         }
     }
 
@@ -63,6 +66,13 @@ impl LLVMCodegen {
         label
     }
 
+    /// Reserve a stack slot in the entry block
+    fn entry_alloca(&mut self, ty: &str) -> String {
+        let slot = self.fresh_var();
+        self.entry_allocas.push(format!("  {} = alloca {}\n", slot, ty));
+        slot
+    }
+
     /// Create a global constant array for tensor data
     fn create_tensor_global(&mut self, data: &[f64], name_hint: &str) -> String {
         let global_name = format!("@tensor_data_{}", self.global_constants.len());
@@ -118,7 +128,7 @@ impl LLVMCodegen {
     }
 
     /// Generate element-wise unary operation on a tensor
-    fn gen_tensor_unary_op(&mut self, ir: &mut String, input: &LLVMValue, op: &str) -> Result<LLVMValue, String> {
+    fn gen_tensor_unary_op(&mut self, ir: &mut String, input: &LLVMValue, op: &str, param: Option<&str>) -> Result<LLVMValue, String> {
         let (in_ptr, shape) = match input {
             LLVMValue::Tensor { data_ptr, shape } => (data_ptr.clone(), shape.clone()),
             LLVMValue::Scalar(_) => return Err("Expected tensor for unary op".to_string()),
@@ -154,16 +164,37 @@ impl LLVMCodegen {
         let in_val = self.fresh_var();
         ir.push_str(&format!("  {} = load double, double* {}\n", in_val, in_elem_ptr));
         
-        // Apply operation
-        let out_val = match op {
+        let out_val = self.emit_unary_elem(ir, op, &in_val, param)?;
+        
+        // Store result
+        let out_elem_ptr = self.fresh_var();
+        ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", out_elem_ptr, out_ptr, idx));
+        ir.push_str(&format!("  store double {}, double* {}\n", out_val, out_elem_ptr));
+        
+        // Increment and branch back
+        let next_idx = self.fresh_var();
+        ir.push_str(&format!("  {} = add i64 {}, 1\n", next_idx, idx));
+        ir.push_str(&format!("  store i64 {}, i64* {}\n", next_idx, loop_alloca));
+        ir.push_str(&format!("  br label %{}\n", loop_header));
+        
+        // Loop end
+        ir.push_str(&format!("{}:\n", loop_end));
+        
+        Ok(LLVMValue::Tensor { data_ptr: out_ptr, shape })
+    }
+
+    /// Emit the scalar instruction(s) for one element of a unary op and return the result SSA value.
+    /// `param` carries an optional scalar hyperparameter (e.g. the leaky_relu/elu alpha).
+    fn emit_unary_elem(&mut self, ir: &mut String, op: &str, x: &str, param: Option<&str>) -> Result<String, String> {
+        let result = match op {
             "neg" => {
                 let v = self.fresh_var();
-                ir.push_str(&format!("  {} = fsub double 0.0, {}\n", v, in_val));
+                ir.push_str(&format!("  {} = fsub double 0.0, {}\n", v, x));
                 v
             }
             "sigmoid" => {
                 let neg = self.fresh_var();
-                ir.push_str(&format!("  {} = fsub double 0.0, {}\n", neg, in_val));
+                ir.push_str(&format!("  {} = fsub double 0.0, {}\n", neg, x));
                 let exp_val = self.fresh_var();
                 ir.push_str(&format!("  {} = call double @llvm.exp.f64(double {})\n", exp_val, neg));
                 let one_plus = self.fresh_var();
@@ -174,72 +205,819 @@ impl LLVMCodegen {
             }
             "relu" => {
                 let result = self.fresh_var();
-                ir.push_str(&format!("  {} = call double @llvm.maxnum.f64(double {}, double 0.0)\n", result, in_val));
+                ir.push_str(&format!("  {} = call double @llvm.maxnum.f64(double {}, double 0.0)\n", result, x));
                 result
             }
             "tanh" => {
                 let result = self.fresh_var();
-                ir.push_str(&format!("  {} = call double @llvm.tanh.f64(double {})\n", result, in_val));
+                ir.push_str(&format!("  {} = call double @llvm.tanh.f64(double {})\n", result, x));
                 result
             }
             "exp" => {
                 let result = self.fresh_var();
-                ir.push_str(&format!("  {} = call double @llvm.exp.f64(double {})\n", result, in_val));
+                ir.push_str(&format!("  {} = call double @llvm.exp.f64(double {})\n", result, x));
                 result
             }
             "log" => {
                 let result = self.fresh_var();
-                ir.push_str(&format!("  {} = call double @llvm.log.f64(double {})\n", result, in_val));
+                ir.push_str(&format!("  {} = call double @llvm.log.f64(double {})\n", result, x));
                 result
             }
             "sqrt" => {
                 let result = self.fresh_var();
-                ir.push_str(&format!("  {} = call double @llvm.sqrt.f64(double {})\n", result, in_val));
+                ir.push_str(&format!("  {} = call double @llvm.sqrt.f64(double {})\n", result, x));
                 result
             }
             "sin" => {
                 let result = self.fresh_var();
-                ir.push_str(&format!("  {} = call double @llvm.sin.f64(double {})\n", result, in_val));
+                ir.push_str(&format!("  {} = call double @llvm.sin.f64(double {})\n", result, x));
                 result
             }
             "cos" => {
                 let result = self.fresh_var();
-                ir.push_str(&format!("  {} = call double @llvm.cos.f64(double {})\n", result, in_val));
+                ir.push_str(&format!("  {} = call double @llvm.cos.f64(double {})\n", result, x));
                 result
             }
-            _ => return Err(format!("Unsupported unary tensor op: {}", op)),
+            "sign" | "not" => self.emit_mask_unary(ir, op, x),
+            "leaky_relu" | "elu" | "gelu" | "softplus" | "swish" => self.emit_activation(ir, op, x, param),
+            _ => return Err(format!("Unsupported unary op: {}", op)),
         };
-        
-        // Store result
+        Ok(result)
+    }
+
+    /// Emit the scalar instruction(s) for one element of a binary op and return the result SSA value.
+    /// Comparisons and logic ops produce 0.0/1.0 masks.
+    fn emit_binary_elem(&mut self, ir: &mut String, op: &str, l: &str, r: &str) -> Result<String, String> {
+        let fmf = if self.fast_math { " fast" } else { "" };
+        let result = self.fresh_var();
+        match op {
+            "add" => ir.push_str(&format!("  {} = fadd{} double {}, {}\n", result, fmf, l, r)),
+            "sub" => ir.push_str(&format!("  {} = fsub{} double {}, {}\n", result, fmf, l, r)),
+            "mul" => ir.push_str(&format!("  {} = fmul{} double {}, {}\n", result, fmf, l, r)),
+            "div" => ir.push_str(&format!("  {} = fdiv{} double {}, {}\n", result, fmf, l, r)),
+            "pow" => ir.push_str(&format!("  {} = call double @llvm.pow.f64(double {}, double {})\n", result, l, r)),
+            "maximum" => ir.push_str(&format!("  {} = call double @llvm.maxnum.f64(double {}, double {})\n", result, l, r)),
+            "minimum" => ir.push_str(&format!("  {} = call double @llvm.minnum.f64(double {}, double {})\n", result, l, r)),
+            "eq" | "ne" | "lt" | "gt" | "le" | "ge" => {
+                let pred = match op {
+                    "eq" => "oeq", "ne" => "une", "lt" => "olt",
+                    "gt" => "ogt", "le" => "ole", _ => "oge",
+                };
+                let cmp = self.fresh_var();
+                ir.push_str(&format!("  {} = fcmp {} double {}, {}\n", cmp, pred, l, r));
+                ir.push_str(&format!("  {} = uitofp i1 {} to double\n", result, cmp));
+            }
+            "and" | "or" => {
+                let l0 = self.fresh_var();
+                ir.push_str(&format!("  {} = fcmp une double {}, 0.0\n", l0, l));
+                let r0 = self.fresh_var();
+                ir.push_str(&format!("  {} = fcmp une double {}, 0.0\n", r0, r));
+                let pred = self.fresh_var();
+                ir.push_str(&format!("  {} = {} i1 {}, {}\n", pred, op, l0, r0));
+                ir.push_str(&format!("  {} = uitofp i1 {} to double\n", result, pred));
+            }
+            _ => return Err(format!("Unsupported binary tensor op: {}", op)),
+        }
+        Ok(result)
+    }
+
+    /// Generate element-wise where(cond, a, b) / clip(x, lo, hi). Operands broadcast against each
+    /// other like in the interpreter; scalars broadcast to any shape.
+    fn gen_tensor_ternary_op(&mut self, ir: &mut String, args: &[LLVMValue], op: &str) -> Result<LLVMValue, String> {
+        let mut shape: Option<Vec<usize>> = None;
+        for a in args {
+            if let LLVMValue::Tensor { shape: s, .. } = a {
+                shape = Some(match shape {
+                    Some(prev) => broadcast_shapes(&prev, s)
+                        .map_err(|_| format!("Tensor shape mismatch: {:?} vs {:?}", prev, s))?,
+                    None => s.clone(),
+                });
+            }
+        }
+
+        let emit = |this: &mut Self, ir: &mut String, vals: &[String]| -> String {
+            match op {
+                "where" => {
+                    let cond = this.fresh_var();
+                    ir.push_str(&format!("  {} = fcmp une double {}, 0.0\n", cond, vals[0]));
+                    let v = this.fresh_var();
+                    ir.push_str(&format!("  {} = select i1 {}, double {}, double {}\n", v, cond, vals[1], vals[2]));
+                    v
+                }
+                _ => {
+                    let lower = this.fresh_var();
+                    ir.push_str(&format!("  {} = call double @llvm.maxnum.f64(double {}, double {})\n", lower, vals[0], vals[1]));
+                    let v = this.fresh_var();
+                    ir.push_str(&format!("  {} = call double @llvm.minnum.f64(double {}, double {})\n", v, lower, vals[2]));
+                    v
+                }
+            }
+        };
+
+        let Some(shape) = shape else {
+            let vals: Vec<String> = args.iter().map(|a| match a { LLVMValue::Scalar(s) => s.clone(), _ => unreachable!() }).collect();
+            return Ok(LLVMValue::Scalar(emit(self, ir, &vals)));
+        };
+
+        let size: usize = shape.iter().product();
+        let out_ptr = self.gen_tensor_alloc(ir, size);
+
+        let loop_alloca = self.fresh_var();
+        let loop_header = self.fresh_label("ternary_header_");
+        let loop_body = self.fresh_label("ternary_body_");
+        let loop_end = self.fresh_label("ternary_end_");
+
+        ir.push_str(&format!("  {} = alloca i64\n", loop_alloca));
+        ir.push_str(&format!("  store i64 0, i64* {}\n", loop_alloca));
+        ir.push_str(&format!("  br label %{}\n", loop_header));
+
+        ir.push_str(&format!("{}:\n", loop_header));
+        let idx = self.fresh_var();
+        ir.push_str(&format!("  {} = load i64, i64* {}\n", idx, loop_alloca));
+        let cond = self.fresh_var();
+        ir.push_str(&format!("  {} = icmp slt i64 {}, {}\n", cond, idx, size));
+        ir.push_str(&format!("  br i1 {}, label %{}, label %{}\n", cond, loop_body, loop_end));
+
+        ir.push_str(&format!("{}:\n", loop_body));
+        let mut vals = Vec::with_capacity(args.len());
+        for a in args {
+            match a {
+                LLVMValue::Scalar(s) => vals.push(s.clone()),
+                LLVMValue::Tensor { data_ptr, shape: s } => {
+                    let index = self.emit_broadcast_index(ir, &idx, &shape, s);
+                    let elem_ptr = self.fresh_var();
+                    ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", elem_ptr, data_ptr, index));
+                    let v = self.fresh_var();
+                    ir.push_str(&format!("  {} = load double, double* {}\n", v, elem_ptr));
+                    vals.push(v);
+                }
+            }
+        }
+        let result = emit(self, ir, &vals);
+
         let out_elem_ptr = self.fresh_var();
         ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", out_elem_ptr, out_ptr, idx));
-        ir.push_str(&format!("  store double {}, double* {}\n", out_val, out_elem_ptr));
-        
-        // Increment and branch back
+        ir.push_str(&format!("  store double {}, double* {}\n", result, out_elem_ptr));
+
         let next_idx = self.fresh_var();
         ir.push_str(&format!("  {} = add i64 {}, 1\n", next_idx, idx));
         ir.push_str(&format!("  store i64 {}, i64* {}\n", next_idx, loop_alloca));
         ir.push_str(&format!("  br label %{}\n", loop_header));
-        
-        // Loop end
+
         ir.push_str(&format!("{}:\n", loop_end));
-        
+
         Ok(LLVMValue::Tensor { data_ptr: out_ptr, shape })
     }
 
+    /// Index of the element of a tensor of `shape` that lines up with element `idx` of its
+    /// broadcast to `out_shape`; `idx` itself when no broadcasting is needed
+    fn emit_broadcast_index(&mut self, ir: &mut String, idx: &str, out_shape: &[usize], shape: &[usize]) -> String {
+        if shape == out_shape {
+            return idx.to_string();
+        }
+        let offset = out_shape.len() - shape.len();
+        let mut index = "0".to_string();
+        let (mut out_stride, mut stride) = (1usize, 1usize);
+        for d in (0..out_shape.len()).rev() {
+            let dim = if d < offset { 1 } else { shape[d - offset] };
+            if dim != 1 {
+                let q = self.fresh_var();
+                ir.push_str(&format!("  {} = udiv i64 {}, {}\n", q, idx, out_stride));
+                let coord = self.fresh_var();
+                ir.push_str(&format!("  {} = urem i64 {}, {}\n", coord, q, out_shape[d]));
+                let term = self.fresh_var();
+                ir.push_str(&format!("  {} = mul i64 {}, {}\n", term, coord, stride));
+                let sum = self.fresh_var();
+                ir.push_str(&format!("  {} = add i64 {}, {}\n", sum, index, term));
+                index = sum;
+            }
+            out_stride *= out_shape[d];
+            stride *= dim;
+        }
+        index
+    }
+
+    /// Emit one element of leaky_relu/elu/gelu/softplus/swish. gelu uses the tanh approximation,
+    /// softplus the overflow-safe form max(x, 0) + log(1 + exp(-|x|)).
+    fn emit_activation(&mut self, ir: &mut String, op: &str, x: &str, param: Option<&str>) -> String {
+        let default_alpha = if op == "leaky_relu" { 0.01 } else { 1.0 };
+        let alpha = param.map(str::to_string).unwrap_or_else(|| self.fmt_f64(default_alpha));
+        let result = self.fresh_var();
+        match op {
+            "leaky_relu" | "elu" => {
+                let pos = self.fresh_var();
+                ir.push_str(&format!("  {} = fcmp ogt double {}, 0.0\n", pos, x));
+                let neg = if op == "leaky_relu" {
+                    let v = self.fresh_var();
+                    ir.push_str(&format!("  {} = fmul double {}, {}\n", v, alpha, x));
+                    v
+                } else {
+                    let e = self.fresh_var();
+                    ir.push_str(&format!("  {} = call double @llvm.exp.f64(double {})\n", e, x));
+                    let em1 = self.fresh_var();
+                    ir.push_str(&format!("  {} = fsub double {}, 1.0\n", em1, e));
+                    let v = self.fresh_var();
+                    ir.push_str(&format!("  {} = fmul double {}, {}\n", v, alpha, em1));
+                    v
+                };
+                ir.push_str(&format!("  {} = select i1 {}, double {}, double {}\n", result, pos, x, neg));
+            }
+            "gelu" => {
+                let x2 = self.fresh_var();
+                ir.push_str(&format!("  {} = fmul double {}, {}\n", x2, x, x));
+                let x3 = self.fresh_var();
+                ir.push_str(&format!("  {} = fmul double {}, {}\n", x3, x2, x));
+                let cubic = self.fresh_var();
+                ir.push_str(&format!("  {} = fmul double {}, {}\n", cubic, self.fmt_f64(0.044715), x3));
+                let inner = self.fresh_var();
+                ir.push_str(&format!("  {} = fadd double {}, {}\n", inner, x, cubic));
+                let u = self.fresh_var();
+                ir.push_str(&format!("  {} = fmul double {}, {}\n", u, self.fmt_f64(0.7978845608028654), inner));
+                // tanh(u) = 1 - 2 / (exp(2u) + 1), avoiding a libm tanh dependency
+                let two_u = self.fresh_var();
+                ir.push_str(&format!("  {} = fmul double 2.0, {}\n", two_u, u));
+                let e = self.fresh_var();
+                ir.push_str(&format!("  {} = call double @llvm.exp.f64(double {})\n", e, two_u));
+                let e1 = self.fresh_var();
+                ir.push_str(&format!("  {} = fadd double {}, 1.0\n", e1, e));
+                let frac = self.fresh_var();
+                ir.push_str(&format!("  {} = fdiv double 2.0, {}\n", frac, e1));
+                let t = self.fresh_var();
+                ir.push_str(&format!("  {} = fsub double 1.0, {}\n", t, frac));
+                let one_t = self.fresh_var();
+                ir.push_str(&format!("  {} = fadd double 1.0, {}\n", one_t, t));
+                let half_x = self.fresh_var();
+                ir.push_str(&format!("  {} = fmul double 0.5, {}\n", half_x, x));
+                ir.push_str(&format!("  {} = fmul double {}, {}\n", result, half_x, one_t));
+            }
+            "softplus" => {
+                let neg = self.fresh_var();
+                ir.push_str(&format!("  {} = fsub double 0.0, {}\n", neg, x));
+                let abs = self.fresh_var();
+                ir.push_str(&format!("  {} = call double @llvm.maxnum.f64(double {}, double {})\n", abs, x, neg));
+                let neg_abs = self.fresh_var();
+                ir.push_str(&format!("  {} = fsub double 0.0, {}\n", neg_abs, abs));
+                let e = self.fresh_var();
+                ir.push_str(&format!("  {} = call double @llvm.exp.f64(double {})\n", e, neg_abs));
+                let one_e = self.fresh_var();
+                ir.push_str(&format!("  {} = fadd double 1.0, {}\n", one_e, e));
+                let lg = self.fresh_var();
+                ir.push_str(&format!("  {} = call double @llvm.log.f64(double {})\n", lg, one_e));
+                let relu = self.fresh_var();
+                ir.push_str(&format!("  {} = call double @llvm.maxnum.f64(double {}, double 0.0)\n", relu, x));
+                ir.push_str(&format!("  {} = fadd double {}, {}\n", result, relu, lg));
+            }
+            _ => {
+                // swish(x) = x * sigmoid(x)
+                let neg = self.fresh_var();
+                ir.push_str(&format!("  {} = fsub double 0.0, {}\n", neg, x));
+                let e = self.fresh_var();
+                ir.push_str(&format!("  {} = call double @llvm.exp.f64(double {})\n", e, neg));
+                let one_e = self.fresh_var();
+                ir.push_str(&format!("  {} = fadd double 1.0, {}\n", one_e, e));
+                ir.push_str(&format!("  {} = fdiv double {}, {}\n", result, x, one_e));
+            }
+        }
+        result
+    }
+
+    /// Apply a unary op to a scalar or (element-wise) to a tensor
+    fn gen_unary(&mut self, ir: &mut String, input: &LLVMValue, op: &str, param: Option<&str>) -> Result<LLVMValue, String> {
+        match input {
+            LLVMValue::Scalar(x) => Ok(LLVMValue::Scalar(self.emit_unary_elem(ir, op, x, param)?)),
+            LLVMValue::Tensor { .. } => self.gen_tensor_unary_op(ir, input, op, param),
+        }
+    }
+
+    /// Open a counted loop over `0..count`; returns (index, counter slot, header label, end label)
+    fn begin_loop(&mut self, ir: &mut String, prefix: &str, count: usize) -> (String, String, String, String) {
+        let counter = self.entry_alloca("i64");
+        let header = self.fresh_label(&format!("{}_header_", prefix));
+        let body = self.fresh_label(&format!("{}_body_", prefix));
+        let end = self.fresh_label(&format!("{}_end_", prefix));
+        ir.push_str(&format!("  store i64 0, i64* {}\n", counter));
+        ir.push_str(&format!("  br label %{}\n", header));
+        ir.push_str(&format!("{}:\n", header));
+        let idx = self.fresh_var();
+        ir.push_str(&format!("  {} = load i64, i64* {}\n", idx, counter));
+        let cond = self.fresh_var();
+        ir.push_str(&format!("  {} = icmp slt i64 {}, {}\n", cond, idx, count));
+        ir.push_str(&format!("  br i1 {}, label %{}, label %{}\n", cond, body, end));
+        ir.push_str(&format!("{}:\n", body));
+        (idx, counter, header, end)
+    }
+
+    /// Close a loop opened with `begin_loop`
+    fn end_loop(&mut self, ir: &mut String, (idx, counter, header, end): (String, String, String, String)) {
+        let next = self.fresh_var();
+        ir.push_str(&format!("  {} = add i64 {}, 1\n", next, idx));
+        ir.push_str(&format!("  store i64 {}, i64* {}\n", next, counter));
+        ir.push_str(&format!("  br label %{}\n", header));
+        ir.push_str(&format!("{}:\n", end));
+    }
+
+    /// Generate a numerically stable softmax (or log-softmax) along `axis`
+    fn gen_tensor_softmax(&mut self, ir: &mut String, input: &LLVMValue, axis: usize, log: bool) -> Result<LLVMValue, String> {
+        let (in_ptr, shape) = match input {
+            LLVMValue::Tensor { data_ptr, shape } if axis < shape.len() => (data_ptr.clone(), shape.clone()),
+            _ => return Err("softmax expects a tensor and a valid axis".to_string()),
+        };
+        let dim = shape[axis];
+        let inner: usize = shape[axis + 1..].iter().product();
+        let rows = shape.iter().product::<usize>() / dim.max(1);
+        let out_ptr = self.gen_tensor_alloc(ir, rows * dim);
+
+        let max_slot = self.entry_alloca("double");
+        let sum_slot = self.entry_alloca("double");
+
+        // Row r covers elements base + k * inner, with base = (r / inner) * dim * inner + r % inner
+        let row = self.begin_loop(ir, "softmax_row", rows);
+        let r = row.0.clone();
+        let outer_idx = self.fresh_var();
+        ir.push_str(&format!("  {} = udiv i64 {}, {}\n", outer_idx, r, inner));
+        let inner_idx = self.fresh_var();
+        ir.push_str(&format!("  {} = urem i64 {}, {}\n", inner_idx, r, inner));
+        let outer_off = self.fresh_var();
+        ir.push_str(&format!("  {} = mul i64 {}, {}\n", outer_off, outer_idx, dim * inner));
+        let base = self.fresh_var();
+        ir.push_str(&format!("  {} = add i64 {}, {}\n", base, outer_off, inner_idx));
+
+        let elem_ptr = |this: &mut Self, ir: &mut String, ptr: &str, k: &str| -> String {
+            let off = this.fresh_var();
+            ir.push_str(&format!("  {} = mul i64 {}, {}\n", off, k, inner));
+            let pos = this.fresh_var();
+            ir.push_str(&format!("  {} = add i64 {}, {}\n", pos, base, off));
+            let p = this.fresh_var();
+            ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", p, ptr, pos));
+            p
+        };
+
+        // Pass 1: row maximum
+        ir.push_str(&format!("  store double 0xFFF0000000000000, double* {}\n", max_slot));
+        let lp = self.begin_loop(ir, "softmax_max", dim);
+        let p = elem_ptr(self, ir, &in_ptr, &lp.0);
+        let v = self.fresh_var();
+        ir.push_str(&format!("  {} = load double, double* {}\n", v, p));
+        let cur = self.fresh_var();
+        ir.push_str(&format!("  {} = load double, double* {}\n", cur, max_slot));
+        let m = self.fresh_var();
+        ir.push_str(&format!("  {} = call double @llvm.maxnum.f64(double {}, double {})\n", m, cur, v));
+        ir.push_str(&format!("  store double {}, double* {}\n", m, max_slot));
+        self.end_loop(ir, lp);
+        let max = self.fresh_var();
+        ir.push_str(&format!("  {} = load double, double* {}\n", max, max_slot));
+
+        // Pass 2: sum of exp(x - max)
+        ir.push_str(&format!("  store double 0.0, double* {}\n", sum_slot));
+        let lp = self.begin_loop(ir, "softmax_sum", dim);
+        let p = elem_ptr(self, ir, &in_ptr, &lp.0);
+        let v = self.fresh_var();
+        ir.push_str(&format!("  {} = load double, double* {}\n", v, p));
+        let shifted = self.fresh_var();
+        ir.push_str(&format!("  {} = fsub double {}, {}\n", shifted, v, max));
+        let e = self.fresh_var();
+        ir.push_str(&format!("  {} = call double @llvm.exp.f64(double {})\n", e, shifted));
+        let cur = self.fresh_var();
+        ir.push_str(&format!("  {} = load double, double* {}\n", cur, sum_slot));
+        let acc = self.fresh_var();
+        ir.push_str(&format!("  {} = fadd double {}, {}\n", acc, cur, e));
+        ir.push_str(&format!("  store double {}, double* {}\n", acc, sum_slot));
+        self.end_loop(ir, lp);
+        let sum = self.fresh_var();
+        ir.push_str(&format!("  {} = load double, double* {}\n", sum, sum_slot));
+        let log_sum = self.fresh_var();
+        ir.push_str(&format!("  {} = call double @llvm.log.f64(double {})\n", log_sum, sum));
+
+        // Pass 3: write exp(x - max) / sum or x - max - log(sum)
+        let lp = self.begin_loop(ir, "softmax_out", dim);
+        let p = elem_ptr(self, ir, &in_ptr, &lp.0);
+        let v = self.fresh_var();
+        ir.push_str(&format!("  {} = load double, double* {}\n", v, p));
+        let shifted = self.fresh_var();
+        ir.push_str(&format!("  {} = fsub double {}, {}\n", shifted, v, max));
+        let out = self.fresh_var();
+        if log {
+            ir.push_str(&format!("  {} = fsub double {}, {}\n", out, shifted, log_sum));
+        } else {
+            let e = self.fresh_var();
+            ir.push_str(&format!("  {} = call double @llvm.exp.f64(double {})\n", e, shifted));
+            ir.push_str(&format!("  {} = fdiv double {}, {}\n", out, e, sum));
+        }
+        let q = elem_ptr(self, ir, &out_ptr, &lp.0);
+        ir.push_str(&format!("  store double {}, double* {}\n", out, q));
+        self.end_loop(ir, lp);
+
+        self.end_loop(ir, row);
+        Ok(LLVMValue::Tensor { data_ptr: out_ptr, shape })
+    }
+
+    /// Seed drand48 with the current time; only unseeded programs draw noise at runtime
+    fn gen_rng_seed(&mut self, ir: &mut String) {
+        self.extern_decls.insert("declare void @srand48(i64)".to_string());
+        self.extern_decls.insert("declare i64 @time(i64*)".to_string());
+        let t = self.fresh_var();
+        ir.push_str(&format!("  {} = call i64 @time(i64* null)\n", t));
+        ir.push_str(&format!("  call void @srand48(i64 {})\n", t));
+    }
+
+    /// Embed a value computed by the interpreter as a constant
+    fn gen_precomputed(&mut self, ir: &mut String, val: &Value) -> LLVMValue {
+        match val {
+            Value::Tensor(tensor) => {
+                let global_name = self.create_tensor_global(&tensor.data, "precomputed");
+                let data_ptr = self.gen_tensor_copy_from_global(ir, &global_name, tensor.data.len());
+                LLVMValue::Tensor { data_ptr, shape: tensor.shape.clone() }
+            }
+            Value::Scalar(v) => {
+                let var = self.fresh_var();
+                ir.push_str(&format!("  {} = fadd double {}, 0.0\n", var, self.fmt_f64(*v)));
+                LLVMValue::Scalar(var)
+            }
+        }
+    }
+
+    /// Draw one uniform [a, b) or normal (mean a, std b, via Box-Muller) sample at runtime
+    fn emit_noise_elem(&mut self, ir: &mut String, op: &str, a: &str, b: &str) -> String {
+        self.extern_decls.insert("declare double @drand48()".to_string());
+        let u = self.fresh_var();
+        ir.push_str(&format!("  {} = call double @drand48()\n", u));
+        let result = self.fresh_var();
+        if op == "noise_uniform" {
+            let width = self.fresh_var();
+            ir.push_str(&format!("  {} = fsub double {}, {}\n", width, b, a));
+            let scaled = self.fresh_var();
+            ir.push_str(&format!("  {} = fmul double {}, {}\n", scaled, width, u));
+            ir.push_str(&format!("  {} = fadd double {}, {}\n", result, a, scaled));
+            return result;
+        }
+        // z = sqrt(-2 ln(1 - u1)) * cos(2 pi u2); 1 - u1 is in (0, 1]
+        let u2 = self.fresh_var();
+        ir.push_str(&format!("  {} = call double @drand48()\n", u2));
+        let one_minus = self.fresh_var();
+        ir.push_str(&format!("  {} = fsub double 1.0, {}\n", one_minus, u));
+        let ln = self.fresh_var();
+        ir.push_str(&format!("  {} = call double @llvm.log.f64(double {})\n", ln, one_minus));
+        let m2ln = self.fresh_var();
+        ir.push_str(&format!("  {} = fmul double -2.0, {}\n", m2ln, ln));
+        let radius = self.fresh_var();
+        ir.push_str(&format!("  {} = call double @llvm.sqrt.f64(double {})\n", radius, m2ln));
+        let angle = self.fresh_var();
+        ir.push_str(&format!("  {} = fmul double {}, {}\n", angle, self.fmt_f64(2.0 * std::f64::consts::PI), u2));
+        let cos = self.fresh_var();
+        ir.push_str(&format!("  {} = call double @llvm.cos.f64(double {})\n", cos, angle));
+        let z = self.fresh_var();
+        ir.push_str(&format!("  {} = fmul double {}, {}\n", z, radius, cos));
+        let scaled = self.fresh_var();
+        ir.push_str(&format!("  {} = fmul double {}, {}\n", scaled, b, z));
+        ir.push_str(&format!("  {} = fadd double {}, {}\n", result, a, scaled));
+        result
+    }
+
+    /// Generate per-step noise: a scalar, or a fresh tensor filled element by element
+    fn gen_noise(&mut self, ir: &mut String, op: &str, a: &str, b: &str, shape: Option<Vec<usize>>) -> LLVMValue {
+        let Some(shape) = shape else {
+            return LLVMValue::Scalar(self.emit_noise_elem(ir, op, a, b));
+        };
+        let size = shape.iter().product();
+        let out_ptr = self.gen_tensor_alloc(ir, size);
+        let lp = self.begin_loop(ir, "noise", size);
+        let v = self.emit_noise_elem(ir, op, a, b);
+        let p = self.fresh_var();
+        ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", p, out_ptr, lp.0));
+        ir.push_str(&format!("  store double {}, double* {}\n", v, p));
+        self.end_loop(ir, lp);
+        LLVMValue::Tensor { data_ptr: out_ptr, shape }
+    }
+
+    /// Generate layer normalization over the last axis, with optional scalar or per-feature gamma/beta
+    fn gen_tensor_layer_norm(&mut self, ir: &mut String, input: &LLVMValue, gamma: Option<&LLVMValue>, beta: Option<&LLVMValue>, eps: &str) -> Result<LLVMValue, String> {
+        let (in_ptr, shape) = match input {
+            LLVMValue::Tensor { data_ptr, shape } if !shape.is_empty() => (data_ptr.clone(), shape.clone()),
+            _ => return Err("layer_norm expects a tensor".to_string()),
+        };
+        let dim = shape[shape.len() - 1];
+        let rows = shape.iter().product::<usize>() / dim.max(1);
+        for param in [gamma, beta].into_iter().flatten() {
+            if let LLVMValue::Tensor { shape: s, .. } = param {
+                if s.iter().product::<usize>() != dim {
+                    return Err(format!("layer_norm gamma/beta must have {} elements, got shape {:?}", dim, s));
+                }
+            }
+        }
+        let out_ptr = self.gen_tensor_alloc(ir, rows * dim);
+        let sum_slot = self.entry_alloca("double");
+
+        let row = self.begin_loop(ir, "layernorm_row", rows);
+        let base = self.fresh_var();
+        ir.push_str(&format!("  {} = mul i64 {}, {}\n", base, row.0, dim));
+        let elem_ptr = |this: &mut Self, ir: &mut String, ptr: &str, k: &str| -> String {
+            let pos = this.fresh_var();
+            ir.push_str(&format!("  {} = add i64 {}, {}\n", pos, base, k));
+            let p = this.fresh_var();
+            ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", p, ptr, pos));
+            p
+        };
+        let param_at = |this: &mut Self, ir: &mut String, param: Option<&LLVMValue>, k: &str, default: f64| -> String {
+            match param {
+                None => this.fmt_f64(default),
+                Some(LLVMValue::Scalar(s)) => s.clone(),
+                Some(LLVMValue::Tensor { data_ptr, .. }) => {
+                    let p = this.fresh_var();
+                    ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", p, data_ptr, k));
+                    let v = this.fresh_var();
+                    ir.push_str(&format!("  {} = load double, double* {}\n", v, p));
+                    v
+                }
+            }
+        };
+
+        // Pass 1: mean; pass 2: biased variance
+        let mut stats = Vec::new();
+        for pass in ["layernorm_mean", "layernorm_var"] {
+            ir.push_str(&format!("  store double 0.0, double* {}\n", sum_slot));
+            let lp = self.begin_loop(ir, pass, dim);
+            let p = elem_ptr(self, ir, &in_ptr, &lp.0);
+            let mut v = self.fresh_var();
+            ir.push_str(&format!("  {} = load double, double* {}\n", v, p));
+            if let Some(mean) = stats.first() {
+                let d = self.fresh_var();
+                ir.push_str(&format!("  {} = fsub double {}, {}\n", d, v, mean));
+                v = self.fresh_var();
+                ir.push_str(&format!("  {} = fmul double {}, {}\n", v, d, d));
+            }
+            let cur = self.fresh_var();
+            ir.push_str(&format!("  {} = load double, double* {}\n", cur, sum_slot));
+            let acc = self.fresh_var();
+            ir.push_str(&format!("  {} = fadd double {}, {}\n", acc, cur, v));
+            ir.push_str(&format!("  store double {}, double* {}\n", acc, sum_slot));
+            self.end_loop(ir, lp);
+            let total = self.fresh_var();
+            ir.push_str(&format!("  {} = load double, double* {}\n", total, sum_slot));
+            let avg = self.fresh_var();
+            ir.push_str(&format!("  {} = fdiv double {}, {}\n", avg, total, self.fmt_f64(dim as f64)));
+            stats.push(avg);
+        }
+        let var_eps = self.fresh_var();
+        ir.push_str(&format!("  {} = fadd double {}, {}\n", var_eps, stats[1], eps));
+        let std = self.fresh_var();
+        ir.push_str(&format!("  {} = call double @llvm.sqrt.f64(double {})\n", std, var_eps));
+
+        // Pass 3: (x - mean) / std * gamma + beta
+        let lp = self.begin_loop(ir, "layernorm_out", dim);
+        let p = elem_ptr(self, ir, &in_ptr, &lp.0);
+        let v = self.fresh_var();
+        ir.push_str(&format!("  {} = load double, double* {}\n", v, p));
+        let centered = self.fresh_var();
+        ir.push_str(&format!("  {} = fsub double {}, {}\n", centered, v, stats[0]));
+        let xhat = self.fresh_var();
+        ir.push_str(&format!("  {} = fdiv double {}, {}\n", xhat, centered, std));
+        let g = param_at(self, ir, gamma, &lp.0, 1.0);
+        let b = param_at(self, ir, beta, &lp.0, 0.0);
+        let scaled = self.fresh_var();
+        ir.push_str(&format!("  {} = fmul double {}, {}\n", scaled, xhat, g));
+        let out = self.fresh_var();
+        ir.push_str(&format!("  {} = fadd double {}, {}\n", out, scaled, b));
+        let q = elem_ptr(self, ir, &out_ptr, &lp.0);
+        ir.push_str(&format!("  store double {}, double* {}\n", out, q));
+        self.end_loop(ir, lp);
+
+        self.end_loop(ir, row);
+        Ok(LLVMValue::Tensor { data_ptr: out_ptr, shape })
+    }
+
+    /// Generate the mean-reduced losses mse / huber / binary_cross_entropy from element-wise ops
+    fn gen_pointwise_loss(&mut self, ir: &mut String, op: &str, pred: &LLVMValue, target: &LLVMValue, delta: Option<&str>) -> Result<LLVMValue, String> {
+        let per_elem = match op {
+            "mse" => {
+                let d = self.gen_tensor_binary_op(ir, pred, target, "sub")?;
+                self.gen_tensor_binary_op(ir, &d, &d, "mul")?
+            }
+            "huber" => {
+                // where(|d| <= delta, 0.5 d^2, delta * (|d| - 0.5 delta))
+                let delta = LLVMValue::Scalar(delta.map(str::to_string).unwrap_or_else(|| self.fmt_f64(1.0)));
+                let half = LLVMValue::Scalar(self.fmt_f64(0.5));
+                let d = self.gen_tensor_binary_op(ir, pred, target, "sub")?;
+                let neg_d = self.gen_unary(ir, &d, "neg", None)?;
+                let abs = self.gen_tensor_binary_op(ir, &d, &neg_d, "maximum")?;
+                let inside = self.gen_tensor_binary_op(ir, &abs, &delta, "le")?;
+                let sq = self.gen_tensor_binary_op(ir, &d, &d, "mul")?;
+                let quad = self.gen_tensor_binary_op(ir, &half, &sq, "mul")?;
+                let half_delta = self.gen_tensor_binary_op(ir, &half, &delta, "mul")?;
+                let excess = self.gen_tensor_binary_op(ir, &abs, &half_delta, "sub")?;
+                let lin = self.gen_tensor_binary_op(ir, &delta, &excess, "mul")?;
+                self.gen_tensor_ternary_op(ir, &[inside, quad, lin], "where")?
+            }
+            _ => {
+                // -(t log p + (1 - t) log(1 - p)) with p clamped away from 0 and 1
+                let lo = LLVMValue::Scalar(self.fmt_f64(1e-12));
+                let hi = LLVMValue::Scalar(self.fmt_f64(1.0 - 1e-12));
+                let one = LLVMValue::Scalar(self.fmt_f64(1.0));
+                let p = self.gen_tensor_ternary_op(ir, &[pred.clone(), lo, hi], "clip")?;
+                let log_p = self.gen_unary(ir, &p, "log", None)?;
+                let q = self.gen_tensor_binary_op(ir, &one, &p, "sub")?;
+                let log_q = self.gen_unary(ir, &q, "log", None)?;
+                let neg_t = self.gen_tensor_binary_op(ir, &one, target, "sub")?;
+                let a = self.gen_tensor_binary_op(ir, target, &log_p, "mul")?;
+                let b = self.gen_tensor_binary_op(ir, &neg_t, &log_q, "mul")?;
+                let s = self.gen_tensor_binary_op(ir, &a, &b, "add")?;
+                self.gen_unary(ir, &s, "neg", None)?
+            }
+        };
+        self.gen_tensor_mean(ir, &per_elem)
+    }
+
+    /// Emit row-major linear index arithmetic for `indices` within `dims`
+    fn emit_linear_index(&mut self, ir: &mut String, indices: &[&str], dims: &[usize]) -> String {
+        let mut acc = indices[0].to_string();
+        for (idx, dim) in indices.iter().zip(dims).skip(1) {
+            let scaled = self.fresh_var();
+            ir.push_str(&format!("  {} = mul i64 {}, {}\n", scaled, acc, dim));
+            let next = self.fresh_var();
+            ir.push_str(&format!("  {} = add i64 {}, {}\n", next, scaled, idx));
+            acc = next;
+        }
+        acc
+    }
+
+    /// Emit the input coordinate read along the vertical or horizontal axis at output position `o`
+    /// and kernel tap `k`.
+    /// Returns (in-bounds flag, coordinate clamped to 0 when it falls in the padding).
+    fn emit_window_coord(&mut self, ir: &mut String, g: &ConvGeom, vertical: bool, o: &str, k: &str) -> (String, String) {
+        let (stride, dil, pad, size) = if vertical {
+            (g.stride.0, g.dil.0, g.pad.0, g.h)
+        } else {
+            (g.stride.1, g.dil.1, g.pad.1, g.w)
+        };
+        let os = self.fresh_var();
+        ir.push_str(&format!("  {} = mul i64 {}, {}\n", os, o, stride));
+        let kd = self.fresh_var();
+        ir.push_str(&format!("  {} = mul i64 {}, {}\n", kd, k, dil));
+        let sum = self.fresh_var();
+        ir.push_str(&format!("  {} = add i64 {}, {}\n", sum, os, kd));
+        let pos = self.fresh_var();
+        ir.push_str(&format!("  {} = sub i64 {}, {}\n", pos, sum, pad));
+        let lo = self.fresh_var();
+        ir.push_str(&format!("  {} = icmp sge i64 {}, 0\n", lo, pos));
+        let hi = self.fresh_var();
+        ir.push_str(&format!("  {} = icmp slt i64 {}, {}\n", hi, pos, size));
+        let inb = self.fresh_var();
+        ir.push_str(&format!("  {} = and i1 {}, {}\n", inb, lo, hi));
+        let safe = self.fresh_var();
+        ir.push_str(&format!("  {} = select i1 {}, i64 {}, i64 0\n", safe, inb, pos));
+        (inb, safe)
+    }
+
+    /// Generate the loop nest of conv1d/conv2d, max/avg pooling or im2col over NCHW data.
+    /// Padding taps are read from a clamped index and masked out with `select`.
+    fn gen_window_op(&mut self, ir: &mut String, op: &str, args: &[LLVMValue], g: &ConvGeom, out_shape: Vec<usize>) -> Result<LLVMValue, String> {
+        let x_ptr = match &args[0] {
+            LLVMValue::Tensor { data_ptr, .. } => data_ptr.clone(),
+            LLVMValue::Scalar(_) => return Err(format!("{} expects a tensor input", op)),
+        };
+        let conv = matches!(op, "conv1d" | "conv2d");
+        let (w_ptr, filters) = match args.get(1) {
+            Some(LLVMValue::Tensor { data_ptr, shape }) if conv => (data_ptr.clone(), shape[0]),
+            _ if conv => return Err(format!("{} expects a tensor weight", op)),
+            _ => (String::new(), g.c),
+        };
+        let x_dims = [g.n, g.c, g.h, g.w];
+        let out_ptr = self.gen_tensor_alloc(ir, out_shape.iter().product());
+
+        if op == "im2col" {
+            let ln = self.begin_loop(ir, "im2col_n", g.n);
+            let lc = self.begin_loop(ir, "im2col_c", g.c);
+            let lky = self.begin_loop(ir, "im2col_ky", g.kh);
+            let lkx = self.begin_loop(ir, "im2col_kx", g.kw);
+            let loy = self.begin_loop(ir, "im2col_oy", g.oh);
+            let lox = self.begin_loop(ir, "im2col_ox", g.ow);
+            let (in_y, iy) = self.emit_window_coord(ir, g, true, &loy.0, &lky.0);
+            let (in_x, ix) = self.emit_window_coord(ir, g, false, &lox.0, &lkx.0);
+            let inb = self.fresh_var();
+            ir.push_str(&format!("  {} = and i1 {}, {}\n", inb, in_y, in_x));
+            let src = self.emit_linear_index(ir, &[&ln.0, &lc.0, &iy, &ix], &x_dims);
+            let src_ptr = self.fresh_var();
+            ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", src_ptr, x_ptr, src));
+            let v = self.fresh_var();
+            ir.push_str(&format!("  {} = load double, double* {}\n", v, src_ptr));
+            let masked = self.fresh_var();
+            ir.push_str(&format!("  {} = select i1 {}, double {}, double 0.0\n", masked, inb, v));
+            // Row-major over [N, C, KH, KW, OH, OW] is exactly the [N, C*KH*KW, OH*OW] layout
+            let dst = self.emit_linear_index(ir, &[&ln.0, &lc.0, &lky.0, &lkx.0, &loy.0, &lox.0], &[g.n, g.c, g.kh, g.kw, g.oh, g.ow]);
+            let dst_ptr = self.fresh_var();
+            ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", dst_ptr, out_ptr, dst));
+            ir.push_str(&format!("  store double {}, double* {}\n", masked, dst_ptr));
+            for lp in [lox, loy, lkx, lky, lc, ln] {
+                self.end_loop(ir, lp);
+            }
+            return Ok(LLVMValue::Tensor { data_ptr: out_ptr, shape: out_shape });
+        }
+
+        let acc = self.entry_alloca("double");
+        let init = if op == "max_pool2d" { "0xFFF0000000000000" } else { "0.0" };
+        let ln = self.begin_loop(ir, "window_n", g.n);
+        let la = self.begin_loop(ir, "window_ch", filters);
+        let loy = self.begin_loop(ir, "window_oy", g.oh);
+        let lox = self.begin_loop(ir, "window_ox", g.ow);
+        ir.push_str(&format!("  store double {}, double* {}\n", init, acc));
+        let lc = if conv { Some(self.begin_loop(ir, "window_c", g.c)) } else { None };
+        let c = lc.as_ref().map(|l| l.0.clone()).unwrap_or_else(|| la.0.clone());
+        let lky = self.begin_loop(ir, "window_ky", g.kh);
+        let lkx = self.begin_loop(ir, "window_kx", g.kw);
+
+        let (in_y, iy) = self.emit_window_coord(ir, g, true, &loy.0, &lky.0);
+        let (in_x, ix) = self.emit_window_coord(ir, g, false, &lox.0, &lkx.0);
+        let inb = self.fresh_var();
+        ir.push_str(&format!("  {} = and i1 {}, {}\n", inb, in_y, in_x));
+        let src = self.emit_linear_index(ir, &[&ln.0, &c, &iy, &ix], &x_dims);
+        let src_ptr = self.fresh_var();
+        ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", src_ptr, x_ptr, src));
+        let xv = self.fresh_var();
+        ir.push_str(&format!("  {} = load double, double* {}\n", xv, src_ptr));
+        let cur = self.fresh_var();
+        ir.push_str(&format!("  {} = load double, double* {}\n", cur, acc));
+        let next = self.fresh_var();
+        match op {
+            "max_pool2d" => {
+                let m = self.fresh_var();
+                ir.push_str(&format!("  {} = call double @llvm.maxnum.f64(double {}, double {})\n", m, cur, xv));
+                ir.push_str(&format!("  {} = select i1 {}, double {}, double {}\n", next, inb, m, cur));
+            }
+            _ => {
+                let term = if conv {
+                    let w_idx = self.emit_linear_index(ir, &[&la.0, &c, &lky.0, &lkx.0], &[filters, g.c, g.kh, g.kw]);
+                    let w_elem = self.fresh_var();
+                    ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", w_elem, w_ptr, w_idx));
+                    let wv = self.fresh_var();
+                    ir.push_str(&format!("  {} = load double, double* {}\n", wv, w_elem));
+                    let prod = self.fresh_var();
+                    ir.push_str(&format!("  {} = fmul double {}, {}\n", prod, xv, wv));
+                    prod
+                } else {
+                    xv
+                };
+                let masked = self.fresh_var();
+                ir.push_str(&format!("  {} = select i1 {}, double {}, double 0.0\n", masked, inb, term));
+                ir.push_str(&format!("  {} = fadd double {}, {}\n", next, cur, masked));
+            }
+        }
+        ir.push_str(&format!("  store double {}, double* {}\n", next, acc));
+        self.end_loop(ir, lkx);
+        self.end_loop(ir, lky);
+        if let Some(lc) = lc {
+            self.end_loop(ir, lc);
+        }
+
+        let total = self.fresh_var();
+        ir.push_str(&format!("  {} = load double, double* {}\n", total, acc));
+        let result = if op == "avg_pool2d" {
+            let v = self.fresh_var();
+            ir.push_str(&format!("  {} = fdiv double {}, {}\n", v, total, self.fmt_f64((g.kh * g.kw) as f64)));
+            v
+        } else {
+            total
+        };
+        let dst = self.emit_linear_index(ir, &[&ln.0, &la.0, &loy.0, &lox.0], &[g.n, filters, g.oh, g.ow]);
+        let dst_ptr = self.fresh_var();
+        ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", dst_ptr, out_ptr, dst));
+        ir.push_str(&format!("  store double {}, double* {}\n", result, dst_ptr));
+        for lp in [lox, loy, la, ln] {
+            self.end_loop(ir, lp);
+        }
+        Ok(LLVMValue::Tensor { data_ptr: out_ptr, shape: out_shape })
+    }
+
+    /// Emit sign(x) = (x > 0) - (x < 0) or not(x) = (x == 0) for a single element
+    fn emit_mask_unary(&mut self, ir: &mut String, op: &str, x: &str) -> String {
+        if op == "not" {
+            let cmp = self.fresh_var();
+            ir.push_str(&format!("  {} = fcmp oeq double {}, 0.0\n", cmp, x));
+            let v = self.fresh_var();
+            ir.push_str(&format!("  {} = uitofp i1 {} to double\n", v, cmp));
+            return v;
+        }
+        let pos = self.fresh_var();
+        ir.push_str(&format!("  {} = fcmp ogt double {}, 0.0\n", pos, x));
+        let pos_f = self.fresh_var();
+        ir.push_str(&format!("  {} = uitofp i1 {} to double\n", pos_f, pos));
+        let neg = self.fresh_var();
+        ir.push_str(&format!("  {} = fcmp olt double {}, 0.0\n", neg, x));
+        let neg_f = self.fresh_var();
+        ir.push_str(&format!("  {} = uitofp i1 {} to double\n", neg_f, neg));
+        let v = self.fresh_var();
+        ir.push_str(&format!("  {} = fsub double {}, {}\n", v, pos_f, neg_f));
+        v
+    }
+
     /// Generate element-wise binary operation on tensors (with broadcasting support for scalar)
     fn gen_tensor_binary_op(&mut self, ir: &mut String, left: &LLVMValue, right: &LLVMValue, op: &str) -> Result<LLVMValue, String> {
-        let fmf = if self.fast_math { " fast" } else { "" };
-        
         match (left, right) {
-            // Tensor op Tensor (same shape)
+            // Tensor op Tensor (broadcast like the interpreter)
             (LLVMValue::Tensor { data_ptr: l_ptr, shape: l_shape }, 
              LLVMValue::Tensor { data_ptr: r_ptr, shape: r_shape }) => {
-                if l_shape != r_shape {
-                    return Err(format!("Tensor shape mismatch: {:?} vs {:?}", l_shape, r_shape));
-                }
+                let out_shape = broadcast_shapes(l_shape, r_shape)
+                    .map_err(|_| format!("Tensor shape mismatch: {:?} vs {:?}", l_shape, r_shape))?;
                 
-                let size: usize = l_shape.iter().product();
+                let size: usize = out_shape.iter().product();
                 let out_ptr = self.gen_tensor_alloc(ir, size);
                 
                 // Generate loop
@@ -260,24 +1038,18 @@ impl LLVMCodegen {
                 ir.push_str(&format!("  br i1 {}, label %{}, label %{}\n", cond, loop_body, loop_end));
                 
                 ir.push_str(&format!("{}:\n", loop_body));
+                let l_idx = self.emit_broadcast_index(ir, &idx, &out_shape, l_shape);
                 let l_elem_ptr = self.fresh_var();
-                ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", l_elem_ptr, l_ptr, idx));
+                ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", l_elem_ptr, l_ptr, l_idx));
                 let l_val = self.fresh_var();
                 ir.push_str(&format!("  {} = load double, double* {}\n", l_val, l_elem_ptr));
+                let r_idx = self.emit_broadcast_index(ir, &idx, &out_shape, r_shape);
                 let r_elem_ptr = self.fresh_var();
-                ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", r_elem_ptr, r_ptr, idx));
+                ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", r_elem_ptr, r_ptr, r_idx));
                 let r_val = self.fresh_var();
                 ir.push_str(&format!("  {} = load double, double* {}\n", r_val, r_elem_ptr));
                 
-                let result = self.fresh_var();
-                match op {
-                    "add" => ir.push_str(&format!("  {} = fadd{} double {}, {}\n", result, fmf, l_val, r_val)),
-                    "sub" => ir.push_str(&format!("  {} = fsub{} double {}, {}\n", result, fmf, l_val, r_val)),
-                    "mul" => ir.push_str(&format!("  {} = fmul{} double {}, {}\n", result, fmf, l_val, r_val)),
-                    "div" => ir.push_str(&format!("  {} = fdiv{} double {}, {}\n", result, fmf, l_val, r_val)),
-                    "pow" => ir.push_str(&format!("  {} = call double @llvm.pow.f64(double {}, double {})\n", result, l_val, r_val)),
-                    _ => return Err(format!("Unsupported binary tensor op: {}", op)),
-                }
+                let result = self.emit_binary_elem(ir, op, &l_val, &r_val)?;
                 
                 let out_elem_ptr = self.fresh_var();
                 ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", out_elem_ptr, out_ptr, idx));
@@ -290,7 +1062,7 @@ impl LLVMCodegen {
                 
                 ir.push_str(&format!("{}:\n", loop_end));
                 
-                Ok(LLVMValue::Tensor { data_ptr: out_ptr, shape: l_shape.clone() })
+                Ok(LLVMValue::Tensor { data_ptr: out_ptr, shape: out_shape })
             }
             // Scalar op Tensor (broadcast scalar)
             (LLVMValue::Scalar(s_var), LLVMValue::Tensor { data_ptr: t_ptr, shape }) |
@@ -327,15 +1099,7 @@ impl LLVMCodegen {
                     (t_val, s_var.clone())
                 };
                 
-                let result = self.fresh_var();
-                match op {
-                    "add" => ir.push_str(&format!("  {} = fadd{} double {}, {}\n", result, fmf, l_val, r_val)),
-                    "sub" => ir.push_str(&format!("  {} = fsub{} double {}, {}\n", result, fmf, l_val, r_val)),
-                    "mul" => ir.push_str(&format!("  {} = fmul{} double {}, {}\n", result, fmf, l_val, r_val)),
-                    "div" => ir.push_str(&format!("  {} = fdiv{} double {}, {}\n", result, fmf, l_val, r_val)),
-                    "pow" => ir.push_str(&format!("  {} = call double @llvm.pow.f64(double {}, double {})\n", result, l_val, r_val)),
-                    _ => return Err(format!("Unsupported binary tensor op: {}", op)),
-                }
+                let result = self.emit_binary_elem(ir, op, &l_val, &r_val)?;
                 
                 let out_elem_ptr = self.fresh_var();
                 ir.push_str(&format!("  {} = getelementptr double, double* {}, i64 {}\n", out_elem_ptr, out_ptr, idx));
@@ -352,15 +1116,7 @@ impl LLVMCodegen {
             }
             // Scalar op Scalar - should not reach here normally
             (LLVMValue::Scalar(l), LLVMValue::Scalar(r)) => {
-                let result = self.fresh_var();
-                match op {
-                    "add" => ir.push_str(&format!("  {} = fadd{} double {}, {}\n", result, fmf, l, r)),
-                    "sub" => ir.push_str(&format!("  {} = fsub{} double {}, {}\n", result, fmf, l, r)),
-                    "mul" => ir.push_str(&format!("  {} = fmul{} double {}, {}\n", result, fmf, l, r)),
-                    "div" => ir.push_str(&format!("  {} = fdiv{} double {}, {}\n", result, fmf, l, r)),
-                    "pow" => ir.push_str(&format!("  {} = call double @llvm.pow.f64(double {}, double {})\n", result, l, r)),
-                    _ => return Err(format!("Unsupported binary op: {}", op)),
-                }
+                let result = self.emit_binary_elem(ir, op, l, r)?;
                 Ok(LLVMValue::Scalar(result))
             }
         }
@@ -624,6 +1380,7 @@ impl LLVMCodegen {
         self.extern_decls.clear();
         self.global_constants.clear();
         self.allocated_tensors.clear();
+        self.entry_allocas.clear();
         
         let mut body_ir = String::new();
         let mut var_map: HashMap<NodeId, LLVMValue> = HashMap::new();
@@ -635,6 +1392,14 @@ impl LLVMCodegen {
 
         let mut last_value: Option<LLVMValue> = None;
 
+        // Sample-once random nodes are baked from their precomputed values. Per-step noise of a
+        // seeded program is baked too, so the binary repeats the interpreter's ChaCha draws; an
+        // unseeded program draws it at runtime from the C generator, seeded once up front
+        let seeded = graph.seed().is_some();
+        if !seeded && node_ids.iter().any(|id| matches!(&nodes[id].node_type, NodeType::FunctionCall(n) if n.starts_with("noise_"))) {
+            self.gen_rng_seed(&mut body_ir);
+        }
+
         // Process nodes in deterministic order
         for node_id in node_ids {
             let node = &nodes[&node_id];
@@ -686,7 +1451,7 @@ impl LLVMCodegen {
                     let needs_tensor_op = matches!((&left_val, &right_val), 
                         (LLVMValue::Tensor { .. }, _) | (_, LLVMValue::Tensor { .. }));
                     
-                    if needs_tensor_op && matches!(op_str.as_str(), "add" | "sub" | "mul" | "div" | "pow") {
+                    if needs_tensor_op && matches!(op_str.as_str(), "add" | "sub" | "mul" | "div" | "pow" | "eq" | "ne" | "lt" | "gt" | "le" | "ge" | "and" | "or") {
                         let result = self.gen_tensor_binary_op(&mut body_ir, &left_val, &right_val, op_str)?;
                         var_map.insert(node_id, result.clone());
                         last_value = Some(result);
@@ -735,7 +1500,7 @@ impl LLVMCodegen {
                             }
                             "eq" | "ne" | "lt" | "gt" | "le" | "ge" => {
                                 let pred = match op_str.as_str() {
-                                    "eq" => "oeq", "ne" => "one", "lt" => "olt",
+                                    "eq" => "oeq", "ne" => "une", "lt" => "olt",
                                     "gt" => "ogt", "le" => "ole", "ge" => "oge",
                                     _ => unreachable!(),
                                 };
@@ -747,9 +1512,9 @@ impl LLVMCodegen {
                             }
                             "and" => {
                                 let l0 = self.fresh_var();
-                                body_ir.push_str(&format!("  {} = fcmp one double {}, 0.0\n", l0, left_var));
+                                body_ir.push_str(&format!("  {} = fcmp une double {}, 0.0\n", l0, left_var));
                                 let r0 = self.fresh_var();
-                                body_ir.push_str(&format!("  {} = fcmp one double {}, 0.0\n", r0, right_var));
+                                body_ir.push_str(&format!("  {} = fcmp une double {}, 0.0\n", r0, right_var));
                                 let pred = self.fresh_var();
                                 body_ir.push_str(&format!("  {} = and i1 {}, {}\n", pred, l0, r0));
                                 let result_var = self.fresh_var();
@@ -758,9 +1523,9 @@ impl LLVMCodegen {
                             }
                             "or" => {
                                 let l0 = self.fresh_var();
-                                body_ir.push_str(&format!("  {} = fcmp one double {}, 0.0\n", l0, left_var));
+                                body_ir.push_str(&format!("  {} = fcmp une double {}, 0.0\n", l0, left_var));
                                 let r0 = self.fresh_var();
-                                body_ir.push_str(&format!("  {} = fcmp one double {}, 0.0\n", r0, right_var));
+                                body_ir.push_str(&format!("  {} = fcmp une double {}, 0.0\n", r0, right_var));
                                 let pred = self.fresh_var();
                                 body_ir.push_str(&format!("  {} = or i1 {}, {}\n", pred, l0, r0));
                                 let result_var = self.fresh_var();
@@ -781,7 +1546,7 @@ impl LLVMCodegen {
 
                     let result = match &arg_val {
                         LLVMValue::Tensor { .. } => {
-                            self.gen_tensor_unary_op(&mut body_ir, &arg_val, op_str)?
+                            self.gen_tensor_unary_op(&mut body_ir, &arg_val, op_str, None)?
                         }
                         LLVMValue::Scalar(arg_var) => {
                             match op_str.as_str() {
@@ -790,9 +1555,7 @@ impl LLVMCodegen {
                                     body_ir.push_str(&format!("  {} = fsub double 0.0, {}\n", v, arg_var));
                                     LLVMValue::Scalar(v)
                                 }
-                                "not" => {
-                                    return Err("NOT operator not supported in numeric code generation".to_string());
-                                }
+                                "not" => LLVMValue::Scalar(self.emit_mask_unary(&mut body_ir, "not", arg_var)),
                                 _ => return Err(format!("Unsupported unary operator: {}", op_str)),
                             }
                         }
@@ -810,7 +1573,7 @@ impl LLVMCodegen {
                             
                             match &arg_val {
                                 LLVMValue::Tensor { .. } => {
-                                    self.gen_tensor_unary_op(&mut body_ir, &arg_val, func_name)?
+                                    self.gen_tensor_unary_op(&mut body_ir, &arg_val, func_name, None)?
                                 }
                                 LLVMValue::Scalar(arg_var) => {
                                     match func_name.as_str() {
@@ -902,12 +1665,216 @@ impl LLVMCodegen {
                         }
                         "rand" => {
                             if node.inputs.len() != 0 { return Err("rand expects 0 arguments".to_string()); }
-                            self.extern_decls.insert("declare double @drand48()".to_string());
                             let v = self.fresh_var();
-                            body_ir.push_str(&format!("  {} = call double @drand48()\n", v));
+                            match node.value.as_ref().and_then(|v| v.as_scalar()) {
+                                // Bake the draw from the graph RNG so binaries reproduce the interpreter
+                                Some(sample) => body_ir.push_str(&format!("  {} = fadd double {}, 0.0\n", v, self.fmt_f64(sample))),
+                                None => {
+                                    self.extern_decls.insert("declare double @drand48()".to_string());
+                                    body_ir.push_str(&format!("  {} = call double @drand48()\n", v));
+                                }
+                            }
                             LLVMValue::Scalar(v)
                         }
-                        "print" => {
+                        "maximum" | "minimum" | "max" | "min" => {
+                            if node.inputs.len() != 2 { return Err(format!("{} expects 2 arguments", func_name)); }
+                            let a_val = var_map.get(&node.inputs[0]).ok_or("Arg a not found")?.clone();
+                            let b_val = var_map.get(&node.inputs[1]).ok_or("Arg b not found")?.clone();
+                            let op = if func_name.starts_with("max") { "maximum" } else { "minimum" };
+                            self.gen_tensor_binary_op(&mut body_ir, &a_val, &b_val, op)?
+                        }
+                        "where" | "clip" => {
+                            if node.inputs.len() != 3 { return Err(format!("{} expects 3 arguments", func_name)); }
+                            let mut args = Vec::with_capacity(3);
+                            for inp in &node.inputs {
+                                args.push(var_map.get(inp).ok_or("Argument not found")?.clone());
+                            }
+                            self.gen_tensor_ternary_op(&mut body_ir, &args, func_name)?
+                        }
+                        "sign" => {
+                            if node.inputs.len() != 1 { return Err("sign expects 1 argument".to_string()); }
+                            let arg_val = var_map.get(&node.inputs[0]).ok_or("Argument not found")?.clone();
+                            match &arg_val {
+                                LLVMValue::Scalar(arg_var) => LLVMValue::Scalar(self.emit_mask_unary(&mut body_ir, "sign", arg_var)),
+                                LLVMValue::Tensor { .. } => self.gen_tensor_unary_op(&mut body_ir, &arg_val, "sign", None)?,
+                            }
+                        }
+                        "leaky_relu" | "elu" | "gelu" | "softplus" | "swish" => {
+                            let max_args = if matches!(func_name.as_str(), "leaky_relu" | "elu") { 2 } else { 1 };
+                            if node.inputs.is_empty() || node.inputs.len() > max_args {
+                                return Err(format!("{} expects at most {} argument(s)", func_name, max_args));
+                            }
+                            let arg_val = var_map.get(&node.inputs[0]).ok_or("Argument not found")?.clone();
+                            let alpha = match node.inputs.get(1).map(|inp| var_map.get(inp)) {
+                                Some(Some(LLVMValue::Scalar(a))) => Some(a.clone()),
+                                Some(_) => return Err(format!("{} alpha must be a scalar", func_name)),
+                                None => None,
+                            };
+                            self.gen_unary(&mut body_ir, &arg_val, func_name, alpha.as_deref())?
+                        }
+                        "softmax" | "log_softmax" => {
+                            let arg_val = var_map.get(&node.inputs[0]).ok_or("Argument not found")?.clone();
+                            let rank = match &arg_val {
+                                LLVMValue::Tensor { shape, .. } => shape.len(),
+                                LLVMValue::Scalar(_) => return Err(format!("{} expects a tensor", func_name)),
+                            };
+                            // The axis must be a compile-time constant; use its precomputed value
+                            let axis = match node.inputs.get(1) {
+                                Some(inp) => {
+                                    let a = nodes[inp].value.as_ref().and_then(|v| v.as_scalar()).ok_or("softmax axis must be a constant scalar")? as i64;
+                                    if a < 0 { (a + rank as i64) as usize } else { a as usize }
+                                }
+                                None => rank - 1,
+                            };
+                            self.gen_tensor_softmax(&mut body_ir, &arg_val, axis, func_name == "log_softmax")?
+                        }
+                        "noise_uniform" | "noise_normal" if seeded && node.value.is_some() => {
+                            self.gen_precomputed(&mut body_ir, node.value.as_ref().unwrap())
+                        }
+                        "noise_uniform" | "noise_normal" => {
+                            // The shape is static; the distribution parameters may be runtime scalars
+                            let args = node.inputs.iter()
+                                .map(|inp| nodes[inp].value.clone().ok_or(format!("{} arguments must have precomputed values", func_name)))
+                                .collect::<Result<Vec<_>, _>>()?;
+                            let (_, _, shape) = noise_params(func_name, &args)?;
+                            let mut params = Vec::with_capacity(2);
+                            for inp in &node.inputs[..2] {
+                                match var_map.get(inp) {
+                                    Some(LLVMValue::Scalar(s)) => params.push(s.clone()),
+                                    _ => return Err(format!("{} parameters must be scalars", func_name)),
+                                }
+                            }
+                            self.gen_noise(&mut body_ir, func_name, &params[0], &params[1], shape)
+                        }
+                        "dropout" => {
+                            // Compiled code runs in inference mode, where dropout is the identity
+                            if node.inputs.len() != 2 { return Err("dropout expects 2 arguments (x, p)".to_string()); }
+                            var_map.get(&node.inputs[0]).ok_or("Argument not found")?.clone()
+                        }
+                        "layer_norm" => {
+                            if !matches!(node.inputs.len(), 1 | 3 | 4) {
+                                return Err("layer_norm expects (x), (x, gamma, beta) or (x, gamma, beta, eps)".to_string());
+                            }
+                            let arg_val = var_map.get(&node.inputs[0]).ok_or("Argument not found")?.clone();
+                            let gamma = node.inputs.get(1).map(|inp| var_map.get(inp).cloned().ok_or("layer_norm gamma not found")).transpose()?;
+                            let beta = node.inputs.get(2).map(|inp| var_map.get(inp).cloned().ok_or("layer_norm beta not found")).transpose()?;
+                            let eps = match node.inputs.get(3).map(|inp| var_map.get(inp)) {
+                                Some(Some(LLVMValue::Scalar(e))) => e.clone(),
+                                Some(_) => return Err("layer_norm eps must be a scalar".to_string()),
+                                None => self.fmt_f64(1e-5),
+                            };
+                            self.gen_tensor_layer_norm(&mut body_ir, &arg_val, gamma.as_ref(), beta.as_ref(), &eps)?
+                        }
+                        "batch_norm" => {
+                            // Inference mode: the running statistics and affine parameters fold
+                            // into constant per-element scale and shift tensors
+                            let arg_val = var_map.get(&node.inputs[0]).ok_or("Argument not found")?.clone();
+                            let shape = match &arg_val {
+                                LLVMValue::Tensor { shape, .. } => shape.clone(),
+                                LLVMValue::Scalar(_) => return Err("batch_norm expects a tensor".to_string()),
+                            };
+                            let args = node.inputs.iter()
+                                .map(|inp| nodes[inp].value.clone().ok_or("batch_norm parameters must have precomputed values"))
+                                .collect::<Result<Vec<_>, _>>()?;
+                            let (scale, shift) = graph.batch_norm_affine(node_id, &args)?;
+                            let mut folded = Vec::new();
+                            for (data, hint) in [(scale, "bn_scale"), (shift, "bn_shift")] {
+                                let global_name = self.create_tensor_global(&data, hint);
+                                let data_ptr = self.gen_tensor_copy_from_global(&mut body_ir, &global_name, data.len());
+                                folded.push(LLVMValue::Tensor { data_ptr, shape: shape.clone() });
+                            }
+                            let scaled = self.gen_tensor_binary_op(&mut body_ir, &arg_val, &folded[0], "mul")?;
+                            self.gen_tensor_binary_op(&mut body_ir, &scaled, &folded[1], "add")?
+                        }
+                        "mse" | "huber" | "binary_cross_entropy" => {
+                            let max_args = if func_name == "huber" { 3 } else { 2 };
+                            if node.inputs.len() < 2 || node.inputs.len() > max_args {
+                                return Err(format!("{} expects 2{} arguments", func_name, if max_args == 3 { " or 3" } else { "" }));
+                            }
+                            let pred = var_map.get(&node.inputs[0]).ok_or("Prediction not found")?.clone();
+                            let target = var_map.get(&node.inputs[1]).ok_or("Target not found")?.clone();
+                            let delta = match node.inputs.get(2).map(|inp| var_map.get(inp)) {
+                                Some(Some(LLVMValue::Scalar(d))) => Some(d.clone()),
+                                Some(_) => return Err("huber delta must be a scalar".to_string()),
+                                None => None,
+                            };
+                            self.gen_pointwise_loss(&mut body_ir, func_name, &pred, &target, delta.as_deref())?
+                        }
+                        "cross_entropy" => {
+                            if node.inputs.len() != 2 { return Err("cross_entropy expects 2 arguments".to_string()); }
+                            let logits = var_map.get(&node.inputs[0]).ok_or("Logits not found")?.clone();
+                            let shape = match &logits {
+                                LLVMValue::Tensor { shape, .. } => shape.clone(),
+                                LLVMValue::Scalar(_) => return Err("cross_entropy expects tensor logits".to_string()),
+                            };
+                            let classes = shape[shape.len() - 1];
+                            let rows = shape.iter().product::<usize>() / classes.max(1);
+                            let log_probs = self.gen_tensor_softmax(&mut body_ir, &logits, shape.len() - 1, true)?;
+                            let targets = match var_map.get(&node.inputs[1]).ok_or("Labels not found")? {
+                                LLVMValue::Tensor { shape: s, .. } if *s == shape => var_map[&node.inputs[1]].clone(),
+                                _ => {
+                                    // Class indices: expand the precomputed labels into a one-hot constant
+                                    let labels = nodes[&node.inputs[1]].value.clone().ok_or("cross_entropy labels have no value")?;
+                                    let indices = match labels {
+                                        Value::Scalar(c) => vec![c],
+                                        Value::Tensor(t) => t.data,
+                                    };
+                                    if indices.len() != rows { return Err("cross_entropy labels do not match logits rows".to_string()); }
+                                    let mut one_hot = vec![0.0; rows * classes];
+                                    for (r, c) in indices.iter().enumerate() {
+                                        if *c < 0.0 || *c as usize >= classes { return Err(format!("cross_entropy label {} out of range", c)); }
+                                        one_hot[r * classes + *c as usize] = 1.0;
+                                    }
+                                    let global_name = self.create_tensor_global(&one_hot, "labels");
+                                    let data_ptr = self.gen_tensor_copy_from_global(&mut body_ir, &global_name, one_hot.len());
+                                    LLVMValue::Tensor { data_ptr, shape: shape.clone() }
+                                }
+                            };
+                            let weighted = self.gen_tensor_binary_op(&mut body_ir, &targets, &log_probs, "mul")?;
+                            let total = match self.gen_tensor_sum(&mut body_ir, &weighted)? {
+                                LLVMValue::Scalar(s) => s,
+                                _ => return Err("Expected scalar from sum".to_string()),
+                            };
+                            let v = self.fresh_var();
+                            body_ir.push_str(&format!("  {} = fdiv double {}, {}\n", v, total, self.fmt_f64(-(rows as f64))));
+                            LLVMValue::Scalar(v)
+                        }
+                        "conv1d" | "conv2d" | "max_pool2d" | "avg_pool2d" | "im2col" => {
+                            // Shapes and hyperparameters are static; read them from the precomputed values
+                            let mut values = Vec::with_capacity(node.inputs.len());
+                            let mut args = Vec::with_capacity(node.inputs.len());
+                            for inp in &node.inputs {
+                                values.push(nodes[inp].value.clone().ok_or_else(|| format!("{} argument has no value", func_name))?);
+                                args.push(var_map.get(inp).ok_or("Argument not found")?.clone());
+                            }
+                            let geom = conv_geometry(func_name, &values)?;
+                            let out_shape = match &node.value {
+                                Some(Value::Tensor(t)) => t.shape.clone(),
+                                _ => return Err(format!("{} has no precomputed output shape", func_name)),
+                            };
+                            self.gen_window_op(&mut body_ir, func_name, &args, &geom, out_shape)?
+                        }
+                        "reshape" | "squeeze" | "unsqueeze" => {
+                            // Row-major data is unchanged; only the tracked shape differs
+                            let arg_val = var_map.get(&node.inputs[0]).ok_or("Argument not found")?.clone();
+                            match (arg_val, &node.value) {
+                                (LLVMValue::Tensor { data_ptr, .. }, Some(Value::Tensor(out))) => {
+                                    LLVMValue::Tensor { data_ptr, shape: out.shape.clone() }
+                                }
+                                (LLVMValue::Tensor { data_ptr, .. }, Some(Value::Scalar(_))) => {
+                                    let v = self.fresh_var();
+                                    body_ir.push_str(&format!("  {} = load double, double* {}\n", v, data_ptr));
+                                    LLVMValue::Scalar(v)
+                                }
+                                (LLVMValue::Scalar(_), Some(Value::Tensor(out))) => {
+                                    let global_name = self.create_tensor_global(&out.data, "precomputed");
+                                    let data_ptr = self.gen_tensor_copy_from_global(&mut body_ir, &global_name, out.data.len());
+                                    LLVMValue::Tensor { data_ptr, shape: out.shape.clone() }
+                                }
+                                (arg_val, _) => arg_val,
+                            }
+                        }
+                        "print" | "log_metric" => {
                             // print is a no-op in compiled code for now
                             if node.inputs.len() != 1 { return Err("print expects 1 argument".to_string()); }
                             let arg_val = var_map.get(&node.inputs[0]).ok_or("Argument not found")?.clone();
@@ -917,18 +1884,7 @@ impl LLVMCodegen {
                             // First check if this node has a pre-computed value (e.g., rand_normal_tensor, he_init, etc.)
                             // These are evaluated during forward_pass() and their results are stored in node.value
                             if let Some(val) = &node.value {
-                                match val {
-                                    Value::Tensor(tensor) => {
-                                        let global_name = self.create_tensor_global(&tensor.data, "precomputed");
-                                        let data_ptr = self.gen_tensor_copy_from_global(&mut body_ir, &global_name, tensor.data.len());
-                                        LLVMValue::Tensor { data_ptr, shape: tensor.shape.clone() }
-                                    }
-                                    Value::Scalar(v) => {
-                                        let var = self.fresh_var();
-                                        body_ir.push_str(&format!("  {} = fadd double {}, 0.0\n", var, self.fmt_f64(*v)));
-                                        LLVMValue::Scalar(var)
-                                    }
-                                }
+                                self.gen_precomputed(&mut body_ir, val)
                             } else {
                                 // Unknown function without pre-computed value - try to call as external
                                 let mut arg_scalars = Vec::new();
@@ -1001,6 +1957,9 @@ impl LLVMCodegen {
 
         // Generate compute function
         ir.push_str("define double @compute() {\nentry:\n");
+        for slot in &self.entry_allocas {
+            ir.push_str(slot);
+        }
         ir.push_str(&body_ir);
 
         // Return value - extract scalar if it's a tensor (e.g., return first element or sum)
@@ -1033,6 +1992,7 @@ impl LLVMCodegen {
         ir.push_str("declare double @llvm.pow.f64(double, double)\n");
         ir.push_str("declare double @llvm.exp.f64(double)\n");
         ir.push_str("declare double @llvm.maxnum.f64(double, double)\n");
+        ir.push_str("declare double @llvm.minnum.f64(double, double)\n");
         ir.push_str("declare double @llvm.sin.f64(double)\n");
         ir.push_str("declare double @llvm.cos.f64(double)\n");
         ir.push_str("declare double @llvm.log.f64(double)\n");
@@ -1054,6 +2014,7 @@ impl LLVMCodegen {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::graph::ExecutionMode;
 
     #[test]
     fn test_llvm_constant() {
@@ -1094,4 +2055,142 @@ mod tests {
         assert!(ir.contains("@tensor_data_"));
         assert!(ir.contains("@malloc"));
     }
+
+    #[test]
+    fn test_llvm_tensor_masks_and_where() {
+        let mut graph = ComputationalGraph::new();
+        let t = graph.add_constant_tensor(vec![-1.0, 0.5, 2.0], vec![3]).unwrap();
+        let zero = graph.add_constant(0.0);
+        let one = graph.add_constant(1.0);
+        let mask = graph.add_binary_op("gt", t, zero);
+        let w = graph.add_function_call("where".to_string(), vec![mask, t, zero]);
+        let _c = graph.add_function_call("clip".to_string(), vec![w, zero, one]);
+
+        let mut codegen = LLVMCodegen::new();
+        let ir = codegen.generate(&graph).expect("IR generation failed");
+
+        assert!(ir.contains("fcmp ogt double"));
+        assert!(ir.contains("select i1"));
+        assert!(ir.contains("@llvm.minnum.f64"));
+    }
+
+    #[test]
+    fn test_llvm_softmax_and_losses() {
+        let mut graph = ComputationalGraph::new();
+        let logits = graph.add_constant_tensor(vec![1.0, 2.0, 3.0, 0.5, -1.0, 4.0], vec![2, 3]).unwrap();
+        let labels = graph.add_constant_tensor(vec![2.0, 0.0], vec![2]).unwrap();
+        let ce = graph.add_function_call("cross_entropy".to_string(), vec![logits, labels]);
+        let act = graph.add_function_call("gelu".to_string(), vec![ce]);
+        let _loss = graph.add_function_call("huber".to_string(), vec![act, labels]);
+        graph.forward_pass().unwrap();
+
+        let mut codegen = LLVMCodegen::new();
+        let ir = codegen.generate(&graph).expect("IR generation failed");
+
+        assert!(ir.contains("softmax_max_header_"));
+        assert!(ir.contains("0xFFF0000000000000"));
+        assert!(!ir.contains("call double @llvm.tanh.f64"));
+        assert!(ir.contains("select i1"));
+    }
+
+    #[test]
+    fn test_llvm_rand_uses_graph_draw() {
+        let mut graph = ComputationalGraph::new();
+        graph.set_seed(42);
+        let r = graph.add_function_call("rand".to_string(), vec![]);
+        graph.forward_pass().unwrap();
+        let sample = graph.get_node(r).unwrap().value.clone().unwrap().as_scalar().unwrap();
+
+        let mut codegen = LLVMCodegen::new();
+        let ir = codegen.generate(&graph).expect("IR generation failed");
+
+        assert!(!ir.contains("drand48"));
+        assert!(ir.contains(&codegen.fmt_f64(sample)));
+    }
+
+    #[test]
+    fn test_llvm_noise_drawn_at_runtime() {
+        let mut graph = ComputationalGraph::new();
+        let (zero, one, four) = (graph.add_constant(0.0), graph.add_constant(1.0), graph.add_constant(4.0));
+        let init = graph.add_function_call("rand_normal_tensor".to_string(), vec![zero, one, four]);
+        let noise = graph.add_function_call("noise_normal".to_string(), vec![zero, one, four]);
+        let _sum = graph.add_binary_op("add", init, noise);
+        graph.forward_pass().unwrap();
+
+        let mut codegen = LLVMCodegen::new();
+        let ir = codegen.generate(&graph).expect("IR generation failed");
+
+        assert!(ir.contains("call i64 @time(i64* null)"));
+        assert!(ir.contains("noise_header_"));
+        assert!(ir.contains("call double @llvm.cos.f64"));
+        // The initializer is baked, so the only draws are the two Box-Muller uniforms
+        assert_eq!(ir.matches("call double @drand48()").count(), 2);
+    }
+
+    #[test]
+    fn test_llvm_seeded_noise_baked() {
+        let mut graph = ComputationalGraph::new();
+        graph.set_seed(7);
+        let (zero, one, three) = (graph.add_constant(0.0), graph.add_constant(1.0), graph.add_constant(3.0));
+        let noise = graph.add_function_call("noise_normal".to_string(), vec![zero, one, three]);
+        let jitter = graph.add_function_call("noise_uniform".to_string(), vec![zero, one]);
+        let _sum = graph.add_binary_op("add", noise, jitter);
+        graph.forward_pass().unwrap();
+
+        let mut codegen = LLVMCodegen::new();
+        let ir = codegen.generate(&graph).expect("IR generation failed");
+
+        // A seeded binary repeats the interpreter's draws instead of sampling drand48
+        assert!(!ir.contains("drand48"));
+        let sample = graph.get_node(jitter).unwrap().value.clone().unwrap().as_scalar().unwrap();
+        assert!(ir.contains(&codegen.fmt_f64(sample)));
+        assert!(ir.contains("constant [3 x double]"));
+    }
+
+    #[test]
+    fn test_llvm_norm_ops_inference() {
+        let mut graph = ComputationalGraph::new();
+        let x = graph.add_constant_tensor(vec![1.0, 2.0, 3.0, 6.0], vec![2, 2]).unwrap();
+        let gamma = graph.add_constant_tensor(vec![2.0, 0.5], vec![2]).unwrap();
+        let beta = graph.add_constant(1.0);
+        let ln = graph.add_function_call("layer_norm".to_string(), vec![x, gamma, beta]);
+        let p = graph.add_constant(0.5);
+        let d = graph.add_function_call("dropout".to_string(), vec![ln, p]);
+        let bn = graph.add_function_call("batch_norm".to_string(), vec![d, gamma, beta]);
+        let _s = graph.add_function_call("sum".to_string(), vec![bn]);
+        graph.set_mode(ExecutionMode::Eval);
+        graph.forward_pass().unwrap();
+
+        let mut codegen = LLVMCodegen::new();
+        let ir = codegen.generate(&graph).expect("IR generation failed");
+
+        assert!(ir.contains("layernorm_var_header_"));
+        assert!(ir.contains("call double @llvm.sqrt.f64"));
+        // Input plus the folded batch_norm scale and shift tensors
+        assert_eq!(ir.matches("constant [4 x double]").count(), 3);
+    }
+
+    #[test]
+    fn test_llvm_conv_and_pool_loops() {
+        let mut graph = ComputationalGraph::new();
+        let x = graph.add_constant_tensor((0..32).map(|v| v as f64).collect(), vec![1, 2, 4, 4]).unwrap();
+        let w = graph.add_constant_tensor(vec![0.5; 16], vec![2, 2, 2, 2]).unwrap();
+        let stride = graph.add_constant(1.0);
+        let pad = graph.add_constant(1.0);
+        let y = graph.add_function_call("conv2d".to_string(), vec![x, w, stride, pad]);
+        let kernel = graph.add_constant(2.0);
+        let p = graph.add_function_call("max_pool2d".to_string(), vec![y, kernel]);
+        let _s = graph.add_function_call("sum".to_string(), vec![p]);
+        graph.forward_pass().unwrap();
+
+        let mut codegen = LLVMCodegen::new();
+        let ir = codegen.generate(&graph).expect("IR generation failed");
+
+        assert!(ir.contains("window_kx_header_"));
+        assert!(ir.contains("icmp sge i64"));
+        // Loop counters and accumulators are hoisted out of the loop nest
+        let start = ir.find("window_n_header_").unwrap();
+        let end = ir.rfind("window_n_end_").unwrap();
+        assert_eq!(ir[start..end].matches("alloca").count(), 0);
+    }
 }
@@ -1,8 +1,9 @@
 use clap::{Parser, Subcommand};
-use noma_compiler::{Lexer, Parser as NomaParser, ComputationalGraph, LLVMCodegen, PTXCodegen, FunctionRegistry, OptimizerType, OptimizerConfig, OptimizerState};
+use noma_compiler::{Lexer, Parser as NomaParser, ComputationalGraph, ExecutionMode, LLVMCodegen, PTXCodegen, FunctionRegistry, OptimizerType, OptimizerConfig, OptimizerState};
 use std::fs;
 use std::path::PathBuf;
 use std::collections::HashMap;
+use std::rc::Rc;
 use std::process::Command;
 use std::env;
 
@@ -32,6 +33,49 @@ fn collect_functions(ast: &noma_compiler::Program) -> (FunctionRegistry, Option<
     (func_registry, main_func)
 }
 
+/// State shared by every optimize loop of one program run
+struct Session {
+    /// Optimizer moments and step count, carried across optimize loops
+    optimizer_state: OptimizerState,
+    /// `--log-file` telemetry
+    metrics_log: Option<noma_compiler::MetricsLogger>,
+    /// Optimize loops started so far; the `loop` field of log records
+    loops: usize,
+    /// `--resume` checkpoint (with its path), applied when execution reaches its position
+    resume: Option<(String, noma_compiler::Checkpoint)>,
+    /// `checkpoint(path, every)` statements of the running optimize loop
+    checkpoints: Vec<(String, Option<usize>)>,
+    /// Steps of the running optimize loop done before the resumed checkpoint
+    resumed_steps: usize,
+    /// Datasets declared with `dataset name = "path";`, streamed by batch loops
+    datasets: HashMap<String, Rc<noma_compiler::Dataset>>,
+    /// The hyperparameter-variable deprecation warning was printed
+    warned_hyperparams: bool,
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            optimizer_state: OptimizerState::new(),
+            metrics_log: None,
+            loops: 0,
+            resume: None,
+            checkpoints: Vec::new(),
+            resumed_steps: 0,
+            datasets: HashMap::new(),
+            warned_hyperparams: false,
+        }
+    }
+
+    /// Restore `checkpoint` into the graph and the optimizer state
+    fn restore(&mut self, graph: &mut ComputationalGraph, path: &str, checkpoint: &noma_compiler::Checkpoint) -> Result<(), String> {
+        for name in checkpoint.restore(graph, &mut self.optimizer_state)? {
+            eprintln!("Warning: checkpoint '{}' has parameter '{}', which is not a learnable here; skipped", path, name);
+        }
+        Ok(())
+    }
+}
+
 /// Shared function to lower statements into the computational graph with user function support
 fn lower_statements_shared(
     graph: &mut ComputationalGraph,
@@ -39,9 +83,10 @@ fn lower_statements_shared(
     stmts: &[noma_compiler::Statement],
     last_node: &mut Option<noma_compiler::NodeId>,
     func_registry: &FunctionRegistry,
-    optimizer_state: &mut OptimizerState,
+    session: &mut Session,
 ) -> Result<(), String> {
     for stmt in stmts {
+        graph.begin_statement();
         match stmt {
             noma_compiler::Statement::LearnDeclaration { name, value } => {
                 match value {
@@ -100,7 +145,7 @@ fn lower_statements_shared(
                 }
             }
             noma_compiler::Statement::Block(inner) => {
-                lower_statements_shared(graph, variables, inner, last_node, func_registry, optimizer_state)?;
+                lower_statements_shared(graph, variables, inner, last_node, func_registry, session)?;
             }
             noma_compiler::Statement::If { condition, then_branch, else_branch } => {
                 let cond_id = graph.build_from_expression_with_functions(condition, variables, func_registry)?;
@@ -110,9 +155,9 @@ fn lower_statements_shared(
                     .and_then(|v| match v { noma_compiler::Value::Scalar(s) => Some(s), _ => None })
                     .unwrap_or(0.0);
                 if cond_val != 0.0 {
-                    lower_statements_shared(graph, variables, then_branch, last_node, func_registry, optimizer_state)?;
+                    lower_statements_shared(graph, variables, then_branch, last_node, func_registry, session)?;
                 } else {
-                    lower_statements_shared(graph, variables, else_branch, last_node, func_registry, optimizer_state)?;
+                    lower_statements_shared(graph, variables, else_branch, last_node, func_registry, session)?;
                 }
             }
             noma_compiler::Statement::While { condition, body } => {
@@ -124,19 +169,106 @@ fn lower_statements_shared(
                         .and_then(|v| match v { noma_compiler::Value::Scalar(s) => Some(s), _ => None })
                         .unwrap_or(0.0);
                     if cond_val == 0.0 { break; }
-                    lower_statements_shared(graph, variables, body, last_node, func_registry, optimizer_state)?;
+                    lower_statements_shared(graph, variables, body, last_node, func_registry, session)?;
                 }
             }
-            noma_compiler::Statement::OptimizeLoop { target, condition, body, .. } => {
+            noma_compiler::Statement::OptimizeLoop {
+                targets, optimizer, schedule, grad_transforms, param_groups, stop_criteria, max_iter, condition, stats, body,
+            } => {
+                let targets = &graph.expand_learnable_names(targets);
+                // checkpoint() in the body saves during the loop instead of once at lowering time
+                let (checkpoints, body): (Vec<_>, Vec<_>) = body.iter().cloned()
+                    .partition(|s| matches!(s, noma_compiler::Statement::Checkpoint { .. }));
                 // Lower body first so condition can reference values like `loss`
                 let mut loop_last: Option<noma_compiler::NodeId> = None;
-                lower_statements_shared(graph, variables, body, &mut loop_last, func_registry, optimizer_state)?;
+                lower_statements_shared(graph, variables, &body, &mut loop_last, func_registry, session)?;
                 let objective = loop_last.or(*last_node).ok_or_else(|| "Optimize loop body produced no expressions".to_string())?;
-                let cond_id = graph.build_from_expression_with_functions(condition, variables, func_registry)?;
+                let cond_id = condition.as_ref()
+                    .map(|condition| graph.build_from_expression_with_functions(condition, variables, func_registry))
+                    .transpose()?;
+                let mut criteria = Vec::new();
+                let mut monitor_id = None;
+                for spec in stop_criteria {
+                    criteria.push(noma_compiler::StopCriterion::from_spec(spec)?);
+                    if let Some(expr) = &spec.monitor {
+                        monitor_id = Some(graph.build_from_expression_with_functions(expr, variables, func_registry)?);
+                    }
+                }
+                let mut monitor = noma_compiler::StopMonitor::new(criteria, monitor_id);
+
+                // An explicit `with` ignores the hyperparameter variables entirely; without it the
+                // optimizer keeps the legacy defaults, and `max_iter` overrides only the iterations
+                let (mut config, iters) = match optimizer {
+                    Some(spec) => (OptimizerConfig::from_spec(spec)?, max_iter.unwrap_or(1000)),
+                    None => {
+                        warn_hyperparam_variables(session, variables, &targets.join(", "));
+                        let (config, iters) = pick_hyperparams(graph, variables, 0.1, 1000);
+                        (config, max_iter.unwrap_or(iters))
+                    }
+                };
+                if let Some(spec) = schedule {
+                    config.schedule = noma_compiler::LrSchedule::from_spec(spec)?;
+                }
+                config.grad_transforms = grad_transforms.iter()
+                    .map(noma_compiler::GradTransform::from_spec)
+                    .collect::<Result<_, _>>()?;
+                config.param_groups = param_groups.iter()
+                    .map(noma_compiler::ParamGroup::from_spec)
+                    .collect::<Result<_, _>>()?;
+                config.params = targets.clone();
+
+                // --resume: loops finished before the checkpoint are skipped, and the loop it was
+                // written in continues from its step
+                session.loops += 1;
+                session.resumed_steps = 0;
+                let mut skip = false;
+                if let Some((path, checkpoint)) = session.resume.take() {
+                    if session.loops <= checkpoint.loops_done {
+                        eprintln!("Resuming from {}: skipping optimize loop {}, finished before the checkpoint", path, session.loops);
+                        skip = true;
+                        // A checkpoint written partway through the next loop stays pending for it
+                        if session.loops == checkpoint.loops_done && checkpoint.loop_step == 0 {
+                            session.restore(graph, &path, &checkpoint)?;
+                        } else {
+                            session.resume = Some((path, checkpoint));
+                        }
+                    } else {
+                        session.restore(graph, &path, &checkpoint)?;
+                        session.resumed_steps = checkpoint.loop_step;
+                        if let Some(progress) = &checkpoint.stop {
+                            monitor.resume(progress);
+                        }
+                    }
+                }
+                session.checkpoints = checkpoints.into_iter().filter_map(|s| match s {
+                    noma_compiler::Statement::Checkpoint { path, every } => Some((path, every)),
+                    _ => None,
+                }).collect();
 
-                let (config, iters) = pick_hyperparams(graph, variables, 0.1, 1000);
                 // Use shared optimizer state to preserve momentum across optimize loops
-                run_optimize_loop(graph, variables, cond_id, objective, target, config, iters, optimizer_state)?;
+                let (reason, iterations, loss) = if skip {
+                    (noma_compiler::StopReason::Skipped, 0, f64::NAN)
+                } else {
+                    let iters = iters.saturating_sub(session.resumed_steps);
+                    run_optimize_loop(graph, variables, cond_id, objective, targets, config, iters, session, &mut monitor)?
+                };
+                if reason == noma_compiler::StopReason::MaxIter && cond_id.is_some() {
+                    // Log warning but continue (non-convergence is allowed; program continues)
+                    eprintln!("Warning: Optimize loop reached max iterations without satisfying condition");
+                }
+                if let Some(name) = stats {
+                    for (field, value) in [
+                        ("iterations", (session.resumed_steps + iterations) as f64),
+                        ("reason", reason.code()),
+                        ("converged", if reason.converged() { 1.0 } else { 0.0 }),
+                        ("loss", loss),
+                        ("best", monitor.best()),
+                        ("elapsed", monitor.elapsed()),
+                    ] {
+                        let id = graph.add_constant(value);
+                        variables.insert(format!("{}.{}", name, field), id);
+                    }
+                }
                 *last_node = Some(objective);
             }
             noma_compiler::Statement::Alloc { name, shape } => {
@@ -160,6 +292,16 @@ fn lower_statements_shared(
                 variables.remove(name);
                 // free doesn't change last_node
             }
+            noma_compiler::Statement::Freeze { names } => {
+                for name in &graph.expand_learnable_names(names) {
+                    graph.freeze(name)?;
+                }
+            }
+            noma_compiler::Statement::Unfreeze { names } => {
+                for name in &graph.expand_learnable_names(names) {
+                    graph.unfreeze(name)?;
+                }
+            }
             noma_compiler::Statement::Realloc { name, shape } => {
                 // Evaluate shape dimensions; avoid forward passes during realloc to prevent transient mismatches
                 let mut dims = Vec::new();
@@ -203,35 +345,71 @@ fn lower_statements_shared(
                     *last_node = Some(node_id);
                 }
             }
-            noma_compiler::Statement::LoadCsv { name, path } => {
-                // Load CSV file and create tensor
-                let tensor_data = noma_compiler::load_csv_file(path)?;
-                let node_id = graph.add_constant_tensor(tensor_data.0, tensor_data.1)?;
+            noma_compiler::Statement::LoadCsv { name, labels_name, path, options } => {
+                // Load the selected CSV columns, and the label columns if split off
+                let (features, labels) = noma_compiler::load_csv_with(path, options)?;
+                let node_id = graph.add_constant_tensor(features.data, features.shape)?;
                 variables.insert(name.clone(), node_id);
                 *last_node = Some(node_id);
+                if let (Some(labels_name), Some(labels)) = (labels_name, labels) {
+                    let labels_id = graph.add_constant_tensor(labels.data, labels.shape)?;
+                    variables.insert(labels_name.clone(), labels_id);
+                }
             }
-            noma_compiler::Statement::SaveCsv { tensor, path } => {
+            noma_compiler::Statement::SaveCsv { tensor, path, header } => {
                 // Evaluate tensor and save to CSV
                 let tensor_id = graph.build_from_expression_with_functions(tensor, variables, func_registry)?;
                 graph.forward_pass()?;
                 let tensor_val = graph.get_node(tensor_id)
                     .and_then(|n| n.value.clone())
                     .ok_or_else(|| "Cannot evaluate tensor for save_csv".to_string())?;
-                noma_compiler::save_csv_file(&tensor_val, path)?;
+                noma_compiler::save_csv_file_with(&tensor_val, path, header)?;
                 *last_node = Some(tensor_id);
             }
             noma_compiler::Statement::LoadSafetensors { name, path } => {
-                // Load Safetensors file and create a tensor
-                let tensors = noma_compiler::load_safetensors_file(path)?;
-                if let Some((_, (data, shape))) = tensors.into_iter().next() {
-                    let node_id = graph.add_constant_tensor(data, shape)?;
-                    variables.insert(name.clone(), node_id);
+                for (variable, tensor) in noma_compiler::safetensors_bindings(name, path)? {
+                    let node_id = graph.add_loaded_tensor(tensor.data, tensor.shape)?;
+                    variables.insert(variable, node_id);
+                    *last_node = Some(node_id);
+                }
+            }
+            noma_compiler::Statement::LoadNpy { name, path } => {
+                let (data, shape) = noma_compiler::load_npy_file(path)?;
+                let node_id = graph.add_loaded_tensor(data, shape)?;
+                variables.insert(name.clone(), node_id);
+                *last_node = Some(node_id);
+            }
+            noma_compiler::Statement::SaveNpy { tensor, path, dtype } => {
+                let tensor_id = graph.build_from_expression_with_functions(tensor, variables, func_registry)?;
+                graph.forward_pass()?;
+                let tensor_val = graph.get_node(tensor_id)
+                    .and_then(|n| n.value.clone())
+                    .ok_or_else(|| "Cannot evaluate tensor for save_npy".to_string())?;
+                let dtype = dtype.as_deref().and_then(noma_compiler::NpyDtype::parse).unwrap_or(noma_compiler::NpyDtype::F64);
+                noma_compiler::save_npy_file(&tensor_val, path, dtype)?;
+                *last_node = Some(tensor_id);
+            }
+            noma_compiler::Statement::LoadNpz { name, path } => {
+                for (variable, tensor) in noma_compiler::npz_bindings(name, path)? {
+                    let node_id = graph.add_loaded_tensor(tensor.data, tensor.shape)?;
+                    variables.insert(variable, node_id);
                     *last_node = Some(node_id);
-                } else {
-                    return Err(format!("No tensors found in safetensors file: {}", path));
                 }
             }
-            noma_compiler::Statement::SaveSafetensors { tensors, path } => {
+            noma_compiler::Statement::SaveNpz { arrays, path, dtype } => {
+                let mut array_map = Vec::new();
+                for (array_name, array_expr) in arrays {
+                    let array_id = graph.build_from_expression_with_functions(array_expr, variables, func_registry)?;
+                    graph.forward_pass()?;
+                    let array_val = graph.get_node(array_id)
+                        .and_then(|n| n.value.clone())
+                        .ok_or_else(|| format!("Cannot evaluate array '{}' for save_npz", array_name))?;
+                    array_map.push((array_name.clone(), array_val));
+                }
+                let dtype = dtype.as_deref().and_then(noma_compiler::NpyDtype::parse).unwrap_or(noma_compiler::NpyDtype::F64);
+                noma_compiler::save_npz_file(&array_map, path, dtype)?;
+            }
+            noma_compiler::Statement::SaveSafetensors { tensors, path, dtype, metadata } => {
                 // Evaluate all tensors and save to Safetensors format
                 let mut tensor_map = Vec::new();
                 for (tensor_name, tensor_expr) in tensors {
@@ -242,15 +420,50 @@ fn lower_statements_shared(
                         .ok_or_else(|| format!("Cannot evaluate tensor '{}' for save_safetensors", tensor_name))?;
                     tensor_map.push((tensor_name.clone(), tensor_val));
                 }
-                noma_compiler::save_safetensors_file(&tensor_map, path)?;
+                let dtype = dtype.as_deref().and_then(noma_compiler::SafetensorsDtype::from_name)
+                    .unwrap_or(noma_compiler::SafetensorsDtype::F64);
+                noma_compiler::save_safetensors_file_with(&tensor_map, path, dtype, metadata)?;
             }
-            noma_compiler::Statement::BatchLoop { item_name, index_name, data, batch_size, body } => {
-                // Evaluate data tensor and batch size
-                let data_id = graph.build_from_expression_with_functions(data, variables, func_registry)?;
-                graph.forward_pass()?;
-                let data_val = graph.get_node(data_id)
-                    .and_then(|n| n.value.clone())
-                    .ok_or_else(|| "Cannot evaluate data for batch loop".to_string())?;
+            noma_compiler::Statement::Dataset { name, path, options } => {
+                // Only a shuffled dataset without a seed draws one, so other programs keep their draws
+                let seed = match options.seed {
+                    Some(seed) => seed,
+                    None if options.shuffle => graph.draw_seed(),
+                    None => 0,
+                };
+                let dataset = noma_compiler::Dataset::open(path, options, seed)?;
+                session.datasets.insert(name.clone(), Rc::new(dataset));
+                variables.remove(name);
+            }
+            noma_compiler::Statement::ImportOnnx { name, path, inputs } => {
+                let input_ids = inputs.iter()
+                    .map(|e| graph.build_from_expression_with_functions(e, variables, func_registry))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for (variable, node_id) in noma_compiler::import_onnx_file(graph, path, name, &input_ids, variables)? {
+                    variables.insert(variable, node_id);
+                }
+                *last_node = variables.get(name).copied();
+            }
+            noma_compiler::Statement::BatchLoop { item_names, index_name, epoch_name, data, batch_size, options, body } => {
+                // Sources: datasets streamed from their files, or tensors evaluated now
+                let mut sources = Vec::new();
+                for expr in data {
+                    let dataset = match expr {
+                        noma_compiler::Expression::Identifier(name) if !variables.contains_key(name) => session.datasets.get(name),
+                        _ => None,
+                    };
+                    if let Some(dataset) = dataset {
+                        sources.push(noma_compiler::BatchSource::Dataset(Rc::clone(dataset)));
+                        continue;
+                    }
+                    let data_id = graph.build_from_expression_with_functions(expr, variables, func_registry)?;
+                    graph.forward_pass()?;
+                    let data_val = graph.get_node(data_id)
+                        .and_then(|n| n.value.clone())
+                        .ok_or_else(|| "Cannot evaluate data for batch loop".to_string())?;
+                    sources.push(noma_compiler::BatchSource::from_value(data_val));
+                }
+                let streaming = sources.iter().any(|s| matches!(s, noma_compiler::BatchSource::Dataset(_)));
                 
                 let batch_size_id = graph.build_from_expression_with_functions(batch_size, variables, func_registry)?;
                 graph.forward_pass()?;
@@ -262,54 +475,71 @@ fn lower_statements_shared(
                 if batch_size_val == 0 {
                     return Err("Batch size cannot be zero".to_string());
                 }
-                
-                // Get tensor data
-                let (tensor_data, tensor_shape) = match &data_val {
-                    noma_compiler::Value::Tensor(t) => (t.data.clone(), t.shape.clone()),
-                    noma_compiler::Value::Scalar(s) => (vec![*s], vec![1]),
+                let epochs = match &options.epochs {
+                    Some(expr) => {
+                        let epochs_id = graph.build_from_expression_with_functions(expr, variables, func_registry)?;
+                        graph.forward_pass()?;
+                        let epochs = graph.get_node(epochs_id).and_then(|n| n.value.clone()).and_then(|v| v.as_scalar());
+                        match epochs {
+                            Some(e) if e >= 1.0 && e.fract() == 0.0 => Some(e as usize),
+                            _ => return Err("Batch loop epochs must be a positive integer".to_string()),
+                        }
+                    }
+                    None => None,
                 };
+                let shuffle = options.shuffle.then(|| options.seed.unwrap_or_else(|| graph.draw_seed()));
+                let schedule = noma_compiler::BatchSource::schedule(&sources, shuffle, options.drop_last, epochs)?;
                 
-                let num_samples = if tensor_shape.is_empty() { 1 } else { tensor_shape[0] };
-                let num_batches = (num_samples + batch_size_val - 1) / batch_size_val;
-                
-                // Iterate over batches
-                for batch_idx in 0..num_batches {
-                    let start = batch_idx * batch_size_val;
-                    let end = (start + batch_size_val).min(num_samples);
-                    
-                    // Extract batch data
-                    let batch_data = if tensor_shape.len() == 1 {
-                        tensor_data[start..end].to_vec()
-                    } else {
-                        let row_size: usize = tensor_shape[1..].iter().product();
-                        tensor_data[start * row_size..end * row_size].to_vec()
-                    };
-                    
-                    let batch_shape = if tensor_shape.len() == 1 {
-                        vec![end - start]
-                    } else {
-                        let mut shape = vec![end - start];
-                        shape.extend(&tensor_shape[1..]);
-                        shape
-                    };
-                    
-                    // Create batch tensor node
-                    let batch_node_id = graph.add_constant_tensor(batch_data, batch_shape)?;
-                    variables.insert(item_name.clone(), batch_node_id);
-                    
-                    // Optionally set index variable
-                    if let Some(idx_name) = index_name {
-                        let idx_node_id = graph.add_constant(batch_idx as f64);
-                        variables.insert(idx_name.clone(), idx_node_id);
+                let first = graph.next_node_index();
+                for epoch in 0..schedule.epochs {
+                    if let Some(epoch_name) = epoch_name {
+                        let epoch_node_id = graph.add_constant(epoch as f64);
+                        variables.insert(epoch_name.clone(), epoch_node_id);
+                    }
+                    for (batch_idx, rows) in schedule.batches(epoch, batch_size_val).iter().enumerate() {
+                        // Create one batch tensor node per source, all with the same rows
+                        for (item_name, source) in item_names.iter().zip(&sources) {
+                            let batch = source.read_rows(rows)?;
+                            let batch_node_id = graph.add_constant_tensor(batch.data, batch.shape)?;
+                            variables.insert(item_name.clone(), batch_node_id);
+                        }
+                        
+                        // Optionally set index variable
+                        if let Some(idx_name) = index_name {
+                            let idx_node_id = graph.add_constant(batch_idx as f64);
+                            variables.insert(idx_name.clone(), idx_node_id);
+                        }
+                        
+                        // Execute batch body
+                        lower_statements_shared(graph, variables, body, last_node, func_registry, session)?;
+
+                        // A streamed batch runs its prints, then the nodes of earlier batches that
+                        // nothing refers to are dropped, so only live batches stay in memory. The
+                        // pass leaves RNG and batch_norm state as an in-memory loop would.
+                        if streaming {
+                            graph.evaluate_detached(first)?;
+                            graph.prune_since(first, variables.values().copied().chain(*last_node));
+                        }
                     }
-                    
-                    // Execute batch body
-                    lower_statements_shared(graph, variables, body, last_node, func_registry, optimizer_state)?;
                 }
             }
+            noma_compiler::Statement::Checkpoint { path, .. } => {
+                noma_compiler::Checkpoint::capture(graph, &session.optimizer_state, None, session.loops, 0).save(path)?;
+            }
+            noma_compiler::Statement::Resume { path } => {
+                let checkpoint = noma_compiler::Checkpoint::load(path)?;
+                session.restore(graph, path, &checkpoint)?;
+            }
             noma_compiler::Statement::ResetOptimizer => {
                 // Clear all optimizer state (m, v, t) to restart from scratch
-                optimizer_state.reset();
+                session.optimizer_state.reset();
+            }
+            noma_compiler::Statement::Seed(expr) => {
+                // Reseed at lowering time so every later draw is reproducible; only the seed
+                // expression is evaluated, so the rest of the graph is left as it was
+                let seed_id = graph.build_from_expression_with_functions(expr, variables, func_registry)?;
+                let value = graph.evaluate_node(seed_id)?;
+                graph.reseed(noma_compiler::seed_value(value)?);
             }
         }
     }
@@ -402,6 +632,10 @@ enum Commands {
         /// Additional library search paths (passed as -L<path>)
         #[arg(long = "link-path", value_name = "PATH", num_args = 1.., action = clap::ArgAction::Append)]
         link_paths: Vec<String>,
+
+        /// Seed the random number generator (overridden by seed() statements)
+        #[arg(long, value_name = "SEED")]
+        seed: Option<u64>,
     },
 
     /// Compile NOMA to PTX (placeholder backend)
@@ -443,6 +677,55 @@ enum Commands {
         /// Input .noma file
         #[arg(value_name = "FILE")]
         file: PathBuf,
+
+        /// Run in inference mode: disable dropout and freeze batch_norm statistics
+        #[arg(long)]
+        eval: bool,
+
+        /// Seed the random number generator (overridden by seed() statements)
+        #[arg(long, value_name = "SEED")]
+        seed: Option<u64>,
+
+        /// Record step, time, lr, grad_norm and log() metrics of optimize loops
+        /// (CSV if the path ends in .csv, JSON lines otherwise)
+        #[arg(long = "log-file", value_name = "PATH")]
+        log_file: Option<PathBuf>,
+
+        /// Log every N-th optimizer step (the last step of each loop is always logged)
+        #[arg(long = "log-every", value_name = "N", default_value_t = 1)]
+        log_every: usize,
+
+        /// Continue training from a checkpoint() file: optimize loops finished before it
+        /// are skipped and the loop it was written in resumes at its step
+        #[arg(long, value_name = "PATH")]
+        resume: Option<PathBuf>,
+    },
+
+    /// Run a NOMA source file and export the trained forward graph of its result as ONNX
+    ExportOnnx {
+        /// Input .noma file
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        /// Output .onnx file
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: PathBuf,
+
+        /// Variable fed at inference time (default: every named tensor constant the output reads)
+        #[arg(long = "input-var", value_name = "NAME", num_args = 1.., action = clap::ArgAction::Append)]
+        input_vars: Vec<String>,
+
+        /// Variable holding the model output (default: the returned value)
+        #[arg(long = "output-var", value_name = "NAME")]
+        output_var: Option<String>,
+
+        /// Make the first axis of every input and output a symbolic batch size
+        #[arg(long = "dynamic-batch")]
+        dynamic_batch: bool,
+
+        /// Seed the random number generator (overridden by seed() statements)
+        #[arg(long, value_name = "SEED")]
+        seed: Option<u64>,
     },
 
     /// Compile and run a NOMA source file (faster execution, no training support)
@@ -488,8 +771,8 @@ fn main() -> anyhow::Result<()> {
             Commands::Compile { file, output, optimize, opt_level, emit_asm, emit_obj, fast_math } => {
                 compile_to_llvm(file, output, optimize, opt_level, emit_asm, emit_obj, fast_math)?;
         }
-        Commands::BuildExe { file, output, opt_level, fast_math, link_libs, link_paths } => {
-            build_executable(file, output, opt_level, fast_math, link_libs, link_paths)?;
+        Commands::BuildExe { file, output, opt_level, fast_math, link_libs, link_paths, seed } => {
+            build_executable(file, output, opt_level, fast_math, link_libs, link_paths, seed)?;
         }
         Commands::CompilePtx { file, output, n_elems, host_stub, optimize, fast_math } => {
             compile_to_ptx(file, output, n_elems, host_stub, optimize, fast_math)?;
@@ -497,8 +780,11 @@ fn main() -> anyhow::Result<()> {
         Commands::Check { file } => {
             check_file(file)?;
         }
-        Commands::Run { file } => {
-            run_noma(file)?;
+        Commands::Run { file, eval, seed, log_file, log_every, resume } => {
+            run_noma(file, eval, seed, log_file, log_every, resume)?;
+        }
+        Commands::ExportOnnx { file, output, input_vars, output_var, dynamic_batch, seed } => {
+            export_onnx_model(file, output, input_vars, output_var, dynamic_batch, seed)?;
         }
         Commands::FastRun { file, opt_level, fast_math } => {
             fast_run_noma(file, opt_level, fast_math)?;
@@ -531,45 +817,172 @@ fn main() -> anyhow::Result<()> {
 fn run_optimize_loop(
     graph: &mut ComputationalGraph,
     variables: &HashMap<String, noma_compiler::NodeId>,
-    cond_id: noma_compiler::NodeId,
+    cond_id: Option<noma_compiler::NodeId>,
     objective_id: noma_compiler::NodeId,
-    target: &str,
+    targets: &[String],
     config: OptimizerConfig,
     max_iter: usize,
-    optimizer_state: &mut OptimizerState,
-) -> Result<(), String> {
-    if !variables.contains_key(target) {
-        return Err(format!("Optimize target '{}' not defined", target));
+    session: &mut Session,
+    monitor: &mut noma_compiler::StopMonitor,
+) -> Result<(noma_compiler::StopReason, usize, f64), String> {
+    let Session { optimizer_state, metrics_log, loops, checkpoints, resumed_steps, .. } = session;
+    for target in targets {
+        let node = variables.get(target).and_then(|id| graph.get_node(*id))
+            .ok_or_else(|| format!("Optimize target '{}' not defined", target))?;
+        if !matches!(&node.node_type, noma_compiler::NodeType::Learnable(name) if name == target) {
+            return Err(format!("Optimize target '{}' is not a learnable parameter", target));
+        }
     }
 
     // Use shared optimizer state to preserve momentum across optimize loops
     // This enables faster convergence when architecture changes (realloc)
 
-    for _ in 0..max_iter {
+    // Pre-clip gradient norms are summarised on stderr when the block clips
+    let clips = config.grad_transforms.iter().any(|t| matches!(
+        t.kind,
+        noma_compiler::GradTransformKind::ClipNorm(_) | noma_compiler::GradTransformKind::ClipValue(_)
+    ));
+    let (mut steps, mut max_norm) = (0usize, 0.0f64);
+    let scalar_of = |graph: &ComputationalGraph, id: noma_compiler::NodeId| match graph.get_node(id).and_then(|n| n.value.as_ref()) {
+        Some(noma_compiler::Value::Scalar(s)) => *s,
+        Some(noma_compiler::Value::Tensor(t)) => t.data.iter().sum(),
+        None => 0.0,
+    };
+    // `stop early(..., restore_best=1)`: the monitor keeps the learnables at the best monitored value
+    let ids = graph.trainable_ids(&config);
+
+    let mut iterations = 0usize;
+    let mut loss = f64::NAN;
+    let mut unlogged: Option<Vec<(String, f64)>> = None;
+    let reason = loop {
+        if iterations == max_iter {
+            break noma_compiler::StopReason::MaxIter;
+        }
+        // Expose the scheduled learning rate to current_lr() in the body
+        graph.set_current_lr(config.learning_rate_at(optimizer_state));
         graph.forward_pass()?;
-        let cond_val = graph.get_node(cond_id).and_then(|n| n.value.clone()).and_then(|v| match v { noma_compiler::Value::Scalar(s) => Some(s), _ => None }).unwrap_or(0.0);
-        if cond_val != 0.0 {
-            return Ok(());
+        loss = scalar_of(graph, objective_id);
+        if let Some(cond_id) = cond_id {
+            let cond_val = graph.get_node(cond_id).and_then(|n| n.value.clone()).and_then(|v| match v { noma_compiler::Value::Scalar(s) => Some(s), _ => None }).unwrap_or(0.0);
+            if cond_val != 0.0 {
+                break noma_compiler::StopReason::Until;
+            }
+        }
+        let monitored = monitor.monitor().map(|id| scalar_of(graph, id));
+        if let Some(reason) = monitor.before_step(loss, monitored) {
+            break reason;
+        }
+        if monitor.improved() && monitor.restores_best() {
+            monitor.keep_best(graph.flat_params(&ids), loss);
         }
 
-        graph.backward_pass(objective_id)?;
-        graph.optimize_step_with_config(optimizer_state, &config)?;
-        graph.reset_gradients();
+        if config.optimizer_type.is_second_order() {
+            if monitor.needs_grad_norm() || metrics_log.is_some() {
+                // Second-order steps compute their own gradients; record the norm at the start point
+                graph.backward_pass(objective_id)?;
+                graph.apply_grad_transforms(&config)?;
+                graph.reset_gradients();
+            }
+            graph.optimize_step_second_order(optimizer_state, &config, objective_id)?;
+        } else {
+            graph.backward_pass(objective_id)?;
+            graph.optimize_step_with_config(optimizer_state, &config)?;
+            graph.reset_gradients();
+            steps += 1;
+            max_norm = max_norm.max(graph.grad_norm());
+        }
+        iterations += 1;
+        optimizer_state.observe_loss(&config, loss);
+        if let Some(log) = metrics_log.as_mut() {
+            let mut record = vec![
+                ("step".to_string(), optimizer_state.t as f64),
+                ("loop".to_string(), *loops as f64),
+                ("time".to_string(), log.elapsed()),
+                ("lr".to_string(), graph.current_lr()),
+                ("grad_norm".to_string(), graph.grad_norm()),
+            ];
+            record.extend(graph.metrics().iter().cloned());
+            // The last step of the loop is always logged, whatever the interval
+            if optimizer_state.t % log.every() == 0 {
+                log.write(&record)?;
+                unlogged = None;
+            } else {
+                unlogged = Some(record);
+            }
+        }
+        let loop_step = *resumed_steps + iterations;
+        for (path, every) in checkpoints.iter() {
+            if every.is_some_and(|every| loop_step % every == 0) {
+                noma_compiler::Checkpoint::capture(graph, optimizer_state, Some(monitor), *loops - 1, loop_step).save(path)?;
+            }
+        }
+        if let Some(reason) = monitor.after_step(graph.grad_norm()) {
+            break reason;
+        }
+    };
+
+    if let Some(log) = metrics_log.as_mut() {
+        if let Some(record) = unlogged {
+            log.write(&record)?;
+        }
+        log.flush()?;
     }
 
-    // Log warning but continue (non-convergence is allowed; program continues)
-    eprintln!("Warning: Optimize loop reached max iterations without satisfying condition");
-    Ok(())
+    // The reported loss is the one the restored parameters gave
+    if let Some((params, loss_at_best)) = monitor.best_params() {
+        graph.set_flat_params(&ids, params)?;
+        loss = loss_at_best;
+    }
+    for (path, _) in checkpoints.iter() {
+        noma_compiler::Checkpoint::capture(graph, optimizer_state, None, *loops, 0).save(path)?;
+    }
+    if clips && steps > 0 {
+        eprintln!(
+            "Gradient clipping: pre-clip global norm max {:.6e}, last {:.6e} over {} steps",
+            max_norm,
+            graph.grad_norm(),
+            steps
+        );
+    }
+    Ok((reason, iterations, loss))
+}
+
+/// Variable names read by `pick_hyperparams`
+const HYPERPARAM_VARIABLES: &[&str] = &[
+    "optimizer", "use_adam", "use_rmsprop", "learning_rate", "lr", "beta1", "beta2", "rho",
+    "epsilon", "eps", "momentum", "weight_decay", "history_size", "max_iterations", "max_iter",
+    "iterations",
+];
+
+/// Warn (once per program) when an optimize block without `with` is configured through
+/// hyperparameter variables
+fn warn_hyperparam_variables(session: &mut Session, variables: &HashMap<String, noma_compiler::NodeId>, target: &str) {
+    let used: Vec<&str> = HYPERPARAM_VARIABLES.iter().copied().filter(|name| variables.contains_key(*name)).collect();
+    if used.is_empty() || std::mem::replace(&mut session.warned_hyperparams, true) {
+        return;
+    }
+    eprintln!(
+        "Warning: configuring optimize through variables ({}) is deprecated; write the settings \
+         in the header instead, e.g. `optimize({}) with adam(lr=0.01) max_iter 1000 until ...`",
+        used.join(", "),
+        target
+    );
 }
 
 /// Pick hyperparameters and optimizer configuration from NOMA variables if present.
-/// Recognized names:
-///  - optimizer: "optimizer" (values: "sgd", "adam", "rmsprop")
-///  - learning rate: "learning_rate", "lr"
+/// Deprecated: only used for optimize blocks without a `with`/`max_iter` header.
+/// Recognized names (see `HYPERPARAM_VARIABLES`):
+///  - optimizer: "optimizer" (codes: 1 sgd, 2 adam, 3 rmsprop, 4 momentum, 5 nesterov, 6 adamw,
+///    7 adagrad, 8 adadelta, 9 lion, 10 lbfgs, 11 newton_cg; others mean sgd), or the flags
+///    "use_adam", "use_rmsprop"
+///  - learning rate: "learning_rate", "lr" (default 1.0 for L-BFGS/Newton-CG)
 ///  - max iterations: "max_iterations", "max_iter", "iterations"
-///  - Adam/RMSprop beta1: "beta1" (default 0.9)
-///  - Adam/RMSprop beta2: "beta2" (default 0.999 for Adam, 0.9 for RMSprop)
-///  - epsilon: "epsilon", "eps" (default 1e-8)
+///  - beta1: "beta1" (default 0.9)
+///  - beta2: "beta2", or "rho" for Adadelta (default 0.999; 0.9 for RMSprop/Adadelta, 0.99 for Lion)
+///  - epsilon: "epsilon", "eps" (default 1e-8; 1e-6 for Adadelta)
+///  - Momentum/Nesterov velocity decay: "momentum" (default 0.9)
+///  - decoupled weight decay: "weight_decay" (default 0.01 for AdamW, otherwise 0)
+///  - L-BFGS memory: "history_size" (default 10)
 /// If not found or invalid, fall back to provided defaults.
 fn pick_hyperparams(
     graph: &mut ComputationalGraph,
@@ -589,12 +1002,22 @@ fn pick_hyperparams(
     };
 
     // Determine optimizer type
-    // We use a convention: optimizer = 1.0 for SGD, 2.0 for Adam, 3.0 for RMSprop
+    // We use a convention: optimizer = 1.0 for SGD, 2.0 for Adam, 3.0 for RMSprop, 4.0 for
+    // Momentum, 5.0 for Nesterov, 6.0 for AdamW, 7.0 for Adagrad, 8.0 for Adadelta, 9.0 for Lion,
+    // 10.0 for L-BFGS, 11.0 for Newton-CG
     // Or check variable names: use_adam, use_rmsprop as flags
     let optimizer_type = if let Some(opt_val) = read_scalar("optimizer") {
         match opt_val as i32 {
             2 => OptimizerType::Adam,
             3 => OptimizerType::RMSprop,
+            4 => OptimizerType::Momentum,
+            5 => OptimizerType::Nesterov,
+            6 => OptimizerType::AdamW,
+            7 => OptimizerType::Adagrad,
+            8 => OptimizerType::Adadelta,
+            9 => OptimizerType::Lion,
+            10 => OptimizerType::LBFGS,
+            11 => OptimizerType::NewtonCG,
             _ => OptimizerType::SGD,
         }
     } else if read_scalar("use_adam").map(|v| v != 0.0).unwrap_or(false) {
@@ -605,7 +1028,8 @@ fn pick_hyperparams(
         OptimizerType::SGD
     };
 
-    // learning rate
+    // learning rate (the initial line-search step for L-BFGS/Newton-CG)
+    let default_lr = if optimizer_type.is_second_order() { 1.0 } else { default_lr };
     let lr = read_scalar("learning_rate")
         .or_else(|| read_scalar("lr"))
         .filter(|v| v.is_finite() && *v > 0.0)
@@ -617,18 +1041,36 @@ fn pick_hyperparams(
         .unwrap_or(0.9);
 
     let default_beta2 = match optimizer_type {
-        OptimizerType::Adam => 0.999,
-        OptimizerType::RMSprop => 0.9,
-        OptimizerType::SGD => 0.999,
+        OptimizerType::RMSprop | OptimizerType::Adadelta => 0.9,
+        OptimizerType::Lion => 0.99,
+        _ => 0.999,
     };
+    // Adadelta calls its decay rate rho
     let beta2 = read_scalar("beta2")
+        .or_else(|| read_scalar("rho"))
         .filter(|v| v.is_finite() && *v >= 0.0 && *v < 1.0)
         .unwrap_or(default_beta2);
 
+    let default_epsilon = if optimizer_type == OptimizerType::Adadelta { 1e-6 } else { 1e-8 };
     let epsilon = read_scalar("epsilon")
         .or_else(|| read_scalar("eps"))
         .filter(|v| v.is_finite() && *v > 0.0)
-        .unwrap_or(1e-8);
+        .unwrap_or(default_epsilon);
+
+    // Momentum/Nesterov velocity decay and AdamW/Lion decoupled weight decay
+    let momentum = read_scalar("momentum")
+        .filter(|v| v.is_finite() && *v >= 0.0 && *v < 1.0)
+        .unwrap_or(0.9);
+    let default_weight_decay = if optimizer_type == OptimizerType::AdamW { 0.01 } else { 0.0 };
+    let weight_decay = read_scalar("weight_decay")
+        .filter(|v| v.is_finite() && *v >= 0.0)
+        .unwrap_or(default_weight_decay);
+
+    // L-BFGS memory
+    let history_size = read_scalar("history_size")
+        .filter(|v| v.is_finite() && *v >= 1.0)
+        .map(|v| v as usize)
+        .unwrap_or(10);
 
     // iterations
     let iters_f = read_scalar("max_iterations")
@@ -648,6 +1090,10 @@ fn pick_hyperparams(
         beta1,
         beta2,
         epsilon,
+        momentum,
+        weight_decay,
+        history_size,
+        ..Default::default()
     };
 
     (config, iters)
@@ -804,14 +1250,17 @@ fn compile_to_llvm(file: PathBuf, output: Option<PathBuf>, optimize: bool, opt_l
     let mut graph = ComputationalGraph::new();
     let mut variables: HashMap<String, noma_compiler::NodeId> = HashMap::new();
     let mut last_node: Option<noma_compiler::NodeId> = None;
-    let mut optimizer_state = OptimizerState::new();
+    let mut session = Session::new();
 
-    lower_statements_shared(&mut graph, &mut variables, &func.body, &mut last_node, &func_registry, &mut optimizer_state)
+    lower_statements_shared(&mut graph, &mut variables, &func.body, &mut last_node, &func_registry, &mut session)
         .map_err(|e| anyhow::anyhow!(e))?;
 
     // Ensure we have something to return
     let _output_node = last_node.ok_or_else(|| anyhow::anyhow!("No expressions to compile"))?;
 
+    // Compiled code is inference-only: bake eval-mode values (no dropout, running statistics)
+    graph.set_mode(ExecutionMode::Eval);
+
     // Perform forward pass to compute values (best-effort; allows constants/learnables)
     let _ = graph.forward_pass();
 
@@ -926,7 +1375,14 @@ fn output_ir(output: Option<PathBuf>, ir: String) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn run_noma(file: PathBuf) -> anyhow::Result<()> {
+fn run_noma(
+    file: PathBuf,
+    eval: bool,
+    seed: Option<u64>,
+    log_file: Option<PathBuf>,
+    log_every: usize,
+    resume: Option<PathBuf>,
+) -> anyhow::Result<()> {
     println!("Running: {}", file.display());
 
     let source = std::fs::read_to_string(&file)?;
@@ -944,11 +1400,31 @@ fn run_noma(file: PathBuf) -> anyhow::Result<()> {
     let mut graph = ComputationalGraph::new();
     let mut variables: HashMap<String, noma_compiler::NodeId> = HashMap::new();
     let mut last_node: Option<noma_compiler::NodeId> = None;
-    let mut optimizer_state = OptimizerState::new();
+    let mut session = Session::new();
+    if eval {
+        graph.set_mode(ExecutionMode::Eval);
+    }
+    if let Some(seed) = seed {
+        graph.set_seed(seed);
+    }
+    if let Some(path) = log_file {
+        let log = noma_compiler::MetricsLogger::create(&path.to_string_lossy(), log_every).map_err(|e| anyhow::anyhow!(e))?;
+        session.metrics_log = Some(log);
+    }
+    if let Some(path) = resume {
+        let path = path.to_string_lossy().to_string();
+        let checkpoint = noma_compiler::Checkpoint::load(&path).map_err(|e| anyhow::anyhow!(e))?;
+        session.resume = Some((path, checkpoint));
+    }
 
-    lower_statements_shared(&mut graph, &mut variables, &func.body, &mut last_node, &func_registry, &mut optimizer_state)
+    lower_statements_shared(&mut graph, &mut variables, &func.body, &mut last_node, &func_registry, &mut session)
         .map_err(|e| anyhow::anyhow!(e))?;
 
+    // A checkpoint written after the last optimize loop is restored once the program is lowered
+    if let Some((path, checkpoint)) = session.resume.take() {
+        session.restore(&mut graph, &path, &checkpoint).map_err(|e| anyhow::anyhow!(e))?;
+    }
+
     graph.forward_pass().map_err(|e| anyhow::anyhow!(e))?;
     let out_node = last_node.ok_or_else(|| anyhow::anyhow!("No value to return"))?;
     let val = graph.get_node(out_node).and_then(|n| n.value.clone()).ok_or_else(|| anyhow::anyhow!("No value computed"))?;
@@ -960,6 +1436,71 @@ fn run_noma(file: PathBuf) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn export_onnx_model(
+    file: PathBuf,
+    output: PathBuf,
+    input_vars: Vec<String>,
+    output_var: Option<String>,
+    dynamic_batch: bool,
+    seed: Option<u64>,
+) -> anyhow::Result<()> {
+    let source = fs::read_to_string(&file)?;
+    let mut lexer = Lexer::new(&source);
+    let tokens = lexer.tokenize().map_err(|e| anyhow::anyhow!("{:?}", e))?;
+
+    let mut parser = NomaParser::new(tokens);
+    let ast = parser.parse().map_err(|e| anyhow::anyhow!("{:?}", e))?;
+
+    let (func_registry, main_func) = collect_functions(&ast);
+    let func = main_func.ok_or_else(|| anyhow::anyhow!("No function found to export"))?;
+
+    // Train exactly like `noma run`, then trace the result in inference mode
+    let mut graph = ComputationalGraph::new();
+    let mut variables: HashMap<String, noma_compiler::NodeId> = HashMap::new();
+    let mut last_node: Option<noma_compiler::NodeId> = None;
+    let mut session = Session::new();
+    if let Some(seed) = seed {
+        graph.set_seed(seed);
+    }
+    lower_statements_shared(&mut graph, &mut variables, &func.body, &mut last_node, &func_registry, &mut session)
+        .map_err(|e| anyhow::anyhow!(e))?;
+    graph.set_mode(ExecutionMode::Eval);
+    graph.forward_pass().map_err(|e| anyhow::anyhow!(e))?;
+
+    let (output_name, output_id) = match &output_var {
+        Some(name) => (name.as_str(), *variables.get(name).ok_or_else(|| anyhow::anyhow!("Unknown output variable '{}'", name))?),
+        None => ("output", last_node.ok_or_else(|| anyhow::anyhow!("No value to export"))?),
+    };
+    let inputs: Vec<(String, noma_compiler::NodeId)> = if input_vars.is_empty() {
+        let mut named: Vec<(&String, &noma_compiler::NodeId)> = variables.iter()
+            .filter(|(_, id)| matches!(graph.get_node(**id).map(|n| &n.node_type), Some(noma_compiler::NodeType::Constant(noma_compiler::Value::Tensor(_)))))
+            .collect();
+        named.sort_by_key(|(name, id)| (id.index(), *name));
+        named.dedup_by_key(|(_, id)| **id);
+        named.into_iter().map(|(name, id)| (name.clone(), *id)).collect()
+    } else {
+        input_vars.iter()
+            .map(|name| variables.get(name).map(|id| (name.clone(), *id)).ok_or_else(|| anyhow::anyhow!("Unknown input variable '{}'", name)))
+            .collect::<anyhow::Result<_>>()?
+    };
+
+    let model = noma_compiler::export_onnx(&graph, &inputs, (output_name, output_id), dynamic_batch)
+        .map_err(|e| anyhow::anyhow!(e))?;
+    for name in &input_vars {
+        if !model.graph.inputs.iter().any(|i| &i.name == name) {
+            anyhow::bail!("Input variable '{}' does not feed the exported output", name);
+        }
+    }
+    fs::write(&output, model.to_bytes())?;
+
+    let input_names: Vec<&str> = model.graph.inputs.iter().map(|i| i.name.as_str()).collect();
+    println!(
+        "Exported ONNX model to {} (opset {}, {} nodes, {} initializers, inputs: [{}])",
+        output.display(), model.opset, model.graph.nodes.len(), model.graph.initializers.len(), input_names.join(", ")
+    );
+    Ok(())
+}
+
 fn compile_to_ptx(file: PathBuf, output: Option<PathBuf>, n_elems: Option<u32>, host_stub: bool, optimize: bool, fast_math: bool) -> anyhow::Result<()> {
     // Read source file
     let source = fs::read_to_string(&file)?;
@@ -979,9 +1520,9 @@ fn compile_to_ptx(file: PathBuf, output: Option<PathBuf>, n_elems: Option<u32>,
     let mut graph = ComputationalGraph::new();
     let mut variables: HashMap<String, noma_compiler::NodeId> = HashMap::new();
     let mut last_node: Option<noma_compiler::NodeId> = None;
-    let mut optimizer_state = OptimizerState::new();
+    let mut session = Session::new();
 
-    lower_statements_shared(&mut graph, &mut variables, &func.body, &mut last_node, &func_registry, &mut optimizer_state)
+    lower_statements_shared(&mut graph, &mut variables, &func.body, &mut last_node, &func_registry, &mut session)
         .map_err(|e| anyhow::anyhow!(e))?;
 
     let mut codegen = PTXCodegen::new();
@@ -1046,11 +1587,12 @@ fn fast_run_noma(file: PathBuf, opt_level: Option<u8>, fast_math: bool) -> anyho
     let mut graph = ComputationalGraph::new();
     let mut variables: HashMap<String, noma_compiler::NodeId> = HashMap::new();
     let mut last_node: Option<noma_compiler::NodeId> = None;
-    let mut optimizer_state = OptimizerState::new();
+    let mut session = Session::new();
     
-    lower_statements_shared(&mut graph, &mut variables, &func.body, &mut last_node, &func_registry, &mut optimizer_state)
+    lower_statements_shared(&mut graph, &mut variables, &func.body, &mut last_node, &func_registry, &mut session)
         .map_err(|e| anyhow::anyhow!(e))?;
     
+    graph.set_mode(ExecutionMode::Eval);
     let _ = graph.forward_pass();
     
     let mut codegen = LLVMCodegen::new().with_fast_math(fast_math);
@@ -1125,7 +1667,7 @@ fn fast_run_noma(file: PathBuf, opt_level: Option<u8>, fast_math: bool) -> anyho
     Ok(())
 }
 
-fn build_executable(file: PathBuf, output: PathBuf, opt_level: Option<u8>, fast_math: bool, link_libs: Vec<String>, link_paths: Vec<String>) -> anyhow::Result<()> {
+fn build_executable(file: PathBuf, output: PathBuf, opt_level: Option<u8>, fast_math: bool, link_libs: Vec<String>, link_paths: Vec<String>, seed: Option<u64>) -> anyhow::Result<()> {
     println!("Building executable: {} -> {}", file.display(), output.display());
     
     // Read source file
@@ -1146,11 +1688,17 @@ fn build_executable(file: PathBuf, output: PathBuf, opt_level: Option<u8>, fast_
     let mut graph = ComputationalGraph::new();
     let mut variables: HashMap<String, noma_compiler::NodeId> = HashMap::new();
     let mut last_node: Option<noma_compiler::NodeId> = None;
-    let mut optimizer_state = OptimizerState::new();
+    let mut session = Session::new();
+    if let Some(seed) = seed {
+        graph.set_seed(seed);
+    }
 
-    lower_statements_shared(&mut graph, &mut variables, &func.body, &mut last_node, &func_registry, &mut optimizer_state)
+    lower_statements_shared(&mut graph, &mut variables, &func.body, &mut last_node, &func_registry, &mut session)
         .map_err(|e| anyhow::anyhow!(e))?;
 
+    // Compiled code is inference-only: bake eval-mode values (no dropout, running statistics)
+    graph.set_mode(ExecutionMode::Eval);
+
     // Perform forward pass (values are already computed after optimization)
     let _ = graph.forward_pass();
 
@@ -1257,3 +1805,284 @@ fn build_executable(file: PathBuf, output: PathBuf, opt_level: Option<u8>, fast_
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn try_lower_source(source: &str, session: &mut Session) -> Result<(ComputationalGraph, HashMap<String, noma_compiler::NodeId>), String> {
+        let tokens = Lexer::new(source).tokenize().unwrap();
+        let ast = NomaParser::new(tokens).parse().unwrap();
+        let (func_registry, main_func) = collect_functions(&ast);
+        let mut graph = ComputationalGraph::new();
+        let mut variables = HashMap::new();
+        let mut last_node = None;
+        lower_statements_shared(&mut graph, &mut variables, &main_func.unwrap().body, &mut last_node, &func_registry, session)?;
+        graph.forward_pass()?;
+        Ok((graph, variables))
+    }
+
+    fn lower_source(source: &str, session: &mut Session) -> (ComputationalGraph, HashMap<String, noma_compiler::NodeId>) {
+        try_lower_source(source, session).unwrap()
+    }
+
+    fn scalar(graph: &ComputationalGraph, id: noma_compiler::NodeId) -> f64 {
+        match graph.get_node(id).and_then(|n| n.value.clone()) {
+            Some(noma_compiler::Value::Scalar(s)) => s,
+            other => panic!("expected a scalar, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn resume_partway_through_second_loop() {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| dir.join(format!("noma_resume_{}_{}.ckpt", name, std::process::id())).to_string_lossy().to_string();
+        let (saved, reference) = (path("saved"), path("reference"));
+        let program = |checkpoints: &str| format!(r#"
+            fn main() {{
+                seed(5);
+                let X = tensor [[1.0, 2.0], [3.0, 5.0], [0.5, -1.0], [2.0, 0.0]];
+                learn g = tensor [1.0, 1.0];
+                learn w = 0.0;
+                optimize(w) with sgd(lr=0.1) max_iter 10 {{
+                    minimize (w - 1.0) * (w - 1.0);
+                }}
+                optimize(w, g) with adam(lr=0.05) stop early(patience=100, restore_best=1) on val max_iter 30 -> fit {{
+                    let d = dropout(batch_norm(X, g, 0.0), 0.2) * w - 2.0;
+                    let loss = mean(d * d);
+                    let val = (w - 1.5) * (w - 1.5);
+                    minimize loss;
+                    {}
+                }}
+                return w;
+            }}
+        "#, checkpoints);
+
+        let (graph, variables) = lower_source(&program(&format!("checkpoint(\"{}\", 10);", reference)), &mut Session::new());
+
+        // Saving to a missing directory at step 25 aborts the run; the last checkpoint is step 20
+        let crashing = program(&format!("checkpoint(\"{}\", 10); checkpoint(\"/nonexistent/noma.ckpt\", 25);", saved));
+        assert!(try_lower_source(&crashing, &mut Session::new()).is_err());
+        let checkpoint = noma_compiler::Checkpoint::load(&saved).unwrap();
+        assert_eq!((checkpoint.loops_done, checkpoint.loop_step), (1, 20));
+
+        // The first loop is skipped and the second continues from step 20 to the same end point
+        let mut session = Session::new();
+        session.resume = Some((saved.clone(), checkpoint));
+        let (resumed, resumed_vars) = lower_source(&program(&format!("checkpoint(\"{}\", 10);", saved)), &mut session);
+        std::fs::remove_file(&saved).ok();
+        std::fs::remove_file(&reference).ok();
+        assert!(session.resume.is_none());
+        for field in ["fit.iterations", "fit.best", "w"] {
+            assert_eq!(scalar(&resumed, resumed_vars[field]), scalar(&graph, variables[field]), "{}", field);
+        }
+        assert_eq!(scalar(&graph, variables["fit.iterations"]), 30.0);
+        assert_eq!(resumed.get_node(resumed_vars["g"]).unwrap().value, graph.get_node(variables["g"]).unwrap().value);
+        assert_eq!(resumed.running_stats(resumed_vars["g"]), graph.running_stats(variables["g"]));
+        assert!(graph.running_stats(variables["g"]).is_some());
+    }
+
+    #[test]
+    fn seed_statement_only_resets_the_rng() {
+        // seed() evaluates nothing but its argument, so a draw declared before it but not yet
+        // evaluated comes from the new stream
+        let draws = |body: &str| {
+            let (graph, variables) = lower_source(&format!("fn main() {{ {} return b; }}", body), &mut Session::new());
+            (scalar(&graph, variables["a"]), scalar(&graph, variables["b"]), graph.rng_state())
+        };
+        let reference = draws("seed(7); let a = rand(); let b = rand();");
+        assert_eq!(draws("let a = rand(); seed(7); let b = rand();"), reference);
+        assert_eq!(draws("let a = rand(); let s = 3.0 + 4.0; seed(s); let b = rand();"), reference);
+    }
+
+    #[test]
+    fn save_safetensors_writes_metadata() {
+        let path = env::temp_dir().join(format!("noma_metadata_{}.safetensors", std::process::id()));
+        let source = format!(
+            "fn main() {{ let W = tensor [1.0, 2.0]; save_safetensors {{ W: W }}, \"{}\", \"F32\", metadata {{ format: \"pt\", \"noma.step\": \"3\" }}; return W; }}",
+            path.display()
+        );
+        lower_source(&source, &mut Session::new());
+        let path = path.to_str().unwrap();
+        assert_eq!(noma_compiler::load_safetensors_metadata(path).unwrap(), vec![
+            ("format".to_string(), "pt".to_string()),
+            ("noma.step".to_string(), "3".to_string()),
+        ]);
+        assert_eq!(noma_compiler::load_safetensors_tensor(path, "W").unwrap(), (vec![1.0, 2.0], vec![2]));
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn restored_best_reports_its_own_loss() {
+        // w walks from 0 towards 3; the monitored value is best near w = 1.5 and is restored
+        let source = r#"
+            fn main() {
+                learn w = 0.0;
+                optimize(w) with sgd(lr=0.05) stop early(patience=5, restore_best=1) on val max_iter 100 -> fit {
+                    let loss = (w - 3.0) * (w - 3.0);
+                    let val = (w - 1.5) * (w - 1.5);
+                    minimize loss;
+                }
+                let check = (w - 3.0) * (w - 3.0);
+                return check;
+            }
+        "#;
+        let (graph, variables) = lower_source(source, &mut Session::new());
+        assert!((scalar(&graph, variables["w"]) - 1.5).abs() < 0.2);
+        assert_eq!(scalar(&graph, variables["fit.loss"]), scalar(&graph, variables["check"]));
+        assert_eq!(scalar(&graph, variables["fit.reason"]), 5.0);
+    }
+
+    /// Run examples/07_rosenbrock.noma with `with` inserted into its optimize header and the loop
+    /// statistics bound to `fit`. Returns (x, y, fit.reason, fit.iterations).
+    fn run_rosenbrock_example(with: &str) -> (f64, f64, f64, f64) {
+        let example = include_str!("../examples/07_rosenbrock.noma");
+        let source = example.replace("optimize(x, y) until loss < 0.0001 {", &format!("optimize(x, y) with {} until loss < 0.0001 -> fit {{", with));
+        assert_ne!(source, example, "optimize header of the example changed");
+        let (graph, variables) = lower_source(&source, &mut Session::new());
+        let stat = |field: &str| scalar(&graph, variables[&format!("fit.{}", field)]);
+        (scalar(&graph, variables["x"]), scalar(&graph, variables["y"]), stat("reason"), stat("iterations"))
+    }
+
+    #[test]
+    fn optimizers_converge_on_rosenbrock_example() {
+        // Each optimizer stops on the example's own condition (loss < 1e-4), not on max_iter
+        for with in [
+            "momentum(lr=0.001, momentum=0.9) max_iter 2000",
+            "nesterov(lr=0.001, momentum=0.9) max_iter 2000",
+            "adamw(lr=0.02, weight_decay=1e-4) max_iter 1000",
+            "adagrad(lr=0.5) max_iter 6000",
+            "adadelta(lr=0.01, rho=0.9, eps=1e-6) max_iter 15000",
+            "lion(lr=0.0003, beta1=0.9, beta2=0.99) max_iter 6000",
+        ] {
+            let (x, y, reason, _) = run_rosenbrock_example(with);
+            assert_eq!(reason, 1.0, "{} did not converge: x = {}, y = {}", with, x, y);
+            assert!((x - 1.0).abs() < 0.02 && (y - 1.0).abs() < 0.04, "{}: x = {}, y = {}", with, x, y);
+        }
+    }
+
+    #[test]
+    fn second_order_optimizers_on_rosenbrock_example() {
+        // First-order methods need thousands of steps here
+        for with in ["lbfgs(lr=1.0, history_size=10) max_iter 50", "newton_cg(lr=1.0) max_iter 50"] {
+            let (x, y, reason, iterations) = run_rosenbrock_example(with);
+            assert_eq!(reason, 1.0, "{} did not converge in {} steps", with, iterations);
+            assert!((x - 1.0).abs() < 0.02 && (y - 1.0).abs() < 0.04, "{}: x = {}, y = {}", with, x, y);
+        }
+    }
+
+    #[test]
+    fn max_iter_without_with_keeps_legacy_optimizer() {
+        // One SGD step from w = 0 on (w - 1)^2 moves w by 2 * lr
+        let step = |prelude: &str, session: &mut Session| {
+            let source = format!("fn main() {{ {} learn w = 0.0; optimize(w) max_iter 1 {{ minimize (w - 1.0) * (w - 1.0); }} return w; }}", prelude);
+            let (graph, variables) = lower_source(&source, session);
+            scalar(&graph, variables["w"])
+        };
+        let mut session = Session::new();
+        assert!((step("", &mut session) - 0.2).abs() < 1e-12);
+        assert!(!session.warned_hyperparams);
+        assert!((step("let learning_rate = 0.25;", &mut session) - 0.5).abs() < 1e-12);
+        assert!(session.warned_hyperparams);
+    }
+
+    #[test]
+    fn legacy_load_csv_rejects_missing_cells() {
+        let path = env::temp_dir().join(format!("noma_blank_{}.csv", std::process::id()));
+        fs::write(&path, "1.0,2.0\n3.0,\n").unwrap();
+        let source = |options: &str| format!("fn main() {{ {} let total = sum(X); return total; }}", match options {
+            "" => format!("load_csv X = \"{}\";", path.display()),
+            options => format!("let X = load_csv(\"{}\", {});", path.display(), options),
+        });
+        let err = try_lower_source(&source(""), &mut Session::new()).err().unwrap();
+        assert!(err.contains("line 2: missing value in column 1"), "{}", err);
+        let (graph, variables) = lower_source(&source("na=0.5"), &mut Session::new());
+        fs::remove_file(&path).ok();
+        assert_eq!(scalar(&graph, variables["total"]), 6.5);
+    }
+
+    #[test]
+    fn streamed_dataset_trains_like_in_memory_data() {
+        let path = env::temp_dir().join(format!("noma_stream_{}.csv", std::process::id()));
+        fs::write(&path, "1,2,5\n3,1,6\n0.5,2,7\n2,2,9\n1,0,1\n").unwrap();
+        let program = |source: &str| format!(r#"
+            fn main() {{
+                seed(3);
+                {}
+                learn W = tensor [[0.1], [0.2]];
+                learn g = tensor [1.0, 1.0];
+                batch b in data with 2 {{
+                    optimize(W, g) with adam(lr=0.05) max_iter 5 {{
+                        let h = dropout(batch_norm(b[:, 0:2], g, 0.0), 0.3);
+                        let E = matmul(h, W) + noise_normal(0.0, 0.01) - b[:, 2:3];
+                        minimize mean(E * E);
+                    }}
+                }}
+                return W;
+            }}
+        "#, source);
+        let streamed = program(&format!("dataset data = \"{}\";", path.display()));
+        let in_memory = program(&format!("let data = load_csv(\"{}\");", path.display()));
+        let (a, a_vars) = lower_source(&streamed, &mut Session::new());
+        let (b, b_vars) = lower_source(&in_memory, &mut Session::new());
+        fs::remove_file(&path).ok();
+        assert_eq!(a.get_node(a_vars["W"]).unwrap().value, b.get_node(b_vars["W"]).unwrap().value);
+        assert_eq!(a.running_stats(a_vars["g"]), b.running_stats(b_vars["g"]));
+    }
+
+    /// Compile `source` with build-exe, run it and return (compiled, interpreted) results, or
+    /// None when llc is not installed
+    fn compiled_and_interpreted(name: &str, source: &str) -> Option<(f64, f64)> {
+        if Command::new("llc").arg("--version").output().is_err() {
+            return None;
+        }
+        let (graph, variables) = lower_source(source, &mut Session::new());
+        let expected = scalar(&graph, variables["out"]);
+
+        let dir = env::temp_dir();
+        let file = dir.join(format!("noma_{}_{}.noma", name, std::process::id()));
+        let exe = dir.join(format!("noma_{}_{}", name, std::process::id()));
+        fs::write(&file, source).unwrap();
+        build_executable(file.clone(), exe.clone(), Some(0), false, Vec::new(), Vec::new(), None).unwrap();
+        let output = Command::new(&exe).output().unwrap();
+        fs::remove_file(&file).ok();
+        fs::remove_file(&exe).ok();
+        Some((String::from_utf8_lossy(&output.stdout).trim().parse().unwrap(), expected))
+    }
+
+    #[test]
+    fn build_exe_matches_interpreter_randomness() {
+        let source = r#"
+            fn main() {
+                seed(11);
+                let W = rand_normal_tensor(0.0, 1.0, 3.0);
+                let out = sum(W * noise_normal(0.0, 0.5, 3.0)) + noise_uniform(-1.0, 1.0);
+                return out;
+            }
+        "#;
+        if let Some((compiled, expected)) = compiled_and_interpreted("seeded", source) {
+            assert!((compiled - expected).abs() < 1e-5, "compiled {} vs interpreted {}", compiled, expected);
+        }
+    }
+
+    #[test]
+    fn build_exe_matches_interpreter_masks_and_broadcasting() {
+        // 0.1 + 0.2 differs from 0.3 by less than f64::EPSILON, so both backends must compare exactly
+        let source = r#"
+            fn main() {
+                let X = tensor [[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]];
+                let third = 0.1 + 0.2;
+                let equal = sum((tensor [0.3, 1.0]) == third) + sum((tensor [0.3, 1.0]) != third);
+                let w = where(X > 2.5, X, tensor [10.0, 20.0, 30.0]);
+                let c = clip(X, tensor [[2.0], [3.0]], 5.0);
+                let shifted = X - tensor [[1.0], [2.0]];
+                let out = sum(w) + 100.0 * sum(c) + 1000.0 * sum(shifted) + 100000.0 * equal;
+                return out;
+            }
+        "#;
+        if let Some((compiled, expected)) = compiled_and_interpreted("masks", source) {
+            assert!((compiled - expected).abs() < 1e-5, "compiled {} vs interpreted {}", compiled, expected);
+        }
+    }
+
+}
@@ -0,0 +1,1546 @@
+use crate::graph::{broadcast_shapes, resolve_axis, tensor_of, transpose_perm, ComputationalGraph, Node, NodeId, NodeType, Tensor, Value};
+use std::collections::{HashMap, HashSet};
+
+/// ONNX operator set (default domain) that exported models declare
+pub const ONNX_OPSET: i64 = 13;
+
+/// IR version matching `ONNX_OPSET`
+const ONNX_IR_VERSION: i64 = 7;
+
+// ============================================================================
+// Protobuf Wire Format
+// ============================================================================
+
+/// Append-only protobuf message encoder (proto2 encoding: repeated scalars unpacked)
+#[derive(Default)]
+struct ProtoWriter {
+    buf: Vec<u8>,
+}
+
+impl ProtoWriter {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+    }
+
+    fn key(&mut self, field: u32, wire: u64) {
+        self.varint(((field as u64) << 3) | wire);
+    }
+
+    fn int(&mut self, field: u32, v: i64) {
+        self.key(field, 0);
+        self.varint(v as u64);
+    }
+
+    fn float(&mut self, field: u32, v: f32) {
+        self.key(field, 5);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, b: &[u8]) {
+        self.key(field, 2);
+        self.varint(b.len() as u64);
+        self.buf.extend_from_slice(b);
+    }
+
+    fn string(&mut self, field: u32, s: &str) {
+        self.bytes(field, s.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, m: &ProtoWriter) {
+        self.bytes(field, &m.buf);
+    }
+}
+
+/// One decoded field value
+enum Wire<'a> {
+    Varint(u64),
+    Fixed64([u8; 8]),
+    Bytes(&'a [u8]),
+    Fixed32([u8; 4]),
+}
+
+impl<'a> Wire<'a> {
+    fn int(&self) -> Result<i64, String> {
+        match self {
+            Wire::Varint(v) => Ok(*v as i64),
+            _ => Err("expected a varint field".to_string()),
+        }
+    }
+
+    fn bytes(&self) -> Result<&'a [u8], String> {
+        match self {
+            Wire::Bytes(b) => Ok(b),
+            _ => Err("expected a length-delimited field".to_string()),
+        }
+    }
+
+    fn string(&self) -> Result<String, String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| "string field is not valid UTF-8".to_string())
+    }
+
+    fn float(&self) -> Result<f32, String> {
+        match self {
+            Wire::Fixed32(b) => Ok(f32::from_le_bytes(*b)),
+            _ => Err("expected a fixed32 field".to_string()),
+        }
+    }
+
+    /// Repeated varints, either one per field or packed
+    fn ints(&self, out: &mut Vec<i64>) -> Result<(), String> {
+        match self {
+            Wire::Bytes(b) => {
+                let mut r = ProtoReader::new(b);
+                while r.pos < b.len() {
+                    out.push(r.varint()? as i64);
+                }
+                Ok(())
+            }
+            _ => { out.push(self.int()?); Ok(()) }
+        }
+    }
+
+    /// Repeated floats, either one per field or packed
+    fn floats(&self, out: &mut Vec<f32>) -> Result<(), String> {
+        match self {
+            Wire::Bytes(b) if b.len() % 4 == 0 => {
+                out.extend(b.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])));
+                Ok(())
+            }
+            Wire::Bytes(_) => Err("packed float field has a partial element".to_string()),
+            _ => { out.push(self.float()?); Ok(()) }
+        }
+    }
+
+    /// Repeated doubles, either one per field or packed
+    fn doubles(&self, out: &mut Vec<f64>) -> Result<(), String> {
+        match self {
+            Wire::Fixed64(b) => { out.push(f64::from_le_bytes(*b)); Ok(()) }
+            Wire::Bytes(b) if b.len() % 8 == 0 => {
+                out.extend(b.chunks_exact(8).map(|c| f64::from_le_bytes(c.try_into().unwrap())));
+                Ok(())
+            }
+            _ => Err("expected a double field".to_string()),
+        }
+    }
+}
+
+/// Sequential protobuf field reader; unknown fields are left to the caller to skip
+struct ProtoReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        ProtoReader { buf, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = *self.buf.get(self.pos).ok_or("truncated protobuf varint")?;
+            self.pos += 1;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err("protobuf varint longer than 10 bytes".to_string())
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.buf.len()).ok_or("truncated protobuf field")?;
+        let b = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(b)
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u32, Wire<'a>)>, String> {
+        if self.pos >= self.buf.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let wire = match key & 7 {
+            0 => Wire::Varint(self.varint()?),
+            1 => Wire::Fixed64(self.take(8)?.try_into().unwrap()),
+            2 => {
+                let n = self.varint()? as usize;
+                Wire::Bytes(self.take(n)?)
+            }
+            5 => Wire::Fixed32(self.take(4)?.try_into().unwrap()),
+            w => return Err(format!("unsupported protobuf wire type {}", w)),
+        };
+        Ok(Some(((key >> 3) as u32, wire)))
+    }
+}
+
+// ============================================================================
+// ONNX Model Subset
+// ============================================================================
+
+/// Element type of an ONNX tensor (TensorProto.DataType)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnnxDtype {
+    Float,
+    Int32,
+    Int64,
+    Double,
+}
+
+impl OnnxDtype {
+    pub fn from_code(code: i64) -> Result<Self, String> {
+        match code {
+            1 => Ok(Self::Float),
+            6 => Ok(Self::Int32),
+            7 => Ok(Self::Int64),
+            11 => Ok(Self::Double),
+            _ => Err(format!("unsupported ONNX data type {} (expected float, double, int32 or int64)", code)),
+        }
+    }
+
+    pub fn code(self) -> i64 {
+        match self {
+            Self::Float => 1,
+            Self::Int32 => 6,
+            Self::Int64 => 7,
+            Self::Double => 11,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Self::Float | Self::Int32 => 4,
+            Self::Int64 | Self::Double => 8,
+        }
+    }
+}
+
+/// TensorProto with its elements widened to f64
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnnxTensor {
+    pub name: String,
+    pub dtype: OnnxDtype,
+    pub dims: Vec<i64>,
+    pub data: Vec<f64>,
+}
+
+impl OnnxTensor {
+    /// Float tensor for a NOMA value, rounded to the precision it is stored with
+    fn from_value(name: String, value: &Value) -> Self {
+        let t = tensor_of(value);
+        let data = t.data.iter().map(|&v| v as f32 as f64).collect();
+        OnnxTensor { name, dtype: OnnxDtype::Float, dims: t.shape.iter().map(|&d| d as i64).collect(), data }
+    }
+
+    pub fn to_tensor(&self) -> Result<Tensor, String> {
+        let shape = self.dims.iter().map(|&d| usize::try_from(d).map_err(|_| format!("tensor '{}' has negative dimension {}", self.name, d)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Tensor::new(self.data.clone(), shape)
+    }
+
+    fn write(&self) -> ProtoWriter {
+        let mut w = ProtoWriter::default();
+        for &d in &self.dims {
+            w.int(1, d);
+        }
+        w.int(2, self.dtype.code());
+        w.string(8, &self.name);
+        let mut raw = Vec::with_capacity(self.data.len() * self.dtype.size());
+        for &v in &self.data {
+            match self.dtype {
+                OnnxDtype::Float => raw.extend_from_slice(&(v as f32).to_le_bytes()),
+                OnnxDtype::Int32 => raw.extend_from_slice(&(v as i32).to_le_bytes()),
+                OnnxDtype::Int64 => raw.extend_from_slice(&(v as i64).to_le_bytes()),
+                OnnxDtype::Double => raw.extend_from_slice(&v.to_le_bytes()),
+            }
+        }
+        w.bytes(9, &raw);
+        w
+    }
+
+    fn read(buf: &[u8]) -> Result<Self, String> {
+        let mut r = ProtoReader::new(buf);
+        let (mut name, mut dtype, mut dims, mut raw) = (String::new(), None, Vec::new(), None);
+        let (mut floats, mut ints, mut doubles) = (Vec::new(), Vec::new(), Vec::new());
+        while let Some((field, wire)) = r.next_field()? {
+            match field {
+                1 => wire.ints(&mut dims)?,
+                2 => dtype = Some(OnnxDtype::from_code(wire.int()?)?),
+                4 => wire.floats(&mut floats)?,
+                5 | 7 => wire.ints(&mut ints)?,
+                8 => name = wire.string()?,
+                9 => raw = Some(wire.bytes()?),
+                10 => wire.doubles(&mut doubles)?,
+                13 | 14 => return Err(format!("tensor '{}' stores its data externally, which is not supported", name)),
+                _ => {}
+            }
+        }
+        let dtype = dtype.ok_or_else(|| format!("tensor '{}' has no data type", name))?;
+        let data: Vec<f64> = match (raw, dtype) {
+            (Some(raw), _) if raw.len() % dtype.size() != 0 => {
+                return Err(format!("tensor '{}' raw data is not a whole number of elements", name));
+            }
+            (Some(raw), OnnxDtype::Float) => raw.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]) as f64).collect(),
+            (Some(raw), OnnxDtype::Int32) => raw.chunks_exact(4).map(|c| i32::from_le_bytes([c[0], c[1], c[2], c[3]]) as f64).collect(),
+            (Some(raw), OnnxDtype::Int64) => raw.chunks_exact(8).map(|c| i64::from_le_bytes(c.try_into().unwrap()) as f64).collect(),
+            (Some(raw), OnnxDtype::Double) => raw.chunks_exact(8).map(|c| f64::from_le_bytes(c.try_into().unwrap())).collect(),
+            (None, OnnxDtype::Float) => floats.iter().map(|&v| v as f64).collect(),
+            (None, OnnxDtype::Int32) => ints.iter().map(|&v| v as i32 as f64).collect(),
+            (None, OnnxDtype::Int64) => ints.iter().map(|&v| v as f64).collect(),
+            (None, OnnxDtype::Double) => doubles,
+        };
+        let expected: i64 = dims.iter().product();
+        if expected != data.len() as i64 {
+            return Err(format!("tensor '{}' has {} elements for dims {:?}", name, data.len(), dims));
+        }
+        Ok(OnnxTensor { name, dtype, dims, data })
+    }
+}
+
+/// Dimension of a graph input or output: fixed, or a named symbolic size
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnnxDim {
+    Value(i64),
+    Param(String),
+}
+
+/// ValueInfoProto of a tensor-typed graph input or output
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnnxValueInfo {
+    pub name: String,
+    pub dtype: OnnxDtype,
+    pub dims: Vec<OnnxDim>,
+}
+
+impl OnnxValueInfo {
+    fn write(&self) -> ProtoWriter {
+        let mut shape = ProtoWriter::default();
+        for dim in &self.dims {
+            let mut d = ProtoWriter::default();
+            match dim {
+                OnnxDim::Value(v) => d.int(1, *v),
+                OnnxDim::Param(p) => d.string(2, p),
+            }
+            shape.message(1, &d);
+        }
+        let mut tensor_type = ProtoWriter::default();
+        tensor_type.int(1, self.dtype.code());
+        tensor_type.message(2, &shape);
+        let mut type_proto = ProtoWriter::default();
+        type_proto.message(1, &tensor_type);
+        let mut w = ProtoWriter::default();
+        w.string(1, &self.name);
+        w.message(2, &type_proto);
+        w
+    }
+
+    fn read(buf: &[u8]) -> Result<Self, String> {
+        let mut r = ProtoReader::new(buf);
+        let (mut name, mut tensor_type) = (String::new(), None);
+        while let Some((field, wire)) = r.next_field()? {
+            match field {
+                1 => name = wire.string()?,
+                2 => {
+                    let mut t = ProtoReader::new(wire.bytes()?);
+                    while let Some((field, wire)) = t.next_field()? {
+                        if field == 1 {
+                            tensor_type = Some(wire.bytes()?);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let tensor_type = tensor_type.ok_or_else(|| format!("graph value '{}' is not a tensor", name))?;
+        let (mut dtype, mut dims) = (None, Vec::new());
+        let mut r = ProtoReader::new(tensor_type);
+        while let Some((field, wire)) = r.next_field()? {
+            match field {
+                1 => dtype = Some(OnnxDtype::from_code(wire.int()?).map_err(|e| format!("graph value '{}': {}", name, e))?),
+                2 => {
+                    let mut shape = ProtoReader::new(wire.bytes()?);
+                    while let Some((field, wire)) = shape.next_field()? {
+                        if field != 1 {
+                            continue;
+                        }
+                        let mut d = ProtoReader::new(wire.bytes()?);
+                        let mut dim = OnnxDim::Param(String::new());
+                        while let Some((field, wire)) = d.next_field()? {
+                            match field {
+                                1 => dim = OnnxDim::Value(wire.int()?),
+                                2 => dim = OnnxDim::Param(wire.string()?),
+                                _ => {}
+                            }
+                        }
+                        dims.push(dim);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let dtype = dtype.ok_or_else(|| format!("graph value '{}' has no element type", name))?;
+        Ok(OnnxValueInfo { name, dtype, dims })
+    }
+}
+
+/// AttributeProto value
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnnxAttribute {
+    Float(f32),
+    Int(i64),
+    String(String),
+    Tensor(OnnxTensor),
+    Floats(Vec<f32>),
+    Ints(Vec<i64>),
+}
+
+impl OnnxAttribute {
+    fn write(&self, name: &str) -> ProtoWriter {
+        let mut w = ProtoWriter::default();
+        w.string(1, name);
+        let kind = match self {
+            OnnxAttribute::Float(v) => { w.float(2, *v); 1 }
+            OnnxAttribute::Int(v) => { w.int(3, *v); 2 }
+            OnnxAttribute::String(s) => { w.string(4, s); 3 }
+            OnnxAttribute::Tensor(t) => { w.message(5, &t.write()); 4 }
+            OnnxAttribute::Floats(vs) => { vs.iter().for_each(|&v| w.float(7, v)); 6 }
+            OnnxAttribute::Ints(vs) => { vs.iter().for_each(|&v| w.int(8, v)); 7 }
+        };
+        w.int(20, kind);
+        w
+    }
+
+    fn read(buf: &[u8]) -> Result<(String, Self), String> {
+        let mut r = ProtoReader::new(buf);
+        let (mut name, mut kind) = (String::new(), 0);
+        let (mut f, mut i, mut s, mut t) = (0.0, 0, String::new(), None);
+        let (mut floats, mut ints) = (Vec::new(), Vec::new());
+        while let Some((field, wire)) = r.next_field()? {
+            match field {
+                1 => name = wire.string()?,
+                2 => f = wire.float()?,
+                3 => i = wire.int()?,
+                4 => s = wire.string()?,
+                5 => t = Some(OnnxTensor::read(wire.bytes()?)?),
+                7 => wire.floats(&mut floats)?,
+                8 => wire.ints(&mut ints)?,
+                20 => kind = wire.int()?,
+                _ => {}
+            }
+        }
+        let value = match kind {
+            1 => OnnxAttribute::Float(f),
+            2 => OnnxAttribute::Int(i),
+            3 => OnnxAttribute::String(s),
+            4 => OnnxAttribute::Tensor(t.ok_or_else(|| format!("attribute '{}' has no tensor", name))?),
+            6 => OnnxAttribute::Floats(floats),
+            7 => OnnxAttribute::Ints(ints),
+            _ => return Err(format!("attribute '{}' has unsupported type {}", name, kind)),
+        };
+        Ok((name, value))
+    }
+}
+
+/// NodeProto of the default operator domain
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnnxNode {
+    pub name: String,
+    pub op_type: String,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    pub attributes: Vec<(String, OnnxAttribute)>,
+}
+
+impl OnnxNode {
+    pub fn attribute(&self, name: &str) -> Option<&OnnxAttribute> {
+        self.attributes.iter().find(|(n, _)| n == name).map(|(_, a)| a)
+    }
+
+    fn write(&self) -> ProtoWriter {
+        let mut w = ProtoWriter::default();
+        for input in &self.inputs {
+            w.string(1, input);
+        }
+        for output in &self.outputs {
+            w.string(2, output);
+        }
+        w.string(3, &self.name);
+        w.string(4, &self.op_type);
+        for (name, attr) in &self.attributes {
+            w.message(5, &attr.write(name));
+        }
+        w
+    }
+
+    fn read(buf: &[u8]) -> Result<Self, String> {
+        let mut r = ProtoReader::new(buf);
+        let mut node = OnnxNode { name: String::new(), op_type: String::new(), inputs: Vec::new(), outputs: Vec::new(), attributes: Vec::new() };
+        let mut domain = String::new();
+        while let Some((field, wire)) = r.next_field()? {
+            match field {
+                1 => node.inputs.push(wire.string()?),
+                2 => node.outputs.push(wire.string()?),
+                3 => node.name = wire.string()?,
+                4 => node.op_type = wire.string()?,
+                5 => node.attributes.push(OnnxAttribute::read(wire.bytes()?)?),
+                7 => domain = wire.string()?,
+                _ => {}
+            }
+        }
+        if !domain.is_empty() && domain != "ai.onnx" {
+            return Err(format!("node '{}' uses operator domain '{}'; only the default ONNX domain is supported", node.name, domain));
+        }
+        Ok(node)
+    }
+}
+
+/// GraphProto: nodes in topological order, weights as initializers
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct OnnxGraph {
+    pub name: String,
+    pub nodes: Vec<OnnxNode>,
+    pub initializers: Vec<OnnxTensor>,
+    pub inputs: Vec<OnnxValueInfo>,
+    pub outputs: Vec<OnnxValueInfo>,
+}
+
+impl OnnxGraph {
+    fn write(&self) -> ProtoWriter {
+        let mut w = ProtoWriter::default();
+        for node in &self.nodes {
+            w.message(1, &node.write());
+        }
+        w.string(2, &self.name);
+        for init in &self.initializers {
+            w.message(5, &init.write());
+        }
+        for input in &self.inputs {
+            w.message(11, &input.write());
+        }
+        for output in &self.outputs {
+            w.message(12, &output.write());
+        }
+        w
+    }
+
+    fn read(buf: &[u8]) -> Result<Self, String> {
+        let mut r = ProtoReader::new(buf);
+        let mut graph = OnnxGraph::default();
+        while let Some((field, wire)) = r.next_field()? {
+            match field {
+                1 => graph.nodes.push(OnnxNode::read(wire.bytes()?)?),
+                2 => graph.name = wire.string()?,
+                5 => graph.initializers.push(OnnxTensor::read(wire.bytes()?)?),
+                11 => graph.inputs.push(OnnxValueInfo::read(wire.bytes()?)?),
+                12 => graph.outputs.push(OnnxValueInfo::read(wire.bytes()?)?),
+                _ => {}
+            }
+        }
+        Ok(graph)
+    }
+}
+
+/// ModelProto restricted to one graph in the default operator domain
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnnxModel {
+    pub ir_version: i64,
+    pub opset: i64,
+    pub producer: String,
+    pub graph: OnnxGraph,
+}
+
+impl OnnxModel {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = ProtoWriter::default();
+        w.int(1, self.ir_version);
+        w.string(2, &self.producer);
+        w.string(3, env!("CARGO_PKG_VERSION"));
+        w.message(7, &self.graph.write());
+        let mut opset = ProtoWriter::default();
+        opset.string(1, "");
+        opset.int(2, self.opset);
+        w.message(8, &opset);
+        w.buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, String> {
+        let mut r = ProtoReader::new(buf);
+        let (mut ir_version, mut opset, mut producer, mut graph) = (0, None, String::new(), None);
+        while let Some((field, wire)) = r.next_field()? {
+            match field {
+                1 => ir_version = wire.int()?,
+                2 => producer = wire.string()?,
+                7 => graph = Some(OnnxGraph::read(wire.bytes()?)?),
+                8 => {
+                    let mut o = ProtoReader::new(wire.bytes()?);
+                    let (mut domain, mut version) = (String::new(), 0);
+                    while let Some((field, wire)) = o.next_field()? {
+                        match field {
+                            1 => domain = wire.string()?,
+                            2 => version = wire.int()?,
+                            _ => {}
+                        }
+                    }
+                    if domain.is_empty() || domain == "ai.onnx" {
+                        opset = Some(version);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let graph = graph.ok_or("ONNX model has no graph")?;
+        let opset = opset.ok_or("ONNX model does not import the default operator set")?;
+        Ok(OnnxModel { ir_version, opset, producer, graph })
+    }
+}
+
+// ============================================================================
+// ONNX Export
+// ============================================================================
+
+/// Elementwise NOMA builtins with one argument and their ONNX operators
+const UNARY_OPS: [(&str, &str); 13] = [
+    ("sigmoid", "Sigmoid"), ("relu", "Relu"), ("tanh", "Tanh"), ("exp", "Exp"), ("log", "Log"),
+    ("sqrt", "Sqrt"), ("abs", "Abs"), ("sin", "Sin"), ("cos", "Cos"), ("floor", "Floor"),
+    ("ceil", "Ceil"), ("sign", "Sign"), ("softplus", "Softplus"),
+];
+
+fn onnx_unary_op(name: &str) -> Option<&'static str> {
+    UNARY_OPS.iter().find(|(noma, _)| *noma == name).map(|(_, onnx)| *onnx)
+}
+
+fn onnx_binary_op(op: &str) -> Result<&'static str, String> {
+    match op {
+        "add" => Ok("Add"),
+        "sub" => Ok("Sub"),
+        "mul" => Ok("Mul"),
+        "div" => Ok("Div"),
+        "pow" => Ok("Pow"),
+        _ => Err(format!("ONNX export does not support the '{}' operator", op)),
+    }
+}
+
+struct OnnxExporter<'g> {
+    graph: &'g ComputationalGraph,
+    inputs: HashMap<NodeId, String>,
+    /// Nodes that depend on a learnable or a graph input; everything else is folded
+    live: HashSet<NodeId>,
+    dynamic_batch: bool,
+    names: HashMap<NodeId, String>,
+    scalars: HashMap<u64, String>,
+    out: OnnxGraph,
+}
+
+impl<'g> OnnxExporter<'g> {
+    fn node(&self, id: NodeId) -> Result<&'g Node, String> {
+        self.graph.get_node(id).ok_or_else(|| format!("node {} is not in the graph", id.index()))
+    }
+
+    fn value(&self, id: NodeId) -> Result<&'g Value, String> {
+        self.node(id)?.value.as_ref()
+            .ok_or_else(|| format!("node {} has no value; run a forward pass before exporting", id.index()))
+    }
+
+    fn value_info(&self, name: &str, value: &Value) -> OnnxValueInfo {
+        let shape = tensor_of(value).shape;
+        let dims = shape.iter().enumerate().map(|(i, &d)| match i {
+            0 if self.dynamic_batch => OnnxDim::Param("batch".to_string()),
+            _ => OnnxDim::Value(d as i64),
+        }).collect();
+        OnnxValueInfo { name: name.to_string(), dtype: OnnxDtype::Float, dims }
+    }
+
+    /// Name of an initializer holding `data`, e.g. a Reshape target or a decomposition constant
+    fn constant(&mut self, dtype: OnnxDtype, dims: Vec<i64>, data: Vec<f64>) -> String {
+        let name = format!("const_{}", self.out.initializers.len());
+        self.out.initializers.push(OnnxTensor { name: name.clone(), dtype, dims, data });
+        name
+    }
+
+    fn scalar(&mut self, v: f64) -> String {
+        if let Some(name) = self.scalars.get(&v.to_bits()) {
+            return name.clone();
+        }
+        let name = self.constant(OnnxDtype::Float, Vec::new(), vec![v]);
+        self.scalars.insert(v.to_bits(), name.clone());
+        name
+    }
+
+    fn axes(&mut self, axes: &[usize]) -> String {
+        self.constant(OnnxDtype::Int64, vec![axes.len() as i64], axes.iter().map(|&a| a as f64).collect())
+    }
+
+    fn push(&mut self, op_type: &str, inputs: Vec<String>, attributes: Vec<(String, OnnxAttribute)>) -> String {
+        let name = format!("{}_{}", op_type.to_lowercase(), self.out.nodes.len());
+        self.out.nodes.push(OnnxNode { name: name.clone(), op_type: op_type.to_string(), inputs, outputs: vec![name.clone()], attributes });
+        name
+    }
+
+    /// Graph input, learnable or folded constant for `node`; None if it is an operator to lower
+    fn emit_leaf(&mut self, node: &Node) -> Result<Option<String>, String> {
+        if let Some(name) = self.inputs.get(&node.id).cloned() {
+            let info = self.value_info(&name, self.value(node.id)?);
+            self.out.inputs.push(info);
+            return Ok(Some(name));
+        }
+        let name = match &node.node_type {
+            NodeType::Learnable(name) => name.clone(),
+            NodeType::FreedTensor(name) => return Err(format!("tensor '{}' was freed before export", name)),
+            _ if !self.live.contains(&node.id) => format!("const_{}", self.out.initializers.len()),
+            _ => return Ok(None),
+        };
+        let tensor = OnnxTensor::from_value(name.clone(), self.value(node.id)?);
+        self.out.initializers.push(tensor);
+        Ok(Some(name))
+    }
+
+    /// Inputs of an operator node that carry data; the rest (axis, alpha, dims) become attributes
+    fn data_inputs(&self, node: &Node) -> Result<Vec<NodeId>, String> {
+        let n = match &node.node_type {
+            NodeType::Variable(_) => 1,
+            NodeType::UnaryOp(op) if op == "neg" => 1,
+            NodeType::UnaryOp(op) => return Err(format!("ONNX export does not support the '{}' operator", op)),
+            NodeType::BinaryOp(op) => { onnx_binary_op(op)?; 2 }
+            NodeType::FunctionCall(name) => match name.as_str() {
+                "matmul" | "matvec" | "vecmat" | "dot" | "maximum" | "max" | "minimum" | "min" => 2,
+                "clip" => 3,
+                "concat" => match node.inputs.last() {
+                    Some(&last) if node.inputs.len() > 1 && matches!(self.value(last)?, Value::Scalar(_)) => node.inputs.len() - 1,
+                    _ => node.inputs.len(),
+                },
+                "print" | "log_metric" | "dropout" | "sum" | "mean" | "softmax" | "log_softmax" | "leaky_relu" | "elu"
+                | "gelu" | "swish" | "reshape" | "transpose" | "squeeze" | "unsqueeze" => 1,
+                name if onnx_unary_op(name).is_some() => 1,
+                "mse" | "huber" | "cross_entropy" | "binary_cross_entropy" => {
+                    return Err(format!("ONNX export reached the training loss '{}'; export the model output instead (--output-var)", name));
+                }
+                name => return Err(format!("ONNX export does not support '{}'", name)),
+            },
+            other => return Err(format!("ONNX export does not support {:?} nodes", other)),
+        };
+        Ok(node.inputs[..n].to_vec())
+    }
+
+    /// Lower an operator whose data inputs are already named; returns the name of its value
+    fn emit_op(&mut self, node: &Node) -> Result<String, String> {
+        let data = self.data_inputs(node)?;
+        let mut x: Vec<String> = data.iter().map(|id| self.names[id].clone()).collect();
+        let arg = |i: usize| node.inputs.get(i).map(|&id| self.value(id)).transpose();
+        let name = match &node.node_type {
+            NodeType::Variable(_) => x.remove(0),
+            NodeType::UnaryOp(_) => self.push("Neg", x, Vec::new()),
+            NodeType::BinaryOp(op) => self.push(onnx_binary_op(op)?, x, Vec::new()),
+            NodeType::FunctionCall(name) => match name.as_str() {
+                // Pass-throughs at inference time
+                "print" | "log_metric" | "dropout" => x.remove(0),
+                "matmul" | "matvec" | "vecmat" | "dot" => self.push("MatMul", x, Vec::new()),
+                "maximum" | "max" => self.push("Max", x, Vec::new()),
+                "minimum" | "min" => self.push("Min", x, Vec::new()),
+                "clip" => {
+                    let hi = x.pop().unwrap();
+                    let lo = self.push("Max", x, Vec::new());
+                    self.push("Min", vec![lo, hi], Vec::new())
+                }
+                "sum" | "mean" if matches!(self.value(data[0])?, Value::Scalar(_)) => x.remove(0),
+                "sum" => self.push("ReduceSum", x, vec![("keepdims".to_string(), OnnxAttribute::Int(0))]),
+                "mean" => self.push("ReduceMean", x, vec![("keepdims".to_string(), OnnxAttribute::Int(0))]),
+                "softmax" | "log_softmax" => {
+                    let rank = tensor_of(self.value(data[0])?).shape.len();
+                    let axis = match arg(1)? {
+                        Some(a) => resolve_axis(a, rank, name)?,
+                        None => rank - 1,
+                    };
+                    let op = if name == "softmax" { "Softmax" } else { "LogSoftmax" };
+                    self.push(op, x, vec![("axis".to_string(), OnnxAttribute::Int(axis as i64))])
+                }
+                "leaky_relu" | "elu" => {
+                    let alpha = match arg(1)? {
+                        Some(a) => a.as_scalar().ok_or_else(|| format!("{} alpha must be a scalar", name))?,
+                        None if name == "leaky_relu" => 0.01,
+                        None => 1.0,
+                    };
+                    let op = if name == "leaky_relu" { "LeakyRelu" } else { "Elu" };
+                    self.push(op, x, vec![("alpha".to_string(), OnnxAttribute::Float(alpha as f32))])
+                }
+                "gelu" => {
+                    // tanh approximation: 0.5 * x * (1 + tanh(sqrt(2/pi) * (x + 0.044715 * x^3)))
+                    let v = x.remove(0);
+                    let (c, k, half, one) = (self.scalar(0.7978845608028654), self.scalar(0.044715), self.scalar(0.5), self.scalar(1.0));
+                    let x2 = self.push("Mul", vec![v.clone(), v.clone()], Vec::new());
+                    let x3 = self.push("Mul", vec![x2, v.clone()], Vec::new());
+                    let kx3 = self.push("Mul", vec![k, x3], Vec::new());
+                    let inner = self.push("Add", vec![v.clone(), kx3], Vec::new());
+                    let scaled = self.push("Mul", vec![c, inner], Vec::new());
+                    let t = self.push("Tanh", vec![scaled], Vec::new());
+                    let gate = self.push("Add", vec![one, t], Vec::new());
+                    let hx = self.push("Mul", vec![half, v], Vec::new());
+                    self.push("Mul", vec![hx, gate], Vec::new())
+                }
+                "swish" => {
+                    let s = self.push("Sigmoid", x.clone(), Vec::new());
+                    self.push("Mul", vec![x.remove(0), s], Vec::new())
+                }
+                "reshape" => {
+                    let input = tensor_of(self.value(data[0])?).shape;
+                    let output = tensor_of(self.value(node.id)?).shape;
+                    let dims: Vec<f64> = output.iter().enumerate().map(|(i, &d)| {
+                        // 0 copies the input's (symbolic) batch size
+                        if i == 0 && self.dynamic_batch && input.first() == Some(&d) { 0.0 } else { d as f64 }
+                    }).collect();
+                    let shape = self.constant(OnnxDtype::Int64, vec![dims.len() as i64], dims);
+                    x.push(shape);
+                    self.push("Reshape", x, Vec::new())
+                }
+                "transpose" => {
+                    let rest = node.inputs[1..].iter().map(|&id| self.value(id).cloned()).collect::<Result<Vec<_>, _>>()?;
+                    let perm = transpose_perm(&tensor_of(self.value(data[0])?), &rest)?;
+                    self.push("Transpose", x, vec![("perm".to_string(), OnnxAttribute::Ints(perm.iter().map(|&p| p as i64).collect()))])
+                }
+                "concat" => {
+                    let rank = tensor_of(self.value(node.id)?).shape.len();
+                    let axis = match node.inputs.get(data.len()) {
+                        Some(&id) => resolve_axis(self.value(id)?, rank, name)?,
+                        None => 0,
+                    };
+                    self.push("Concat", x, vec![("axis".to_string(), OnnxAttribute::Int(axis as i64))])
+                }
+                "squeeze" => {
+                    let shape = tensor_of(self.value(data[0])?).shape;
+                    let axes: Vec<usize> = match arg(1)? {
+                        Some(a) => vec![resolve_axis(a, shape.len(), name)?],
+                        None => (0..shape.len()).filter(|&i| shape[i] == 1).collect(),
+                    };
+                    let axes = self.axes(&axes);
+                    x.push(axes);
+                    self.push("Squeeze", x, Vec::new())
+                }
+                "unsqueeze" => {
+                    let rank = tensor_of(self.value(data[0])?).shape.len() + 1;
+                    let axis = resolve_axis(arg(1)?.ok_or("unsqueeze expects (x, axis)")?, rank, name)?;
+                    let axes = self.axes(&[axis]);
+                    x.push(axes);
+                    self.push("Unsqueeze", x, Vec::new())
+                }
+                name => {
+                    let op = onnx_unary_op(name).ok_or_else(|| format!("ONNX export does not support '{}'", name))?;
+                    self.push(op, x, Vec::new())
+                }
+            },
+            _ => unreachable!("leaf nodes are emitted by emit_leaf"),
+        };
+        Ok(name)
+    }
+}
+
+/// Export the forward graph computing `output` as an ONNX model. Learnables become float
+/// initializers with their current values and `inputs` become graph inputs; any subgraph that
+/// depends on neither is folded into an initializer. Values must come from a forward pass.
+/// With `dynamic_batch` the first axis of every input and output is the symbolic size "batch".
+pub fn export_onnx(
+    graph: &ComputationalGraph,
+    inputs: &[(String, NodeId)],
+    output: (&str, NodeId),
+    dynamic_batch: bool,
+) -> Result<OnnxModel, String> {
+    let inputs: HashMap<NodeId, String> = inputs.iter().map(|(name, id)| (*id, name.clone())).collect();
+    let mut ids: Vec<NodeId> = graph.nodes().keys().copied().collect();
+    ids.sort_by_key(|id| id.index());
+    // Node inputs are always created before the node itself
+    let mut live = HashSet::new();
+    for id in ids {
+        let node = &graph.nodes()[&id];
+        if matches!(node.node_type, NodeType::Learnable(_)) || inputs.contains_key(&id) || node.inputs.iter().any(|i| live.contains(i)) {
+            live.insert(id);
+        }
+    }
+
+    let mut ex = OnnxExporter { graph, inputs, live, dynamic_batch, names: HashMap::new(), scalars: HashMap::new(), out: OnnxGraph::default() };
+    ex.out.name = "noma".to_string();
+    let (output_name, output_id) = output;
+    let mut stack = vec![(output_id, false)];
+    while let Some((id, ready)) = stack.pop() {
+        if ex.names.contains_key(&id) {
+            continue;
+        }
+        let node = ex.node(id)?;
+        if ready {
+            let name = ex.emit_op(node)?;
+            ex.names.insert(id, name);
+        } else if let Some(name) = ex.emit_leaf(node)? {
+            ex.names.insert(id, name);
+        } else {
+            stack.push((id, true));
+            stack.extend(ex.data_inputs(node)?.into_iter().rev().map(|d| (d, false)));
+        }
+    }
+
+    // Name the output after the variable; a graph input or initializer is passed through Identity
+    let value = ex.names[&output_id].clone();
+    let produced = ex.out.nodes.iter().any(|n| n.outputs[0] == value);
+    let taken = ex.out.initializers.iter().any(|t| t.name == output_name) || ex.out.inputs.iter().any(|i| i.name == output_name);
+    let output_name = if produced && !taken {
+        for node in &mut ex.out.nodes {
+            for name in node.inputs.iter_mut().chain(node.outputs.iter_mut()) {
+                if *name == value {
+                    *name = output_name.to_string();
+                }
+            }
+        }
+        output_name.to_string()
+    } else {
+        let renamed = if taken { format!("{}_output", output_name) } else { output_name.to_string() };
+        ex.push("Identity", vec![value], Vec::new());
+        ex.out.nodes.last_mut().unwrap().outputs[0] = renamed.clone();
+        renamed
+    };
+    let info = ex.value_info(&output_name, ex.value(output_id)?);
+    ex.out.outputs.push(info);
+
+    Ok(OnnxModel { ir_version: ONNX_IR_VERSION, opset: ONNX_OPSET, producer: "noma".to_string(), graph: ex.out })
+}
+
+// ============================================================================
+// ONNX Import
+// ============================================================================
+
+impl OnnxNode {
+    fn int_attr(&self, name: &str, default: i64) -> Result<i64, String> {
+        match self.attribute(name) {
+            Some(OnnxAttribute::Int(v)) => Ok(*v),
+            Some(_) => Err(format!("{} attribute '{}' must be an integer", self.op_type, name)),
+            None => Ok(default),
+        }
+    }
+
+    fn float_attr(&self, name: &str, default: f64) -> Result<f64, String> {
+        match self.attribute(name) {
+            Some(OnnxAttribute::Float(v)) => Ok(*v as f64),
+            Some(_) => Err(format!("{} attribute '{}' must be a float", self.op_type, name)),
+            None => Ok(default),
+        }
+    }
+
+    fn ints_attr(&self, name: &str) -> Result<Option<Vec<i64>>, String> {
+        match self.attribute(name) {
+            Some(OnnxAttribute::Ints(v)) => Ok(Some(v.clone())),
+            Some(_) => Err(format!("{} attribute '{}' must be a list of integers", self.op_type, name)),
+            None => Ok(None),
+        }
+    }
+}
+
+struct OnnxImporter<'g> {
+    graph: &'g mut ComputationalGraph,
+    opset: i64,
+    /// Node of every ONNX value defined so far
+    values: HashMap<String, NodeId>,
+    /// Shape of every node built so far, inferred from the shapes of its inputs
+    shapes: HashMap<NodeId, Vec<usize>>,
+}
+
+impl OnnxImporter<'_> {
+    fn input(&self, node: &OnnxNode, i: usize) -> Result<NodeId, String> {
+        self.optional_input(node, i)?
+            .ok_or_else(|| format!("{} node '{}' is missing input {}", node.op_type, node.name, i))
+    }
+
+    /// Node of input `i`, or None if it is omitted (absent or named "")
+    fn optional_input(&self, node: &OnnxNode, i: usize) -> Result<Option<NodeId>, String> {
+        match node.inputs.get(i).map(String::as_str) {
+            None | Some("") => Ok(None),
+            Some(name) => self.values.get(name).copied().map(Some)
+                .ok_or_else(|| format!("{} node '{}' reads undefined value '{}'", node.op_type, node.name, name)),
+        }
+    }
+
+    /// Shape of a node built by the import, or of an initializer, constant or evaluated input
+    fn shape(&self, id: NodeId) -> Result<Vec<usize>, String> {
+        if let Some(shape) = self.shapes.get(&id) {
+            return Ok(shape.clone());
+        }
+        self.graph.get_node(id).and_then(|n| n.value.as_ref()).map(|v| tensor_of(v).shape)
+            .ok_or_else(|| "the shape of a value could not be inferred".to_string())
+    }
+
+    fn input_shape(&self, node: &OnnxNode, i: usize) -> Result<Vec<usize>, String> {
+        self.shape(self.input(node, i)?)
+    }
+
+    /// Integer list held by a constant input (Reshape shape, Squeeze axes, ...)
+    fn const_ints(&self, node: &OnnxNode, i: usize) -> Result<Option<Vec<i64>>, String> {
+        let Some(id) = self.optional_input(node, i)? else { return Ok(None) };
+        match self.graph.get_node(id).map(|n| &n.node_type) {
+            Some(NodeType::Constant(value)) => Ok(Some(tensor_of(value).data.iter().map(|&v| v as i64).collect())),
+            _ => Err(format!("{} node '{}' needs input {} to be a constant", node.op_type, node.name, i)),
+        }
+    }
+
+    /// Axes of a Squeeze/Unsqueeze/Reduce node: an input from opset 13 on, an attribute before
+    fn axes(&self, node: &OnnxNode, input_since: i64) -> Result<Option<Vec<i64>>, String> {
+        if self.opset >= input_since { self.const_ints(node, 1) } else { node.ints_attr("axes") }
+    }
+
+    fn call(&mut self, name: &str, args: Vec<NodeId>) -> NodeId {
+        self.graph.add_function_call(name.to_string(), args)
+    }
+
+    fn constants(&mut self, values: impl IntoIterator<Item = i64>) -> Vec<NodeId> {
+        values.into_iter().map(|v| self.graph.add_constant(v as f64)).collect()
+    }
+
+    fn lower(&mut self, node: &OnnxNode) -> Result<NodeId, String> {
+        let op = node.op_type.as_str();
+        let id = match op {
+            "Add" | "Sub" | "Mul" | "Div" | "Pow" => {
+                let (a, b) = (self.input(node, 0)?, self.input(node, 1)?);
+                self.graph.add_binary_op(&op.to_lowercase(), a, b)
+            }
+            "Neg" => {
+                let x = self.input(node, 0)?;
+                self.graph.add_unary_op("neg", x)
+            }
+            "Identity" | "Dropout" => self.input(node, 0)?,
+            "MatMul" => {
+                let (a, b) = (self.input(node, 0)?, self.input(node, 1)?);
+                let name = match (self.shape(a)?.len(), self.shape(b)?.len()) {
+                    (2, 2) => "matmul",
+                    (2, 1) => "matvec",
+                    (1, 2) => "vecmat",
+                    (1, 1) => "dot",
+                    (ra, rb) => return Err(format!("MatMul node '{}' multiplies rank {} by rank {}; only rank 1 and 2 are supported", node.name, ra, rb)),
+                };
+                self.call(name, vec![a, b])
+            }
+            "Gemm" => {
+                // alpha * op(A) @ op(B) + beta * C
+                let (mut a, mut b) = (self.input(node, 0)?, self.input(node, 1)?);
+                if node.int_attr("transA", 0)? != 0 {
+                    a = self.call("transpose", vec![a]);
+                }
+                if node.int_attr("transB", 0)? != 0 {
+                    b = self.call("transpose", vec![b]);
+                }
+                let mut y = self.call("matmul", vec![a, b]);
+                let alpha = node.float_attr("alpha", 1.0)?;
+                if alpha != 1.0 {
+                    let s = self.graph.add_constant(alpha);
+                    y = self.graph.add_binary_op("mul", y, s);
+                }
+                if let Some(mut c) = self.optional_input(node, 2)? {
+                    let beta = node.float_attr("beta", 1.0)?;
+                    if beta != 1.0 {
+                        let s = self.graph.add_constant(beta);
+                        c = self.graph.add_binary_op("mul", c, s);
+                    }
+                    y = self.graph.add_binary_op("add", y, c);
+                }
+                y
+            }
+            "LeakyRelu" | "Elu" => {
+                let x = self.input(node, 0)?;
+                let alpha = node.float_attr("alpha", if op == "Elu" { 1.0 } else { 0.01 })?;
+                let alpha = self.graph.add_constant(alpha);
+                self.call(if op == "Elu" { "elu" } else { "leaky_relu" }, vec![x, alpha])
+            }
+            "Gelu" => {
+                let x = self.input(node, 0)?;
+                match node.attribute("approximate") {
+                    Some(OnnxAttribute::String(s)) if s == "tanh" => self.call("gelu", vec![x]),
+                    _ => return Err(format!("Gelu node '{}' uses the exact form; NOMA's gelu is the tanh approximation", node.name)),
+                }
+            }
+            "Softmax" | "LogSoftmax" => {
+                let x = self.input(node, 0)?;
+                let rank = self.shape(x)?.len() as i64;
+                // Before opset 13 the input is flattened at `axis` (default 1), which only matches
+                // NOMA's per-axis softmax when that is the last axis
+                let axis = node.int_attr("axis", if self.opset >= 13 { -1 } else { 1 })?;
+                if self.opset < 13 && (axis + rank) % rank.max(1) != rank - 1 {
+                    return Err(format!("{} node '{}' (opset {}) flattens at axis {}; only the last axis is supported", op, node.name, self.opset, axis));
+                }
+                let axis = self.graph.add_constant(axis as f64);
+                self.call(if op == "Softmax" { "softmax" } else { "log_softmax" }, vec![x, axis])
+            }
+            "Max" | "Min" => {
+                let mut acc = self.input(node, 0)?;
+                for i in 1..node.inputs.len() {
+                    let next = self.input(node, i)?;
+                    acc = self.call(if op == "Max" { "maximum" } else { "minimum" }, vec![acc, next]);
+                }
+                acc
+            }
+            "Clip" => {
+                let x = self.input(node, 0)?;
+                let (lo, hi) = if self.opset >= 11 {
+                    (self.optional_input(node, 1)?, self.optional_input(node, 2)?)
+                } else {
+                    let lo = node.float_attr("min", f64::NEG_INFINITY)?;
+                    let hi = node.float_attr("max", f64::INFINITY)?;
+                    (Some(self.graph.add_constant(lo)), Some(self.graph.add_constant(hi)))
+                };
+                let lo = lo.unwrap_or_else(|| self.graph.add_constant(f64::NEG_INFINITY));
+                let hi = hi.unwrap_or_else(|| self.graph.add_constant(f64::INFINITY));
+                self.call("clip", vec![x, lo, hi])
+            }
+            "ReduceSum" | "ReduceMean" => {
+                let x = self.input(node, 0)?;
+                let rank = self.shape(x)?.len() as i64;
+                let axes = self.axes(node, if op == "ReduceSum" { 13 } else { 18 })?.unwrap_or_default();
+                let full = axes.is_empty() || (0..rank).all(|a| axes.iter().any(|&b| (b + rank) % rank.max(1) == a));
+                if node.int_attr("keepdims", 1)? != 0 || !full {
+                    return Err(format!("{} node '{}' must reduce every axis with keepdims=0", op, node.name));
+                }
+                self.call(if op == "ReduceSum" { "sum" } else { "mean" }, vec![x])
+            }
+            "Reshape" => {
+                let x = self.input(node, 0)?;
+                let input = self.shape(x)?;
+                let target = self.const_ints(node, 1)?.ok_or_else(|| format!("Reshape node '{}' has no shape", node.name))?;
+                let dims: Vec<i64> = target.iter().enumerate()
+                    .map(|(i, &d)| if d == 0 { input.get(i).map(|&n| n as i64).unwrap_or(0) } else { d })
+                    .collect();
+                let mut args = vec![x];
+                args.extend(self.constants(dims));
+                self.call("reshape", args)
+            }
+            "Flatten" => {
+                let x = self.input(node, 0)?;
+                let shape = self.shape(x)?;
+                let axis = node.int_attr("axis", 1)?;
+                let axis = if axis < 0 { axis + shape.len() as i64 } else { axis } as usize;
+                let outer: usize = shape[..axis.min(shape.len())].iter().product();
+                let inner: usize = shape[axis.min(shape.len())..].iter().product();
+                let mut args = vec![x];
+                args.extend(self.constants([outer as i64, inner as i64]));
+                self.call("reshape", args)
+            }
+            "Transpose" => {
+                let mut args = vec![self.input(node, 0)?];
+                args.extend(self.constants(node.ints_attr("perm")?.unwrap_or_default()));
+                self.call("transpose", args)
+            }
+            "Concat" => {
+                let mut args = (0..node.inputs.len()).map(|i| self.input(node, i)).collect::<Result<Vec<_>, _>>()?;
+                let axis = node.int_attr("axis", i64::MIN)?;
+                if axis == i64::MIN {
+                    return Err(format!("Concat node '{}' has no axis", node.name));
+                }
+                args.push(self.graph.add_constant(axis as f64));
+                self.call("concat", args)
+            }
+            "Squeeze" | "Unsqueeze" => {
+                let mut x = self.input(node, 0)?;
+                let axes = self.axes(node, 13)?;
+                match axes {
+                    None if op == "Squeeze" => self.call("squeeze", vec![x]),
+                    None => return Err(format!("Unsqueeze node '{}' has no axes", node.name)),
+                    Some(axes) => {
+                        // One axis at a time: squeeze from the back, unsqueeze from the front
+                        let rank = (self.shape(x)?.len() + if op == "Unsqueeze" { axes.len() } else { 0 }) as i64;
+                        let mut axes: Vec<i64> = axes.iter().map(|&a| if a < 0 { a + rank } else { a }).collect();
+                        axes.sort_unstable();
+                        if op == "Squeeze" {
+                            axes.reverse();
+                        }
+                        for a in axes {
+                            let a = self.graph.add_constant(a as f64);
+                            x = self.call(&op.to_lowercase(), vec![x, a]);
+                        }
+                        x
+                    }
+                }
+            }
+            "Constant" => match node.attributes.first() {
+                Some((_, OnnxAttribute::Tensor(t))) => {
+                    let t = t.to_tensor()?;
+                    self.graph.add_loaded_tensor(t.data, t.shape)?
+                }
+                Some((_, OnnxAttribute::Float(v))) => self.graph.add_constant(*v as f64),
+                Some((_, OnnxAttribute::Int(v))) => self.graph.add_constant(*v as f64),
+                Some((_, OnnxAttribute::Floats(vs))) => self.graph.add_constant_tensor(vs.iter().map(|&v| v as f64).collect(), vec![vs.len()])?,
+                Some((_, OnnxAttribute::Ints(vs))) => self.graph.add_constant_tensor(vs.iter().map(|&v| v as f64).collect(), vec![vs.len()])?,
+                _ => return Err(format!("Constant node '{}' has no numeric value", node.name)),
+            },
+            op => match UNARY_OPS.iter().find(|(_, onnx)| *onnx == op) {
+                Some((noma, _)) => {
+                    let x = self.input(node, 0)?;
+                    self.call(noma, vec![x])
+                }
+                None => return Err(format!("unsupported ONNX op '{}' (node '{}')", op, node.name)),
+            },
+        };
+        Ok(id)
+    }
+
+    /// Shape of the output of `node`, once `lower` has accepted it
+    fn output_shape(&self, node: &OnnxNode) -> Result<Vec<usize>, String> {
+        let op = node.op_type.as_str();
+        let shape = match op {
+            "Add" | "Sub" | "Mul" | "Div" | "Pow" | "Max" | "Min" => {
+                let mut shape = self.input_shape(node, 0)?;
+                for i in 1..node.inputs.len() {
+                    shape = broadcast_shapes(&shape, &self.input_shape(node, i)?)?;
+                }
+                shape
+            }
+            "MatMul" => {
+                let (a, b) = (self.input_shape(node, 0)?, self.input_shape(node, 1)?);
+                a[..a.len() - 1].iter().chain(b.get(1)).copied().collect()
+            }
+            "Gemm" => {
+                let (a, b) = (self.input_shape(node, 0)?, self.input_shape(node, 1)?);
+                let rows = if node.int_attr("transA", 0)? != 0 { a.get(1) } else { a.first() };
+                let cols = if node.int_attr("transB", 0)? != 0 { b.first() } else { b.get(1) };
+                let shape = [rows, cols].iter().map(|d| d.copied().ok_or_else(|| format!("Gemm node '{}' needs matrices", node.name)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                match self.optional_input(node, 2)? {
+                    Some(c) => broadcast_shapes(&shape, &self.shape(c)?)?,
+                    None => shape,
+                }
+            }
+            "ReduceSum" | "ReduceMean" => Vec::new(),
+            "Reshape" => {
+                let input = self.input_shape(node, 0)?;
+                let target = self.const_ints(node, 1)?.unwrap_or_default();
+                let mut shape: Vec<usize> = target.iter().enumerate()
+                    .map(|(i, &d)| if d == 0 { input.get(i).copied().unwrap_or(0) } else { d.max(1) as usize })
+                    .collect();
+                if let Some(i) = target.iter().position(|&d| d == -1) {
+                    let known: usize = shape.iter().product();
+                    shape[i] = input.iter().product::<usize>() / known.max(1);
+                }
+                shape
+            }
+            "Flatten" => {
+                let shape = self.input_shape(node, 0)?;
+                let axis = node.int_attr("axis", 1)?;
+                let axis = (if axis < 0 { axis + shape.len() as i64 } else { axis } as usize).min(shape.len());
+                vec![shape[..axis].iter().product(), shape[axis..].iter().product()]
+            }
+            "Transpose" => {
+                let shape = self.input_shape(node, 0)?;
+                match node.ints_attr("perm")? {
+                    Some(perm) => perm.iter().map(|&p| shape[if p < 0 { p + shape.len() as i64 } else { p } as usize]).collect(),
+                    None => shape.into_iter().rev().collect(),
+                }
+            }
+            "Concat" => {
+                let mut shape = self.input_shape(node, 0)?;
+                let axis = node.int_attr("axis", 0)?;
+                let axis = if axis < 0 { axis + shape.len() as i64 } else { axis } as usize;
+                for i in 1..node.inputs.len() {
+                    shape[axis] += self.input_shape(node, i)?[axis];
+                }
+                shape
+            }
+            "Squeeze" | "Unsqueeze" => {
+                let mut shape = self.input_shape(node, 0)?;
+                match self.axes(node, 13)? {
+                    None => shape.retain(|&d| d != 1),
+                    Some(axes) => {
+                        let rank = (shape.len() + if op == "Unsqueeze" { axes.len() } else { 0 }) as i64;
+                        let mut axes: Vec<usize> = axes.iter().map(|&a| if a < 0 { a + rank } else { a } as usize).collect();
+                        axes.sort_unstable();
+                        if op == "Squeeze" {
+                            for a in axes.into_iter().rev() {
+                                shape.remove(a);
+                            }
+                        } else {
+                            for a in axes {
+                                shape.insert(a, 1);
+                            }
+                        }
+                    }
+                }
+                shape
+            }
+            // Constants carry their value; every other op keeps the shape of its first input
+            "Constant" => return self.shape(self.values.get(&node.outputs[0]).copied().ok_or("Constant node has no output")?),
+            _ => self.input_shape(node, 0)?,
+        };
+        Ok(shape)
+    }
+}
+
+/// Build the graph of an ONNX model on top of `graph`. Float initializers of rank 1 or more become
+/// learnables named `name.<initializer>`, reusing the learnable `variables` already binds to that
+/// name; other initializers become constants. Graph inputs read `inputs` in order, or else the
+/// variables of the same name. Returns the variables to bind: the learnables, `name.<output>` for
+/// every graph output and `name` for the first one.
+pub fn import_onnx(
+    graph: &mut ComputationalGraph,
+    model: &OnnxModel,
+    name: &str,
+    inputs: &[NodeId],
+    variables: &HashMap<String, NodeId>,
+) -> Result<Vec<(String, NodeId)>, String> {
+    if model.graph.outputs.is_empty() {
+        return Err("the model has no outputs".to_string());
+    }
+    let mut bindings = Vec::new();
+    let mut values = HashMap::new();
+    for init in &model.graph.initializers {
+        let tensor = init.to_tensor()?;
+        let variable = format!("{}.{}", name, init.name);
+        let trainable = matches!(init.dtype, OnnxDtype::Float | OnnxDtype::Double) && !tensor.shape.is_empty();
+        let id = match variables.get(&variable).and_then(|&id| graph.get_node(id)) {
+            Some(node) if trainable && matches!(&node.node_type, NodeType::Learnable(l) if *l == variable)
+                && node.value.as_ref().map(|v| tensor_of(v).shape) == Some(tensor.shape.clone()) => node.id,
+            _ if trainable => graph.add_learnable_tensor(variable.clone(), tensor.data, tensor.shape)?,
+            _ => graph.add_loaded_tensor(tensor.data, tensor.shape)?,
+        };
+        if trainable {
+            bindings.push((variable, id));
+        }
+        values.insert(init.name.clone(), id);
+    }
+
+    // Models before IR version 4 also list their initializers as inputs
+    let feeds: Vec<&OnnxValueInfo> = model.graph.inputs.iter().filter(|i| !values.contains_key(&i.name)).collect();
+    let feed_names: Vec<&str> = feeds.iter().map(|i| i.name.as_str()).collect();
+    if !inputs.is_empty() && inputs.len() != feeds.len() {
+        return Err(format!("the model has {} inputs ({}), got {}", feeds.len(), feed_names.join(", "), inputs.len()));
+    }
+    let mut importer = OnnxImporter { graph, opset: model.opset, values, shapes: HashMap::new() };
+    for (i, feed) in feeds.iter().enumerate() {
+        let id = match inputs.get(i) {
+            Some(&id) => id,
+            None => *variables.get(&feed.name).ok_or_else(|| format!(
+                "model input '{}' is not a variable; define it or pass the inputs ({}) after the path",
+                feed.name, feed_names.join(", ")
+            ))?,
+        };
+        // An input that has not been evaluated yet is, on its own, without disturbing training
+        let shape = match importer.shape(id) {
+            Ok(shape) => shape,
+            Err(_) => tensor_of(&importer.graph.evaluate_node_detached(id)?
+                .ok_or_else(|| format!("model input '{}' could not be evaluated", feed.name))?).shape,
+        };
+        importer.shapes.insert(id, shape.clone());
+        let fits = shape.len() == feed.dims.len()
+            && shape.iter().zip(&feed.dims).all(|(&n, d)| !matches!(d, OnnxDim::Value(v) if *v != n as i64));
+        if !feed.dims.is_empty() && !fits {
+            let dims: Vec<String> = feed.dims.iter().map(|d| match d {
+                OnnxDim::Value(v) => v.to_string(),
+                OnnxDim::Param(p) => p.clone(),
+            }).collect();
+            return Err(format!("model input '{}' expects shape [{}], got {:?}", feed.name, dims.join(", "), shape));
+        }
+        importer.values.insert(feed.name.clone(), id);
+    }
+
+    for node in &model.graph.nodes {
+        let id = importer.lower(node)?;
+        if let Some(output) = node.outputs.first() {
+            importer.values.insert(output.clone(), id);
+            let shape = importer.output_shape(node)?;
+            importer.shapes.insert(id, shape);
+        }
+    }
+
+    for (i, output) in model.graph.outputs.iter().enumerate() {
+        let id = *importer.values.get(&output.name).ok_or_else(|| format!("graph output '{}' is never computed", output.name))?;
+        bindings.push((format!("{}.{}", name, output.name), id));
+        if i == 0 {
+            bindings.push((name.to_string(), id));
+        }
+    }
+    Ok(bindings)
+}
+
+/// Read an ONNX file into `graph`; see `import_onnx`
+pub fn import_onnx_file(
+    graph: &mut ComputationalGraph,
+    path: &str,
+    name: &str,
+    inputs: &[NodeId],
+    variables: &HashMap<String, NodeId>,
+) -> Result<Vec<(String, NodeId)>, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Failed to read ONNX file '{}': {}", path, e))?;
+    let model = OnnxModel::from_bytes(&bytes).map_err(|e| format!("Invalid ONNX file '{}': {}", path, e))?;
+    import_onnx(graph, &model, name, inputs, variables).map_err(|e| format!("import_onnx(\"{}\"): {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_onnx_export_round_trip() {
+        let mut graph = ComputationalGraph::new();
+        let x = graph.add_constant_tensor(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], vec![2, 3]).unwrap();
+        let w = graph.add_learnable_tensor("W".to_string(), vec![0.1, -0.2, 0.3, 0.4, -0.5, 0.6], vec![3, 2]).unwrap();
+        let b = graph.add_learnable_tensor("b".to_string(), vec![0.5, -0.5], vec![2]).unwrap();
+        // Preprocessing that reads neither the input nor a learnable is folded
+        let two = graph.add_constant(2.0);
+        let half = graph.add_constant(0.5);
+        let scale = graph.add_binary_op("mul", two, half);
+        let h = graph.add_function_call("matmul".to_string(), vec![x, w]);
+        let z = graph.add_binary_op("add", h, b);
+        let s = graph.add_binary_op("mul", z, scale);
+        let a = graph.add_function_call("leaky_relu".to_string(), vec![s, half]);
+        let y = graph.add_function_call("softmax".to_string(), vec![a]);
+        graph.forward_pass().unwrap();
+
+        let model = export_onnx(&graph, &[("X".to_string(), x)], ("probs", y), true).unwrap();
+        let ops: Vec<&str> = model.graph.nodes.iter().map(|n| n.op_type.as_str()).collect();
+        assert_eq!(ops, ["MatMul", "Add", "Mul", "LeakyRelu", "Softmax"]);
+        assert_eq!(model.graph.nodes[3].attribute("alpha"), Some(&OnnxAttribute::Float(0.5)));
+        assert_eq!(model.graph.nodes[4].attribute("axis"), Some(&OnnxAttribute::Int(1)));
+        assert_eq!(model.graph.nodes[4].outputs, ["probs"]);
+        let names: Vec<&str> = model.graph.initializers.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["W", "b", "const_2"]);
+        assert_eq!(model.graph.initializers[2].data, vec![1.0]);
+        assert_eq!(model.graph.inputs[0].dims, [OnnxDim::Param("batch".to_string()), OnnxDim::Value(3)]);
+
+        let decoded = OnnxModel::from_bytes(&model.to_bytes()).unwrap();
+        assert_eq!(decoded, model);
+        assert_eq!(decoded.opset, ONNX_OPSET);
+        let w = decoded.graph.initializers[0].to_tensor().unwrap();
+        assert_eq!(w.shape, vec![3, 2]);
+        assert_eq!(w.data[1], -0.2f32 as f64);
+    }
+
+    #[test]
+    fn test_onnx_export_errors() {
+        let mut graph = ComputationalGraph::new();
+        let x = graph.add_constant_tensor(vec![1.0, 2.0], vec![2]).unwrap();
+        let w = graph.add_learnable_tensor("w".to_string(), vec![0.5, 0.5], vec![2]).unwrap();
+        let p = graph.add_binary_op("mul", x, w);
+        let loss = graph.add_function_call("mse".to_string(), vec![p, x]);
+        let cmp = graph.add_binary_op("lt", p, x);
+        graph.forward_pass().unwrap();
+
+        let err = export_onnx(&graph, &[("x".to_string(), x)], ("loss", loss), false).unwrap_err();
+        assert!(err.contains("training loss 'mse'"), "{}", err);
+        let err = export_onnx(&graph, &[("x".to_string(), x)], ("cmp", cmp), false).unwrap_err();
+        assert!(err.contains("'lt'"), "{}", err);
+        // A learnable output is passed through Identity
+        let model = export_onnx(&graph, &[], ("w", w), false).unwrap();
+        assert_eq!(model.graph.nodes[0].op_type, "Identity");
+        assert_eq!(model.graph.outputs[0].name, "w_output");
+
+        assert!(OnnxModel::from_bytes(&[0x0a, 0x05, 0x01]).is_err());
+    }
+
+    fn approx_eq(a: &Value, b: &Value) -> bool {
+        let (a, b) = (tensor_of(a), tensor_of(b));
+        a.shape == b.shape && a.data.iter().zip(&b.data).all(|(x, y)| (x - y).abs() <= 1e-5 * (1.0 + y.abs()))
+    }
+
+    #[test]
+    fn test_onnx_import_round_trip() {
+        let mut graph = ComputationalGraph::new();
+        let x = graph.add_constant_tensor(vec![0.5, -1.0, 2.0, 1.5, 0.25, -0.75], vec![2, 3]).unwrap();
+        let w = graph.add_learnable_tensor("W".to_string(), vec![0.1, -0.2, 0.3, 0.4, -0.5, 0.6], vec![3, 2]).unwrap();
+        let b = graph.add_learnable_tensor("b".to_string(), vec![0.5, -0.5], vec![2]).unwrap();
+        let h = graph.add_function_call("matmul".to_string(), vec![x, w]);
+        let z = graph.add_binary_op("add", h, b);
+        let g = graph.add_function_call("gelu".to_string(), vec![z]);
+        let s = graph.add_function_call("swish".to_string(), vec![z]);
+        let (zero, lo, hi) = (graph.add_constant(0.0), graph.add_constant(-0.1), graph.add_constant(0.2));
+        let c = graph.add_function_call("concat".to_string(), vec![g, s, zero]);
+        let c = graph.add_function_call("clip".to_string(), vec![c, lo, hi]);
+        let t = graph.add_function_call("transpose".to_string(), vec![c]);
+        let (four, minus_one) = (graph.add_constant(4.0), graph.add_constant(-1.0));
+        let r = graph.add_function_call("reshape".to_string(), vec![t, four, minus_one]);
+        let u = graph.add_function_call("unsqueeze".to_string(), vec![r, zero]);
+        let q = graph.add_function_call("squeeze".to_string(), vec![u]);
+        let y = graph.add_function_call("log_softmax".to_string(), vec![q, zero]);
+        graph.forward_pass().unwrap();
+        let expected = graph.get_node(y).unwrap().value.clone().unwrap();
+
+        let model = export_onnx(&graph, &[("X".to_string(), x)], ("y", y), true).unwrap();
+        let model = OnnxModel::from_bytes(&model.to_bytes()).unwrap();
+
+        // The input is not evaluated yet and draws noise (scaled away); the import infers the
+        // shapes it needs without evaluating the model or moving the RNG
+        let mut imported = ComputationalGraph::new();
+        imported.set_seed(5);
+        let x = imported.add_constant_tensor(vec![0.5, -1.0, 2.0, 1.5, 0.25, -0.75], vec![2, 3]).unwrap();
+        let (zero, one, three) = (imported.add_constant(0.0), imported.add_constant(1.0), imported.add_constant(3.0));
+        let noise = imported.add_function_call("noise_normal".to_string(), vec![zero, one, three]);
+        let noise = imported.add_binary_op("mul", noise, zero);
+        let input = imported.add_binary_op("add", x, noise);
+        let rng = imported.rng_state();
+        let first = imported.next_node_index();
+        let bindings = import_onnx(&mut imported, &model, "net", &[input], &HashMap::new()).unwrap();
+        let names: Vec<&str> = bindings.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["net.W", "net.b", "net.y", "net"]);
+        assert_eq!(imported.learnables(), ["net.W", "net.b"]);
+        assert_eq!(imported.rng_state(), rng);
+        assert!(imported.get_node(input).unwrap().value.is_none());
+        assert!((first..imported.next_node_index()).filter_map(|i| imported.get_node(NodeId::new(i)))
+            .all(|n| n.value.is_none() || matches!(n.node_type, NodeType::Constant(_) | NodeType::Learnable(_))));
+        imported.forward_pass().unwrap();
+        let out = imported.get_node(bindings[3].1).unwrap().value.clone().unwrap();
+        assert!(approx_eq(&out, &expected), "{:?} vs {:?}", out, expected);
+
+        // Importing again under the same name reuses the learnables
+        let variables: HashMap<String, NodeId> = bindings.into_iter().collect();
+        let again = import_onnx(&mut imported, &model, "net", &[input], &variables).unwrap();
+        assert_eq!(again[0].1, variables["net.W"]);
+        assert_eq!(imported.learnables().len(), 2);
+        assert_eq!(imported.expand_learnable_names(&["net".to_string()]), ["net.W", "net.b"]);
+    }
+
+    #[test]
+    fn test_onnx_import_gemm() {
+        let tensor = |name: &str, dims: Vec<i64>, data: Vec<f64>| OnnxTensor { name: name.to_string(), dtype: OnnxDtype::Float, dims, data };
+        let node = |op: &str, inputs: &[&str], attributes: Vec<(String, OnnxAttribute)>| OnnxNode {
+            name: format!("{}_node", op),
+            op_type: op.to_string(),
+            inputs: inputs.iter().map(|s| s.to_string()).collect(),
+            outputs: vec![op.to_lowercase()],
+            attributes,
+        };
+        let info = |name: &str, dims: Vec<OnnxDim>| OnnxValueInfo { name: name.to_string(), dtype: OnnxDtype::Float, dims };
+        // PyTorch-style Linear: y = relu(2 * flatten(x) @ W^T + 0.5 * b), with an offset Constant
+        let model = OnnxModel {
+            ir_version: 3,
+            opset: 11,
+            producer: "test".to_string(),
+            graph: OnnxGraph {
+                name: "linear".to_string(),
+                nodes: vec![
+                    node("Flatten", &["x"], vec![]),
+                    node("Gemm", &["flatten", "fc.weight", "fc.bias"], vec![
+                        ("alpha".to_string(), OnnxAttribute::Float(2.0)),
+                        ("beta".to_string(), OnnxAttribute::Float(0.5)),
+                        ("transB".to_string(), OnnxAttribute::Int(1)),
+                    ]),
+                    node("Constant", &[], vec![("value".to_string(), OnnxAttribute::Tensor(tensor("", vec![], vec![-1.0])))]),
+                    node("Add", &["gemm", "constant"], vec![]),
+                    node("Relu", &["add"], vec![]),
+                ],
+                initializers: vec![tensor("fc.weight", vec![2, 3], vec![1.0, 0.0, -1.0, 0.5, 0.5, 0.5]), tensor("fc.bias", vec![2], vec![2.0, -4.0])],
+                // IR version 3 lists the initializers as inputs too
+                inputs: vec![info("x", vec![OnnxDim::Value(1), OnnxDim::Value(3), OnnxDim::Value(1)]), info("fc.weight", vec![]), info("fc.bias", vec![])],
+                outputs: vec![info("relu", vec![])],
+            },
+        };
+        let model = OnnxModel::from_bytes(&model.to_bytes()).unwrap();
+
+        let mut graph = ComputationalGraph::new();
+        let x = graph.add_constant_tensor(vec![1.0, 2.0, 3.0], vec![1, 3, 1]).unwrap();
+        let variables = HashMap::from([("x".to_string(), x)]);
+        let bindings = import_onnx(&mut graph, &model, "m", &[], &variables).unwrap();
+        graph.forward_pass().unwrap();
+        let (_, y) = bindings.iter().find(|(n, _)| n == "m").unwrap();
+        // 2 * [1 - 3, 3] + 0.5 * [2, -4] - 1 = [-4, 3] -> relu
+        assert_eq!(graph.get_node(*y).unwrap().value, Some(Value::Tensor(Tensor { data: vec![0.0, 3.0], shape: vec![1, 2] })));
+        assert_eq!(graph.learnables(), ["m.fc.weight", "m.fc.bias"]);
+
+        let mut conv = model.clone();
+        conv.graph.nodes[4].op_type = "Conv".to_string();
+        let err = import_onnx(&mut graph, &conv, "m2", &[x], &HashMap::new()).unwrap_err();
+        assert!(err.contains("unsupported ONNX op 'Conv'"), "{}", err);
+        let err = import_onnx(&mut ComputationalGraph::new(), &model, "m", &[], &HashMap::new()).unwrap_err();
+        assert!(err.contains("model input 'x' is not a variable"), "{}", err);
+    }
+
+}
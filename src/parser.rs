@@ -2,6 +2,9 @@ use crate::ast::*;
 use crate::error::NomaError;
 use crate::token::{Token, TokenType};
 
+/// NumPy dtypes accepted by save_npy and save_npz
+const NPY_DTYPES: &str = "a type string such as \"<f8\", \">f4\", \"<i8\", \"|u1\" or \"|b1\"";
+
 /// Parser for the NOMA language
 /// Converts a stream of tokens into an Abstract Syntax Tree
 pub struct Parser {
@@ -84,6 +87,381 @@ mod tests {
             panic!("unexpected return expression shape");
         }
     }
+
+    #[test]
+    fn parse_slice_indices() {
+        let tokens = crate::lexer::Lexer::new("fn main() { return a[1:3, :, 2]; }").tokenize().unwrap();
+        let mut parser = Parser::new(tokens);
+        let program = parser.parse().expect("should parse slice");
+        let func = match &program.items[0] {
+            Item::Function(f) => f,
+            _ => panic!("expected function"),
+        };
+
+        if let Statement::Return(Some(Expression::Index { indices, .. })) = &func.body[0] {
+            assert_eq!(indices.len(), 3);
+            assert_eq!(indices[0], Expression::Range {
+                start: Some(Box::new(Expression::Number(1.0))),
+                end: Some(Box::new(Expression::Number(3.0))),
+            });
+            assert_eq!(indices[1], Expression::Range { start: None, end: None });
+            assert_eq!(indices[2], Expression::Number(2.0));
+        } else {
+            panic!("expected index expression");
+        }
+    }
+
+    #[test]
+    fn parse_chained_indices() {
+        let tokens = crate::lexer::Lexer::new("fn main() { let r = a[1][0]; return a[1:3][0]; }").tokenize().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let Item::Function(func) = &program.items[0] else { panic!("expected function") };
+
+        // Plain indices merge; an index after a slice applies to the slice
+        let Statement::LetDeclaration { value: Expression::Index { target, indices }, .. } = &func.body[0] else {
+            panic!("expected index expression")
+        };
+        assert_eq!(**target, Expression::Identifier("a".to_string()));
+        assert_eq!(indices[..], [Expression::Number(1.0), Expression::Number(0.0)]);
+        let Statement::Return(Some(Expression::Index { target, indices })) = &func.body[1] else {
+            panic!("expected index expression")
+        };
+        assert_eq!(indices[..], [Expression::Number(0.0)]);
+        assert!(matches!(&**target, Expression::Index { indices, .. } if matches!(indices[..], [Expression::Range { .. }])));
+    }
+
+    #[test]
+    fn parse_seed_statement() {
+        let tokens = crate::lexer::Lexer::new("fn main() { seed(42); return rand(); }").tokenize().unwrap();
+        let mut parser = Parser::new(tokens);
+        let program = parser.parse().expect("should parse seed");
+        let func = match &program.items[0] {
+            Item::Function(f) => f,
+            _ => panic!("expected function"),
+        };
+        assert_eq!(func.body[0], Statement::Seed(Expression::Number(42.0)));
+    }
+
+    #[test]
+    fn parse_checkpoint_statements() {
+        let tokens = crate::lexer::Lexer::new(r#"fn main() { resume("run.ckpt"); checkpoint("a.ckpt"); checkpoint("b.ckpt", 50); }"#).tokenize().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let Item::Function(func) = &program.items[0] else { panic!("expected function") };
+        assert_eq!(func.body, vec![
+            Statement::Resume { path: "run.ckpt".into() },
+            Statement::Checkpoint { path: "a.ckpt".into(), every: None },
+            Statement::Checkpoint { path: "b.ckpt".into(), every: Some(50) },
+        ]);
+        let tokens = crate::lexer::Lexer::new(r#"fn main() { checkpoint("a.ckpt", 0.5); }"#).tokenize().unwrap();
+        assert!(Parser::new(tokens).parse().unwrap_err().to_string().contains("checkpoint interval must be a positive integer"));
+    }
+
+    #[test]
+    fn parse_load_safetensors_forms() {
+        let tokens = crate::lexer::Lexer::new(r#"fn main() { learn W = load_safetensors("m.st", "W1"); load_safetensors m = "m.st"; return m.W1.x; }"#).tokenize().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let Item::Function(func) = &program.items[0] else { panic!("expected function") };
+        assert_eq!(func.body, vec![
+            Statement::LearnDeclaration {
+                name: "W".into(),
+                value: Expression::Call {
+                    name: "load_safetensors".into(),
+                    args: vec![Expression::StringLiteral("m.st".into()), Expression::StringLiteral("W1".into())],
+                },
+            },
+            Statement::LoadSafetensors { name: "m".into(), path: "m.st".into() },
+            Statement::Return(Some(Expression::Identifier("m.W1.x".into()))),
+        ]);
+
+        let tokens = crate::lexer::Lexer::new(r#"fn main() { save_safetensors { W: W }, "m.st", "BF16"; }"#).tokenize().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let Item::Function(func) = &program.items[0] else { panic!("expected function") };
+        assert_eq!(func.body[0], Statement::SaveSafetensors {
+            tensors: vec![("W".into(), Expression::Identifier("W".into()))],
+            path: "m.st".into(),
+            dtype: Some("BF16".into()),
+            metadata: Vec::new(),
+        });
+        let tokens = crate::lexer::Lexer::new(r#"fn main() { save_safetensors { W: W }, "m.st", metadata { format: "pt", "noma.epoch": "3" }; }"#).tokenize().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let Item::Function(func) = &program.items[0] else { panic!("expected function") };
+        let Statement::SaveSafetensors { dtype, metadata, .. } = &func.body[0] else { panic!("expected save_safetensors") };
+        assert_eq!(*dtype, None);
+        assert_eq!(*metadata, vec![("format".into(), "pt".into()), ("noma.epoch".into(), "3".into())]);
+        let tokens = crate::lexer::Lexer::new(r#"fn main() { save_safetensors { W: W }, "m.st", "F32", metadata { a: "1", a: "2" }; }"#).tokenize().unwrap();
+        assert!(Parser::new(tokens).parse().unwrap_err().to_string().contains("Duplicate metadata key 'a'"));
+        let tokens = crate::lexer::Lexer::new(r#"fn main() { save_safetensors { W: W }, "m.st", "F8"; }"#).tokenize().unwrap();
+        assert!(Parser::new(tokens).parse().unwrap_err().to_string().contains("Unknown safetensors dtype 'F8'"));
+    }
+
+    #[test]
+    fn parse_numpy_io() {
+        let source = r#"fn main() { load_npy X = "x.npy"; load_npz d = "d.npz"; let y = load_npz("d.npz", "y");
+            save_npy X, "o.npy", ">f4"; save_npz { X: X }, "o.npz"; }"#;
+        let tokens = crate::lexer::Lexer::new(source).tokenize().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let Item::Function(func) = &program.items[0] else { panic!("expected function") };
+        let x = || Expression::Identifier("X".into());
+        assert_eq!(func.body, vec![
+            Statement::LoadNpy { name: "X".into(), path: "x.npy".into() },
+            Statement::LoadNpz { name: "d".into(), path: "d.npz".into() },
+            Statement::LetDeclaration {
+                name: "y".into(),
+                value: Expression::Call {
+                    name: "load_npz".into(),
+                    args: vec![Expression::StringLiteral("d.npz".into()), Expression::StringLiteral("y".into())],
+                },
+            },
+            Statement::SaveNpy { tensor: x(), path: "o.npy".into(), dtype: Some(">f4".into()) },
+            Statement::SaveNpz { arrays: vec![("X".into(), x())], path: "o.npz".into(), dtype: None },
+        ]);
+        let tokens = crate::lexer::Lexer::new(r#"fn main() { save_npy X, "o.npy", "float32"; }"#).tokenize().unwrap();
+        assert!(Parser::new(tokens).parse().unwrap_err().to_string().contains("Unknown NumPy dtype 'float32'"));
+    }
+
+    #[test]
+    fn parse_load_csv_options() {
+        let source = r#"fn main() { load_csv d = "d.csv"; let X = load_csv("d.csv", header=true, delimiter="\t", columns=["x1", 3], na=-1);
+            let (F, y) = load_csv("d.csv", labels=["y"]); save_csv X, "o.csv", header=["a", "b"]; }"#;
+        let tokens = crate::lexer::Lexer::new(source).tokenize().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let Item::Function(func) = &program.items[0] else { panic!("expected function") };
+        assert_eq!(func.body, vec![
+            Statement::LoadCsv { name: "d".into(), labels_name: None, path: "d.csv".into(), options: CsvOptions::default() },
+            Statement::LoadCsv {
+                name: "X".into(),
+                labels_name: None,
+                path: "d.csv".into(),
+                options: CsvOptions {
+                    header: true,
+                    delimiter: '\t',
+                    columns: vec![CsvColumn::Name("x1".into()), CsvColumn::Index(3)],
+                    labels: Vec::new(),
+                    na: Some(-1.0),
+                },
+            },
+            Statement::LoadCsv {
+                name: "F".into(),
+                labels_name: Some("y".into()),
+                path: "d.csv".into(),
+                options: CsvOptions { labels: vec![CsvColumn::Name("y".into())], ..CsvOptions::default() },
+            },
+            Statement::SaveCsv { tensor: Expression::Identifier("X".into()), path: "o.csv".into(), header: vec!["a".into(), "b".into()] },
+        ]);
+
+        for (source, error) in [
+            (r#"let (X, y) = load_csv("d.csv");"#, "needs labels=[...]"),
+            (r#"let X = load_csv("d.csv", labels=[0]);"#, "binds two tensors"),
+            (r#"let X = load_csv("d.csv", delimiter=";;");"#, "single character"),
+            (r#"let X = load_csv("d.csv", header=yes);"#, "header must be true or false"),
+            (r#"let X = load_csv("d.csv", columns=[1.5]);"#, "non-negative integer indices"),
+            (r#"let X = load_csv("d.csv", skip=1);"#, "Unknown load_csv option 'skip'"),
+        ] {
+            let tokens = crate::lexer::Lexer::new(&format!("fn main() {{ {} }}", source)).tokenize().unwrap();
+            let err = Parser::new(tokens).parse().unwrap_err().to_string();
+            assert!(err.contains(error), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn parse_dataset_statement() {
+        let source = r#"fn main() { dataset d = "d.csv", header=true, columns=["x"], shuffle=true, seed=7, drop_last=true, epochs=3;
+            dataset w = "m.safetensors", tensor="W"; batch b in d with 32 { } }"#;
+        let tokens = crate::lexer::Lexer::new(source).tokenize().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let Item::Function(func) = &program.items[0] else { panic!("expected function") };
+        assert_eq!(func.body[0], Statement::Dataset {
+            name: "d".into(),
+            path: "d.csv".into(),
+            options: DatasetOptions {
+                csv: CsvOptions { header: true, columns: vec![CsvColumn::Name("x".into())], ..CsvOptions::default() },
+                tensor: None,
+                shuffle: true,
+                seed: Some(7),
+                drop_last: true,
+                epochs: 3,
+            },
+        });
+        assert_eq!(func.body[1], Statement::Dataset {
+            name: "w".into(),
+            path: "m.safetensors".into(),
+            options: DatasetOptions { tensor: Some("W".into()), ..DatasetOptions::default() },
+        });
+
+        for (source, error) in [
+            (r#"dataset d = "d.csv", epochs=0;"#, "epochs must be a positive integer"),
+            (r#"dataset d = "d.csv", labels=["y"];"#, "declare a second dataset"),
+            (r#"dataset d = "d.npy", delimiter=";";"#, "only to CSV datasets"),
+            (r#"dataset d = "d.csv", tensor="W";"#, "only to .safetensors datasets"),
+            (r#"dataset d = "d.csv", chunk=10;"#, "Unknown dataset option 'chunk'"),
+        ] {
+            let tokens = crate::lexer::Lexer::new(&format!("fn main() {{ {} }}", source)).tokenize().unwrap();
+            let err = Parser::new(tokens).parse().unwrap_err().to_string();
+            assert!(err.contains(error), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn parse_batch_loop_headers() {
+        let source = r#"fn main() { batch x in X with batch_size { } batch x, i in X with batch_size shuffle { }
+            batch (x, y), i, epoch in (X, Y) with batch_size 32 shuffle(seed=7) drop_last epochs 10 { } }"#;
+        let tokens = crate::lexer::Lexer::new(source).tokenize().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let Item::Function(func) = &program.items[0] else { panic!("expected function") };
+        let id = |name: &str| Expression::Identifier(name.into());
+        assert_eq!(func.body, vec![
+            Statement::BatchLoop {
+                item_names: vec!["x".into()],
+                index_name: None,
+                epoch_name: None,
+                data: vec![id("X")],
+                batch_size: id("batch_size"),
+                options: BatchOptions::default(),
+                body: vec![],
+            },
+            Statement::BatchLoop {
+                item_names: vec!["x".into()],
+                index_name: Some("i".into()),
+                epoch_name: None,
+                data: vec![id("X")],
+                batch_size: id("batch_size"),
+                options: BatchOptions { shuffle: true, ..BatchOptions::default() },
+                body: vec![],
+            },
+            Statement::BatchLoop {
+                item_names: vec!["x".into(), "y".into()],
+                index_name: Some("i".into()),
+                epoch_name: Some("epoch".into()),
+                data: vec![id("X"), id("Y")],
+                batch_size: Expression::Number(32.0),
+                options: BatchOptions { shuffle: true, seed: Some(7), drop_last: true, epochs: Some(Expression::Number(10.0)) },
+                body: vec![],
+            },
+        ]);
+
+        for (source, error) in [
+            ("batch (x, y) in (X, Y, Z) with 2 { }", "batch binds 2 names to 3 sources"),
+            ("batch x in X with 2 shuffle(seed=-1) { }", "non-negative integer"),
+        ] {
+            let tokens = crate::lexer::Lexer::new(&format!("fn main() {{ {} }}", source)).tokenize().unwrap();
+            let err = Parser::new(tokens).parse().unwrap_err().to_string();
+            assert!(err.contains(error), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn parse_import_onnx() {
+        let source = r#"fn main() { let net = import_onnx("m.onnx"); let head = import_onnx("h.onnx", net * 2.0);
+            freeze net.fc1.weight; optimize(head) with adam(lr=0.01) max_iter 10 { minimize mean(head); } }"#;
+        let tokens = crate::lexer::Lexer::new(source).tokenize().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let Item::Function(func) = &program.items[0] else { panic!("expected function") };
+        assert_eq!(func.body[0], Statement::ImportOnnx { name: "net".into(), path: "m.onnx".into(), inputs: vec![] });
+        let Statement::ImportOnnx { inputs, .. } = &func.body[1] else { panic!("expected import_onnx") };
+        assert!(matches!(inputs[..], [Expression::BinaryOp { .. }]));
+        assert_eq!(func.body[2], Statement::Freeze { names: vec!["net.fc1.weight".into()] });
+        // Without '(' import_onnx is an ordinary name
+        let tokens = crate::lexer::Lexer::new("fn main() { let import_onnx = 1.0; let x = import_onnx; }").tokenize().unwrap();
+        assert!(Parser::new(tokens).parse().is_ok());
+    }
+
+    #[test]
+    fn parse_freeze_statements() {
+        let tokens = crate::lexer::Lexer::new("fn main() { freeze w1, b1; unfreeze w1; }").tokenize().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let Item::Function(func) = &program.items[0] else { panic!("expected function") };
+        assert_eq!(func.body[0], Statement::Freeze { names: vec!["w1".into(), "b1".into()] });
+        assert_eq!(func.body[1], Statement::Unfreeze { names: vec!["w1".into()] });
+    }
+
+    #[test]
+    fn parse_optimize_header() {
+        let parse_with_targets = |targets: &str, header: &str| {
+            let source = format!("fn main() {{ learn w = 0.0; learn b = 0.0; optimize({}) {} until loss < 1e-3 {{ let loss = w * w; minimize loss; }} return w; }}", targets, header);
+            let tokens = crate::lexer::Lexer::new(&source).tokenize().unwrap();
+            Parser::new(tokens).parse().map(|program| match &program.items[0] {
+                Item::Function(f) => f.body[2].clone(),
+                _ => panic!("expected function"),
+            })
+        };
+        let parse = |header: &str| parse_with_targets("w", header);
+
+        let Statement::OptimizeLoop { optimizer, max_iter, condition, .. } =
+            parse("with adam(lr=0.01, beta1=0.9) max_iter 5000").unwrap() else { panic!("expected optimize loop") };
+        assert_eq!(optimizer, Some(OptimizerSpec {
+            name: "adam".into(),
+            params: vec![("lr".into(), 0.01), ("beta1".into(), 0.9)],
+        }));
+        assert_eq!(max_iter, Some(5000));
+        assert!(matches!(condition, Some(Expression::BinaryOp { ref right, .. }) if **right == Expression::Number(0.001)));
+
+        let Statement::OptimizeLoop { optimizer, max_iter, .. } = parse("with lbfgs").unwrap() else { panic!() };
+        assert_eq!((optimizer.unwrap().params.len(), max_iter), (0, None));
+        let Statement::OptimizeLoop { optimizer, max_iter, .. } = parse("").unwrap() else { panic!() };
+        assert_eq!((optimizer, max_iter), (None, None));
+        let Statement::OptimizeLoop { optimizer, schedule, max_iter, .. } =
+            parse("with sgd(lr=0.1) schedule cosine(period=50, warmup=5) max_iter 200").unwrap() else { panic!() };
+        assert_eq!(optimizer.unwrap().name, "sgd");
+        assert_eq!(schedule, Some(ScheduleSpec {
+            name: "cosine".into(),
+            params: vec![("period".into(), 50.0), ("warmup".into(), 5.0)],
+        }));
+        assert_eq!(max_iter, Some(200));
+        let Statement::OptimizeLoop { grad_transforms, max_iter, .. } =
+            parse("grad clip_norm(max_norm=1.0) grad l2(weight=1e-4) for w, b max_iter 10").unwrap() else { panic!() };
+        assert_eq!(grad_transforms, vec![
+            GradTransformSpec { name: "clip_norm".into(), params: vec![("max_norm".into(), 1.0)], targets: vec![] },
+            GradTransformSpec { name: "l2".into(), params: vec![("weight".into(), 1e-4)], targets: vec!["w".into(), "b".into()] },
+        ]);
+        assert_eq!(max_iter, Some(10));
+        let Statement::OptimizeLoop { targets, param_groups, .. } =
+            parse_with_targets("w, b", "with adam(lr=0.01) group(lr=0.001) for b").unwrap() else { panic!() };
+        assert_eq!(targets, vec!["w".to_string(), "b".to_string()]);
+        assert_eq!(param_groups, vec![ParamGroupSpec { params: vec![("lr".into(), 0.001)], targets: vec!["b".into()] }]);
+        assert!(parse_with_targets("w", "group(lr=0.001) for b").unwrap_err().to_string().contains("'b', which is not an optimize target"));
+        assert!(parse_with_targets("w", "group(lr=0.001)").unwrap_err().to_string().contains("group needs 'for'"));
+        assert!(parse_with_targets("w", "with lbfgs group(lr=0.5) for w").unwrap_err().to_string().contains("lbfgs does not support parameter groups"));
+
+        let source = "fn main() { learn w = 0.0; optimize(w) stop early(patience=5) on val - 1.0 stop time_limit(seconds=10) max_iter 100 -> stats { let val = w; minimize w * w; } print(stats.iterations); }";
+        let program = Parser::new(crate::lexer::Lexer::new(source).tokenize().unwrap()).parse().unwrap();
+        let Item::Function(func) = &program.items[0] else { panic!("expected function") };
+        let Statement::OptimizeLoop { stop_criteria, condition, stats, .. } = &func.body[1] else { panic!() };
+        assert_eq!(stop_criteria.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), ["early", "time_limit"]);
+        assert!(matches!(stop_criteria[0].monitor, Some(Expression::BinaryOp { op: BinaryOperator::Sub, .. })));
+        assert_eq!((condition, stats), (&None, &Some("stats".to_string())));
+        assert_eq!(func.body[2], Statement::Expression(Expression::Call {
+            name: "print".into(),
+            args: vec![Expression::Identifier("stats.iterations".into())],
+        }));
+        // `->` follows the until condition, which is only optional with max_iter or stop clauses
+        assert!(parse("-> result").unwrap_err().to_string().contains("Expected 'until'"));
+
+        // Validated at parse time
+        for (header, message) in [
+            ("with adamm(lr=0.1)", "Unknown optimizer 'adamm'"),
+            ("with sgd(beta1=0.9)", "sgd does not take 'beta1'"),
+            ("with adam(lr=-0.1)", "adam lr must be > 0"),
+            ("with adam(beta2=1.5)", "adam beta2 must be in [0, 1)"),
+            ("with adam(lr=0.1, learning_rate=0.2)", "lr is given more than once"),
+            ("with adam(lr=x)", "must be a number literal"),
+            ("max_iter 2.5", "max_iter must be a positive integer"),
+            ("schedule linear", "Unknown schedule 'linear'"),
+            ("schedule step(period=10)", "step schedule does not take 'period'"),
+            ("schedule exponential(gamma=1.5)", "exponential schedule gamma must be in (0, 1]"),
+            ("schedule cosine(warmup=2.5)", "cosine schedule warmup must be a non-negative integer"),
+            ("grad clip(max=1.0)", "Unknown gradient transform 'clip'"),
+            ("grad clip_norm(max=1.0)", "clip_norm takes exactly one parameter, max_norm"),
+            ("grad noise(std=-1.0)", "noise std must be >= 0"),
+            ("grad l2(weight=0.1) for", "Expected learnable name after 'for'"),
+            ("with lbfgs grad clip_value(max=1.0)", "lbfgs does not support gradient transforms"),
+            ("stop rel_improvement(tol=1e-6, window=0)", "rel_improvement window must be a positive integer"),
+            ("stop early(patience=3)", "stop early needs 'on'"),
+            ("stop grad_norm(tol=1e-6) stop grad_norm(tol=1e-8)", "stop grad_norm is given more than once"),
+        ] {
+            let err = parse(header).unwrap_err().to_string();
+            assert!(err.contains(message), "{}: {}", header, err);
+        }
+    }
 }
 
 impl Parser {
@@ -208,12 +586,21 @@ impl Parser {
             TokenType::Return => self.parse_return(),
             TokenType::Alloc => self.parse_alloc(),
             TokenType::Free => self.parse_free(),
+            TokenType::Freeze | TokenType::Unfreeze => self.parse_freeze(),
             TokenType::Realloc => self.parse_realloc(),
             TokenType::ResetOptimizer => self.parse_reset_optimizer(),
+            TokenType::Seed => self.parse_seed(),
             TokenType::LoadCsv => self.parse_load_csv(),
             TokenType::SaveCsv => self.parse_save_csv(),
             TokenType::LoadSafetensors => self.parse_load_safetensors(),
             TokenType::SaveSafetensors => self.parse_save_safetensors(),
+            TokenType::LoadNpy => self.parse_load_npy(),
+            TokenType::SaveNpy => self.parse_save_npy(),
+            TokenType::LoadNpz => self.parse_load_npz(),
+            TokenType::SaveNpz => self.parse_save_npz(),
+            TokenType::Checkpoint => self.parse_checkpoint(),
+            TokenType::Resume => self.parse_resume(),
+            TokenType::Dataset => self.parse_dataset(),
             TokenType::Batch => self.parse_batch_loop(),
             _ => {
                 // Handle assignment: identifier '=' expr;
@@ -268,8 +655,24 @@ impl Parser {
     /// Parse 'let' declaration
     fn parse_let_declaration(&mut self) -> Result<Statement, NomaError> {
         self.consume(TokenType::Let, "Expected 'let'")?;
+        if matches!(self.peek().token_type, TokenType::LParen) {
+            // let (X, y) = load_csv("data.csv", labels=["y"]);
+            self.advance(); // consume '('
+            let name = self.parse_identifier("Expected feature variable name")?;
+            self.consume(TokenType::Comma, "Expected ',' between feature and label names")?;
+            let labels_name = self.parse_identifier("Expected label variable name")?;
+            self.consume(TokenType::RParen, "Expected ')'")?;
+            self.consume(TokenType::Assign, "Expected '='")?;
+            return self.parse_load_csv_call(name, Some(labels_name));
+        }
         let name = self.parse_identifier("Expected variable name")?;
         self.consume(TokenType::Assign, "Expected '='")?;
+        if matches!(self.peek().token_type, TokenType::LoadCsv) {
+            return self.parse_load_csv_call(name, None);
+        }
+        if self.peek_contextual("import_onnx") && matches!(self.peek_next().map(|t| &t.token_type), Some(TokenType::LParen)) {
+            return self.parse_import_onnx_call(name);
+        }
         let value = self.parse_expression()?;
         self.consume(TokenType::Semicolon, "Expected ';'")?;
 
@@ -289,20 +692,261 @@ impl Parser {
     /// Parse optimize loop: optimize <target> until <condition> { body }
     fn parse_optimize_loop(&mut self) -> Result<Statement, NomaError> {
         self.consume(TokenType::Optimize, "Expected 'optimize'")?;
-        // Support both: optimize target until ... AND optimize(target) until ...
-        let target = if matches!(self.peek().token_type, TokenType::LParen) {
+        // Support both: optimize target until ... AND optimize(target, ...) until ...
+        let targets = if matches!(self.peek().token_type, TokenType::LParen) {
             self.advance(); // consume '('
-            let t = self.parse_identifier("Expected target to optimize")?;
+            let targets = self.parse_learnable_list("Expected target to optimize")?;
             self.consume(TokenType::RParen, "Expected ')'")?;
-            t
+            targets
         } else {
-            self.parse_identifier("Expected target to optimize")?
+            vec![self.parse_dotted_name("Expected target to optimize")?]
+        };
+
+        // Optional header, in this order:
+        // with adam(lr=0.01) schedule cosine(period=500) grad clip_norm(max_norm=1.0) for w
+        // group(lr=0.001) for b stop early(patience=10) on val_loss max_iter 5000
+        let optimizer = if self.peek_contextual("with") {
+            Some(self.parse_optimizer_spec()?)
+        } else {
+            None
+        };
+        let schedule = if self.peek_contextual("schedule") {
+            Some(self.parse_schedule_spec()?)
+        } else {
+            None
+        };
+        let mut grad_transforms = Vec::new();
+        while self.peek_contextual("grad") {
+            grad_transforms.push(self.parse_grad_transform_spec()?);
+        }
+        let mut param_groups = Vec::new();
+        while self.peek_contextual("group") {
+            param_groups.push(self.parse_param_group_spec(&targets)?);
+        }
+        if let Some(spec) = optimizer.as_ref().filter(|spec| matches!(spec.name.as_str(), "lbfgs" | "newton_cg")) {
+            let unsupported = if !grad_transforms.is_empty() {
+                Some("gradient transforms")
+            } else if !param_groups.is_empty() {
+                Some("parameter groups")
+            } else {
+                None
+            };
+            if let Some(what) = unsupported {
+                let token = self.peek();
+                return Err(NomaError::ParseError {
+                    message: format!("{} does not support {}", spec.name, what),
+                    line: token.line,
+                    column: token.column,
+                });
+            }
+        }
+        let mut stop_criteria: Vec<StopSpec> = Vec::new();
+        while self.peek_contextual("stop") {
+            let start = self.peek();
+            let spec = self.parse_stop_spec()?;
+            if stop_criteria.iter().any(|other| other.name == spec.name) {
+                return Err(NomaError::ParseError {
+                    message: format!("stop {} is given more than once", spec.name),
+                    line: start.line,
+                    column: start.column,
+                });
+            }
+            stop_criteria.push(spec);
+        }
+        let max_iter = if self.peek_contextual("max_iter") {
+            self.advance();
+            let token = self.peek();
+            match self.parse_number_literal()? {
+                n if n >= 1.0 && n.fract() == 0.0 => Some(n as usize),
+                n => {
+                    return Err(NomaError::ParseError {
+                        message: format!("max_iter must be a positive integer, got {}", n),
+                        line: token.line,
+                        column: token.column,
+                    })
+                }
+            }
+        } else {
+            None
+        };
+
+        // `until` may be left out when max_iter or a stop criterion bounds the loop
+        let condition = if matches!(self.peek().token_type, TokenType::Until) || (max_iter.is_none() && stop_criteria.is_empty()) {
+            self.consume(TokenType::Until, "Expected 'until'")?;
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+        let stats = if matches!(self.peek().token_type, TokenType::Arrow) {
+            self.advance(); // consume '->'
+            Some(self.parse_identifier("Expected a name for the loop statistics after '->'")?)
+        } else {
+            None
         };
-        self.consume(TokenType::Until, "Expected 'until'")?;
-        let condition = self.parse_expression()?;
         self.consume(TokenType::LBrace, "Expected '{'")?;
         let body = self.parse_block()?;
-        Ok(Statement::OptimizeLoop { target, condition, body })
+        Ok(Statement::OptimizeLoop {
+            targets,
+            optimizer,
+            schedule,
+            grad_transforms,
+            param_groups,
+            stop_criteria,
+            max_iter,
+            condition,
+            stats,
+            body,
+        })
+    }
+
+    /// Parse `with name` or `with name(key=value, ...)`, validating it against the optimizer's
+    /// accepted hyperparameters
+    fn parse_optimizer_spec(&mut self) -> Result<OptimizerSpec, NomaError> {
+        self.advance(); // consume 'with'
+        let start = self.peek();
+        let name = self.parse_identifier("Expected optimizer name after 'with'")?;
+        let params = self.parse_header_args("Optimizer")?;
+
+        let spec = OptimizerSpec { name, params };
+        crate::graph::OptimizerConfig::from_spec(&spec).map_err(|message| NomaError::ParseError {
+            message,
+            line: start.line,
+            column: start.column,
+        })?;
+        Ok(spec)
+    }
+
+    /// Parse `schedule name` or `schedule name(key=value, ...)`, validating its parameters
+    fn parse_schedule_spec(&mut self) -> Result<ScheduleSpec, NomaError> {
+        self.advance(); // consume 'schedule'
+        let start = self.peek();
+        let name = self.parse_identifier("Expected schedule name after 'schedule'")?;
+        let params = self.parse_header_args("Schedule")?;
+
+        let spec = ScheduleSpec { name, params };
+        crate::graph::LrSchedule::from_spec(&spec).map_err(|message| NomaError::ParseError {
+            message,
+            line: start.line,
+            column: start.column,
+        })?;
+        Ok(spec)
+    }
+
+    /// Parse `grad name(key=value)`, optionally followed by `for p1, p2, ...`
+    fn parse_grad_transform_spec(&mut self) -> Result<GradTransformSpec, NomaError> {
+        self.advance(); // consume 'grad'
+        let start = self.peek();
+        let name = self.parse_identifier("Expected gradient transform name after 'grad'")?;
+        let params = self.parse_header_args("Gradient transform")?;
+        let mut targets = Vec::new();
+        if self.peek_contextual("for") {
+            self.advance(); // consume 'for'
+            targets = self.parse_learnable_list("Expected learnable name after 'for'")?;
+        }
+
+        let spec = GradTransformSpec { name, params, targets };
+        crate::graph::GradTransform::from_spec(&spec).map_err(|message| NomaError::ParseError {
+            message,
+            line: start.line,
+            column: start.column,
+        })?;
+        Ok(spec)
+    }
+
+    /// Parse `stop name(key=value, ...)`, optionally followed by `on <expression>`
+    fn parse_stop_spec(&mut self) -> Result<StopSpec, NomaError> {
+        self.advance(); // consume 'stop'
+        let start = self.peek();
+        let name = self.parse_identifier("Expected stop criterion name after 'stop'")?;
+        let params = self.parse_header_args("Stop criterion")?;
+        let monitor = if self.peek_contextual("on") {
+            self.advance(); // consume 'on'
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+
+        let spec = StopSpec { name, params, monitor };
+        crate::graph::StopCriterion::from_spec(&spec).map_err(|message| NomaError::ParseError {
+            message,
+            line: start.line,
+            column: start.column,
+        })?;
+        Ok(spec)
+    }
+
+    /// Parse `group(lr=value) for p1, p2`; every listed learnable must be an optimize target
+    fn parse_param_group_spec(&mut self, optimized: &[String]) -> Result<ParamGroupSpec, NomaError> {
+        self.advance(); // consume 'group'
+        let start = self.peek();
+        let params = self.parse_header_args("Group")?;
+        let mut targets = Vec::new();
+        if self.peek_contextual("for") {
+            self.advance(); // consume 'for'
+            targets = self.parse_learnable_list("Expected learnable name after 'for'")?;
+        }
+
+        let spec = ParamGroupSpec { params, targets };
+        let error = |message| NomaError::ParseError { message, line: start.line, column: start.column };
+        crate::graph::ParamGroup::from_spec(&spec).map_err(error)?;
+        if let Some(name) = spec.targets.iter().find(|name| !optimized.contains(name)) {
+            return Err(error(format!("group lists '{}', which is not an optimize target", name)));
+        }
+        Ok(spec)
+    }
+
+    /// Parse one or more comma-separated identifiers
+    fn parse_name_list(&mut self, message: &str) -> Result<Vec<String>, NomaError> {
+        let mut names = vec![self.parse_identifier(message)?];
+        while matches!(self.peek().token_type, TokenType::Comma) {
+            self.advance(); // consume comma
+            names.push(self.parse_identifier(message)?);
+        }
+        Ok(names)
+    }
+
+    /// Comma-separated learnable names, which may have fields (`net.fc1.weight`)
+    fn parse_learnable_list(&mut self, message: &str) -> Result<Vec<String>, NomaError> {
+        let mut names = vec![self.parse_dotted_name(message)?];
+        while matches!(self.peek().token_type, TokenType::Comma) {
+            self.advance(); // consume comma
+            names.push(self.parse_dotted_name(message)?);
+        }
+        Ok(names)
+    }
+
+    /// Identifier with optional fields, e.g. `net.W1` for a learnable bound by import_onnx
+    fn parse_dotted_name(&mut self, message: &str) -> Result<String, NomaError> {
+        let mut name = self.parse_identifier(message)?;
+        while matches!(self.peek().token_type, TokenType::Dot) {
+            self.advance(); // consume '.'
+            name = format!("{}.{}", name, self.parse_identifier("Expected field name after '.'")?);
+        }
+        Ok(name)
+    }
+
+    /// Parse an optional `(key=value, ...)` list of number literals in an optimize header
+    fn parse_header_args(&mut self, what: &str) -> Result<Vec<(String, f64)>, NomaError> {
+        let mut params = Vec::new();
+        if matches!(self.peek().token_type, TokenType::LParen) {
+            self.advance(); // consume '('
+            while !matches!(self.peek().token_type, TokenType::RParen) {
+                let key = self.parse_identifier(&format!("Expected {} parameter name", what.to_lowercase()))?;
+                self.consume(TokenType::Assign, &format!("Expected '=' after {} parameter name", what.to_lowercase()))?;
+                let value = self.parse_number_literal().map_err(|_| NomaError::ParseError {
+                    message: format!("{} parameter '{}' must be a number literal", what, key),
+                    line: self.peek().line,
+                    column: self.peek().column,
+                })?;
+                params.push((key, value));
+                if !matches!(self.peek().token_type, TokenType::Comma) {
+                    break;
+                }
+                self.advance(); // consume comma
+            }
+            self.consume(TokenType::RParen, &format!("Expected ')' after {} parameters", what.to_lowercase()))?;
+        }
+        Ok(params)
     }
 
     /// Parse 'minimize' statement
@@ -360,6 +1004,16 @@ impl Parser {
         Ok(Statement::Free { name })
     }
 
+    /// Parse 'freeze' / 'unfreeze' statement: freeze w1, b1;
+    fn parse_freeze(&mut self) -> Result<Statement, NomaError> {
+        let freeze = matches!(self.peek().token_type, TokenType::Freeze);
+        self.advance(); // consume 'freeze' / 'unfreeze'
+        let names = self.parse_learnable_list("Expected learnable name")?;
+        self.consume(TokenType::Semicolon, "Expected ';'")?;
+
+        Ok(if freeze { Statement::Freeze { names } } else { Statement::Unfreeze { names } })
+    }
+
     /// Parse 'realloc' statement: realloc name = [dim1, dim2, ...];
     fn parse_realloc(&mut self) -> Result<Statement, NomaError> {
         self.consume(TokenType::Realloc, "Expected 'realloc'")?;
@@ -394,6 +1048,55 @@ impl Parser {
         Ok(Statement::ResetOptimizer)
     }
 
+    /// Parse 'seed' statement: seed(42);
+    fn parse_seed(&mut self) -> Result<Statement, NomaError> {
+        self.consume(TokenType::Seed, "Expected 'seed'")?;
+        self.consume(TokenType::LParen, "Expected '(' after seed")?;
+        let value = self.parse_expression()?;
+        self.consume(TokenType::RParen, "Expected ')'")?;
+        self.consume(TokenType::Semicolon, "Expected ';'")?;
+
+        Ok(Statement::Seed(value))
+    }
+
+    /// Parse 'checkpoint' statement: checkpoint("run.ckpt"); or checkpoint("run.ckpt", 100);
+    fn parse_checkpoint(&mut self) -> Result<Statement, NomaError> {
+        self.consume(TokenType::Checkpoint, "Expected 'checkpoint'")?;
+        self.consume(TokenType::LParen, "Expected '(' after checkpoint")?;
+        let path = self.parse_string_literal("Expected checkpoint file path string")?;
+        let every = if matches!(self.peek().token_type, TokenType::Comma) {
+            self.advance(); // consume comma
+            let token = self.peek();
+            match self.parse_number_literal()? {
+                n if n >= 1.0 && n.fract() == 0.0 => Some(n as usize),
+                n => {
+                    return Err(NomaError::ParseError {
+                        message: format!("checkpoint interval must be a positive integer, got {}", n),
+                        line: token.line,
+                        column: token.column,
+                    })
+                }
+            }
+        } else {
+            None
+        };
+        self.consume(TokenType::RParen, "Expected ')'")?;
+        self.consume(TokenType::Semicolon, "Expected ';'")?;
+
+        Ok(Statement::Checkpoint { path, every })
+    }
+
+    /// Parse 'resume' statement: resume("run.ckpt");
+    fn parse_resume(&mut self) -> Result<Statement, NomaError> {
+        self.consume(TokenType::Resume, "Expected 'resume'")?;
+        self.consume(TokenType::LParen, "Expected '(' after resume")?;
+        let path = self.parse_string_literal("Expected checkpoint file path string")?;
+        self.consume(TokenType::RParen, "Expected ')'")?;
+        self.consume(TokenType::Semicolon, "Expected ';'")?;
+
+        Ok(Statement::Resume { path })
+    }
+
     /// Parse 'load_csv' statement: let name = load_csv("path.csv");
     fn parse_load_csv(&mut self) -> Result<Statement, NomaError> {
         self.consume(TokenType::LoadCsv, "Expected 'load_csv'")?;
@@ -403,18 +1106,216 @@ impl Parser {
         let path = self.parse_string_literal("Expected file path string")?;
         self.consume(TokenType::Semicolon, "Expected ';'")?;
         
-        Ok(Statement::LoadCsv { name, path })
+        Ok(Statement::LoadCsv { name, labels_name: None, path, options: CsvOptions::default() })
+    }
+
+    /// Parse the right-hand side `load_csv("path.csv", key=value, ...);` of a let declaration
+    fn parse_load_csv_call(&mut self, name: String, labels_name: Option<String>) -> Result<Statement, NomaError> {
+        self.consume(TokenType::LoadCsv, "Expected 'load_csv'")?;
+        self.consume(TokenType::LParen, "Expected '(' after load_csv")?;
+        let path = self.parse_string_literal("Expected file path string")?;
+        let mut options = CsvOptions::default();
+        while matches!(self.peek().token_type, TokenType::Comma) {
+            self.advance(); // consume comma
+            let start = self.peek().clone();
+            let error = |message: String| NomaError::ParseError { message, line: start.line, column: start.column };
+            let key = self.parse_identifier("Expected load_csv option name")?;
+            self.consume(TokenType::Assign, "Expected '=' after load_csv option name")?;
+            if !self.parse_csv_option(&key, &mut options)? {
+                return Err(error(format!(
+                    "Unknown load_csv option '{}'; expected header, delimiter, columns, labels or na", key
+                )));
+            }
+        }
+        self.consume(TokenType::RParen, "Expected ')' after load_csv options")?;
+        let end = self.peek().clone();
+        self.consume(TokenType::Semicolon, "Expected ';'")?;
+
+        if labels_name.is_some() == options.labels.is_empty() {
+            let message = if options.labels.is_empty() {
+                "let (X, y) = load_csv(...) needs labels=[...] to pick the label columns".to_string()
+            } else {
+                "load_csv with labels=[...] binds two tensors: let (X, y) = load_csv(...);".to_string()
+            };
+            return Err(NomaError::ParseError { message, line: end.line, column: end.column });
+        }
+        Ok(Statement::LoadCsv { name, labels_name, path, options })
+    }
+
+    /// Parse `import_onnx("m.onnx"[, X, ...]);` after `let name =`
+    fn parse_import_onnx_call(&mut self, name: String) -> Result<Statement, NomaError> {
+        self.advance(); // consume 'import_onnx'
+        self.consume(TokenType::LParen, "Expected '(' after import_onnx")?;
+        let path = self.parse_string_literal("Expected ONNX file path string")?;
+        let mut inputs = Vec::new();
+        while matches!(self.peek().token_type, TokenType::Comma) {
+            self.advance(); // consume comma
+            inputs.push(self.parse_expression()?);
+        }
+        self.consume(TokenType::RParen, "Expected ')' after import_onnx inputs")?;
+        self.consume(TokenType::Semicolon, "Expected ';'")?;
+        Ok(Statement::ImportOnnx { name, path, inputs })
+    }
+
+    /// Parse the value of CSV option `key` into `options`; false if `key` is not a CSV option
+    fn parse_csv_option(&mut self, key: &str, options: &mut CsvOptions) -> Result<bool, NomaError> {
+        let start = self.peek().clone();
+        match key {
+            "header" => options.header = self.parse_bool_option(key)?,
+            "delimiter" => {
+                let text = self.parse_string_literal("Expected delimiter string")?;
+                let mut chars = text.chars();
+                options.delimiter = match (chars.next(), chars.next()) {
+                    (Some(c), None) if c != '"' && c != '\n' && c != '\r' => c,
+                    _ => {
+                        return Err(NomaError::ParseError {
+                            message: format!("delimiter must be a single character, not \"{}\"", text),
+                            line: start.line,
+                            column: start.column,
+                        })
+                    }
+                };
+            }
+            "columns" => options.columns = self.parse_csv_columns()?,
+            "labels" => options.labels = self.parse_csv_columns()?,
+            "na" if self.peek_contextual("nan") => {
+                self.advance();
+                options.na = Some(f64::NAN);
+            }
+            "na" => options.na = Some(self.parse_number_literal()?),
+            _ => return Ok(false),
+        }
+        Ok(true)
     }
 
-    /// Parse 'save_csv' statement: save_csv tensor, "path.csv";
+    /// Parse `true` or `false` as the value of option `key`
+    fn parse_bool_option(&mut self, key: &str) -> Result<bool, NomaError> {
+        let start = self.peek().clone();
+        match self.parse_identifier(&format!("Expected true or false for {}", key))?.as_str() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            other => Err(NomaError::ParseError {
+                message: format!("{} must be true or false, not '{}'", key, other),
+                line: start.line,
+                column: start.column,
+            }),
+        }
+    }
+
+    /// Parse 'dataset' statement: dataset name = "path", key=value, ...;
+    fn parse_dataset(&mut self) -> Result<Statement, NomaError> {
+        self.consume(TokenType::Dataset, "Expected 'dataset'")?;
+        let name = self.parse_identifier("Expected dataset name")?;
+        self.consume(TokenType::Assign, "Expected '='")?;
+        let start = self.peek().clone();
+        let path = self.parse_string_literal("Expected file path string")?;
+        let mut options = DatasetOptions::default();
+        while matches!(self.peek().token_type, TokenType::Comma) {
+            self.advance(); // consume comma
+            let key_token = self.peek().clone();
+            // `seed` and `tensor` are keywords elsewhere
+            let key = match self.peek().token_type {
+                TokenType::Seed | TokenType::Tensor => {
+                    let key = self.peek().token_type.to_string();
+                    self.advance();
+                    key
+                }
+                _ => self.parse_identifier("Expected dataset option name")?,
+            };
+            self.consume(TokenType::Assign, "Expected '=' after dataset option name")?;
+            let value = self.peek().clone();
+            let count = |n: f64, min: f64| (n >= min && n.fract() == 0.0).then_some(n as u64);
+            let error = |message: String, token: &Token| NomaError::ParseError { message, line: token.line, column: token.column };
+            match key.as_str() {
+                "tensor" => options.tensor = Some(self.parse_string_literal("Expected tensor name string")?),
+                "shuffle" => options.shuffle = self.parse_bool_option(&key)?,
+                "drop_last" => options.drop_last = self.parse_bool_option(&key)?,
+                "seed" => {
+                    let n = self.parse_number_literal()?;
+                    options.seed = Some(count(n, 0.0).ok_or_else(|| error("seed must be a non-negative integer".into(), &value))?);
+                }
+                "epochs" => {
+                    let n = self.parse_number_literal()?;
+                    options.epochs = count(n, 1.0).ok_or_else(|| error("epochs must be a positive integer".into(), &value))? as usize;
+                }
+                _ => {
+                    if !self.parse_csv_option(&key, &mut options.csv)? {
+                        return Err(error(format!(
+                            "Unknown dataset option '{}'; expected shuffle, seed, drop_last, epochs, tensor, \
+                             header, delimiter, columns or na", key
+                        ), &key_token));
+                    }
+                }
+            }
+        }
+        self.consume(TokenType::Semicolon, "Expected ';'")?;
+
+        crate::graph::Dataset::check_options(&path, &options).map_err(|message| NomaError::ParseError {
+            message,
+            line: start.line,
+            column: start.column,
+        })?;
+        Ok(Statement::Dataset { name, path, options })
+    }
+
+    /// Parse `["x1", "x2"]` or `[0, 2]`: CSV columns by header name or zero-based index
+    fn parse_csv_columns(&mut self) -> Result<Vec<CsvColumn>, NomaError> {
+        self.consume(TokenType::LBracket, "Expected '[' for column list")?;
+        let mut columns = Vec::new();
+        while !matches!(self.peek().token_type, TokenType::RBracket) {
+            let token = self.peek().clone();
+            columns.push(match token.token_type {
+                TokenType::StringLiteral(name) => CsvColumn::Name(name),
+                TokenType::Number(n) if n >= 0.0 && n.fract() == 0.0 => CsvColumn::Index(n as usize),
+                _ => {
+                    return Err(NomaError::ParseError {
+                        message: "CSV columns must be header names or non-negative integer indices".into(),
+                        line: token.line,
+                        column: token.column,
+                    })
+                }
+            });
+            self.advance();
+            if !matches!(self.peek().token_type, TokenType::Comma) {
+                break;
+            }
+            self.advance(); // consume comma
+        }
+        self.consume(TokenType::RBracket, "Expected ']' after column list")?;
+        Ok(columns)
+    }
+
+    /// Parse 'save_csv' statement: save_csv tensor, "path.csv"; with an optional header=["a", "b"]
     fn parse_save_csv(&mut self) -> Result<Statement, NomaError> {
         self.consume(TokenType::SaveCsv, "Expected 'save_csv'")?;
         let tensor = self.parse_expression()?;
         self.consume(TokenType::Comma, "Expected ',' after tensor expression")?;
         let path = self.parse_string_literal("Expected file path string")?;
+        let mut header = Vec::new();
+        if matches!(self.peek().token_type, TokenType::Comma) {
+            self.advance(); // consume comma
+            if !self.peek_contextual("header") {
+                return Err(NomaError::ParseError {
+                    message: "Expected header=[...] after the save_csv path".into(),
+                    line: self.peek().line,
+                    column: self.peek().column,
+                });
+            }
+            self.advance(); // consume 'header'
+            self.consume(TokenType::Assign, "Expected '=' after header")?;
+            self.consume(TokenType::LBracket, "Expected '[' for header names")?;
+            while !matches!(self.peek().token_type, TokenType::RBracket) {
+                header.push(self.parse_string_literal("Expected column name string")?);
+                if !matches!(self.peek().token_type, TokenType::Comma) {
+                    break;
+                }
+                self.advance(); // consume comma
+            }
+            self.consume(TokenType::RBracket, "Expected ']' after header names")?;
+        }
         self.consume(TokenType::Semicolon, "Expected ';'")?;
-        
-        Ok(Statement::SaveCsv { tensor, path })
+
+        Ok(Statement::SaveCsv { tensor, path, header })
     }
 
     /// Parse 'load_safetensors' statement: load_safetensors name = "path.safetensors";
@@ -432,8 +1333,119 @@ impl Parser {
     /// Parse 'save_safetensors' statement: save_safetensors { name1: tensor1, name2: tensor2 }, "path.safetensors";
     fn parse_save_safetensors(&mut self) -> Result<Statement, NomaError> {
         self.consume(TokenType::SaveSafetensors, "Expected 'save_safetensors'")?;
+        let tensors = self.parse_tensor_dict()?;
+        self.consume(TokenType::Comma, "Expected ',' before file path")?;
+        let path = self.parse_string_literal("Expected file path string")?;
+        let dtype = if matches!(self.peek_next().map(|t| &t.token_type), Some(TokenType::StringLiteral(_))) {
+            self.parse_optional_dtype(
+                |name| crate::graph::SafetensorsDtype::from_name(name).is_some(),
+                "safetensors dtype", "F64, F32, F16, BF16, I64, I32, I16, I8, U64, U32, U16, U8 or BOOL",
+            )?
+        } else {
+            None
+        };
+        let metadata = if matches!(self.peek().token_type, TokenType::Comma) {
+            self.advance();
+            if !self.peek_contextual("metadata") {
+                return Err(NomaError::ParseError {
+                    message: "Expected a dtype string or 'metadata { key: \"value\" }'".to_string(),
+                    line: self.peek().line,
+                    column: self.peek().column,
+                });
+            }
+            self.advance();
+            self.parse_metadata_dict()?
+        } else {
+            Vec::new()
+        };
+        self.consume(TokenType::Semicolon, "Expected ';'")?;
+
+        Ok(Statement::SaveSafetensors { tensors, path, dtype, metadata })
+    }
+
+    /// Parse `{ key: "value", "other.key": "value" }` with unique keys
+    fn parse_metadata_dict(&mut self) -> Result<Vec<(String, String)>, NomaError> {
+        self.consume(TokenType::LBrace, "Expected '{' for metadata")?;
+        let mut entries: Vec<(String, String)> = Vec::new();
+        while !matches!(self.peek().token_type, TokenType::RBrace) {
+            let token = self.peek();
+            let key = match &token.token_type {
+                TokenType::StringLiteral(s) => {
+                    let s = s.clone();
+                    self.advance();
+                    s
+                }
+                _ => self.parse_identifier("Expected metadata key")?,
+            };
+            if entries.iter().any(|(k, _)| *k == key) {
+                return Err(NomaError::ParseError {
+                    message: format!("Duplicate metadata key '{}'", key),
+                    line: token.line,
+                    column: token.column,
+                });
+            }
+            self.consume(TokenType::Colon, "Expected ':' after metadata key")?;
+            let value = self.parse_string_literal("Expected metadata value string")?;
+            entries.push((key, value));
+            if !matches!(self.peek().token_type, TokenType::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        self.consume(TokenType::RBrace, "Expected '}'")?;
+        Ok(entries)
+    }
+
+    /// Parse 'load_npy' statement: load_npy name = "path.npy";
+    fn parse_load_npy(&mut self) -> Result<Statement, NomaError> {
+        self.consume(TokenType::LoadNpy, "Expected 'load_npy'")?;
+        let name = self.parse_identifier("Expected variable name")?;
+        self.consume(TokenType::Assign, "Expected '='")?;
+        let path = self.parse_string_literal("Expected file path string")?;
+        self.consume(TokenType::Semicolon, "Expected ';'")?;
+
+        Ok(Statement::LoadNpy { name, path })
+    }
+
+    /// Parse 'save_npy' statement: save_npy tensor, "path.npy"; with an optional dtype: , "<f4"
+    fn parse_save_npy(&mut self) -> Result<Statement, NomaError> {
+        self.consume(TokenType::SaveNpy, "Expected 'save_npy'")?;
+        let tensor = self.parse_expression()?;
+        self.consume(TokenType::Comma, "Expected ',' after tensor expression")?;
+        let path = self.parse_string_literal("Expected file path string")?;
+        let dtype = self.parse_optional_dtype(|d| crate::graph::NpyDtype::parse(d).is_some(), "NumPy dtype", NPY_DTYPES)?;
+        self.consume(TokenType::Semicolon, "Expected ';'")?;
+
+        Ok(Statement::SaveNpy { tensor, path, dtype })
+    }
+
+    /// Parse 'load_npz' statement: load_npz name = "path.npz";
+    fn parse_load_npz(&mut self) -> Result<Statement, NomaError> {
+        self.consume(TokenType::LoadNpz, "Expected 'load_npz'")?;
+        let name = self.parse_identifier("Expected variable name")?;
+        self.consume(TokenType::Assign, "Expected '='")?;
+        let path = self.parse_string_literal("Expected file path string")?;
+        self.consume(TokenType::Semicolon, "Expected ';'")?;
+
+        Ok(Statement::LoadNpz { name, path })
+    }
+
+    /// Parse 'save_npz' statement: save_npz { X: X, y: y }, "path.npz"; with an optional dtype
+    fn parse_save_npz(&mut self) -> Result<Statement, NomaError> {
+        self.consume(TokenType::SaveNpz, "Expected 'save_npz'")?;
+        let arrays = self.parse_tensor_dict()?;
+        self.consume(TokenType::Comma, "Expected ',' before file path")?;
+        let path = self.parse_string_literal("Expected file path string")?;
+        let dtype = self.parse_optional_dtype(|d| crate::graph::NpyDtype::parse(d).is_some(), "NumPy dtype", NPY_DTYPES)?;
+        self.consume(TokenType::Semicolon, "Expected ';'")?;
+
+        Ok(Statement::SaveNpz { arrays, path, dtype })
+    }
+
+    /// Parse '{ name1: tensor1, name2: tensor2 }'
+    fn parse_tensor_dict(&mut self) -> Result<Vec<(String, Expression)>, NomaError> {
         self.consume(TokenType::LBrace, "Expected '{' for tensor dictionary")?;
-        
+
         let mut tensors = Vec::new();
         if !matches!(self.peek().token_type, TokenType::RBrace) {
             loop {
@@ -450,33 +1462,77 @@ impl Parser {
         }
         
         self.consume(TokenType::RBrace, "Expected '}'")?;
-        self.consume(TokenType::Comma, "Expected ',' before file path")?;
-        let path = self.parse_string_literal("Expected file path string")?;
-        self.consume(TokenType::Semicolon, "Expected ';'")?;
-        
-        Ok(Statement::SaveSafetensors { tensors, path })
+        Ok(tensors)
     }
 
-    /// Parse 'batch' loop: batch item, index in data with batch_size { body }
-    /// or: batch item in data with batch_size { body }
+    /// Parse an optional `, "dtype"` after a file path, checked with `valid`
+    fn parse_optional_dtype(&mut self, valid: impl Fn(&str) -> bool, kind: &str, expected: &str) -> Result<Option<String>, NomaError> {
+        if !matches!(self.peek().token_type, TokenType::Comma) {
+            return Ok(None);
+        }
+        self.advance();
+        let token = self.peek();
+        let name = self.parse_string_literal("Expected dtype string")?;
+        if !valid(&name) {
+            return Err(NomaError::ParseError {
+                message: format!("Unknown {} '{}' (expected {})", kind, name, expected),
+                line: token.line,
+                column: token.column,
+            });
+        }
+        Ok(Some(name))
+    }
+
+    /// Parse 'batch' loop: batch item, index in data with batch_size { body }, or with several
+    /// sources: batch (x, y), i, epoch in (X, Y) with batch_size 32 shuffle drop_last epochs 10 { ... }
     fn parse_batch_loop(&mut self) -> Result<Statement, NomaError> {
         self.consume(TokenType::Batch, "Expected 'batch'")?;
-        
-        // Parse item name
-        let item_name = self.parse_identifier("Expected batch item name")?;
-        
-        // Check for optional index: batch item, index in ...
-        let index_name = if matches!(self.peek().token_type, TokenType::Comma) {
-            self.advance(); // consume comma
-            Some(self.parse_identifier("Expected batch index name")?)
+        let start = self.peek().clone();
+
+        // Item names: `item`, or `(x, y)` with one name per source
+        let paired = matches!(self.peek().token_type, TokenType::LParen);
+        let item_names = if paired {
+            self.advance(); // consume '('
+            let names = self.parse_name_list("Expected batch item name")?;
+            self.consume(TokenType::RParen, "Expected ')' after batch item names")?;
+            names
         } else {
-            None
+            vec![self.parse_identifier("Expected batch item name")?]
         };
-        
+
+        // Optional batch index and epoch: batch item, index, epoch in ...
+        let (mut index_name, mut epoch_name) = (None, None);
+        if matches!(self.peek().token_type, TokenType::Comma) {
+            self.advance(); // consume comma
+            index_name = Some(self.parse_identifier("Expected batch index name")?);
+            if matches!(self.peek().token_type, TokenType::Comma) {
+                self.advance(); // consume comma
+                epoch_name = Some(self.parse_identifier("Expected epoch index name")?);
+            }
+        }
+
         self.consume(TokenType::In, "Expected 'in' after batch variable(s)")?;
-        let data = self.parse_expression()?;
-        
-        // Parse 'with batch_size'
+        let data = if paired {
+            self.consume(TokenType::LParen, "Expected '(' before the batch sources")?;
+            let mut data = vec![self.parse_expression()?];
+            while matches!(self.peek().token_type, TokenType::Comma) {
+                self.advance(); // consume comma
+                data.push(self.parse_expression()?);
+            }
+            self.consume(TokenType::RParen, "Expected ')' after the batch sources")?;
+            data
+        } else {
+            vec![self.parse_expression()?]
+        };
+        if data.len() != item_names.len() {
+            return Err(NomaError::ParseError {
+                message: format!("batch binds {} names to {} sources", item_names.len(), data.len()),
+                line: start.line,
+                column: start.column,
+            });
+        }
+
+        // Parse 'with batch_size', where the word `batch_size` before the size is optional
         if !matches!(self.peek().token_type, TokenType::Identifier(ref s) if s == "with") {
             return Err(NomaError::ParseError {
                 message: "Expected 'with' for batch size".to_string(),
@@ -485,20 +1541,67 @@ impl Parser {
             });
         }
         self.advance(); // consume 'with'
+        // `with batch_size {` and `with batch_size shuffle` still read a variable called batch_size
+        let size_follows = match self.peek_next().map(|t| &t.token_type) {
+            Some(TokenType::Number(_) | TokenType::LParen) => true,
+            Some(TokenType::Identifier(s)) => !matches!(s.as_str(), "shuffle" | "drop_last" | "epochs"),
+            _ => false,
+        };
+        if self.peek_contextual("batch_size") && size_follows {
+            self.advance(); // consume 'batch_size'
+        }
         let batch_size = self.parse_expression()?;
+        let options = self.parse_batch_options()?;
         
         self.consume(TokenType::LBrace, "Expected '{' after batch header")?;
         let body = self.parse_block()?;
         
         Ok(Statement::BatchLoop {
-            item_name,
+            item_names,
             index_name,
+            epoch_name,
             data,
             batch_size,
+            options,
             body,
         })
     }
 
+    /// Parse `shuffle`, `shuffle(seed=7)`, `drop_last` and `epochs <expression>` after the batch size
+    fn parse_batch_options(&mut self) -> Result<BatchOptions, NomaError> {
+        let mut options = BatchOptions::default();
+        loop {
+            if self.peek_contextual("shuffle") {
+                self.advance(); // consume 'shuffle'
+                options.shuffle = true;
+                if matches!(self.peek().token_type, TokenType::LParen) {
+                    self.advance(); // consume '('
+                    self.consume(TokenType::Seed, "Expected 'seed=' in shuffle(...)")?;
+                    self.consume(TokenType::Assign, "Expected '=' after seed")?;
+                    let token = self.peek().clone();
+                    let seed = self.parse_number_literal()?;
+                    if seed < 0.0 || seed.fract() != 0.0 {
+                        return Err(NomaError::ParseError {
+                            message: "shuffle seed must be a non-negative integer".into(),
+                            line: token.line,
+                            column: token.column,
+                        });
+                    }
+                    options.seed = Some(seed as u64);
+                    self.consume(TokenType::RParen, "Expected ')' after shuffle seed")?;
+                }
+            } else if self.peek_contextual("drop_last") {
+                self.advance(); // consume 'drop_last'
+                options.drop_last = true;
+            } else if self.peek_contextual("epochs") {
+                self.advance(); // consume 'epochs'
+                options.epochs = Some(self.parse_expression()?);
+            } else {
+                return Ok(options);
+            }
+        }
+    }
+
     /// Helper to parse a string literal
     fn parse_string_literal(&mut self, error_msg: &str) -> Result<String, NomaError> {
         match &self.peek().token_type {
@@ -668,17 +1771,25 @@ impl Parser {
                 }
                 TokenType::LBracket => {
                     // Indexing: expr[ index ] ; allow chaining: expr[a][b]
+                    // and comma-separated indices/ranges: expr[a, 1:3, :]
                     self.advance(); // consume '['
-                    let index_expr = self.parse_expression()?;
+                    let mut index_exprs = vec![self.parse_index_entry()?];
+                    while matches!(self.peek().token_type, TokenType::Comma) {
+                        self.advance();
+                        index_exprs.push(self.parse_index_entry()?);
+                    }
                     self.consume(TokenType::RBracket, "Expected ']' after index expression")?;
 
-                    // If existing expr is already an Index, append; else create new
+                    // If existing expr is an Index of plain indices, append; else create new.
+                    // A slice keeps its axis, so a[1:3][0] indexes the slice rather than a
                     expr = match expr {
-                        Expression::Index { target, mut indices } => {
-                            indices.push(index_expr);
+                        Expression::Index { target, mut indices }
+                            if !indices.iter().any(|i| matches!(i, Expression::Range { .. })) =>
+                        {
+                            indices.extend(index_exprs);
                             Expression::Index { target, indices }
                         }
-                        other => Expression::Index { target: Box::new(other), indices: vec![index_expr] },
+                        other => Expression::Index { target: Box::new(other), indices: index_exprs },
                     };
                 }
                 TokenType::As => {
@@ -697,6 +1808,33 @@ impl Parser {
         Ok(expr)
     }
 
+    /// Parse a single entry inside index brackets: an expression or a range `start:end`
+    /// where either bound may be omitted (`:`, `1:`, `:3`)
+    fn parse_index_entry(&mut self) -> Result<Expression, NomaError> {
+        let start = if matches!(self.peek().token_type, TokenType::Colon) {
+            None
+        } else {
+            Some(self.parse_expression()?)
+        };
+
+        if !matches!(self.peek().token_type, TokenType::Colon) {
+            return start.ok_or_else(|| NomaError::ParseError {
+                message: "Expected index expression".to_string(),
+                line: self.peek().line,
+                column: self.peek().column,
+            });
+        }
+        self.advance(); // consume ':'
+
+        let end = if matches!(self.peek().token_type, TokenType::Comma | TokenType::RBracket) {
+            None
+        } else {
+            Some(Box::new(self.parse_expression()?))
+        };
+
+        Ok(Expression::Range { start: start.map(Box::new), end })
+    }
+
     fn parse_primary(&mut self) -> Result<Expression, NomaError> {
         match self.peek().token_type {
             TokenType::Tensor => {
@@ -713,7 +1851,21 @@ impl Parser {
                 Ok(Expression::StringLiteral(s))
             }
             TokenType::Identifier(ref name) => {
-                let name = name.clone();
+                let mut name = name.clone();
+                self.advance();
+                // Fields of optimize loop statistics (stats.iterations) and loaded tensors (model.W1)
+                while matches!(self.peek().token_type, TokenType::Dot) {
+                    self.advance(); // consume '.'
+                    name = format!("{}.{}", name, self.parse_identifier("Expected field name after '.'")?);
+                }
+                Ok(Expression::Identifier(name))
+            }
+            // load_safetensors("model.safetensors", "W1"), load_npy("x.npy") and load_npz("d.npz", "X")
+            // read one tensor while the graph is built
+            TokenType::LoadSafetensors | TokenType::LoadNpy | TokenType::LoadNpz
+                if matches!(self.peek_next().map(|t| &t.token_type), Some(TokenType::LParen)) =>
+            {
+                let name = self.peek().token_type.to_string();
                 self.advance();
                 Ok(Expression::Identifier(name))
             }
@@ -923,6 +2075,11 @@ impl Parser {
         }
     }
 
+    /// Contextual keywords such as `with` and `max_iter` stay ordinary identifiers elsewhere
+    fn peek_contextual(&self, word: &str) -> bool {
+        matches!(self.peek().token_type, TokenType::Identifier(ref s) if s == word)
+    }
+
     fn peek_next(&self) -> Option<&Token> {
         self.tokens.get(self.current + 1)
     }
@@ -20,14 +20,24 @@ pub enum TokenType {
     GpuStruct,   // gpu_struct
     Alloc,       // alloc
     Free,        // free
+    Freeze,      // freeze
+    Unfreeze,    // unfreeze
     Realloc,     // realloc
     ResetOptimizer, // reset_optimizer
+    Seed,        // seed
     
     // File I/O
     LoadCsv,     // load_csv
     SaveCsv,     // save_csv
     LoadSafetensors,  // load_safetensors
     SaveSafetensors,  // save_safetensors
+    LoadNpy,     // load_npy
+    SaveNpy,     // save_npy
+    LoadNpz,     // load_npz
+    SaveNpz,     // save_npz
+    Checkpoint,  // checkpoint
+    Resume,      // resume
+    Dataset,     // dataset
     
     // Batch processing
     Batch,       // batch
@@ -114,12 +124,22 @@ impl fmt::Display for TokenType {
             TokenType::GpuStruct => write!(f, "gpu_struct"),
             TokenType::Alloc => write!(f, "alloc"),
             TokenType::Free => write!(f, "free"),
+            TokenType::Freeze => write!(f, "freeze"),
+            TokenType::Unfreeze => write!(f, "unfreeze"),
             TokenType::Realloc => write!(f, "realloc"),
             TokenType::ResetOptimizer => write!(f, "reset_optimizer"),
+            TokenType::Seed => write!(f, "seed"),
             TokenType::LoadCsv => write!(f, "load_csv"),
             TokenType::SaveCsv => write!(f, "save_csv"),
             TokenType::LoadSafetensors => write!(f, "load_safetensors"),
             TokenType::SaveSafetensors => write!(f, "save_safetensors"),
+            TokenType::LoadNpy => write!(f, "load_npy"),
+            TokenType::SaveNpy => write!(f, "save_npy"),
+            TokenType::LoadNpz => write!(f, "load_npz"),
+            TokenType::SaveNpz => write!(f, "save_npz"),
+            TokenType::Checkpoint => write!(f, "checkpoint"),
+            TokenType::Resume => write!(f, "resume"),
+            TokenType::Dataset => write!(f, "dataset"),
             TokenType::Batch => write!(f, "batch"),
             TokenType::In => write!(f, "in"),
             TokenType::Tensor => write!(f, "tensor"),